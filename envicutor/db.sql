@@ -4,3 +4,84 @@ CREATE TABLE IF NOT EXISTS runtime (
     source_file_name VARCHAR(256) NOT NULL,
     created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
 );
+
+CREATE TABLE IF NOT EXISTS suite (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    runtime_id INTEGER NOT NULL,
+    name VARCHAR(256) NOT NULL,
+    revision INTEGER NOT NULL,
+    comparator VARCHAR(32) NOT NULL,
+    limits TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (runtime_id, name, revision)
+);
+
+CREATE TABLE IF NOT EXISTS suite_case (
+    suite_id INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    stdin TEXT NOT NULL,
+    expected_stdout TEXT NOT NULL,
+    points INTEGER NOT NULL,
+    expected_exit_code INTEGER,
+    PRIMARY KEY (suite_id, position)
+);
+
+CREATE TABLE IF NOT EXISTS runtime_alias (
+    alias VARCHAR(256) PRIMARY KEY,
+    runtime_id INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS box_quarantine (
+    box_id INTEGER PRIMARY KEY,
+    reason TEXT NOT NULL,
+    quarantined_at INTEGER NOT NULL,
+    failures INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS webhook_delivery (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    job_id INTEGER NOT NULL,
+    payload TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS webhook_dead_letter (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    job_id INTEGER NOT NULL,
+    payload TEXT NOT NULL,
+    payload_truncated BOOLEAN NOT NULL,
+    last_error TEXT NOT NULL,
+    attempts TEXT NOT NULL,
+    failed_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS execution (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    runtime_id INTEGER NOT NULL,
+    started_at INTEGER NOT NULL,
+    finished_at INTEGER NOT NULL,
+    limits TEXT,
+    exit_status TEXT,
+    exit_code INTEGER,
+    verdict TEXT,
+    cpu_time REAL,
+    wall_time REAL,
+    memory INTEGER,
+    stdout TEXT,
+    stderr TEXT,
+    sandbox TEXT,
+    stdout_sha256 TEXT,
+    stderr_sha256 TEXT
+);
+
+CREATE INDEX IF NOT EXISTS execution_runtime_id ON execution (runtime_id, id);
+CREATE INDEX IF NOT EXISTS execution_finished_at ON execution (finished_at);
+
+CREATE TABLE IF NOT EXISTS execution_request (
+    execution_id INTEGER PRIMARY KEY,
+    request TEXT NOT NULL,
+    runtime_fingerprint TEXT,
+    stored_at INTEGER NOT NULL
+);
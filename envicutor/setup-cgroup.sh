@@ -7,6 +7,6 @@ echo '+cpuset +cpu +io +memory +pids' > cgroup.subtree_control && \
 cd isolate && \
 mkdir init && \
 echo 1 > init/cgroup.procs && \
-echo '+cpuset +memory' > cgroup.subtree_control && \
+echo '+cpuset +cpu +memory' > cgroup.subtree_control && \
 echo "Initialized cgroup" && \
 exec su -- envicutor -c 'ulimit -n 65536 && exec /envicutor/setup-nix-and-db.sh'
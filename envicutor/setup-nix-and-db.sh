@@ -10,7 +10,18 @@ else
     echo /nix/store was found
 fi
 
-sqlite3 /envicutor/runtimes/runtimes.db < /envicutor/db.sql && \
+# Runtimes baked into a read-only image come with their database, the server's
+# own state goes to STATE_DIR then
+if [ -w /envicutor/runtimes ]; then
+    db=/envicutor/runtimes/runtimes.db
+elif [ -z "$STATE_DIR" ]; then
+    echo /envicutor/runtimes is read-only, STATE_DIR has to be set to a writable directory
+    exit 1
+else
+    db="$STATE_DIR/state.db"
+fi
+
+sqlite3 "$db" < /envicutor/db.sql && \
 echo "Initialized the database" && \
 cd /tmp # So PWD does not get leaked in env vars
 exec /envicutor/envicutor
@@ -0,0 +1,138 @@
+use std::{
+    fmt::Write,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+
+use crate::types::{Kilobytes, RuntimeId, Seconds};
+
+// Handlers attach it to their responses (successful or not) so that the
+// request's summary line carries what they know about its outcome
+#[derive(Clone, Default)]
+pub struct RequestOutcome {
+    pub runtime_id: Option<RuntimeId>,
+    pub error_code: Option<&'static str>,
+    pub verdict: Option<String>,
+    pub cpu_time: Option<Seconds>,
+    pub wall_time: Option<Seconds>,
+    pub memory: Option<Kilobytes>,
+    pub queue_wait: Option<Duration>,
+    pub box_id: Option<u64>,
+}
+
+impl RequestOutcome {
+    pub fn attach_to(self, mut res: Response<Body>) -> Response<Body> {
+        res.extensions_mut().insert(self);
+        res
+    }
+}
+
+struct Summary {
+    method: Method,
+    route: String,
+    status: StatusCode,
+    outcome: RequestOutcome,
+    started_at: Instant,
+}
+
+fn push_field(line: &mut String, key: &str, value: Option<impl std::fmt::Display>) {
+    if let Some(value) = value {
+        let _ = write!(line, " {key}={value}");
+    }
+}
+
+impl Summary {
+    fn line(&self) -> String {
+        let outcome = &self.outcome;
+        let mut line = format!(
+            "request method={} route={} status={} duration_ms={}",
+            self.method,
+            self.route,
+            self.status.as_u16(),
+            self.started_at.elapsed().as_millis()
+        );
+        push_field(&mut line, "error_code", outcome.error_code);
+        push_field(&mut line, "runtime_id", outcome.runtime_id);
+        push_field(&mut line, "verdict", outcome.verdict.as_ref());
+        push_field(&mut line, "cpu_time", outcome.cpu_time);
+        push_field(&mut line, "wall_time", outcome.wall_time);
+        push_field(&mut line, "memory", outcome.memory);
+        push_field(
+            &mut line,
+            "queue_wait_ms",
+            outcome.queue_wait.map(|wait| wait.as_millis()),
+        );
+        push_field(&mut line, "box_id", outcome.box_id);
+        line
+    }
+}
+
+// Logged when the response body is dropped, which is once it is fully sent
+// (or the client went away), so streamed responses are logged at their end
+impl Drop for Summary {
+    fn drop(&mut self) {
+        eprintln!("{}", self.line());
+    }
+}
+
+struct LoggedBody {
+    inner: Body,
+    _summary: Summary,
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+pub async fn log_request(req: Request, next: Next) -> Response<Body> {
+    let started_at = Instant::now();
+    let method = req.method().clone();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+
+    let res = next.run(req).await;
+    let (mut parts, body) = res.into_parts();
+    let summary = Summary {
+        method,
+        route,
+        status: parts.status,
+        outcome: parts.extensions.remove().unwrap_or_default(),
+        started_at,
+    };
+    Response::from_parts(
+        parts,
+        Body::new(LoggedBody {
+            inner: body,
+            _summary: summary,
+        }),
+    )
+}
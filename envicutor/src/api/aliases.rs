@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, task};
+
+use crate::{
+    api::{
+        common_responses::{Message, StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
+        json_body::JsonBody,
+        validation::Violations,
+    },
+    globals::DB_PATH,
+    request_limits::{check_count, RequestLimits},
+    strings::{is_valid_name, MAX_NAME_LENGTH},
+    types::{Metadata, RuntimeId},
+};
+
+#[derive(Deserialize)]
+pub struct UpdateAliasesRequest {
+    aliases: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct AliasesResponse {
+    id: RuntimeId,
+    name: String,
+    aliases: Vec<String>,
+}
+
+pub fn check_aliases(
+    aliases: &[String],
+    name: &str,
+    request_limits: &RequestLimits,
+    violations: &mut Violations,
+) {
+    if let Err(e) = check_count("aliases", aliases.len(), request_limits.max_aliases) {
+        violations.add("aliases", e.to_string());
+        return;
+    }
+    if aliases.iter().any(|alias| !is_valid_name(alias)) {
+        violations.add(
+            "aliases",
+            format!("Aliases must have between 1 and {MAX_NAME_LENGTH} printable ASCII characters"),
+        );
+    }
+    for (i, alias) in aliases.iter().enumerate() {
+        if alias == name || aliases[..i].contains(alias) {
+            violations.add("aliases", format!("Duplicate alias: {alias}"));
+        }
+    }
+}
+
+pub fn validate_aliases(
+    aliases: &[String],
+    name: &str,
+    request_limits: &RequestLimits,
+) -> Result<(), Response<Body>> {
+    let mut violations = Violations::default();
+    check_aliases(aliases, name, request_limits, &mut violations);
+    violations.into_result()
+}
+
+// Names and aliases are unique across every runtime, `owner` may keep its own
+pub fn find_conflict<'a>(
+    metadata: &Metadata,
+    names: impl IntoIterator<Item = &'a String>,
+    owner: Option<RuntimeId>,
+) -> Result<(), Response<Body>> {
+    for name in names {
+        if let Some((id, runtime)) = metadata.find_by_name(name) {
+            if Some(id) != owner {
+                return Err((
+                    StatusCode::CONFLICT,
+                    Json(Message {
+                        message: format!(
+                            "{name} is already used by runtime {id}: {}",
+                            runtime.display_name()
+                        ),
+                    }),
+                )
+                    .into_response());
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn replace_aliases(
+    connection: &mut Connection,
+    runtime_id: RuntimeId,
+    aliases: &[String],
+) -> Result<(), rusqlite::Error> {
+    let trx = connection.transaction()?;
+    trx.execute(
+        "DELETE FROM runtime_alias WHERE runtime_id = ?",
+        [runtime_id],
+    )?;
+    for alias in aliases {
+        trx.execute(
+            "INSERT INTO runtime_alias (alias, runtime_id) VALUES (?, ?)",
+            (alias, runtime_id),
+        )?;
+    }
+    trx.commit()
+}
+
+pub async fn update_aliases(
+    Path(id): Path<RuntimeId>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    request_limits: RequestLimits,
+    JsonBody(req): JsonBody<UpdateAliasesRequest>,
+) -> Result<Response<Body>, Response<Body>> {
+    // Installations check names and aliases before storing theirs
+    let _permit = installation_lock.write().await;
+    let mut metadata_guard = metadata_cache.write().await;
+    let Some(runtime) = metadata_guard.get(&id) else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(StaticMessage {
+                message: "Could not find the specified runtime",
+            }),
+        )
+            .into_response());
+    };
+    validate_aliases(&req.aliases, &runtime.name, &request_limits)?;
+    find_conflict(&metadata_guard, &req.aliases, Some(id))?;
+
+    let (res, aliases) = task::spawn_blocking(move || {
+        let res = Connection::open(DB_PATH)
+            .and_then(|mut connection| replace_aliases(&mut connection, id, &req.aliases));
+        (res, req.aliases)
+    })
+    .await
+    .map_err(|e| {
+        eprintln!("Failed to spawn blocking task: {e}");
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    })?;
+    res.map_err(|e| {
+        eprintln!("Failed to update the aliases of runtime {id}: {e}");
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    })?;
+
+    metadata_guard.set_aliases(id, aliases);
+    let Some(runtime) = metadata_guard.get(&id) else {
+        return Err(INTERNAL_SERVER_ERROR_RESPONSE.into_response());
+    };
+    Ok(Json(AliasesResponse {
+        id,
+        name: runtime.display_name(),
+        aliases: runtime.aliases.clone(),
+    })
+    .into_response())
+}
@@ -0,0 +1,134 @@
+use std::sync::{atomic::AtomicU64, Arc};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    api::{
+        common_responses::Message,
+        installation::install_runtime,
+        json_body::{JsonBody, JsonBodyOrDefault},
+    },
+    events::Events,
+    examples::get_examples,
+    request_limits::RequestLimits,
+    types::{Metadata, WholeSeconds},
+};
+
+#[derive(Deserialize, Default)]
+pub struct BootstrapRequest {
+    #[serde(default)]
+    only: Vec<String>,
+}
+
+#[derive(Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BootstrapStatus {
+    Installed,
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize)]
+pub struct BootstrapResult {
+    pub example: &'static str,
+    pub name: String,
+    pub status: BootstrapStatus,
+    pub response: Option<serde_json::Value>,
+}
+
+pub async fn bootstrap_runtimes(
+    only: &[String],
+    installation_timeout: WholeSeconds,
+    box_id: Arc<AtomicU64>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    events: Events,
+    request_limits: RequestLimits,
+) -> Result<Vec<BootstrapResult>, anyhow::Error> {
+    let mut results = Vec::new();
+    for example in get_examples(only)? {
+        let req = example.request()?;
+        let name = req.name.clone();
+
+        let metadata_guard = metadata_cache.read().await;
+        let exists = metadata_guard.values().any(|runtime| runtime.name == name);
+        drop(metadata_guard);
+        if exists {
+            eprintln!("Skipping example {}: {name} is already installed", example.name);
+            results.push(BootstrapResult {
+                example: example.name,
+                name,
+                status: BootstrapStatus::Skipped,
+                response: None,
+            });
+            continue;
+        }
+
+        eprintln!("Installing example {}: {name}", example.name);
+        let res = match install_runtime(
+            installation_timeout,
+            box_id.clone(),
+            metadata_cache.clone(),
+            installation_lock.clone(),
+            events.clone(),
+            request_limits,
+            JsonBody(req),
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(res) => res,
+        };
+        let status = if res.status() == StatusCode::OK {
+            BootstrapStatus::Installed
+        } else {
+            BootstrapStatus::Failed
+        };
+        let body = to_bytes(res.into_body(), usize::MAX).await.ok();
+        results.push(BootstrapResult {
+            example: example.name,
+            name,
+            status,
+            response: body.and_then(|body| serde_json::from_slice(&body).ok()),
+        });
+    }
+    Ok(results)
+}
+
+pub async fn bootstrap(
+    installation_timeout: WholeSeconds,
+    box_id: Arc<AtomicU64>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    events: Events,
+    request_limits: RequestLimits,
+    JsonBodyOrDefault(req): JsonBodyOrDefault<BootstrapRequest>,
+) -> Result<Response<Body>, Response<Body>> {
+    let results = bootstrap_runtimes(
+        &req.only,
+        installation_timeout,
+        box_id,
+        metadata_cache,
+        installation_lock,
+        events,
+        request_limits,
+    )
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(Message {
+                message: e.to_string(),
+            }),
+        )
+            .into_response()
+    })?;
+    Ok(Json(results).into_response())
+}
@@ -0,0 +1,34 @@
+use axum::{
+    body::Body,
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{
+    api::common_responses::{StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
+    quarantine::{self, QuarantineEntry},
+};
+
+pub async fn list_quarantined_boxes() -> Json<Vec<QuarantineEntry>> {
+    Json(quarantine::entries())
+}
+
+// For boxes fixed by hand, the others are tried again on every garbage collection
+pub async fn clear_quarantined_box(Path(box_id): Path<u64>) -> Result<(), Response<Body>> {
+    let cleared = quarantine::clear(box_id).await.map_err(|e| {
+        eprintln!("Failed to clear the quarantine of box {box_id}: {e}");
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    })?;
+    if !cleared {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(StaticMessage {
+                message: "This box is not quarantined",
+            }),
+        )
+            .into_response());
+    }
+    Ok(())
+}
@@ -1,10 +1,82 @@
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
 };
 
-use crate::globals::MAX_BOX_ID;
+use anyhow::{anyhow, Error};
+use axum::{
+    body::Body,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    api::common_responses::{INSUFFICIENT_STORAGE_RESPONSE, INTERNAL_SERVER_ERROR_RESPONSE},
+    globals::FIRST_CLI_BOX_ID,
+    host::{self, HostMonitor},
+    quarantine,
+};
+
+// Set once at startup, the server's boxes unless a CLI subcommand runs
+static BOX_IDS: OnceLock<Range<u64>> = OnceLock::new();
+
+pub fn use_box_ids(ids: Range<u64>) -> Result<(), Error> {
+    BOX_IDS
+        .set(ids)
+        .map_err(|_| anyhow!("The box ids were already set"))
+}
+
+fn next_box_id_in(ids: &Range<u64>, box_id: &AtomicU64) -> u64 {
+    let count = ids.end - ids.start;
+    let mut next = ids.start + box_id.fetch_add(1, Ordering::SeqCst) % count;
+    for _ in 0..count {
+        if !quarantine::is_quarantined(next) {
+            break;
+        }
+        next = ids.start + box_id.fetch_add(1, Ordering::SeqCst) % count;
+    }
+    next
+}
 
+// Skips the quarantined box ids, unless every one is
 pub fn get_next_box_id(box_id: &Arc<AtomicU64>) -> u64 {
-    box_id.fetch_add(1, Ordering::SeqCst) % MAX_BOX_ID
+    next_box_id_in(BOX_IDS.get().unwrap_or(&(0..FIRST_CLI_BOX_ID)), box_id)
+}
+
+// For failed writes, a full disk is reported so that later writes are refused early
+pub fn internal_error(host_monitor: &HostMonitor, message: String) -> Response<Body> {
+    eprintln!("{message}");
+    if host::is_disk_full_error(&message) {
+        host_monitor.report_disk_full();
+        INSUFFICIENT_STORAGE_RESPONSE.into_response()
+    } else {
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::globals::MAX_BOX_ID;
+
+    #[test]
+    fn box_ids_wrap_around_within_their_range() {
+        let box_id = AtomicU64::new(0);
+        let cli_ids = FIRST_CLI_BOX_ID..MAX_BOX_ID;
+        let given: Vec<u64> = (0..2 * (MAX_BOX_ID - FIRST_CLI_BOX_ID))
+            .map(|_| next_box_id_in(&cli_ids, &box_id))
+            .collect();
+        assert_eq!(given[0], FIRST_CLI_BOX_ID);
+        assert!(given.iter().all(|id| cli_ids.contains(id)));
+        assert_eq!(given[cli_ids.clone().count()], FIRST_CLI_BOX_ID);
+
+        let box_id = AtomicU64::new(FIRST_CLI_BOX_ID - 1);
+        assert_eq!(
+            next_box_id_in(&(0..FIRST_CLI_BOX_ID), &box_id),
+            FIRST_CLI_BOX_ID - 1
+        );
+        assert_eq!(next_box_id_in(&(0..FIRST_CLI_BOX_ID), &box_id), 0);
+    }
 }
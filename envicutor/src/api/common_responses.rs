@@ -17,3 +17,18 @@ pub const INTERNAL_SERVER_ERROR_RESPONSE: (StatusCode, Json<StaticMessage>) = (
         message: "Internal server error",
     }),
 );
+
+pub const INSUFFICIENT_STORAGE_RESPONSE: (StatusCode, Json<StaticMessage>) = (
+    StatusCode::INSUFFICIENT_STORAGE,
+    Json(StaticMessage {
+        message: "The disk is full, try again once space is freed",
+    }),
+);
+
+// isolate kept failing by itself, the submission is not to blame
+pub const SANDBOX_ERROR_RESPONSE: (StatusCode, Json<StaticMessage>) = (
+    StatusCode::BAD_GATEWAY,
+    Json(StaticMessage {
+        message: "Sandbox error, the sandbox failed to run the submission",
+    }),
+);
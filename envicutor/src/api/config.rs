@@ -0,0 +1,16 @@
+use std::sync::Arc;
+
+use axum::{response::IntoResponse, Json};
+use serde::Serialize;
+
+use crate::sandbox_env::SandboxEnv;
+
+// What submissions can rely on without knowing how the server is deployed
+#[derive(Serialize, Clone)]
+pub struct PublicConfig {
+    pub default_sandbox_env: SandboxEnv,
+}
+
+pub async fn get_public_config(config: Arc<PublicConfig>) -> impl IntoResponse {
+    Json(config.as_ref().clone())
+}
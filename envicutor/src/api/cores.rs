@@ -0,0 +1,37 @@
+use axum::{
+    body::Body,
+    extract::Path,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{
+    api::common_responses::{StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
+    core_dumps,
+};
+
+const NO_CORE_RESPONSE: (StatusCode, Json<StaticMessage>) = (
+    StatusCode::NOT_FOUND,
+    Json(StaticMessage {
+        message: "No core with this id, it may have expired",
+    }),
+);
+
+pub async fn get_core(Path(id): Path<String>) -> Result<Response<Body>, Response<Body>> {
+    if !core_dumps::is_valid_id(&id) {
+        return Err(NO_CORE_RESPONSE.into_response());
+    }
+    let content = core_dumps::read(&id)
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to read core {id}: {e}");
+            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+        })?
+        .ok_or_else(|| NO_CORE_RESPONSE.into_response())?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        content,
+    )
+        .into_response())
+}
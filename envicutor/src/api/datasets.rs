@@ -0,0 +1,85 @@
+use axum::{
+    body::Body,
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{
+    api::common_responses::{Message, StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
+    datasets::{self, Dataset, Upload},
+};
+
+fn check_name(name: &str) -> Result<(), Response<Body>> {
+    if datasets::is_valid_name(name) {
+        return Ok(());
+    }
+    Err((
+        StatusCode::BAD_REQUEST,
+        Json(Message {
+            message: format!(
+                "Invalid dataset name: {name}, expected letters, digits, '.', '_' and '-', not starting with '.'"
+            ),
+        }),
+    )
+        .into_response())
+}
+
+// The body is the dataset as is, streamed to disk rather than buffered
+pub async fn upload_dataset(
+    Path(name): Path<String>,
+    body: Body,
+) -> Result<Json<Dataset>, Response<Body>> {
+    check_name(&name)?;
+    let upload = datasets::store(&name, body.into_data_stream())
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to store dataset {name}: {e}");
+            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+        })?;
+    match upload {
+        Upload::Stored(dataset) => Ok(Json(dataset)),
+        Upload::TooLarge => Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(StaticMessage {
+                message: "The dataset is larger than MAX_DATASET_SIZE",
+            }),
+        )
+            .into_response()),
+        Upload::TimedOut => Err((
+            StatusCode::REQUEST_TIMEOUT,
+            Json(StaticMessage {
+                message: "Timed out reading the dataset",
+            }),
+        )
+            .into_response()),
+    }
+}
+
+pub async fn list_datasets() -> Result<Json<Vec<Dataset>>, Response<Body>> {
+    let datasets = datasets::list().await.map_err(|e| {
+        eprintln!("Failed to list datasets: {e}");
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    })?;
+    Ok(Json(datasets))
+}
+
+// Executions that already started keep their copy of the dataset
+pub async fn delete_dataset(Path(name): Path<String>) -> Result<StatusCode, Response<Body>> {
+    check_name(&name)?;
+    let deleted = datasets::delete(&name).await.map_err(|e| {
+        eprintln!("Failed to delete dataset {name}: {e}");
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    })?;
+    if !deleted {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(StaticMessage {
+                message: "No dataset with this name",
+            }),
+        )
+            .into_response());
+    }
+    Ok(StatusCode::OK)
+}
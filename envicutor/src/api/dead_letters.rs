@@ -0,0 +1,45 @@
+use axum::{
+    body::Body,
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{
+    api::common_responses::{StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
+    callbacks::{self, DeadLetter, Redelivery},
+};
+
+pub async fn list_dead_letters() -> Result<Json<Vec<DeadLetter>>, Response<Body>> {
+    let dead_letters = callbacks::dead_letters().await.map_err(|e| {
+        eprintln!("Failed to list dead letters: {e}");
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    })?;
+    Ok(Json(dead_letters))
+}
+
+// The delivery is retried in the background, like the first time
+pub async fn redeliver_dead_letter(Path(id): Path<i64>) -> Result<StatusCode, Response<Body>> {
+    let redelivery = callbacks::redeliver(id).await.map_err(|e| {
+        eprintln!("Failed to redeliver dead letter {id}: {e}");
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    })?;
+    match redelivery {
+        Redelivery::Started => Ok(StatusCode::ACCEPTED),
+        Redelivery::NotFound => Err((
+            StatusCode::NOT_FOUND,
+            Json(StaticMessage {
+                message: "No dead letter with this id",
+            }),
+        )
+            .into_response()),
+        Redelivery::PayloadTruncated => Err((
+            StatusCode::CONFLICT,
+            Json(StaticMessage {
+                message: "The payload of this dead letter was truncated, it can't be redelivered",
+            }),
+        )
+            .into_response()),
+    }
+}
@@ -1,4 +1,6 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Error};
 
 use axum::{
     body::Body,
@@ -8,34 +10,98 @@ use axum::{
     Json,
 };
 use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
 use tokio::{sync::RwLock, task};
 
 use crate::{
-    api::common_responses::{StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
+    api::{
+        common_responses::{Message, StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
+        json_body::JsonBody,
+    },
+    events::{Event, Events},
     globals::DB_PATH,
-    types::Metadata,
+    history, storage,
+    types::{Metadata, RuntimeId},
 };
 
-pub async fn delete_runtime(
-    Path(id): Path<u32>,
-    metadata_cache: Arc<RwLock<Metadata>>,
+// Runtimes deleted by one bulk deletion unless its confirm_count says otherwise
+const MAX_BULK_DELETIONS: usize = 10;
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+// Every given filter has to match
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BulkDeletionRequest {
+    name_prefix: Option<String>,
+    // By the execution history, runtimes with no recorded execution are unused
+    // once they were installed that long ago
+    unused_for_days: Option<u32>,
+    // Nothing is deleted unless it is set to false
+    #[serde(default = "default_dry_run")]
+    dry_run: bool,
+    // The number of runtimes the filters match, needed to delete more than
+    // MAX_BULK_DELETIONS at once
+    confirm_count: Option<usize>,
+}
+
+fn default_dry_run() -> bool {
+    true
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionOutcome {
+    WouldDelete,
+    Deleted,
+    NotFound,
+    Failed,
+}
+
+#[derive(Serialize)]
+pub struct BulkDeletedRuntime {
+    id: RuntimeId,
+    name: String,
+    outcome: DeletionOutcome,
+}
+
+#[derive(Serialize)]
+pub struct BulkDeletionResponse {
+    dry_run: bool,
+    runtimes: Vec<BulkDeletedRuntime>,
+}
+
+// The runtime goes with its suites and aliases or not at all, so that no orphan
+// blocks reusing their names
+fn delete_runtime_rows(
+    connection: &mut Connection,
+    id: RuntimeId,
+) -> Result<usize, rusqlite::Error> {
+    let trx = connection.transaction()?;
+    let affected_rows = trx.execute("DELETE FROM runtime WHERE id = ?", [id])?;
+    trx.execute(
+        "DELETE FROM suite_case WHERE suite_id IN (SELECT id FROM suite WHERE runtime_id = ?)",
+        [id],
+    )?;
+    trx.execute("DELETE FROM suite WHERE runtime_id = ?", [id])?;
+    trx.execute("DELETE FROM runtime_alias WHERE runtime_id = ?", [id])?;
+    trx.commit()?;
+    Ok(affected_rows)
+}
+
+async fn delete_by_id(
+    id: RuntimeId,
+    metadata_cache: &RwLock<Metadata>,
+    events: &Events,
 ) -> Result<(), Response<Body>> {
     let affected_rows = task::spawn_blocking(move || {
-        let conn = Connection::open(DB_PATH).map_err(|e| {
+        let mut conn = Connection::open(DB_PATH).map_err(|e| {
             eprintln!("Failed to open SQLite connection: {e}");
             INTERNAL_SERVER_ERROR_RESPONSE.into_response()
         })?;
-        let mut stmt = conn
-            .prepare("DELETE FROM runtime WHERE id = ?")
-            .map_err(|e| {
-                eprintln!("Failed to open SQLite connection: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-            })?;
-        let affected_rows = stmt.execute([id]).map_err(|e| {
-            eprintln!("Failed to prepare SQLite statement: {e}");
+        delete_runtime_rows(&mut conn, id).map_err(|e| {
+            eprintln!("Failed to delete runtime {id} from the database: {e}");
             INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-        })?;
-        Ok(affected_rows)
+        })
     })
     .await
     .map_err(|e| {
@@ -53,5 +119,225 @@ pub async fn delete_runtime(
     }
     let mut metadata_guard = metadata_cache.write().await;
     metadata_guard.remove(&id);
+    events.publish(Event::RuntimeDeleted { id });
     Ok(())
 }
+
+pub async fn delete_runtime(
+    Path(id): Path<RuntimeId>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    events: Events,
+) -> Result<(), Response<Body>> {
+    delete_by_id(id, &metadata_cache, &events).await
+}
+
+// When each runtime was installed, in milliseconds since the epoch
+async fn installed_at_by_runtime() -> Result<HashMap<RuntimeId, u64>, Error> {
+    task::spawn_blocking(move || {
+        let connection = storage::open_runtimes_db()?;
+        let mut stmt = connection.prepare(
+            "SELECT id, CAST(strftime('%s', created_at) AS INTEGER) * 1000 FROM runtime WHERE created_at IS NOT NULL",
+        )?;
+        let installed_at = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        Ok(installed_at)
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+}
+
+// A runtime is used by its executions and by its installation, whichever was last
+fn is_unused(last_finished: Option<u64>, installed_at: Option<u64>, unused_since: u64) -> bool {
+    last_finished
+        .max(installed_at)
+        .map_or(true, |last_used| last_used < unused_since)
+}
+
+fn bad_request(message: String) -> Response<Body> {
+    (StatusCode::BAD_REQUEST, Json(Message { message })).into_response()
+}
+
+// Deletes every runtime matching the filters, one at a time as DELETE
+// /runtimes/:id would, or only lists them on a dry run
+pub async fn delete_runtimes(
+    metadata_cache: Arc<RwLock<Metadata>>,
+    events: Events,
+    JsonBody(req): JsonBody<BulkDeletionRequest>,
+) -> Result<Json<BulkDeletionResponse>, Response<Body>> {
+    if req.name_prefix.is_none() && req.unused_for_days.is_none() {
+        return Err(bad_request(
+            "At least one of name_prefix and unused_for_days has to be given".to_string(),
+        ));
+    }
+    let last_used = match req.unused_for_days {
+        Some(_) if !history::is_enabled() => {
+            return Err(bad_request(
+                "unused_for_days needs the execution history, set EXECUTION_HISTORY to keep it"
+                    .to_string(),
+            ))
+        }
+        Some(days) if !history::covers(Duration::from_millis(u64::from(days) * MILLIS_PER_DAY)) => {
+            return Err(bad_request(format!(
+                "The execution history does not go back {days} days, EXECUTION_HISTORY_MAX_AGE or EXECUTION_HISTORY_MAX_ROWS prune it sooner"
+            )))
+        }
+        Some(_) => {
+            let last_finished = history::last_finished_by_runtime().await.map_err(|e| {
+                eprintln!("Failed to read when runtimes were last used: {e}");
+                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+            })?;
+            let installed_at = installed_at_by_runtime().await.map_err(|e| {
+                eprintln!("Failed to read when runtimes were installed: {e}");
+                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+            })?;
+            Some((last_finished, installed_at))
+        }
+        None => None,
+    };
+    let unused_since = req
+        .unused_for_days
+        .map(|days| history::now_millis().saturating_sub(u64::from(days) * MILLIS_PER_DAY));
+
+    let mut matching: Vec<(RuntimeId, String)> = metadata_cache
+        .read()
+        .await
+        .iter()
+        .filter(|(_, runtime)| {
+            req.name_prefix
+                .as_ref()
+                .map_or(true, |prefix| runtime.name.starts_with(prefix.as_str()))
+        })
+        .filter(|(id, _)| match (&last_used, unused_since) {
+            (Some((last_finished, installed_at)), Some(unused_since)) => is_unused(
+                last_finished.get(id).copied(),
+                installed_at.get(id).copied(),
+                unused_since,
+            ),
+            _ => true,
+        })
+        .map(|(id, runtime)| (*id, runtime.name.clone()))
+        .collect();
+    matching.sort_by_key(|(id, _)| *id);
+
+    if req.dry_run {
+        return Ok(Json(BulkDeletionResponse {
+            dry_run: true,
+            runtimes: matching
+                .into_iter()
+                .map(|(id, name)| BulkDeletedRuntime {
+                    id,
+                    name,
+                    outcome: DeletionOutcome::WouldDelete,
+                })
+                .collect(),
+        }));
+    }
+    if matching.len() > MAX_BULK_DELETIONS && req.confirm_count != Some(matching.len()) {
+        return Err(bad_request(format!(
+            "The filters match {} runtimes, more than the {MAX_BULK_DELETIONS} deleted at once, pass confirm_count: {} to delete them",
+            matching.len(),
+            matching.len()
+        )));
+    }
+
+    let mut runtimes = Vec::with_capacity(matching.len());
+    for (id, name) in matching {
+        let outcome = match delete_by_id(id, &metadata_cache, &events).await {
+            Ok(()) => DeletionOutcome::Deleted,
+            Err(res) if res.status() == StatusCode::NOT_FOUND => DeletionOutcome::NotFound,
+            Err(_) => DeletionOutcome::Failed,
+        };
+        runtimes.push(BulkDeletedRuntime { id, name, outcome });
+    }
+    Ok(Json(BulkDeletionResponse {
+        dry_run: false,
+        runtimes,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database_with_runtime(id: RuntimeId) -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(include_str!("../../db.sql"))
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO runtime (id, name, source_file_name) VALUES (?, 'Python 3', 'main.py')",
+                [id],
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO suite (id, runtime_id, name, revision, comparator) VALUES (7, ?, 'sum', 1, 'exact')",
+                [id],
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO suite_case (suite_id, position, stdin, expected_stdout, points) VALUES (7, 0, '1 2', '3', 1)",
+                [],
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO runtime_alias (alias, runtime_id) VALUES ('py', ?)",
+                [id],
+            )
+            .unwrap();
+        connection
+    }
+
+    fn count(connection: &Connection, table: &str) -> i64 {
+        connection
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn a_runtime_is_deleted_with_its_suites_and_aliases() {
+        let mut connection = database_with_runtime(3);
+        assert_eq!(delete_runtime_rows(&mut connection, 3).unwrap(), 1);
+        for table in ["runtime", "suite", "suite_case", "runtime_alias"] {
+            assert_eq!(count(&connection, table), 0, "{table}");
+        }
+        assert_eq!(delete_runtime_rows(&mut connection, 3).unwrap(), 0);
+    }
+
+    #[test]
+    fn nothing_is_deleted_when_a_statement_fails() {
+        let mut connection = database_with_runtime(3);
+        connection.execute("DROP TABLE runtime_alias", []).unwrap();
+        assert!(delete_runtime_rows(&mut connection, 3).is_err());
+        for table in ["runtime", "suite", "suite_case"] {
+            assert_eq!(count(&connection, table), 1, "{table}");
+        }
+    }
+
+    const DAY: u64 = MILLIS_PER_DAY;
+
+    #[test]
+    fn a_freshly_installed_runtime_is_not_unused() {
+        let now = 100 * DAY;
+        let unused_since = now - 30 * DAY;
+        assert!(!is_unused(None, Some(now - 60_000), unused_since));
+        assert!(!is_unused(None, Some(unused_since), unused_since));
+        assert!(is_unused(None, Some(unused_since - 1), unused_since));
+    }
+
+    #[test]
+    fn the_last_execution_counts_when_it_is_more_recent() {
+        let unused_since = 70 * DAY;
+        assert!(!is_unused(Some(80 * DAY), Some(DAY), unused_since));
+        assert!(is_unused(Some(60 * DAY), Some(DAY), unused_since));
+        // Runtimes installed before installations were timestamped
+        assert!(is_unused(None, None, unused_since));
+        assert!(!is_unused(Some(80 * DAY), None, unused_since));
+    }
+}
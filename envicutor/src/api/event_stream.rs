@@ -0,0 +1,28 @@
+use std::convert::Infallible;
+
+use axum::response::sse::{self, KeepAlive, Sse};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
+};
+
+use crate::events::Events;
+
+pub async fn stream_events(
+    events: Events,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let stream = BroadcastStream::new(events.subscribe()).filter_map(|event| match event {
+        Ok(event) => match sse::Event::default().json_data(&event) {
+            Ok(sse_event) => Some(Ok(sse_event)),
+            Err(e) => {
+                eprintln!("Failed to serialize event: {e}");
+                None
+            }
+        },
+        Err(BroadcastStreamRecvError::Lagged(missed)) => {
+            eprintln!("An event stream subscriber lagged behind and missed {missed} events");
+            None
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
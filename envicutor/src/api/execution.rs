@@ -1,137 +1,1856 @@
-use std::sync::{atomic::AtomicU64, Arc};
+use std::{
+    convert::Infallible,
+    path::{Component, Path},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Error};
 use axum::{
     body::Body,
     extract::Query,
     http::StatusCode,
-    response::{IntoResponse, Response},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     Json,
 };
 use base64::{prelude::BASE64_STANDARD, Engine};
 use serde::{Deserialize, Serialize};
 use tokio::{
     fs,
-    sync::{RwLock, Semaphore},
-    task,
+    sync::{mpsc, mpsc::UnboundedSender, RwLock},
+    task::{self, JoinHandle, JoinSet},
 };
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream, StreamExt};
 
 use crate::{
-    api::common_functions::get_next_box_id,
-    api::common_responses::{Message, INTERNAL_SERVER_ERROR_RESPONSE},
+    access_log::RequestOutcome,
+    api::common_functions::{get_next_box_id, internal_error},
+    api::common_responses::{
+        Message, StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE, SANDBOX_ERROR_RESPONSE,
+    },
+    api::json_body::JsonBody,
+    api::piston::{self, ResponseDialect},
+    api::validation::Violations,
+    coalescing::{self, FailedExecution, Role},
+    compile_cache::{self, CacheResult},
+    core_dumps::{self, CoreDump},
+    datasets::{self, Staging, MAX_DATASETS_PER_EXECUTION},
+    encoding::{self, Bom},
+    events::{Event, Events},
+    fs::BoxFile,
     globals::RUNTIMES_DIR,
-    isolate::{Isolate, StageResult},
-    limits::{GetLimits, Limits, SystemLimits},
+    health::{self, HealthState},
+    history,
+    hooks::{self, PreExecution},
+    host::HostMonitor,
+    isolate::{
+        self, IdleOutputTimeout, Isolate, OutputSink, OutputStream, RunOptions, StageResult,
+    },
+    leases::{ExecutionSlots, QueueFull},
+    limits::{LimitSet, Limits, LimitsResolution, MandatoryLimits, SystemLimits},
+    output::OutputEncoding,
+    priority::{self, Priority},
+    request_limits::check_count,
+    sandbox_env::{self, SandboxEnv},
+    sandbox_info::{self, SandboxInfo},
+    stage::{self, StageId, StageName},
     strings::NewLine,
-    types::Metadata,
+    test_data::{self, FetchBudget},
+    types::{Kilobytes, Metadata, RuntimeId, Seconds},
+    verdict::{self, Comparator, Verdict},
+    verification,
 };
 
 const SOURCE_ZIP_NAME: &str = "source.zip";
+// Submitted files live here and it is the working directory of every stage,
+// the runtime directory is mounted read-only at /runtime
+const SUBMISSION_DIR: &str = "/box/submission";
+
+// Constant on purpose: the environment and the file name only ever reach the stage
+// through the mounted snapshot and positional arguments, never through this string.
+// The time the program starts at is written to isolate::ACTIVATION_MARKER_FILE,
+// in the working directory whatever the environment does. The request's variables,
+// named in sandbox_env::REQUEST_ENV_KEYS, are set again over the runtime's environment
+const STAGE_LOADER: &str = "envicutor_workdir=$PWD; envicutor_request_env=${ENVICUTOR_REQUEST_ENV:+$(declare -p $ENVICUTOR_REQUEST_ENV)}; unset ENVICUTOR_REQUEST_ENV; . /runtime/env.sh && eval \"$envicutor_request_env\" && { printf %s \"$EPOCHREALTIME\" 2>/dev/null >\"$envicutor_workdir/.envicutor_activated\"; exec \"$@\"; }";
+const STAGE_LOADER_NAME: &str = "envicutor-stage";
+const MEMORY_BELOW_RUNTIME_FLOOR: &str = "memory_below_runtime_floor";
+const MAX_SUBMISSION_FILES: usize = 256;
+const MAX_BATCH_CASES: usize = 512;
+const MAX_OUTPUT_FILES: usize = 16;
+// Kept of each output file when there is no maximum output size
+const DEFAULT_OUTPUT_FILE_BYTES: usize = 1024 * 1024;
+const DEFAULT_JUDGED_STDOUT_BYTES: usize = 64 * 1024;
+const MIN_IDLE_OUTPUT_TIMEOUT_MS: u64 = 100;
+// Runs are never killed for silence before this, leaving time to start up
+const IDLE_OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+// Set once at startup
+static MAX_CASE_PARALLELISM: OnceLock<u32> = OnceLock::new();
+
+pub fn use_max_case_parallelism(max: u32) -> Result<(), Error> {
+    MAX_CASE_PARALLELISM
+        .set(max)
+        .map_err(|_| anyhow!("The maximum case parallelism was already set"))
+}
+
+fn max_case_parallelism() -> u32 {
+    MAX_CASE_PARALLELISM.get().copied().unwrap_or(1)
+}
+
+// Set once at startup, the bytes of stdout and of stderr kept of every stage
+static MAX_OUTPUT_BYTES: OnceLock<usize> = OnceLock::new();
+
+pub fn use_max_output_bytes(max: usize) -> Result<(), Error> {
+    MAX_OUTPUT_BYTES
+        .set(max)
+        .map_err(|_| anyhow!("The maximum output size was already set"))
+}
+
+// Errors are safe to show in the response
+fn resolve_max_output_bytes(requested: Option<usize>) -> Result<Option<usize>, String> {
+    let max = MAX_OUTPUT_BYTES.get().copied();
+    match (requested, max) {
+        (Some(requested), Some(max)) if requested > max => {
+            Err(format!("max_output_bytes can't be more than {max}"))
+        }
+        (requested, max) => Ok(requested.or(max)),
+    }
+}
+
+// The CPU time all the stages of a request may use together, None is unlimited
+#[derive(Clone, Copy, Default)]
+pub struct CpuBudget {
+    pub default: Option<Seconds>,
+    pub max: Option<Seconds>,
+}
+
+// Set once at startup
+static CPU_BUDGET: OnceLock<CpuBudget> = OnceLock::new();
+
+pub fn use_cpu_budget(budget: CpuBudget) -> Result<(), Error> {
+    CPU_BUDGET
+        .set(budget)
+        .map_err(|_| anyhow!("The CPU budget was already set"))
+}
+
+// Errors are safe to show in the response
+fn resolve_cpu_budget(requested: Option<Seconds>) -> Result<Option<Seconds>, String> {
+    cpu_budget_of(CPU_BUDGET.get().copied().unwrap_or_default(), requested)
+}
+
+fn cpu_budget_of(config: CpuBudget, requested: Option<Seconds>) -> Result<Option<Seconds>, String> {
+    let Some(budget) = requested.or(config.default).or(config.max) else {
+        return Ok(None);
+    };
+    if budget.is_nan() || budget <= 0.0 {
+        return Err("max_total_cpu_seconds must be positive".to_string());
+    }
+    match config.max {
+        Some(max) if budget > max => Err(format!("max_total_cpu_seconds can't be more than {max}")),
+        _ => Ok(Some(budget)),
+    }
+}
+
+// Errors are safe to show in the response
+fn resolve_idle_output_timeout(
+    requested_ms: Option<u64>,
+) -> Result<Option<IdleOutputTimeout>, String> {
+    match requested_ms {
+        Some(ms) if ms < MIN_IDLE_OUTPUT_TIMEOUT_MS => Err(format!(
+            "idle_output_timeout_ms can't be less than {MIN_IDLE_OUTPUT_TIMEOUT_MS}"
+        )),
+        Some(ms) => Ok(Some(IdleOutputTimeout {
+            timeout: Duration::from_millis(ms),
+            grace: IDLE_OUTPUT_GRACE,
+        })),
+        None => Ok(None),
+    }
+}
+
+// Rounded, 1.001 seconds would otherwise be 1000 milliseconds
+fn seconds_to_millis(seconds: Seconds) -> u64 {
+    (seconds * 1000.0).round() as u64
+}
+
+fn cpu_millis(stage: &StageResult) -> u64 {
+    seconds_to_millis(stage.cpu_time.unwrap_or(0.0))
+}
+
+// Used up once the stages took all of it, nothing more is started then
+fn is_budget_exhausted(budget_millis: Option<u64>, used_millis: u64) -> bool {
+    budget_millis.is_some_and(|budget| used_millis >= budget)
+}
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ExecutionQuery {
-    is_project: bool,
+    #[serde(default)]
+    pub is_project: bool,
+    #[serde(default)]
+    pub explain_limits: bool,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FileEncoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubmissionFile {
+    pub name: String,
+    pub content: String,
+    #[serde(default)]
+    pub encoding: FileEncoding,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BatchCase {
+    #[serde(default)]
+    pub stdin: String,
+    pub expected_output: Option<String>,
+    // Override the request's run limits for this case
+    pub cpu_time: Option<Seconds>,
+    pub wall_time: Option<Seconds>,
+    // Fetched by the server instead of being given inline, the hashes are
+    // checked and let the data be cached
+    pub stdin_url: Option<String>,
+    pub stdin_sha256: Option<String>,
+    #[serde(alias = "expected_stdout_url")]
+    pub expected_output_url: Option<String>,
+    pub expected_output_sha256: Option<String>,
+    // Set when the data of the case couldn't be fetched, it is then not run
+    #[serde(skip)]
+    pub fetch_error: Option<String>,
+}
+
+// What the execution history keeps of a request for it to be replayed
+#[derive(Serialize, Deserialize)]
+pub struct RetainedExecution<R, Q> {
+    pub request: R,
+    pub query: Option<Q>,
+}
+
+impl<'a> RetainedExecution<&'a ExecutionRequest, &'a ExecutionQuery> {
+    fn new(request: &'a ExecutionRequest, query: Option<&'a ExecutionQuery>) -> Self {
+        RetainedExecution { request, query }
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ExecutionRequest {
-    runtime_id: u32,
-    source_code: String,
-    input: Option<String>,
-    compile_limits: Option<Limits>,
-    run_limits: Option<Limits>,
+    pub runtime_id: Option<RuntimeId>,
+    // A runtime name or alias, instead of (or matching) runtime_id
+    #[serde(alias = "runtime_name")]
+    pub runtime: Option<String>,
+    #[serde(default)]
+    pub source_code: String,
+    // Instead of source_code, written relative to the submission directory
+    #[serde(default)]
+    pub files: Vec<SubmissionFile>,
+    // The file the scripts are given, defaults to the runtime's source file name
+    pub entry: Option<String>,
+    // The name source_code is written to instead of the runtime's source file
+    // name, e.g. for Java's class named files
+    pub entry_file_name: Option<String>,
+    // Whether UTF-16 files are made UTF-8 rather than rejected, true when not given
+    pub transcode: Option<bool>,
+    // Keeps the core of a run killed by a signal, when the server and the runtime allow it
+    #[serde(default)]
+    pub capture_core: bool,
+    #[serde(alias = "stdin")]
+    pub input: Option<String>,
+    // Passed to the run script after the source file name
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub compile_limits: Option<Limits>,
+    pub run_limits: Option<Limits>,
+    #[serde(default)]
+    pub validate_only: bool,
+    #[serde(default)]
+    pub override_floor: bool,
+    // Judges the run by its exit code, instead of requiring 0
+    pub expected_exit_code: Option<i32>,
+    // Judges the run by its stdout, compared with `comparator`
+    pub expected_output: Option<String>,
+    #[serde(default)]
+    pub comparator: Comparator,
+    // Runs judged by their output leave their stdout out of the response unless
+    // asked for, and then only its first `max_stdout_bytes`
+    #[serde(default)]
+    pub include_stdout: bool,
+    pub max_stdout_bytes: Option<usize>,
+    // Instead of input, the submission is built once and run once per case
+    #[serde(default)]
+    pub cases: Vec<BatchCase>,
+    #[serde(default)]
+    pub stop_on_first_failure: bool,
+    // Boxes running the cases at once, capped by MAX_CASE_PARALLELISM
+    pub parallelism: Option<u32>,
+    // The measured CPU time of every stage together, no stage starts once it is used up
+    pub max_total_cpu_seconds: Option<Seconds>,
+    // Kills the run stage once it has written nothing for this long
+    pub idle_output_timeout_ms: Option<u64>,
+    // Bytes of stdout and of stderr kept of every stage, up to MAX_OUTPUT_BYTES
+    pub max_output_bytes: Option<usize>,
+    // Kills a stage once it goes over the output limit, instead of throwing the
+    // rest of its output away until it ends
+    #[serde(default)]
+    pub kill_on_output_limit: bool,
+    // Programs writing binary output can have it base64 encoded instead
+    #[serde(default)]
+    pub output_encoding: OutputEncoding,
+    // Set in every stage, the runtime's own environment takes precedence
+    #[serde(default)]
+    pub env: SandboxEnv,
+    // Batch executions run at a lower CPU weight than interactive ones
+    #[serde(default)]
+    pub priority: Priority,
+    // Uploaded datasets, mounted read-only in every stage
+    #[serde(default)]
+    pub datasets: Vec<String>,
+    // Files the run leaves in the submission directory, returned with the response
+    #[serde(default)]
+    pub output_files: Vec<String>,
+    // Waits for the result of an identical execution in flight instead of
+    // running, left out of what makes executions identical
+    #[serde(default, skip_serializing)]
+    pub coalesce: bool,
+    // Set for jobs, which are bounded by MAX_PENDING_JOBS and wait for a permit
+    // however long the queue is
+    #[serde(skip)]
+    pub unbounded_queue: bool,
+}
+
+impl ExecutionRequest {
+    fn judged_stdout_limit(&self) -> usize {
+        if self.include_stdout {
+            self.max_stdout_bytes.unwrap_or(DEFAULT_JUDGED_STDOUT_BYTES)
+        } else {
+            0
+        }
+    }
 }
 
 #[derive(Serialize)]
+struct MemoryFloorDetail {
+    code: &'static str,
+    memory: Kilobytes,
+    min_memory: Kilobytes,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ExecutionLimitsResolution {
+    compile: LimitsResolution,
+    run: LimitsResolution,
+}
+
+#[derive(Serialize)]
+pub struct StageEstimate {
+    stage: StageName,
+    wall_time: Seconds,
+    cpu_time: Seconds,
+    memory: Kilobytes,
+}
+
+// Worst cases, every stage runs and uses up its limits
+#[derive(Serialize)]
+pub struct ExecutionEstimate {
+    runtime_id: RuntimeId,
+    stages: Vec<StageEstimate>,
+    wall_time: Seconds,
+    cpu_time: Seconds,
+    peak_memory: Kilobytes,
+}
+
+impl ExecutionEstimate {
+    fn new(
+        runtime_id: RuntimeId,
+        planned_stages: &[StageName],
+        compile_limits: &MandatoryLimits,
+        run_limits: &MandatoryLimits,
+    ) -> ExecutionEstimate {
+        let stages: Vec<StageEstimate> = planned_stages
+            .iter()
+            .map(|&stage| {
+                // Extractions run with the compile limits
+                let limits = match stage {
+                    StageName::Extract | StageName::Compile => compile_limits,
+                    StageName::Run => run_limits,
+                };
+                StageEstimate {
+                    stage,
+                    wall_time: limits.wall_time + limits.extra_time,
+                    cpu_time: limits.cpu_time,
+                    memory: limits.memory,
+                }
+            })
+            .collect();
+        ExecutionEstimate {
+            runtime_id,
+            wall_time: stages.iter().map(|stage| stage.wall_time).sum(),
+            cpu_time: stages.iter().map(|stage| stage.cpu_time).sum(),
+            peak_memory: stages.iter().map(|stage| stage.memory).max().unwrap_or(0),
+            stages,
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct CaseRun {
+    pub case_index: usize,
+    // Only given when the case has an expected output or the request an expected exit code
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub verdict: Option<Verdict>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run: Option<StageResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fetch_error: Option<String>,
+    #[serde(skip)]
+    pub failed: bool,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputFile {
+    pub name: String,
+    // Base64, cut at the maximum output size
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub missing: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+}
+
+impl OutputFile {
+    fn new(name: &str) -> Self {
+        OutputFile {
+            name: name.to_string(),
+            content: None,
+            size: None,
+            truncated: false,
+            missing: false,
+            error: None,
+        }
+    }
+}
+
+// Shared by the boxes running the cases of a batch, each takes the next case
+// left until there are none
+struct CaseBatch {
+    cases: Vec<BatchCase>,
+    limits: Vec<MandatoryLimits>,
+    mounts: Vec<String>,
+    command: Vec<String>,
+    first_stage_index: u32,
+    expected_exit_code: Option<i32>,
+    comparator: Comparator,
+    stdout_limit: usize,
+    stop_on_first_failure: bool,
+    next_case: AtomicUsize,
+    failed: AtomicBool,
+    events: Option<UnboundedSender<StreamEvent>>,
+    cpu_budget_millis: Option<u64>,
+    // Includes the stages before the batch
+    cpu_used_millis: AtomicU64,
+    budget_exhausted: AtomicBool,
+    run_options: RunOptions<'static>,
+    env: SandboxEnv,
+    // Boxes are renewed from it when isolate fails by itself
+    box_id: Arc<AtomicU64>,
+}
+
+impl CaseBatch {
+    fn is_stopped(&self) -> bool {
+        // Nobody is left to run the remaining cases for once the events can't be sent
+        (self.stop_on_first_failure && self.failed.load(Ordering::SeqCst))
+            || self
+                .events
+                .as_ref()
+                .is_some_and(|events| events.is_closed())
+    }
+
+    fn is_budget_exhausted(&self) -> bool {
+        is_budget_exhausted(
+            self.cpu_budget_millis,
+            self.cpu_used_millis.load(Ordering::SeqCst),
+        )
+    }
+
+    // A case that fails without being run
+    fn skip_case(
+        &self,
+        case_index: usize,
+        verdict: Verdict,
+        fetch_error: Option<String>,
+    ) -> CaseRun {
+        self.failed.store(true, Ordering::SeqCst);
+        let case = CaseRun {
+            case_index,
+            verdict: Some(verdict),
+            run: None,
+            fetch_error,
+            failed: true,
+        };
+        if let Some(events) = &self.events {
+            let _ = events.send(StreamEvent::CaseResult(Box::new(case.clone())));
+        }
+        case
+    }
+
+    async fn run_cases(&self, execution_box: &mut Isolate) -> Result<Vec<CaseRun>, Error> {
+        let mounts: Vec<&str> = self.mounts.iter().map(String::as_str).collect();
+        let command: Vec<&str> = self.command.iter().map(String::as_str).collect();
+        let mut runs = Vec::new();
+        while !self.is_stopped() {
+            let case_index = self.next_case.fetch_add(1, Ordering::SeqCst);
+            let Some(case) = self.cases.get(case_index) else {
+                break;
+            };
+            if let Some(fetch_error) = &case.fetch_error {
+                runs.push(self.skip_case(
+                    case_index,
+                    Verdict::FetchFailed,
+                    Some(fetch_error.clone()),
+                ));
+                continue;
+            }
+            // Cases already running may still go over the budget, no other starts
+            if self.is_budget_exhausted() {
+                self.budget_exhausted.store(true, Ordering::SeqCst);
+                runs.push(self.skip_case(case_index, Verdict::BudgetExhausted, None));
+                continue;
+            }
+            let mut stdin = case.stdin.clone();
+            stdin.add_new_line_if_none();
+            let mut run = run_stage(
+                &self.box_id,
+                execution_box,
+                &mounts,
+                &self.limits[case_index],
+                Some(stdin.as_str()).filter(|stdin| !stdin.is_empty()),
+                SUBMISSION_DIR,
+                &command,
+                RunOptions {
+                    env: Some(&self.env),
+                    ..self.run_options
+                },
+            )
+            .await
+            .map_err(|e| e.context(format!("Failed to run case {case_index} of the submission")))?;
+            self.cpu_used_millis
+                .fetch_add(cpu_millis(&run), Ordering::SeqCst);
+            run.stage = Some(StageId {
+                case_index: Some(case_index as u32),
+                ..StageId::new(StageName::Run, self.first_stage_index + case_index as u32)
+            });
+            run.limit_set = Some(LimitSet::Run);
+            let verdict = (case.expected_output.is_some()
+                || self.expected_exit_code.is_some()
+                || run.no_output_timeout)
+                .then(|| {
+                    verdict::judge(
+                        &run,
+                        self.expected_exit_code,
+                        case.expected_output
+                            .as_ref()
+                            .map(|expected| self.comparator.matches(expected, &run.stdout.text())),
+                    )
+                });
+            let failed = match verdict {
+                Some(verdict) => verdict != Verdict::Ok,
+                None => run.exit_code != Some(0),
+            };
+            if failed {
+                self.failed.store(true, Ordering::SeqCst);
+            }
+            if case.expected_output.is_some() {
+                run.truncate_stdout(self.stdout_limit);
+            }
+            let case = CaseRun {
+                case_index,
+                verdict,
+                run: Some(run),
+                fetch_error: None,
+                failed,
+            };
+            if let Some(events) = &self.events {
+                let _ = events.send(StreamEvent::CaseResult(Box::new(case.clone())));
+            }
+            runs.push(case);
+        }
+        Ok(runs)
+    }
+}
+
+#[derive(Clone)]
+pub enum StreamEvent {
+    // The execution got its permits and left the queue
+    Started,
+    Output {
+        stage: StageName,
+        stream: OutputStream,
+        chunk: String,
+    },
+    CaseResult(Box<CaseRun>),
+    Finished(Box<ExecutionResponse>),
+    Error(String),
+}
+
+fn output_event_name(stage: StageName, stream: OutputStream) -> &'static str {
+    match (stage, stream) {
+        (StageName::Extract, OutputStream::Stdout) => "extract_stdout",
+        (StageName::Extract, OutputStream::Stderr) => "extract_stderr",
+        (StageName::Compile, OutputStream::Stdout) => "compile_stdout",
+        (StageName::Compile, OutputStream::Stderr) => "compile_stderr",
+        (StageName::Run, OutputStream::Stdout) => "run_stdout",
+        (StageName::Run, OutputStream::Stderr) => "run_stderr",
+    }
+}
+
+// Aborts the task once its output has nowhere to go
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[derive(Serialize, Clone)]
 pub struct ExecutionResponse {
-    extract: Option<StageResult>,
-    compile: Option<StageResult>,
-    run: Option<StageResult>,
+    pub extract: Option<StageResult>,
+    pub compile: Option<StageResult>,
+    pub run: Option<StageResult>,
+    // Batches have a run per case instead of `run`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cases: Vec<CaseRun>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub output_files: Vec<OutputFile>,
+    // Only given when the compile cache is enabled and the runtime is compiled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compile_cache: Option<CacheResult>,
+    // Only given when the request asked for it and the run happened
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub core_dump: Option<CoreDump>,
+    // Set when an extraction or compilation failed or the CPU budget was used up,
+    // so the run was never attempted
+    pub run_skipped: bool,
+    // Set when stages or cases were not run because the CPU budget was used up
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub budget_exhausted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits_resolution: Option<ExecutionLimitsResolution>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<&'static str>,
+    // Set when the result is the one of an identical execution that was in flight
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub coalesced: bool,
+    // Only given when SANDBOX_INFO is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<SandboxInfo>,
+    // Only given when the request has an expected exit code
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub verdict: Option<Verdict>,
+    #[serde(skip)]
+    pub queue_wait: Option<Duration>,
+    #[serde(skip)]
+    pub box_id: Option<u64>,
+    #[serde(skip)]
+    pub planned_stages: Vec<StageName>,
+    #[serde(skip)]
+    pub run_limits: Option<MandatoryLimits>,
+}
+
+impl ExecutionResponse {
+    pub fn last_stage(&self) -> Option<&StageResult> {
+        self.run
+            .as_ref()
+            .or(self.compile.as_ref())
+            .or(self.extract.as_ref())
+    }
+
+    pub fn outcome(&self, runtime_id: RuntimeId) -> RequestOutcome {
+        let last_stage = self.last_stage();
+        RequestOutcome {
+            runtime_id: Some(runtime_id),
+            verdict: last_stage.and_then(|result| result.exit_status.clone()),
+            cpu_time: last_stage.and_then(|result| result.cpu_time),
+            wall_time: last_stage.and_then(|result| result.wall_time),
+            memory: last_stage.and_then(|result| result.memory),
+            queue_wait: self.queue_wait,
+            box_id: self.box_id,
+            ..Default::default()
+        }
+    }
+
+    fn into_dialect_response(self, dialect: ResponseDialect, language: &str) -> Response<Body> {
+        match dialect {
+            ResponseDialect::Native => Json(self).into_response(),
+            ResponseDialect::Piston => Json(piston::from_execution(language, self)).into_response(),
+        }
+    }
+}
+
+// The script gets the source file name as $1 and `args` after it, each as its own
+// argument so that nothing in them is expanded
+pub fn stage_command<'a>(
+    script: &'a str,
+    source_file_name: &'a str,
+    args: &'a [String],
+) -> Vec<&'a str> {
+    let mut command = vec![
+        "/bin/bash",
+        "-c",
+        STAGE_LOADER,
+        STAGE_LOADER_NAME,
+        script,
+        source_file_name,
+    ];
+    command.extend(args.iter().map(String::as_str));
+    command
+}
+
+fn dataset_not_found(name: &str) -> Response<Body> {
+    (
+        StatusCode::NOT_FOUND,
+        Json(Message {
+            message: format!("No dataset named {name}"),
+        }),
+    )
+        .into_response()
+}
+
+// Names can only go down from the submission directory, e.g. "src/main.c", and
+// can't be those of the files the server leaves there
+fn is_valid_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && !isolate::is_reserved_file(name)
+        && Path::new(name)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn validate_files(req: &ExecutionRequest, is_project: bool) -> Result<(), String> {
+    if let Some(entry_file_name) = &req.entry_file_name {
+        if is_project || !req.files.is_empty() {
+            return Err("entry_file_name can only be given with source_code".to_string());
+        }
+        if !is_valid_file_name(entry_file_name) {
+            return Err(format!("Invalid entry file name: {entry_file_name}"));
+        }
+    }
+    if req.files.is_empty() {
+        return match req.entry {
+            Some(_) => Err("entry can only be given with files".to_string()),
+            None => Ok(()),
+        };
+    }
+    if is_project || !req.source_code.is_empty() {
+        return Err("Specify either source_code or files, not both".to_string());
+    }
+    check_count("files", req.files.len(), MAX_SUBMISSION_FILES).map_err(|e| e.to_string())?;
+    for (i, file) in req.files.iter().enumerate() {
+        if !is_valid_file_name(&file.name) {
+            return Err(format!("Invalid file name: {}", file.name));
+        }
+        if req.files[..i].iter().any(|other| other.name == file.name) {
+            return Err(format!("Duplicate file name: {}", file.name));
+        }
+    }
+    Ok(())
+}
+
+fn validate_datasets(req: &ExecutionRequest) -> Result<(), String> {
+    check_count("datasets", req.datasets.len(), MAX_DATASETS_PER_EXECUTION)
+        .map_err(|e| e.to_string())?;
+    for (i, name) in req.datasets.iter().enumerate() {
+        if !datasets::is_valid_name(name) {
+            return Err(format!("Invalid dataset name: {name}"));
+        }
+        if req.datasets[..i].contains(name) {
+            return Err(format!("Duplicate dataset: {name}"));
+        }
+    }
+    Ok(())
+}
+
+fn validate_output_files(req: &ExecutionRequest) -> Result<(), String> {
+    if req.output_files.is_empty() {
+        return Ok(());
+    }
+    if !req.cases.is_empty() {
+        return Err("output_files can't be given with cases".to_string());
+    }
+    check_count("output_files", req.output_files.len(), MAX_OUTPUT_FILES)
+        .map_err(|e| e.to_string())?;
+    for (i, name) in req.output_files.iter().enumerate() {
+        if !is_valid_file_name(name) {
+            return Err(format!("Invalid output file name: {name}"));
+        }
+        if req.output_files[..i].contains(name) {
+            return Err(format!("Duplicate output file: {name}"));
+        }
+    }
+    Ok(())
+}
+
+// Names were validated, but the submission could have made any of them a
+// symlink, which `read_file_from_box` refuses to follow
+async fn read_output_files(
+    submission_dir: &str,
+    names: &[String],
+    max_bytes: usize,
+) -> Vec<OutputFile> {
+    let mut files = Vec::with_capacity(names.len());
+    for name in names {
+        let mut file = OutputFile::new(name);
+        match crate::fs::read_file_from_box(submission_dir, name, max_bytes).await {
+            Ok(BoxFile::Read { content, size }) => {
+                file.truncated = (content.len() as u64) < size;
+                file.content = Some(BASE64_STANDARD.encode(content));
+                file.size = Some(size);
+            }
+            Ok(BoxFile::Missing) => file.missing = true,
+            Ok(BoxFile::NotRegular) => file.error = Some("Not a regular file"),
+            Err(e) => {
+                eprintln!("Failed to read output file {name}: {e}");
+                file.error = Some("Failed to read the file");
+            }
+        }
+        files.push(file);
+    }
+    files
+}
+
+fn validate_cases(req: &ExecutionRequest) -> Result<(), String> {
+    if req.cases.is_empty() {
+        return Ok(());
+    }
+    if req.input.is_some() {
+        return Err("Specify either input or cases, not both".to_string());
+    }
+    if req.expected_output.is_some() {
+        return Err("expected_output has to be given per case in a batch".to_string());
+    }
+    check_count("cases", req.cases.len(), MAX_BATCH_CASES).map_err(|e| e.to_string())?;
+    for (i, case) in req.cases.iter().enumerate() {
+        let data = [
+            (
+                "stdin",
+                !case.stdin.is_empty(),
+                &case.stdin_url,
+                &case.stdin_sha256,
+            ),
+            (
+                "expected_output",
+                case.expected_output.is_some(),
+                &case.expected_output_url,
+                &case.expected_output_sha256,
+            ),
+        ];
+        for (name, inline, url, sha256) in data {
+            match url {
+                Some(_) if inline => {
+                    return Err(format!(
+                        "Case {i} has both {name} and {name}_url, specify only one"
+                    ))
+                }
+                Some(url) => test_data::validate_url(url, sha256.as_deref())
+                    .map_err(|e| format!("Case {i}: {e}"))?,
+                None if sha256.is_some() => {
+                    return Err(format!("Case {i} has {name}_sha256 without {name}_url"))
+                }
+                None => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+// Fills in the test data the cases give as URLs, a case whose data can't be
+// fetched is not run
+async fn fetch_test_data(cases: &mut [BatchCase]) {
+    let budget = Arc::new(FetchBudget::new());
+    let mut fetches = JoinSet::new();
+    for (i, case) in cases.iter().enumerate() {
+        let data = [
+            (false, &case.stdin_url, &case.stdin_sha256),
+            (
+                true,
+                &case.expected_output_url,
+                &case.expected_output_sha256,
+            ),
+        ];
+        for (is_expected_output, url, sha256) in data {
+            if let Some(url) = url.clone() {
+                let sha256 = sha256.clone();
+                let budget = budget.clone();
+                fetches.spawn(async move {
+                    let res = test_data::fetch(&url, sha256.as_deref(), &budget).await;
+                    (i, is_expected_output, res)
+                });
+            }
+        }
+    }
+    while let Some(res) = fetches.join_next().await {
+        let (i, is_expected_output, res) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                eprintln!("Failed to join a test data fetch: {e}");
+                continue;
+            }
+        };
+        let case = &mut cases[i];
+        match res {
+            Ok(data) if is_expected_output => case.expected_output = Some(data),
+            Ok(data) => case.stdin = data,
+            Err(e) => {
+                eprintln!("{e}");
+                case.fetch_error.get_or_insert(e);
+            }
+        }
+    }
+}
+
+// Errors name the file and are safe to show in the response
+fn decode_files(files: Vec<SubmissionFile>) -> Result<Vec<(String, Vec<u8>)>, String> {
+    files
+        .into_iter()
+        .map(|file| {
+            let content = match file.encoding {
+                FileEncoding::Utf8 => file.content.into_bytes(),
+                FileEncoding::Base64 => BASE64_STANDARD
+                    .decode(&file.content)
+                    .map_err(|e| format!("Invalid base64 in {}: {e}", file.name))?,
+            };
+            Ok((file.name, content))
+        })
+        .collect()
+}
+
+// The warnings of the byte order marks that were found, each given once
+fn normalize_encodings(
+    req: &mut ExecutionRequest,
+    files: &mut [(String, Vec<u8>)],
+) -> Result<Vec<&'static str>, String> {
+    let transcode = req.transcode.unwrap_or(true);
+    let mut boms = Vec::new();
+    for (name, content) in files.iter_mut() {
+        boms.extend(encoding::normalize(name, content, transcode)?);
+    }
+    // A JSON string can only carry the UTF-8 one, decoded as U+FEFF
+    if let Some(source_code) = req.source_code.strip_prefix('\u{feff}') {
+        req.source_code = source_code.to_string();
+        boms.push(Bom::Utf8);
+    }
+    let mut warnings = Vec::new();
+    for warning in boms.into_iter().map(Bom::warning) {
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+    Ok(warnings)
+}
+
+async fn write_files(submission_dir: &str, files: &[(String, Vec<u8>)]) -> Result<(), Error> {
+    for (name, content) in files {
+        let path = format!("{submission_dir}/{name}");
+        if let Some(parent) = Path::new(&path).parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|e| anyhow!("Failed to create the directory of {path}: {e}"))?;
+        }
+        fs::write(&path, content)
+            .await
+            .map_err(|e| anyhow!("Failed to write {path}: {e}"))?;
+    }
+    Ok(())
 }
 
 pub async fn renew_box(box_id: &Arc<AtomicU64>, execution_box: &mut Isolate) -> Result<(), Error> {
     let new_box = Isolate::init(get_next_box_id(box_id))
         .await
         .map_err(|e| anyhow!("Failed to initialize run sandbox: {e}"))?;
-    fs::rename(
-        format!("{}/submission", &execution_box.box_dir),
-        format!("{}/submission", &new_box.box_dir),
-    )
-    .await
-    .map_err(|e| {
-        anyhow!(
-            "Failed to move {} to {}: {}",
-            execution_box.box_dir,
-            new_box.box_dir,
-            e
-        )
-    })?;
+    // The previous stage could have replaced the submission directory with a symlink
+    let submission_dir = crate::fs::resolve_in_box(&execution_box.box_dir, "submission").await?;
+    let metadata = fs::symlink_metadata(&submission_dir)
+        .await
+        .map_err(|e| anyhow!("Failed to stat {submission_dir}: {e}"))?;
+    if !metadata.is_dir() {
+        return Err(anyhow!("{submission_dir} is no longer a directory"));
+    }
+    fs::rename(&submission_dir, format!("{}/submission", &new_box.box_dir))
+        .await
+        .map_err(|e| {
+            anyhow!(
+                "Failed to move {} to {}: {}",
+                execution_box.box_dir,
+                new_box.box_dir,
+                e
+            )
+        })?;
     *execution_box = new_box;
     Ok(())
 }
 
-pub async fn execute(
-    semaphore: Arc<Semaphore>,
+// Runs a stage, again on a fresh box each time isolate fails by itself, up to
+// the configured number of retries
+#[allow(clippy::too_many_arguments)]
+async fn run_stage(
+    box_id: &Arc<AtomicU64>,
+    execution_box: &mut Isolate,
+    mounts: &[&str],
+    limits: &MandatoryLimits,
+    stdin: Option<&str>,
+    workdir: &str,
+    cmd_args: &[&str],
+    run_options: RunOptions<'_>,
+) -> Result<StageResult, Error> {
+    let retries = isolate::sandbox_error_retries();
+    let mut attempt = 0;
+    loop {
+        let res = execution_box
+            .run_with_output(mounts, limits, stdin, workdir, cmd_args, run_options)
+            .await;
+        match res {
+            Err(e) if isolate::is_sandbox_error(&e) && attempt < retries => {
+                attempt += 1;
+                eprintln!("Retrying the stage on a fresh box ({attempt}/{retries}) after {e}");
+                renew_box(box_id, execution_box).await?;
+            }
+            res => return res,
+        }
+    }
+}
+
+// A stage that kept failing in the sandbox is reported as a sandbox error
+fn stage_error(host_monitor: &HostMonitor, context: &str, e: Error) -> Response<Body> {
+    if isolate::is_sandbox_error(&e) {
+        eprintln!("{context}: {e:#}");
+        return SANDBOX_ERROR_RESPONSE.into_response();
+    }
+    internal_error(host_monitor, format!("{context}: {e:#}"))
+}
+
+#[derive(Serialize)]
+struct QueueFullMessage {
+    message: String,
+    queue_depth: usize,
+    permits: usize,
+}
+
+// The client can retry later, the body tells it how busy the server is
+fn queue_full_response(full: &QueueFull) -> Response<Body> {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        Json(QueueFullMessage {
+            message: format!("The execution queue is full, {full}"),
+            queue_depth: full.queue_depth,
+            permits: full.permits,
+        }),
+    )
+        .into_response()
+}
+
+// What an execution goes through around running the submission: the hooks, the
+// verdict and the events
+#[allow(clippy::too_many_arguments)]
+pub async fn judged_execution(
+    semaphore: Arc<ExecutionSlots>,
     box_id: Arc<AtomicU64>,
     metadata_cache: Arc<RwLock<Metadata>>,
     installation_lock: Arc<RwLock<u8>>,
     system_limits: SystemLimits,
-    Json(mut req): Json<ExecutionRequest>,
+    host_monitor: &HostMonitor,
+    events: &Events,
+    req: ExecutionRequest,
     query: Option<Query<ExecutionQuery>>,
-) -> Result<Response<Body>, Response<Body>> {
-    let _installation_guard = installation_lock.read().await;
-    let _permit = semaphore.acquire().await.map_err(|e| {
-        eprintln!("Failed to acquire execution semaphore: {e}");
-        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-    })?;
-    let is_project = if let Some(query) = query {
-        query.is_project
+    stream_events: Option<&UnboundedSender<StreamEvent>>,
+) -> Result<(RuntimeId, String, ExecutionResponse), Response<Body>> {
+    let validate_only = req.validate_only;
+    let expected_exit_code = req.expected_exit_code;
+    let expected_output = req.expected_output.clone();
+    let judges_cases = req
+        .cases
+        .iter()
+        .any(|case| case.expected_output.is_some() || case.expected_output_url.is_some());
+    let comparator = req.comparator;
+    let stdout_limit = req.judged_stdout_limit();
+    let hooks = hooks::execution_hooks().filter(|_| !validate_only);
+    if let Some(hook) = hooks.and_then(|hooks| hooks.pre_execution.as_ref()) {
+        let payload = PreExecution {
+            runtime_id: req.runtime_id,
+            runtime: req.runtime.as_deref(),
+            source_code_bytes: req.source_code.len(),
+            files: req.files.len(),
+            input_bytes: req.input.as_ref().map_or(0, String::len),
+            args: req.args.len(),
+        };
+        hook.run(&payload).await.map_err(|e| {
+            eprintln!("{e}");
+            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+        })?;
+    }
+    // Kept with the record so that the execution can be replayed
+    let retained_request = (history::keeps_requests() && !validate_only)
+        .then(|| serde_json::to_string(&RetainedExecution::new(&req, query.as_deref())).ok())
+        .flatten();
+    let started_at = history::now_millis();
+    let (runtime_id, language, mut res) = run_submission(
+        semaphore,
+        box_id,
+        metadata_cache,
+        installation_lock,
+        system_limits,
+        host_monitor,
+        req,
+        query,
+        stream_events,
+    )
+    .await?;
+    res.warnings.extend(host_monitor.warning().await);
+    // Runs killed for going silent are judged even when nothing else asked for it
+    let went_silent = res.run.as_ref().is_some_and(|run| run.no_output_timeout)
+        || res
+            .cases
+            .iter()
+            .any(|case| case.verdict == Some(Verdict::NoOutputTimeout));
+    if (expected_exit_code.is_some() || expected_output.is_some() || judges_cases || went_silent)
+        && !validate_only
+    {
+        res.verdict = Some(match &res.run {
+            // A batch is judged by its first failed case
+            None if !res.cases.is_empty() => res
+                .cases
+                .iter()
+                .filter_map(|case| case.verdict)
+                .find(|verdict| *verdict != Verdict::Ok)
+                .unwrap_or(Verdict::Ok),
+            Some(run) => verdict::judge(
+                run,
+                expected_exit_code,
+                expected_output
+                    .as_ref()
+                    .map(|expected| comparator.matches(expected, &run.stdout.text())),
+            ),
+            None if res.budget_exhausted => Verdict::BudgetExhausted,
+            None => Verdict::CompilationError,
+        });
+    }
+    if let (Some(_), Some(run)) = (&expected_output, &mut res.run) {
+        run.truncate_stdout(stdout_limit);
+    }
+    if !validate_only {
+        res.sandbox = sandbox_info::describe(res.box_id);
+        events.publish(Event::execution_finished(runtime_id, &res));
+        history::record(runtime_id, started_at, &res, retained_request);
+    }
+    if let Some(hook) = hooks.and_then(|hooks| hooks.post_execution.as_ref()) {
+        hook.run(&Event::execution_finished(runtime_id, &res))
+            .await
+            .map_err(|e| {
+                eprintln!("{e}");
+                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+            })?;
+    }
+    Ok((runtime_id, language, res))
+}
+
+// Waits for the identical execution in flight when the request asks for it
+// and there is one, otherwise runs it. The events of an execution others may
+// wait for are kept for them as they are forwarded
+#[allow(clippy::too_many_arguments)]
+async fn coalesced_execution(
+    semaphore: Arc<ExecutionSlots>,
+    box_id: Arc<AtomicU64>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    host_monitor: &HostMonitor,
+    events: &Events,
+    req: ExecutionRequest,
+    query: Option<Query<ExecutionQuery>>,
+    stream_events: Option<&UnboundedSender<StreamEvent>>,
+) -> Result<(RuntimeId, String, ExecutionResponse), Response<Body>> {
+    let role = if req.coalesce && !req.validate_only {
+        coalescing::key(&req, query.as_ref().map(|query| &query.0))
+            .map_err(|e| eprintln!("Failed to compute the coalescing key: {e}"))
+            .ok()
+            .and_then(coalescing::join)
     } else {
-        false
+        None
     };
-    let compile_limits = req
-        .compile_limits
-        .get(&system_limits.compile)
-        .map_err(|e| {
-            (
-                StatusCode::BAD_REQUEST,
-                Json(Message {
-                    message: format!("Invalid compile limits: {e}"),
-                }),
+    let leader = match role {
+        Some(Role::Leader(leader)) => leader,
+        Some(Role::Follower(follower)) => {
+            let outcome = follower
+                .follow(|event| {
+                    if let Some(stream_events) = stream_events {
+                        let _ = stream_events.send(event);
+                    }
+                })
+                .await;
+            return match outcome {
+                Some(Ok((runtime_id, language, mut res))) => {
+                    res.coalesced = true;
+                    Ok((runtime_id, language, res))
+                }
+                Some(Err(failed)) => Err(failed.into_response()),
+                None => Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Json(StaticMessage {
+                        message:
+                            "The identical execution this one waited for was cancelled, try again",
+                    }),
+                )
+                    .into_response()),
+            };
+        }
+        None => {
+            return judged_execution(
+                semaphore,
+                box_id,
+                metadata_cache,
+                installation_lock,
+                system_limits,
+                host_monitor,
+                events,
+                req,
+                query,
+                stream_events,
             )
-                .into_response()
-        })?;
-    let run_limits = req.run_limits.get(&system_limits.run).map_err(|e| {
-        (
+            .await
+        }
+    };
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let execution = async move {
+        judged_execution(
+            semaphore,
+            box_id,
+            metadata_cache,
+            installation_lock,
+            system_limits,
+            host_monitor,
+            events,
+            req,
+            query,
+            Some(&sender),
+        )
+        .await
+    };
+    let forwarding = async {
+        while let Some(event) = receiver.recv().await {
+            leader.send(event.clone());
+            if let Some(stream_events) = stream_events {
+                let _ = stream_events.send(event);
+            }
+        }
+    };
+    let (res, ()) = tokio::join!(execution, forwarding);
+    match res {
+        Ok(res) => {
+            leader.finish(Ok(res.clone()));
+            Ok(res)
+        }
+        Err(res) => {
+            let (failed, res) = FailedExecution::from_response(res).await;
+            leader.finish(Err(failed));
+            Err(res)
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn execute(
+    semaphore: Arc<ExecutionSlots>,
+    box_id: Arc<AtomicU64>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    host_monitor: Arc<HostMonitor>,
+    events: Events,
+    dialect: ResponseDialect,
+    JsonBody(req): JsonBody<ExecutionRequest>,
+    query: Option<Query<ExecutionQuery>>,
+) -> Result<Response<Body>, Response<Body>> {
+    let (runtime_id, language, res) = coalesced_execution(
+        semaphore,
+        box_id,
+        metadata_cache,
+        installation_lock,
+        system_limits,
+        &host_monitor,
+        &events,
+        req,
+        query,
+        None,
+    )
+    .await?;
+    let outcome = res.outcome(runtime_id);
+    Ok(outcome.attach_to(res.into_dialect_response(dialect, &language)))
+}
+
+// Sends the output of the compile and run stages as it is produced, or every case
+// of a batch as it finishes, then the whole response. The execution runs in its
+// own task, which is aborted once the client goes away
+#[allow(clippy::too_many_arguments)]
+pub async fn stream_execution(
+    semaphore: Arc<ExecutionSlots>,
+    box_id: Arc<AtomicU64>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    host_monitor: Arc<HostMonitor>,
+    events: Events,
+    JsonBody(req): JsonBody<ExecutionRequest>,
+    query: Option<Query<ExecutionQuery>>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, Response<Body>> {
+    if req.validate_only {
+        return Err((
             StatusCode::BAD_REQUEST,
-            Json(Message {
-                message: format!("Invalid run limits: {e}"),
+            Json(StaticMessage {
+                message: "Validations can't be streamed, use /execute/estimate",
             }),
         )
-            .into_response()
-    })?;
+            .into_response());
+    }
+    // The response of a batch is a summary of its cases
+    let last_event_name = if req.cases.is_empty() {
+        "result"
+    } else {
+        "summary"
+    };
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let task = AbortOnDrop(tokio::spawn(async move {
+        let res = coalesced_execution(
+            semaphore,
+            box_id,
+            metadata_cache,
+            installation_lock,
+            system_limits,
+            &host_monitor,
+            &events,
+            req,
+            query,
+            Some(&sender),
+        )
+        .await;
+        let last_event = match res {
+            Ok((_, _, res)) => StreamEvent::Finished(Box::new(res)),
+            Err(res) => {
+                let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                    .await
+                    .unwrap_or_default();
+                StreamEvent::Error(String::from_utf8_lossy(&body).into_owned())
+            }
+        };
+        // Fails only when the client is gone
+        let _ = sender.send(last_event);
+    }));
+    let stream = UnboundedReceiverStream::new(receiver).filter_map(move |event| {
+        let _task = &task;
+        let sse_event = match &event {
+            StreamEvent::Started => return None,
+            StreamEvent::Output {
+                stage,
+                stream,
+                chunk,
+            } => sse::Event::default()
+                .event(output_event_name(*stage, *stream))
+                .json_data(chunk),
+            StreamEvent::CaseResult(case) => {
+                sse::Event::default().event("case_result").json_data(case)
+            }
+            StreamEvent::Finished(res) => {
+                sse::Event::default().event(last_event_name).json_data(res)
+            }
+            StreamEvent::Error(body) => Ok(sse::Event::default().event("error").data(body)),
+        };
+        match sse_event {
+            Ok(sse_event) => Some(Ok(sse_event)),
+            Err(e) => {
+                eprintln!("Failed to serialize stream event: {e}");
+                None
+            }
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
 
-    let metadata_guard = metadata_cache.read().await;
-    let runtime = metadata_guard.get(&req.runtime_id).ok_or_else(|| {
+// Goes through the same validation and stage planning as an execution without running it
+#[allow(clippy::too_many_arguments)]
+pub async fn estimate_execution(
+    semaphore: Arc<ExecutionSlots>,
+    box_id: Arc<AtomicU64>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    host_monitor: Arc<HostMonitor>,
+    JsonBody(mut req): JsonBody<ExecutionRequest>,
+    query: Option<Query<ExecutionQuery>>,
+) -> Result<Response<Body>, Response<Body>> {
+    req.validate_only = true;
+    let is_project = query.is_some_and(|query| query.is_project);
+    let (runtime_id, _, res) = run_submission(
+        semaphore,
+        box_id,
+        metadata_cache,
+        installation_lock,
+        system_limits,
+        &host_monitor,
+        req,
+        Some(Query(ExecutionQuery {
+            is_project,
+            explain_limits: true,
+        })),
+        None,
+    )
+    .await?;
+    let Some(resolution) = res.limits_resolution else {
+        return Err(INTERNAL_SERVER_ERROR_RESPONSE.into_response());
+    };
+    let estimate = ExecutionEstimate::new(
+        runtime_id,
+        &res.planned_stages,
+        &resolution.compile.effective(),
+        &resolution.run.effective(),
+    );
+    Ok(RequestOutcome {
+        runtime_id: Some(runtime_id),
+        ..Default::default()
+    }
+    .attach_to(Json(estimate).into_response()))
+}
+
+// Returns the runtime's id and name along with the response, and records in the
+// runtime's health whether the execution failed in the sandbox
+#[allow(clippy::too_many_arguments)]
+pub async fn run_submission(
+    semaphore: Arc<ExecutionSlots>,
+    box_id: Arc<AtomicU64>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    host_monitor: &HostMonitor,
+    req: ExecutionRequest,
+    query: Option<Query<ExecutionQuery>>,
+    stream_events: Option<&UnboundedSender<StreamEvent>>,
+) -> Result<(RuntimeId, String, ExecutionResponse), Response<Body>> {
+    let validate_only = req.validate_only;
+    let mut resolved_runtime_id = None;
+    let res = run_submission_stages(
+        semaphore,
+        box_id,
+        metadata_cache.clone(),
+        installation_lock,
+        system_limits,
+        host_monitor,
+        req,
+        query,
+        stream_events,
+        &mut resolved_runtime_id,
+    )
+    .await;
+    // Requests refused before running anything say nothing about the runtime
+    let sandbox_error = match &res {
+        Ok(_) => false,
+        Err(res)
+            if res.status() == StatusCode::INTERNAL_SERVER_ERROR
+                || res.status() == StatusCode::BAD_GATEWAY =>
+        {
+            true
+        }
+        Err(_) => return res,
+    };
+    if let (Some(runtime_id), false) = (resolved_runtime_id, validate_only) {
+        let became_broken = metadata_cache
+            .read()
+            .await
+            .get(&runtime_id)
+            .and_then(|runtime| runtime.health.record(sandbox_error));
+        if let Some(summary) = became_broken {
+            tokio::spawn(health::report_broken_runtime(runtime_id, summary));
+        }
+    }
+    res
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_submission_stages(
+    semaphore: Arc<ExecutionSlots>,
+    box_id: Arc<AtomicU64>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    host_monitor: &HostMonitor,
+    mut req: ExecutionRequest,
+    query: Option<Query<ExecutionQuery>>,
+    stream_events: Option<&UnboundedSender<StreamEvent>>,
+    resolved_runtime_id: &mut Option<RuntimeId>,
+) -> Result<(RuntimeId, String, ExecutionResponse), Response<Body>> {
+    let (is_project, explain_limits) = if let Some(query) = query {
+        (query.is_project, query.explain_limits)
+    } else {
+        (false, false)
+    };
+    // Every problem of the request is answered at once
+    let mut violations = Violations::default();
+    // Without compile limits, compilations run with the run limits of the
+    // request, as far as the compile maximums allow
+    let requested_compile_limits = req.compile_limits.clone().or_else(|| {
+        req.run_limits
+            .as_ref()
+            .map(|limits| limits.clamped_to(&system_limits.compile))
+    });
+    let compile_resolution = violations.check(
+        "compile_limits",
+        Limits::resolve(
+            requested_compile_limits.as_ref(),
+            &system_limits.compile_defaults,
+            &system_limits.compile,
+        )
+        .map_err(|e| format!("Invalid compile limits: {e}")),
+    );
+    let run_resolution = violations.check(
+        "run_limits",
+        Limits::resolve(
+            req.run_limits.as_ref(),
+            &system_limits.run_defaults,
+            &system_limits.run,
+        )
+        .map_err(|e| format!("Invalid run limits: {e}")),
+    );
+    for (field, res) in [
         (
-            StatusCode::BAD_REQUEST,
+            "expected_exit_code",
+            verdict::validate_expected_exit_code(req.expected_exit_code),
+        ),
+        ("files", validate_files(&req, is_project)),
+        ("cases", validate_cases(&req)),
+        ("datasets", validate_datasets(&req)),
+        ("output_files", validate_output_files(&req)),
+        ("env", sandbox_env::validate_request_env(&req.env)),
+    ] {
+        violations.check(field, res);
+    }
+    let cpu_budget = violations.check(
+        "max_total_cpu_seconds",
+        resolve_cpu_budget(req.max_total_cpu_seconds),
+    );
+    let max_bytes = violations.check(
+        "max_output_bytes",
+        resolve_max_output_bytes(req.max_output_bytes),
+    );
+    let idle_timeout = violations.check(
+        "idle_output_timeout_ms",
+        resolve_idle_output_timeout(req.idle_output_timeout_ms),
+    );
+    if req.args.iter().any(|arg| arg.contains('\0')) {
+        violations.add("args", "Arguments can't contain NUL bytes");
+    }
+    // Cases default to the run limits of the request
+    let case_limits = run_resolution.as_ref().and_then(|run_resolution| {
+        let run_limits = run_resolution.effective();
+        let case_limits = req
+            .cases
+            .iter()
+            .enumerate()
+            .map(|(i, case)| {
+                let limits = Limits {
+                    cpu_time: case.cpu_time,
+                    wall_time: case.wall_time,
+                    memory: None,
+                    extra_time: None,
+                    max_open_files: None,
+                    max_file_size: None,
+                    max_number_of_processes: None,
+                };
+                Limits::resolve(Some(&limits), &run_limits, &system_limits.run)
+                    .map(|resolution| resolution.effective())
+                    .map_err(|e| format!("Invalid limits of case {i}: {e}"))
+            })
+            .collect::<Result<Vec<_>, _>>();
+        violations.check("cases", case_limits)
+    });
+    let core_size = if req.capture_core {
+        let core_size = run_resolution.as_ref().and_then(|run_resolution| {
+            core_dumps::core_size(run_resolution.effective().max_file_size)
+        });
+        if !req.cases.is_empty() {
+            violations.add("capture_core", "capture_core can't be given with cases");
+        } else if run_resolution.is_some() && core_size.is_none() {
+            violations.add("capture_core", "Core dumps are not enabled on this server");
+        }
+        core_size
+    } else {
+        None
+    };
+    let mut files = violations
+        .check("files", decode_files(std::mem::take(&mut req.files)))
+        .unwrap_or_default();
+    let warnings = violations
+        .check("files", normalize_encodings(&mut req, &mut files))
+        .unwrap_or_default();
+
+    // Fetched before the runtime is locked and before queueing, neither
+    // installations nor the execution permits wait for the downloads
+    if violations.is_empty() && !req.validate_only {
+        fetch_test_data(&mut req.cases).await;
+    }
+
+    let _installation_guard = installation_lock.read().await;
+    let metadata_guard = metadata_cache.read().await;
+    let by_id = req.runtime_id.map(|id| {
+        metadata_guard
+            .get(&id)
+            .map(|_| id)
+            .ok_or_else(|| format!("Runtime with id: {id} does not exist"))
+    });
+    let by_name = req.runtime.as_ref().map(|name| {
+        metadata_guard
+            .find_by_name(name)
+            .map(|(id, _)| id)
+            .ok_or_else(|| format!("Runtime with name: {name} does not exist"))
+    });
+    let runtime_id = match (by_id, by_name) {
+        (Some(by_id), None) => by_id,
+        (None, Some(by_name)) => by_name,
+        (Some(Ok(id)), Some(Ok(named_id))) if id == named_id => Ok(id),
+        (Some(_), Some(_)) => {
+            violations.add(
+                "runtime_id",
+                "runtime_id and runtime_name refer to different runtimes",
+            );
+            return Err(violations.into_response());
+        }
+        (None, None) => {
+            violations.add("runtime_id", "Missing runtime_id or runtime_name");
+            return Err(violations.into_response());
+        }
+    };
+    drop(metadata_guard);
+    // An unknown runtime is only answered for an otherwise valid request
+    let runtime_id = match runtime_id {
+        Ok(runtime_id) => runtime_id,
+        Err(message) => {
+            violations.into_result()?;
+            return Err((StatusCode::NOT_FOUND, Json(Message { message })).into_response());
+        }
+    };
+    *resolved_runtime_id = Some(runtime_id);
+
+    verification::ensure_verified(&metadata_cache, runtime_id)
+        .await
+        .map_err(|e| {
+            internal_error(
+                host_monitor,
+                format!("Failed to verify runtime {runtime_id}: {e}"),
+            )
+        })?;
+    let metadata_guard = metadata_cache.read().await;
+    let Some(runtime) = metadata_guard.get(&runtime_id) else {
+        return Err(INTERNAL_SERVER_ERROR_RESPONSE.into_response());
+    };
+    if health::health_config().disable_broken
+        && runtime.health.summary().state == HealthState::Broken
+    {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
             Json(Message {
-                message: format!("Runtime with id: {} does not exist", req.runtime_id),
+                message: format!(
+                    "{} is disabled after repeated sandbox errors",
+                    runtime.display_name()
+                ),
             }),
         )
-            .into_response()
+            .into_response());
+    }
+
+    if req.capture_core && req.cases.is_empty() && !runtime.core_dumps {
+        violations.add(
+            "capture_core",
+            format!("{} doesn't allow core dumps", runtime.display_name()),
+        );
+    }
+
+    // Limits below the floor would fail before the submission even starts
+    let mut below_floor = false;
+    if let (Some(min_memory), false) = (runtime.min_memory, req.override_floor) {
+        let mut stage_limits = vec![("run", "run_limits", &run_resolution)];
+        if runtime.is_compiled {
+            stage_limits.insert(0, ("compile", "compile_limits", &compile_resolution));
+        }
+        for (stage, field, resolution) in stage_limits {
+            let Some(resolution) = resolution else {
+                continue;
+            };
+            let memory = resolution.effective().memory;
+            if memory < min_memory {
+                below_floor = true;
+                violations.add_detailed(
+                    field,
+                    format!(
+                        "The {stage} memory limit ({memory} KB) is below the {} KB that {} needs to start, pass override_floor to run anyway",
+                        min_memory, runtime.display_name()
+                    ),
+                    MemoryFloorDetail {
+                        code: MEMORY_BELOW_RUNTIME_FLOOR,
+                        memory,
+                        min_memory,
+                    },
+                );
+            }
+        }
+    }
+
+    let entry = if files.is_empty() {
+        req.entry_file_name
+            .clone()
+            .unwrap_or_else(|| runtime.source_file_name.clone())
+    } else {
+        let entry = req
+            .entry
+            .clone()
+            .unwrap_or_else(|| runtime.source_file_name.clone());
+        if !files.iter().any(|(name, _)| *name == entry) {
+            violations.add(
+                "entry",
+                format!("The entry file {entry} is not one of the files"),
+            );
+        }
+        entry
+    };
+
+    let (
+        Some(compile_resolution),
+        Some(run_resolution),
+        Some(cpu_budget),
+        Some(max_bytes),
+        Some(idle_timeout),
+        Some(case_limits),
+    ) = (
+        compile_resolution,
+        run_resolution,
+        cpu_budget,
+        max_bytes,
+        idle_timeout,
+        case_limits,
+    )
+    else {
+        return Err(violations.into_response());
+    };
+    violations.into_result().map_err(|res| {
+        if below_floor {
+            RequestOutcome {
+                runtime_id: Some(runtime_id),
+                error_code: Some(MEMORY_BELOW_RUNTIME_FLOOR),
+                ..Default::default()
+            }
+            .attach_to(res)
+        } else {
+            res
+        }
     })?;
+    let cpu_budget_millis = cpu_budget.map(seconds_to_millis);
+    let stage_options = RunOptions {
+        max_bytes,
+        kill_on_limit: req.kill_on_output_limit,
+        encoding: req.output_encoding,
+        cpu_weight: priority::cpu_weight(req.priority),
+        ..Default::default()
+    };
+    let run_stage_options = RunOptions {
+        idle_timeout,
+        ..stage_options
+    };
+    let compile_limits = compile_resolution.effective();
+    let run_limits = run_resolution.effective();
+    let mut limits_resolution = if explain_limits {
+        Some(ExecutionLimitsResolution {
+            compile: compile_resolution,
+            run: run_resolution,
+        })
+    } else {
+        None
+    };
+
+    let missing_dataset = datasets::find_missing(&req.datasets)
+        .await
+        .map_err(|e| internal_error(host_monitor, format!("Failed to look up datasets: {e}")))?;
+    if let Some(name) = missing_dataset {
+        return Err(dataset_not_found(name));
+    }
+
+    let plan = stage::plan(is_project, runtime.is_compiled);
+    if req.validate_only {
+        return Ok((
+            runtime_id,
+            runtime.display_name(),
+            ExecutionResponse {
+                extract: None,
+                compile: None,
+                run: None,
+                cases: Vec::new(),
+                output_files: Vec::new(),
+                compile_cache: None,
+                core_dump: None,
+                run_skipped: false,
+                budget_exhausted: false,
+                limits_resolution,
+                warnings,
+                verdict: None,
+                queue_wait: None,
+                box_id: None,
+                planned_stages: plan,
+                run_limits: Some(run_limits.clone()),
+                coalesced: false,
+                sandbox: None,
+            },
+        ));
+    }
+
+    let queued_at = Instant::now();
+    // A saturated host runs fewer submissions at once
+    let permits = host_monitor.execution_permits().await;
+    let lease = semaphore
+        .acquire(permits, req.priority, !req.unbounded_queue)
+        .await
+        .map_err(|e| {
+            if let Some(full) = e.downcast_ref::<QueueFull>() {
+                return queue_full_response(full);
+            }
+            eprintln!("Failed to acquire execution semaphore: {e}");
+            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+        })?;
+    let queue_wait = queued_at.elapsed();
+    if let Some(events) = stream_events {
+        let _ = events.send(StreamEvent::Started);
+    }
 
     let current_box_id = get_next_box_id(&box_id);
-    let mut execution_box = Isolate::init(current_box_id).await.map_err(|e| {
-        eprintln!("Failed to initialize sandbox: {e}");
-        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-    })?;
+    lease.set_box_id(current_box_id);
+    let mut execution_box = Isolate::init(current_box_id)
+        .await
+        .map_err(|e| internal_error(host_monitor, format!("Failed to initialize sandbox: {e}")))?;
 
     let initial_submission_dir = format!("{}/submission", execution_box.box_dir);
     fs::create_dir(&initial_submission_dir).await.map_err(|e| {
-        eprintln!("Failed to create submission directory: {e}");
-        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+        internal_error(
+            host_monitor,
+            format!("Failed to create submission directory: {e}"),
+        )
     })?;
 
     if is_project {
@@ -160,91 +1879,375 @@ pub async fn execute(
             &decoded,
         )
         .await
+        .map_err(Error::from)
+    } else if !files.is_empty() {
+        write_files(&initial_submission_dir, &files).await
     } else {
         req.source_code.add_new_line_if_none();
-        fs::write(
-            format!("{}/{}", initial_submission_dir, runtime.source_file_name),
-            &req.source_code,
-        )
-        .await
+        let source = (entry.clone(), req.source_code.clone().into_bytes());
+        write_files(&initial_submission_dir, &[source]).await
     }
     .map_err(|e| {
-        eprintln!(
-            "Failed to write the source code in {}: {}",
-            execution_box.box_dir, e
-        );
-        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+        internal_error(
+            host_monitor,
+            format!(
+                "Failed to write the source code in {}: {}",
+                execution_box.box_dir, e
+            ),
+        )
     })?;
 
-    let extraction_result = if is_project {
-        let res = execution_box
-            .run(
-                &[],
-                &compile_limits,
-                None,
-                "/box/submission",
-                None,
-                &["/bin/unzip", "-qq", SOURCE_ZIP_NAME],
+    let mut stage_index = 0;
+    let extraction_result = if plan.contains(&StageName::Extract) {
+        let mut res = run_stage(
+            &box_id,
+            &mut execution_box,
+            &[],
+            &compile_limits,
+            None,
+            SUBMISSION_DIR,
+            &["/bin/unzip", "-qq", SOURCE_ZIP_NAME],
+            RunOptions::default(),
+        )
+        .await
+        .map_err(|e| {
+            stage_error(
+                host_monitor,
+                "Failed to run isolate to unzip the source file",
+                e,
             )
-            .await
-            .map_err(|e| {
-                eprintln!("Failed to run isolate to unzip the source file: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-            })?;
+        })?;
+        res.stage = Some(StageId::new(StageName::Extract, stage_index));
+        res.limit_set = Some(LimitSet::Compile);
+        stage_index += 1;
         if res.exit_code != Some(0) {
-            return Ok(Json(ExecutionResponse {
-                extract: Some(res),
-                compile: None,
-                run: None,
-            })
-            .into_response());
+            return Ok((
+                runtime_id,
+                runtime.display_name(),
+                ExecutionResponse {
+                    extract: Some(res),
+                    compile: None,
+                    run: None,
+                    cases: Vec::new(),
+                    output_files: Vec::new(),
+                    compile_cache: None,
+                    core_dump: None,
+                    run_skipped: true,
+                    budget_exhausted: false,
+                    limits_resolution: limits_resolution.take(),
+                    warnings,
+                    verdict: None,
+                    queue_wait: Some(queue_wait),
+                    box_id: Some(execution_box.box_id()),
+                    planned_stages: plan.clone(),
+                    run_limits: Some(run_limits.clone()),
+                    coalesced: false,
+                    sandbox: None,
+                },
+            ));
         }
         renew_box(&box_id, &mut execution_box).await.map_err(|e| {
-            eprintln!("Failed to renew box after extraction: {e}");
-            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+            internal_error(
+                host_monitor,
+                format!("Failed to renew box after extraction: {e}"),
+            )
         })?;
         Some(res)
     } else {
         None
     };
 
-    let runtime_dir = format!("{}/{}", RUNTIMES_DIR, req.runtime_id);
-    let mounts = ["/nix", &format!("/runtime={runtime_dir}")];
+    let runtime_dir = format!("{}/{}", RUNTIMES_DIR, runtime_id);
+    let runtime_mount = format!("/runtime={runtime_dir}");
+    // Linked now, a dataset deleted or replaced during the execution doesn't change under it
+    let staged_datasets = if req.datasets.is_empty() {
+        None
+    } else {
+        match datasets::stage(&req.datasets).await {
+            Ok(Staging::Staged(dir)) => Some(dir),
+            Ok(Staging::Missing(name)) => return Err(dataset_not_found(&name)),
+            Err(e) => {
+                return Err(internal_error(
+                    host_monitor,
+                    format!("Failed to stage datasets: {e}"),
+                ))
+            }
+        }
+    };
+    let datasets_mount = staged_datasets
+        .as_ref()
+        .map(|dir| format!("{}={}", datasets::MOUNT_POINT, dir.path));
+    let mut mounts = vec!["/nix", runtime_mount.as_str()];
+    mounts.extend(datasets_mount.as_deref());
 
-    let compile_result = if runtime.is_compiled {
-        let res = execution_box
-            .run(
+    // Forwards the output of a stage when the execution is streamed
+    let forward_output = |stage: StageName| {
+        stream_events.map(|events| {
+            move |stream: OutputStream, chunk: &str| {
+                let _ = events.send(StreamEvent::Output {
+                    stage,
+                    stream,
+                    chunk: chunk.to_string(),
+                });
+            }
+        })
+    };
+    let mut cpu_used_millis = extraction_result.as_ref().map_or(0, cpu_millis);
+    if plan.contains(&StageName::Compile) && is_budget_exhausted(cpu_budget_millis, cpu_used_millis)
+    {
+        return Ok((
+            runtime_id,
+            runtime.display_name(),
+            ExecutionResponse {
+                extract: extraction_result,
+                compile: None,
+                run: None,
+                cases: Vec::new(),
+                output_files: Vec::new(),
+                compile_cache: None,
+                core_dump: None,
+                run_skipped: true,
+                budget_exhausted: true,
+                limits_resolution,
+                warnings,
+                verdict: None,
+                queue_wait: Some(queue_wait),
+                box_id: Some(execution_box.box_id()),
+                planned_stages: plan,
+                run_limits: Some(run_limits.clone()),
+                coalesced: false,
+                sandbox: None,
+            },
+        ));
+    }
+    let compile_cache_key = if plan.contains(&StageName::Compile) && compile_cache::is_enabled() {
+        let submitted: Vec<(&str, &[u8])> = if is_project {
+            vec![(SOURCE_ZIP_NAME, req.source_code.as_bytes())]
+        } else if !files.is_empty() {
+            files
+                .iter()
+                .map(|(name, content)| (name.as_str(), content.as_slice()))
+                .collect()
+        } else {
+            vec![(entry.as_str(), req.source_code.as_bytes())]
+        };
+        let env = req
+            .env
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()));
+        compile_cache::key(
+            runtime_id,
+            &compile_limits,
+            &entry,
+            submitted.into_iter(),
+            env,
+        )
+        .await
+        .map_err(|e| eprintln!("Failed to compute the compile cache key: {e}"))
+        .ok()
+    } else {
+        None
+    };
+    let compile_cache = match &compile_cache_key {
+        Some(key) => Some(compile_cache::lookup(key, &execution_box.box_dir).await),
+        None => None,
+    };
+    let compile_result =
+        if plan.contains(&StageName::Compile) && compile_cache != Some(CacheResult::Hit) {
+            let compile_output = forward_output(StageName::Compile);
+            let mut res = run_stage(
+                &box_id,
+                &mut execution_box,
                 &mounts,
                 &compile_limits,
                 None,
-                "/box/submission",
-                Some(&format!("{runtime_dir}/env")),
-                &["/runtime/compile"],
+                SUBMISSION_DIR,
+                &stage_command("/runtime/compile", &entry, &[]),
+                RunOptions {
+                    env: Some(&req.env),
+                    on_output: compile_output.as_ref().map(|f| f as &OutputSink),
+                    ..stage_options
+                },
+            )
+            .await
+            .map_err(|e| stage_error(host_monitor, "Failed to compile submission", e))?;
+            res.stage = Some(StageId::new(StageName::Compile, stage_index));
+            res.limit_set = Some(LimitSet::Compile);
+            stage_index += 1;
+            cpu_used_millis += cpu_millis(&res);
+
+            if res.exit_code == Some(0) {
+                renew_box(&box_id, &mut execution_box).await.map_err(|e| {
+                    internal_error(host_monitor, format!("Failed to renew box: {e}"))
+                })?;
+                if let Some(key) = &compile_cache_key {
+                    if let Err(e) = compile_cache::store(key, &execution_box.box_dir).await {
+                        eprintln!("Failed to cache the compilation {key}: {e}");
+                    }
+                }
+            } else {
+                return Ok((
+                    runtime_id,
+                    runtime.display_name(),
+                    ExecutionResponse {
+                        extract: extraction_result,
+                        compile: Some(res),
+                        run: None,
+                        cases: Vec::new(),
+                        output_files: Vec::new(),
+                        compile_cache,
+                        core_dump: None,
+                        run_skipped: true,
+                        budget_exhausted: false,
+                        limits_resolution: limits_resolution.take(),
+                        warnings,
+                        verdict: None,
+                        queue_wait: Some(queue_wait),
+                        box_id: Some(execution_box.box_id()),
+                        planned_stages: plan.clone(),
+                        run_limits: Some(run_limits.clone()),
+                        coalesced: false,
+                        sandbox: None,
+                    },
+                ));
+            }
+            Some(res)
+        } else {
+            None
+        };
+
+    let run_command = stage_command("/runtime/run", &entry, &req.args);
+    if !req.cases.is_empty() {
+        let parallelism = req
+            .parallelism
+            .unwrap_or(1)
+            .min(max_case_parallelism())
+            .min(req.cases.len() as u32)
+            .max(1);
+        let batch = Arc::new(CaseBatch {
+            stdout_limit: req.judged_stdout_limit(),
+            cases: std::mem::take(&mut req.cases),
+            limits: case_limits,
+            mounts: mounts.iter().map(|mount| mount.to_string()).collect(),
+            command: run_command.iter().map(|arg| arg.to_string()).collect(),
+            first_stage_index: stage_index,
+            expected_exit_code: req.expected_exit_code,
+            comparator: req.comparator,
+            stop_on_first_failure: req.stop_on_first_failure,
+            next_case: AtomicUsize::new(0),
+            failed: AtomicBool::new(false),
+            events: stream_events.cloned(),
+            cpu_budget_millis,
+            cpu_used_millis: AtomicU64::new(cpu_used_millis),
+            budget_exhausted: AtomicBool::new(false),
+            run_options: run_stage_options,
+            env: req.env.clone(),
+            box_id: box_id.clone(),
+        });
+
+        // The extra boxes only take permits that are free, waiting for them while
+        // holding some could leave every request waiting on the others
+        let mut extra_leases = Vec::new();
+        let mut boxes = Vec::new();
+        for _ in 1..parallelism {
+            let Some(extra_lease) = semaphore.try_acquire(permits) else {
+                break;
+            };
+            let extra_box_id = get_next_box_id(&box_id);
+            extra_lease.set_box_id(extra_box_id);
+            let extra_box = Isolate::init(extra_box_id).await.map_err(|e| {
+                internal_error(host_monitor, format!("Failed to initialize sandbox: {e}"))
+            })?;
+            crate::fs::copy_dir_from_box(
+                &execution_box.box_dir,
+                "submission",
+                &format!("{}/submission", extra_box.box_dir),
             )
             .await
             .map_err(|e| {
-                eprintln!("Failed to compile submission: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+                internal_error(
+                    host_monitor,
+                    format!("Failed to copy the submission to another box: {e}"),
+                )
             })?;
+            boxes.push(extra_box);
+            extra_leases.push(extra_lease);
+        }
+        let first_box_id = execution_box.box_id();
+        boxes.push(execution_box);
 
-        if res.exit_code == Some(0) {
-            renew_box(&box_id, &mut execution_box).await.map_err(|e| {
-                eprintln!("Failed to renew box: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-            })?;
-        } else {
-            return Ok(Json(ExecutionResponse {
+        // Aborted along with the request
+        let mut workers = JoinSet::new();
+        for mut execution_box in boxes {
+            let batch = batch.clone();
+            workers.spawn(async move { batch.run_cases(&mut execution_box).await });
+        }
+        let mut cases = Vec::with_capacity(batch.cases.len());
+        while let Some(res) = workers.join_next().await {
+            let runs = res
+                .map_err(|e| e.into())
+                .and_then(|runs| runs)
+                .map_err(|e| stage_error(host_monitor, "Failed to run the cases", e))?;
+            cases.extend(runs);
+        }
+        cases.sort_by_key(|case| case.case_index);
+        // Boxes still running when the first failure happened may have run later cases
+        if batch.stop_on_first_failure {
+            if let Some(first_failure) = cases.iter().position(|case| case.failed) {
+                cases.truncate(first_failure + 1);
+            }
+        }
+        return Ok((
+            runtime_id,
+            runtime.display_name(),
+            ExecutionResponse {
                 extract: extraction_result,
-                compile: Some(res),
+                compile: compile_result,
                 run: None,
-            })
-            .into_response());
-        }
-        Some(res)
-    } else {
-        None
-    };
-
+                cases,
+                output_files: Vec::new(),
+                compile_cache,
+                core_dump: None,
+                run_skipped: false,
+                budget_exhausted: batch.budget_exhausted.load(Ordering::SeqCst),
+                limits_resolution,
+                warnings,
+                verdict: None,
+                queue_wait: Some(queue_wait),
+                box_id: Some(first_box_id),
+                planned_stages: plan,
+                run_limits: Some(run_limits.clone()),
+                coalesced: false,
+                sandbox: None,
+            },
+        ));
+    }
+    if is_budget_exhausted(cpu_budget_millis, cpu_used_millis) {
+        return Ok((
+            runtime_id,
+            runtime.display_name(),
+            ExecutionResponse {
+                extract: extraction_result,
+                compile: compile_result,
+                run: None,
+                cases: Vec::new(),
+                output_files: Vec::new(),
+                compile_cache,
+                core_dump: None,
+                run_skipped: true,
+                budget_exhausted: true,
+                limits_resolution,
+                warnings,
+                verdict: None,
+                queue_wait: Some(queue_wait),
+                box_id: Some(execution_box.box_id()),
+                planned_stages: plan,
+                run_limits: Some(run_limits.clone()),
+                coalesced: false,
+                sandbox: None,
+            },
+        ));
+    }
     let stdin = if let Some(mut s) = req.input {
         s.add_new_line_if_none();
         Some(s)
@@ -252,27 +2255,199 @@ pub async fn execute(
         None
     };
 
-    let run_result = Some(
-        execution_box
-            .run(
-                &mounts,
-                &run_limits,
-                stdin.as_deref(),
-                "/box/submission",
-                Some(&format!("{runtime_dir}/env")),
-                &["/runtime/run"],
-            )
-            .await
-            .map_err(|e| {
-                eprintln!("Failed to run submission: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-            })?,
-    );
+    let run_output = forward_output(StageName::Run);
+    let mut run_result = run_stage(
+        &box_id,
+        &mut execution_box,
+        &mounts,
+        &run_limits,
+        stdin.as_deref(),
+        SUBMISSION_DIR,
+        &run_command,
+        RunOptions {
+            env: Some(&req.env),
+            on_output: run_output.as_ref().map(|f| f as &OutputSink),
+            core_size,
+            ..run_stage_options
+        },
+    )
+    .await
+    .map_err(|e| stage_error(host_monitor, "Failed to run submission", e))?;
+    run_result.stage = Some(StageId::new(StageName::Run, stage_index));
+    run_result.limit_set = Some(LimitSet::Run);
+    let core_dump = match core_size {
+        Some(_) => Some(
+            core_dumps::capture(&execution_box.box_dir, "submission", run_result.exit_signal).await,
+        ),
+        None => None,
+    };
+    let output_files = read_output_files(
+        &format!("{}/submission", execution_box.box_dir),
+        &req.output_files,
+        run_stage_options
+            .max_bytes
+            .unwrap_or(DEFAULT_OUTPUT_FILE_BYTES),
+    )
+    .await;
+
+    Ok((
+        runtime_id,
+        runtime.display_name(),
+        ExecutionResponse {
+            extract: extraction_result,
+            compile: compile_result,
+            run: Some(run_result),
+            cases: Vec::new(),
+            output_files,
+            compile_cache,
+            core_dump,
+            run_skipped: false,
+            budget_exhausted: false,
+            limits_resolution,
+            warnings,
+            verdict: None,
+            queue_wait: Some(queue_wait),
+            box_id: Some(execution_box.box_id()),
+            planned_stages: plan,
+            run_limits: Some(run_limits.clone()),
+            coalesced: false,
+            sandbox: None,
+        },
+    ))
+}
 
-    Ok(Json(ExecutionResponse {
-        extract: extraction_result,
-        compile: compile_result,
-        run: run_result,
-    })
-    .into_response())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_budget_is_exhausted_once_reached() {
+        assert!(!is_budget_exhausted(None, u64::MAX));
+        assert!(!is_budget_exhausted(Some(1000), 0));
+        assert!(!is_budget_exhausted(Some(1000), 999));
+        assert!(is_budget_exhausted(Some(1000), 1000));
+        assert!(is_budget_exhausted(Some(1000), 1001));
+    }
+
+    #[test]
+    fn seconds_are_rounded_to_milliseconds() {
+        assert_eq!(seconds_to_millis(0.0), 0);
+        assert_eq!(seconds_to_millis(0.123), 123);
+        assert_eq!(seconds_to_millis(1.001), 1001);
+        assert_eq!(seconds_to_millis(0.0004), 0);
+        assert_eq!(seconds_to_millis(0.0005), 1);
+    }
+
+    #[test]
+    fn the_budget_is_resolved_within_the_maximum() {
+        let unlimited = CpuBudget::default();
+        assert_eq!(cpu_budget_of(unlimited, None), Ok(None));
+        assert_eq!(cpu_budget_of(unlimited, Some(100.0)), Ok(Some(100.0)));
+
+        let config = CpuBudget {
+            default: Some(5.0),
+            max: Some(10.0),
+        };
+        assert_eq!(cpu_budget_of(config, None), Ok(Some(5.0)));
+        assert_eq!(cpu_budget_of(config, Some(10.0)), Ok(Some(10.0)));
+        assert_eq!(
+            cpu_budget_of(config, Some(10.001)),
+            Err("max_total_cpu_seconds can't be more than 10".to_string())
+        );
+        // Without a default, requests get the maximum
+        let config = CpuBudget {
+            default: None,
+            max: Some(10.0),
+        };
+        assert_eq!(cpu_budget_of(config, None), Ok(Some(10.0)));
+    }
+
+    #[test]
+    fn the_budget_must_be_positive() {
+        let error = Err("max_total_cpu_seconds must be positive".to_string());
+        for requested in [0.0, -1.0, f32::NAN] {
+            assert_eq!(cpu_budget_of(CpuBudget::default(), Some(requested)), error);
+        }
+    }
+
+    #[test]
+    fn file_names_and_args_stay_single_arguments_of_the_stage_command() {
+        let hostile = crate::strings::hostile_strings();
+        for name in &hostile {
+            for args in [vec![], vec![name.clone()], hostile[..3].to_vec()] {
+                let command = stage_command("/runtime/run", name, &args);
+                assert_eq!(
+                    command[..5],
+                    [
+                        "/bin/bash",
+                        "-c",
+                        STAGE_LOADER,
+                        STAGE_LOADER_NAME,
+                        "/runtime/run"
+                    ]
+                );
+                assert_eq!(command.len(), 6 + args.len());
+                assert_eq!(command[5], name);
+                assert_eq!(
+                    command[6..],
+                    args.iter().map(String::as_str).collect::<Vec<_>>()
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn the_request_env_takes_precedence_over_the_runtime_env() {
+        let tmp = std::env::temp_dir().to_string_lossy().into_owned();
+        let dir = crate::temp_dir::TempDir::new(format!(
+            "{tmp}/envicutor-stage-env-{}",
+            std::process::id()
+        ))
+        .await
+        .unwrap();
+        let runtime_env = [
+            ("TZ", "Asia/Tokyo"),
+            ("LANG", "C.UTF-8"),
+            ("CC", "gcc"),
+            ("HOME", "/root"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        let env_path = format!("{}/env.sh", dir.path);
+        std::fs::write(
+            &env_path,
+            crate::env_snapshot::serialize_as_shell(&runtime_env),
+        )
+        .unwrap();
+
+        let layer = |entries: &[(&str, &str)]| -> SandboxEnv {
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        let default_env = layer(&[("TZ", "UTC"), ("LANG", "C"), ("ENVICUTOR", "1")]);
+        let request_env = layer(&[("TZ", "Europe/Paris"), ("CC", "it's \"clang\"\n")]);
+        let sandbox_managed = layer(&[("HOME", "/tmp")]);
+        let loader = STAGE_LOADER.replace("/runtime/env.sh", &env_path);
+        assert_ne!(loader, STAGE_LOADER);
+        assert!(STAGE_LOADER.contains(sandbox_env::REQUEST_ENV_KEYS));
+
+        let output = std::process::Command::new("/bin/bash")
+            .args(["-c", &loader, STAGE_LOADER_NAME, "/bin/bash", "-c"])
+            .arg("printf '%s|' \"$TZ\" \"$LANG\" \"$CC\" \"$HOME\" \"$ENVICUTOR\" \"${ENVICUTOR_REQUEST_ENV-unset}\"")
+            .env_clear()
+            .envs(sandbox_env::stage_env(
+                &default_env,
+                &request_env,
+                &sandbox_managed,
+            ))
+            .current_dir(&dir.path)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "Europe/Paris|C.UTF-8|it's \"clang\"\n|/tmp|1|unset|"
+        );
+    }
 }
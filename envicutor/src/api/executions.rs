@@ -0,0 +1,255 @@
+use std::sync::{atomic::AtomicU64, Arc};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    api::{
+        common_responses::{Message, StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
+        execution::{
+            judged_execution, ExecutionQuery, ExecutionRequest, ExecutionResponse,
+            RetainedExecution,
+        },
+        json_body::JsonBodyOrDefault,
+    },
+    events::Events,
+    history::{self, ExecutionRecord, HistoryQuery, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE},
+    host::HostMonitor,
+    leases::ExecutionSlots,
+    limits::SystemLimits,
+    types::{Metadata, RuntimeId},
+};
+
+#[derive(Deserialize)]
+pub struct ExecutionsQuery {
+    runtime_id: Option<RuntimeId>,
+    // Unix milliseconds
+    since: Option<u64>,
+    before: Option<i64>,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct ExecutionsResponse {
+    executions: Vec<ExecutionRecord>,
+    // Passed as `before` to get the next page, absent on the last one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_before: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct PruneResponse {
+    removed: u64,
+}
+
+const HISTORY_DISABLED_RESPONSE: (StatusCode, Json<StaticMessage>) = (
+    StatusCode::NOT_FOUND,
+    Json(StaticMessage {
+        message: "The execution history is not enabled, set EXECUTION_HISTORY to keep it",
+    }),
+);
+
+pub async fn list_executions(
+    query: Option<Query<ExecutionsQuery>>,
+) -> Result<Json<ExecutionsResponse>, Response<Body>> {
+    if !history::is_enabled() {
+        return Err(HISTORY_DISABLED_RESPONSE.into_response());
+    }
+    let query = query.map(|query| query.0);
+    let limit = query
+        .as_ref()
+        .and_then(|query| query.limit)
+        .unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(Message {
+                message: format!("limit must be between 1 and {MAX_PAGE_SIZE}"),
+            }),
+        )
+            .into_response());
+    }
+    let executions = history::query(HistoryQuery {
+        runtime_id: query.as_ref().and_then(|query| query.runtime_id),
+        since: query.as_ref().and_then(|query| query.since),
+        before: query.as_ref().and_then(|query| query.before),
+        limit,
+    })
+    .await
+    .map_err(|e| {
+        eprintln!("Failed to query the execution history: {e}");
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    })?;
+    let next_before = (executions.len() == limit as usize)
+        .then(|| executions.last().map(|execution| execution.id))
+        .flatten();
+    Ok(Json(ExecutionsResponse {
+        executions,
+        next_before,
+    }))
+}
+
+// Prunes the history right away rather than at the next scheduled pruning
+pub async fn prune_executions() -> Result<Json<PruneResponse>, Response<Body>> {
+    if !history::is_enabled() {
+        return Err(HISTORY_DISABLED_RESPONSE.into_response());
+    }
+    let removed = history::prune().await.map_err(|e| {
+        eprintln!("Failed to prune the execution history: {e}");
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    })?;
+    Ok(Json(PruneResponse { removed }))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ReplayRequest {
+    // Replays even though the runtime changed since the execution
+    #[serde(default)]
+    allow_changed_runtime: bool,
+}
+
+#[derive(Serialize)]
+pub struct Compared<T> {
+    original: Option<T>,
+    replay: Option<T>,
+    changed: bool,
+}
+
+impl<T: PartialEq> Compared<T> {
+    fn new(original: Option<T>, replay: Option<T>) -> Self {
+        Compared {
+            changed: original != replay,
+            original,
+            replay,
+        }
+    }
+}
+
+// Of the last stage on both sides, as recorded in the history
+#[derive(Serialize)]
+pub struct ReplayDiff {
+    verdict: Compared<String>,
+    exit_code: Compared<u32>,
+    stdout_sha256: Compared<String>,
+    stderr_sha256: Compared<String>,
+}
+
+#[derive(Serialize)]
+pub struct ReplayResponse {
+    original: ExecutionRecord,
+    replay: ExecutionResponse,
+    runtime_changed: bool,
+    diff: ReplayDiff,
+}
+
+fn replay_error(status: StatusCode, message: &'static str) -> Response<Body> {
+    (status, Json(StaticMessage { message })).into_response()
+}
+
+// Runs a recorded execution again with the request it was recorded with, to
+// tell flaky and environment dependent results apart. Only executions whose
+// request was kept under EXECUTION_HISTORY_REQUEST_BYTES can be replayed
+#[allow(clippy::too_many_arguments)]
+pub async fn replay_execution(
+    semaphore: Arc<ExecutionSlots>,
+    box_id: Arc<AtomicU64>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    host_monitor: Arc<HostMonitor>,
+    events: Events,
+    Path(id): Path<i64>,
+    JsonBodyOrDefault(replay_request): JsonBodyOrDefault<ReplayRequest>,
+) -> Result<Json<ReplayResponse>, Response<Body>> {
+    if !history::is_enabled() {
+        return Err(HISTORY_DISABLED_RESPONSE.into_response());
+    }
+    let original = history::get(id)
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to read execution {id} from the history: {e}");
+            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+        })?
+        .ok_or_else(|| {
+            replay_error(
+                StatusCode::NOT_FOUND,
+                "Could not find the specified execution",
+            )
+        })?;
+    let retained = history::retained_request(id)
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to read the request of execution {id}: {e}");
+            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+        })?
+        .ok_or_else(|| {
+            replay_error(
+                StatusCode::GONE,
+                "The request of the execution was not kept or has been pruned",
+            )
+        })?;
+    let fingerprint = history::runtime_fingerprint(original.runtime_id).await;
+    let runtime_changed = fingerprint.is_none() || fingerprint != retained.runtime_fingerprint;
+    if runtime_changed && !replay_request.allow_changed_runtime {
+        return Err(replay_error(
+            StatusCode::CONFLICT,
+            "The runtime changed since the execution, pass allow_changed_runtime: true to replay it anyway",
+        ));
+    }
+    let RetainedExecution { request, query } = serde_json::from_str::<
+        RetainedExecution<ExecutionRequest, ExecutionQuery>,
+    >(&retained.request)
+    .map_err(|e| {
+        eprintln!("Failed to parse the kept request of execution {id}: {e}");
+        replay_error(
+            StatusCode::GONE,
+            "The kept request of the execution can no longer be read",
+        )
+    })?;
+    let (_, _, replay) = judged_execution(
+        semaphore,
+        box_id,
+        metadata_cache,
+        installation_lock,
+        system_limits,
+        &host_monitor,
+        &events,
+        request,
+        query.map(Query),
+        None,
+    )
+    .await?;
+    let last_stage = replay.last_stage();
+    let diff = ReplayDiff {
+        verdict: Compared::new(
+            original.verdict.clone(),
+            replay.verdict.and_then(history::verdict_name),
+        ),
+        exit_code: Compared::new(
+            original.exit_code,
+            last_stage.and_then(|stage| stage.exit_code),
+        ),
+        stdout_sha256: Compared::new(
+            original.stdout_sha256.clone(),
+            last_stage.map(|stage| stage.stdout_sha256.clone()),
+        ),
+        stderr_sha256: Compared::new(
+            original.stderr_sha256.clone(),
+            last_stage.map(|stage| stage.stderr_sha256.clone()),
+        ),
+    };
+    Ok(Json(ReplayResponse {
+        original,
+        replay,
+        runtime_changed,
+        diff,
+    }))
+}
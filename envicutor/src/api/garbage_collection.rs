@@ -0,0 +1,312 @@
+use std::{collections::BTreeSet, future::Future, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Error};
+use axum::{
+    body::Body,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use tokio::{
+    fs,
+    process::Command,
+    sync::{Mutex, RwLock},
+    time,
+};
+
+use crate::{
+    api::common_responses::INTERNAL_SERVER_ERROR_RESPONSE,
+    events::{Event, Events},
+    globals::{GC_ROOTS_DIR, NIX_BIN_PATH, RUNTIMES_DIR},
+    quarantine,
+    types::{Metadata, RuntimeId, WholeSeconds},
+};
+
+const STORE_DIR: &str = "/nix/store/";
+const STORE_HASH_LENGTH: usize = 32;
+
+// Held by the collection under way, the GC roots directory is rebuilt by each
+static COLLECTING: Mutex<()> = Mutex::const_new(());
+
+#[derive(Serialize)]
+pub struct GarbageCollectionReport {
+    pub roots: usize,
+    pub bytes_freed: Option<u64>,
+    // Quarantined boxes that could be initialized again
+    pub rehabilitated_boxes: usize,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+fn is_store_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "+-._?=".contains(c)
+}
+
+pub fn collect_store_paths(env: &str, paths: &mut BTreeSet<String>) {
+    for (i, _) in env.match_indices(STORE_DIR) {
+        let rest = &env[i + STORE_DIR.len()..];
+        let end = rest.find(|c| !is_store_name_char(c)).unwrap_or(rest.len());
+        let name = &rest[..end];
+        if name.len() > STORE_HASH_LENGTH + 1 && name.as_bytes()[STORE_HASH_LENGTH] == b'-' {
+            paths.insert(format!("{STORE_DIR}{name}"));
+        }
+    }
+}
+
+pub async fn get_runtime_store_paths(runtime_ids: &[RuntimeId]) -> Result<BTreeSet<String>, Error> {
+    read_store_paths(RUNTIMES_DIR, runtime_ids).await
+}
+
+async fn read_store_paths(
+    runtimes_dir: &str,
+    runtime_ids: &[RuntimeId],
+) -> Result<BTreeSet<String>, Error> {
+    let mut paths = BTreeSet::new();
+    for id in runtime_ids {
+        let env_path = format!("{runtimes_dir}/{id}/env");
+        let env = fs::read_to_string(&env_path)
+            .await
+            .map_err(|e| anyhow!("Failed to read {env_path}\nError: {e}"))?;
+        collect_store_paths(&env, &mut paths);
+    }
+    Ok(paths)
+}
+
+pub async fn register_gc_roots(
+    paths: &BTreeSet<String>,
+    gc_roots_dir: &str,
+) -> Result<usize, Error> {
+    crate::fs::create_dir_replacing_existing(&gc_roots_dir.to_string()).await?;
+    let mut roots = 0;
+    for path in paths {
+        if !fs::try_exists(path).await.unwrap_or(false) {
+            eprintln!("Store path {path} referenced by a runtime does not exist, not rooting it");
+            continue;
+        }
+        let Some(name) = Path::new(path).file_name() else {
+            continue;
+        };
+        let link = format!("{gc_roots_dir}/{}", name.to_string_lossy());
+        fs::symlink(path, &link)
+            .await
+            .map_err(|e| anyhow!("Failed to create GC root {link}\nError: {e}"))?;
+        roots += 1;
+    }
+    Ok(roots)
+}
+
+fn parse_size(value: &str, unit: &str) -> Option<u64> {
+    let multiplier: f64 = match unit {
+        "bytes" | "B" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    value.parse::<f64>().ok().map(|v| (v * multiplier) as u64)
+}
+
+// nix reports e.g. "12 store paths deleted, 345.67 MiB freed"
+pub fn parse_bytes_freed(output: &str) -> Option<u64> {
+    output.lines().rev().find_map(|line| {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let freed = words.iter().position(|word| *word == "freed")?;
+        if freed < 2 {
+            return None;
+        }
+        parse_size(words[freed - 2], words[freed - 1])
+    })
+}
+
+// One collection at a time. Installations (which take the lock for writing) wait so
+// that no runtime appears mid-collection, executions don't unless one is waiting
+// already, the lock is fair
+async fn exclusively<T>(installation_lock: &RwLock<u8>, collection: impl Future<Output = T>) -> T {
+    let _collecting = COLLECTING.lock().await;
+    let _permit = installation_lock.read().await;
+    collection.await
+}
+
+pub async fn collect_garbage(
+    gc_timeout: WholeSeconds,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    events: Events,
+) -> Result<GarbageCollectionReport, Error> {
+    exclusively(
+        &installation_lock,
+        collect_rooted_garbage(gc_timeout, metadata_cache, events),
+    )
+    .await
+}
+
+async fn collect_rooted_garbage(
+    gc_timeout: WholeSeconds,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    events: Events,
+) -> Result<GarbageCollectionReport, Error> {
+    let runtime_ids: Vec<RuntimeId> = metadata_cache.read().await.keys().copied().collect();
+    let paths = get_runtime_store_paths(&runtime_ids).await?;
+    let roots = register_gc_roots(&paths, GC_ROOTS_DIR).await?;
+    eprintln!("Registered {roots} GC roots for installed runtimes, collecting garbage");
+
+    let mut cmd = Command::new("env");
+    cmd.arg("-i")
+        .arg("PATH=/bin")
+        .arg(format!("{NIX_BIN_PATH}/nix-store"))
+        .arg("--gc")
+        .kill_on_drop(true);
+    let cmd_res = time::timeout(Duration::from_secs(gc_timeout.into()), cmd.output())
+        .await
+        .map_err(|_| anyhow!("Garbage collection timed out after {gc_timeout} seconds"))?
+        .map_err(|e| anyhow!("Failed to run nix-store --gc: {e}"))?;
+    let stdout = String::from_utf8_lossy(&cmd_res.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&cmd_res.stderr).to_string();
+    if !cmd_res.status.success() {
+        return Err(anyhow!(
+            "nix-store --gc failed with\nstdout: {stdout}\nstderr: {stderr}"
+        ));
+    }
+
+    let bytes_freed = parse_bytes_freed(&stdout).or_else(|| parse_bytes_freed(&stderr));
+    match bytes_freed {
+        Some(bytes_freed) => eprintln!("Garbage collection freed {bytes_freed} bytes"),
+        None => eprintln!("Garbage collection finished, could not determine the freed size"),
+    }
+    events.publish(Event::GarbageCollected { roots, bytes_freed });
+    let rehabilitated_boxes = quarantine::rehabilitate().await;
+    Ok(GarbageCollectionReport {
+        roots,
+        bytes_freed,
+        rehabilitated_boxes,
+        stdout,
+        stderr,
+    })
+}
+
+pub async fn run_garbage_collection(
+    gc_timeout: WholeSeconds,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    events: Events,
+) -> Result<Response<Body>, Response<Body>> {
+    let report = collect_garbage(gc_timeout, metadata_cache, installation_lock, events)
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to collect garbage: {e}");
+            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+        })?;
+    Ok(Json(report).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    const PYTHON: &str = "0123456789abcdfghijklmnpqrsvwxyz-python3-3.11.9";
+    const GCC: &str = "zyxwvsrqpnmlkjihgfdcba9876543210-gcc-wrapper-13.2.0";
+
+    async fn temp_dir(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().to_string_lossy().into_owned();
+        TempDir::new(format!("{dir}/envicutor-gc-{name}-{}", std::process::id()))
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn store_paths_are_picked_out_of_env_values() {
+        let mut paths = BTreeSet::new();
+        collect_store_paths(
+            &format!("PATH=/nix/store/{PYTHON}/bin:/nix/store/{GCC}/bin:/bin\nCC=/nix/store/{GCC}/bin/cc\nSHORT=/nix/store/abc-x\n"),
+            &mut paths,
+        );
+        assert_eq!(
+            paths.into_iter().collect::<Vec<_>>(),
+            [format!("/nix/store/{PYTHON}"), format!("/nix/store/{GCC}")]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_existing_store_paths_of_runtimes_are_rooted() {
+        let runtimes = temp_dir("runtimes").await;
+        let store = temp_dir("store").await;
+        let roots = temp_dir("roots").await;
+        for (id, env) in [
+            (1, format!("PATH=/nix/store/{PYTHON}/bin\n")),
+            (
+                2,
+                format!("PATH=/nix/store/{PYTHON}/bin:/nix/store/{GCC}/bin\n"),
+            ),
+        ] {
+            fs::create_dir(format!("{}/{id}", runtimes.path))
+                .await
+                .unwrap();
+            fs::write(format!("{}/{id}/env", runtimes.path), env)
+                .await
+                .unwrap();
+        }
+        let paths = read_store_paths(&runtimes.path, &[1, 2]).await.unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(read_store_paths(&runtimes.path, &[3]).await.is_err());
+
+        // The paths as they would be in a store, only Python's is there
+        fs::create_dir(format!("{}/{PYTHON}", store.path))
+            .await
+            .unwrap();
+        let in_store: BTreeSet<String> = paths
+            .iter()
+            .map(|path| format!("{}/{}", store.path, &path[STORE_DIR.len()..]))
+            .collect();
+        // Roots left by a previous collection are replaced
+        fs::write(format!("{}/stale", roots.path), "")
+            .await
+            .unwrap();
+        assert_eq!(register_gc_roots(&in_store, &roots.path).await.unwrap(), 1);
+        let link = format!("{}/{PYTHON}", roots.path);
+        assert_eq!(
+            fs::read_link(&link).await.unwrap().to_string_lossy(),
+            format!("{}/{PYTHON}", store.path)
+        );
+        assert!(!fs::try_exists(format!("{}/{GCC}", roots.path))
+            .await
+            .unwrap());
+        assert!(!fs::try_exists(format!("{}/stale", roots.path))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn collections_hold_off_installations_and_each_other_but_not_executions() {
+        let installation_lock = Arc::new(RwLock::new(0));
+        let (started_tx, started_rx) = oneshot::channel();
+        let (finish_tx, finish_rx) = oneshot::channel::<()>();
+        let collection = tokio::spawn({
+            let installation_lock = installation_lock.clone();
+            async move {
+                exclusively(&installation_lock, async move {
+                    started_tx.send(()).unwrap();
+                    finish_rx.await.unwrap();
+                })
+                .await
+            }
+        });
+        started_rx.await.unwrap();
+
+        assert!(installation_lock.try_write().is_err());
+        assert!(installation_lock.try_read().is_ok());
+        let second = exclusively(&installation_lock, async {});
+        tokio::pin!(second);
+        assert!(time::timeout(Duration::from_millis(50), &mut second)
+            .await
+            .is_err());
+
+        finish_tx.send(()).unwrap();
+        collection.await.unwrap();
+        second.await;
+        assert!(installation_lock.try_write().is_ok());
+    }
+}
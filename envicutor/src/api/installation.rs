@@ -1,19 +1,33 @@
 use std::{
     fs::Permissions,
-    os::unix::fs::PermissionsExt,
+    os::unix::{fs::PermissionsExt, process::ExitStatusExt},
     sync::{atomic::AtomicU64, Arc},
 };
 
 use crate::{
+    access_log::RequestOutcome,
     api::{
+        aliases::{check_aliases, find_conflict, replace_aliases},
         common_functions::get_next_box_id,
-        common_responses::{Message, StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
+        common_responses::{StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
+        json_body::JsonBody,
+        validation::Violations,
     },
-    globals::{DB_PATH, RUNTIMES_DIR, TEMP_DIR},
-    strings::NewLine,
+    clock,
+    core_dumps::CORE_DUMPS_DISABLED_FILE_NAME,
+    env_snapshot,
+    events::{Event, Events},
+    globals::{DB_PATH, NIX_BIN_PATH, RUNTIMES_DIR, TEMP_DIR},
+    health::RuntimeHealth,
+    nix_build::{self, NixBuildOptions},
+    nix_errors::{self, FailureCategory},
+    request_limits::{check_count, RequestLimits},
+    script_lint::{self, ScriptFinding},
+    signals::signal_name,
+    strings::{is_valid_name, NewLine},
     temp_dir::TempDir,
     transaction::Transaction,
-    types::{Metadata, Runtime, WholeSeconds},
+    types::{Kilobytes, Metadata, Runtime, RuntimeId, WholeSeconds, MIN_MEMORY_FILE_NAME},
 };
 use axum::{
     body::Body,
@@ -27,57 +41,140 @@ use tokio::{fs, process::Command, sync::RwLock, task};
 
 #[derive(Deserialize)]
 pub struct AddRuntimeRequest {
-    name: String,
-    nix_shell: String,
-    compile_script: String,
-    run_script: String,
-    source_file_name: String,
+    pub name: String,
+    pub nix_shell: String,
+    pub compile_script: String,
+    pub run_script: String,
+    pub source_file_name: String,
+    #[serde(default)]
+    pub env_include: Vec<String>,
+    #[serde(default)]
+    pub env_exclude: Vec<String>,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    pub min_memory: Option<Kilobytes>,
+    #[serde(default)]
+    pub normalize_line_endings: bool,
+    #[serde(default)]
+    pub strict_scripts: bool,
+    // Clamped to NIX_MAX_CORES and NIX_MAX_JOBS
+    pub nix_cores: Option<u32>,
+    pub nix_max_jobs: Option<u32>,
+    // Runs never get core dumps, even when the server allows them
+    #[serde(default)]
+    pub disable_core_dumps: bool,
 }
 
 #[derive(Serialize)]
 pub struct InstallationResponse {
     stdout: String,
     stderr: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filtered_env_variables: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failure_category: Option<FailureCategory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failure_excerpt: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    script_warnings: Vec<ScriptFinding>,
+    nix_build: NixBuildOptions,
+    // Of nix, when it was killed
+    exit_signal: Option<u32>,
+    exit_signal_name: Option<String>,
+    // Around the nix command, see `clock`
+    started_at: String,
+    finished_at: String,
 }
 
-async fn validate_request(req: &AddRuntimeRequest) -> Result<(), Response<Body>> {
-    let bad_request_message = if req.name.is_empty() {
-        "Name can't be empty"
-    } else if req.nix_shell.is_empty() {
-        "Nix shell can't be empty"
-    } else if req.run_script.is_empty() {
-        "Run command can't be empty"
-    } else if req.source_file_name.is_empty() {
-        "Source file name can't be empty"
-    } else if sanitize_filename::sanitize(&req.source_file_name) != req.source_file_name {
-        "Invalid source file name"
-    } else {
-        ""
-    };
-    if !bad_request_message.is_empty() {
-        Err((
-            StatusCode::BAD_REQUEST,
-            Json(Message {
-                message: bad_request_message.to_string(),
-            }),
-        )
-            .into_response())
-    } else {
-        Ok(())
+#[derive(Serialize)]
+struct ScriptFailureResponse {
+    message: &'static str,
+    script_warnings: Vec<ScriptFinding>,
+}
+
+// Scripts with CRLF line endings are refused by `validate_request` unless they are normalized
+fn lint_script_sources(req: &mut AddRuntimeRequest) -> Vec<ScriptFinding> {
+    let mut findings = Vec::new();
+    if req.normalize_line_endings {
+        for (script_name, script) in [
+            ("compile", &mut req.compile_script),
+            ("run", &mut req.run_script),
+        ] {
+            findings.extend(script_lint::normalize_line_endings(script_name, script));
+        }
     }
+    // Executions can give the run script more arguments
+    findings.extend(script_lint::find_unprovided_parameters(
+        "compile",
+        &req.compile_script,
+    ));
+    findings
 }
 
-const NIX_BIN_PATH: &str = "/home/envicutor/.nix-profile/bin";
+// Every problem of the request is answered at once
+pub(crate) async fn validate_request(
+    req: &AddRuntimeRequest,
+    request_limits: &RequestLimits,
+) -> Result<(), Response<Body>> {
+    let mut violations = Violations::default();
+    for (field, patterns) in [
+        ("env_include", &req.env_include),
+        ("env_exclude", &req.env_exclude),
+    ] {
+        if let Err(e) = check_count(field, patterns.len(), request_limits.max_env_patterns) {
+            violations.add(field, e.to_string());
+        }
+    }
+    check_aliases(&req.aliases, &req.name, request_limits, &mut violations);
+    if req.name.is_empty() {
+        violations.add("name", "Name can't be empty");
+    } else if !is_valid_name(&req.name) {
+        violations.add(
+            "name",
+            "Names must have at most 256 printable ASCII characters",
+        );
+    }
+    if req.nix_shell.is_empty() {
+        violations.add("nix_shell", "Nix shell can't be empty");
+    }
+    if req.run_script.is_empty() {
+        violations.add("run_script", "Run command can't be empty");
+    }
+    if req.source_file_name.is_empty() {
+        violations.add("source_file_name", "Source file name can't be empty");
+    } else if sanitize_filename::sanitize(&req.source_file_name) != req.source_file_name {
+        violations.add("source_file_name", "Invalid source file name");
+    }
+    if !req.normalize_line_endings {
+        for (field, script_name, script) in [
+            ("compile_script", "compile", &req.compile_script),
+            ("run_script", "run", &req.run_script),
+        ] {
+            if script_lint::has_crlf(script) {
+                violations.add(
+                    field,
+                    format!("The {script_name} script has CRLF line endings, set normalize_line_endings to convert them"),
+                );
+            }
+        }
+    }
+    violations.into_result()
+}
 
 pub async fn install_runtime(
     installation_timeout: WholeSeconds,
     box_id: Arc<AtomicU64>,
     metadata_cache: Arc<RwLock<Metadata>>,
     installation_lock: Arc<RwLock<u8>>,
-    Json(mut req): Json<AddRuntimeRequest>,
+    events: Events,
+    request_limits: RequestLimits,
+    JsonBody(mut req): JsonBody<AddRuntimeRequest>,
 ) -> Result<Response<Body>, Response<Body>> {
     let _permit = installation_lock.write().await;
-    validate_request(&req).await?;
+    validate_request(&req, &request_limits).await?;
+    let mut script_warnings = lint_script_sources(&mut req);
     req.nix_shell.add_new_line_if_none();
     req.compile_script.add_new_line_if_none();
     req.run_script.add_new_line_if_none();
@@ -112,29 +209,67 @@ pub async fn install_runtime(
         )
             .into_response());
     }
+    find_conflict(
+        &metadata_guard,
+        std::iter::once(&req.name).chain(&req.aliases),
+        None,
+    )?;
     drop(metadata_guard);
 
+    let nix_build = nix_build::resolve(req.nix_cores, req.nix_max_jobs);
     let mut cmd = Command::new("env");
     cmd.arg("-i")
         .arg("PATH=/bin")
         .arg(format!("{NIX_BIN_PATH}/nix-shell"))
         .args(["--timeout".to_string(), installation_timeout.to_string()])
+        .args(nix_build.args())
         .arg(nix_shell_path)
         .args(["--run", "/bin/bash -c env"]);
+    let started_at = clock::now();
     let cmd_res = cmd.output().await.map_err(|e| {
         eprintln!("Failed to run nix-shell: {e}");
         INTERNAL_SERVER_ERROR_RESPONSE.into_response()
     })?;
+    let finished_at = clock::now();
     let stdout = String::from_utf8_lossy(&cmd_res.stdout).to_string();
     let stderr = String::from_utf8_lossy(&cmd_res.stderr).to_string();
     let success = cmd_res.status.success();
+    let exit_signal = cmd_res.status.signal().map(|signal| signal as u32);
+    let mut filtered_env_variables = None;
+    let mut warnings = Vec::new();
+    let mut outcome = RequestOutcome::default();
 
     if success {
+        let env_entries = env_snapshot::parse(&stdout);
+        let path_var = env_entries
+            .iter()
+            .find(|(key, _)| key == "PATH")
+            .map(|(_, value)| value.as_str())
+            .unwrap_or("");
+        for (script_name, script) in [("compile", &req.compile_script), ("run", &req.run_script)] {
+            script_warnings.extend(script_lint::check_interpreter(
+                script_name,
+                script,
+                path_var,
+            ));
+        }
+        if script_lint::blocks_installation(&script_warnings, req.strict_scripts) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ScriptFailureResponse {
+                    message: "The scripts of the runtime can't run",
+                    script_warnings,
+                }),
+            )
+                .into_response());
+        }
+
         let runtime_name = req.name.clone();
         let source_file_name = req.source_file_name.clone();
+        let aliases = req.aliases.clone();
 
         let (runtime_id, mut trx) = task::spawn_blocking(move || {
-            let connection = Connection::open(DB_PATH).map_err(|e| {
+            let mut connection = Connection::open(DB_PATH).map_err(|e| {
                 eprintln!("Failed to open SQLite connection: {e}");
                 INTERNAL_SERVER_ERROR_RESPONSE.into_response()
             })?;
@@ -151,7 +286,14 @@ pub async fn install_runtime(
 
             let trx = Transaction::init(
                 move |conn| {
-                    let res = conn.execute("DELETE FROM runtime WHERE name = ?", [&runtime_name]);
+                    let res = conn
+                        .execute(
+                            "DELETE FROM runtime_alias WHERE runtime_id = (SELECT id FROM runtime WHERE name = ?)",
+                            [&runtime_name],
+                        )
+                        .and_then(|_| {
+                            conn.execute("DELETE FROM runtime WHERE name = ?", [&runtime_name])
+                        });
                     if let Err(e) = res {
                         eprintln!(
                             "Failed to remove runtime with name: {runtime_name} during rollback\nError: {e}"
@@ -160,12 +302,21 @@ pub async fn install_runtime(
                 },
             );
 
-            let row_id = connection
+            let row_id: RuntimeId = connection
                 .query_row("SELECT last_insert_rowid()", (), |row| row.get(0))
                 .map_err(|e| {
                     eprintln!("Failed to get last inserted row id: {e}");
                     INTERNAL_SERVER_ERROR_RESPONSE.into_response()
                 })?;
+            if row_id <= 0 {
+                eprintln!("Refusing to install a runtime with a non-positive id: {row_id}");
+                return Err(INTERNAL_SERVER_ERROR_RESPONSE.into_response());
+            }
+
+            replace_aliases(&mut connection, row_id, &aliases).map_err(|e| {
+                eprintln!("Failed to store aliases: {e}");
+                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+            })?;
 
             Ok((row_id, trx))
         })
@@ -188,7 +339,7 @@ pub async fn install_runtime(
             let compile_script_path = format!("{runtime_dir}/compile");
             crate::fs::write_file_and_set_permissions(
                 &compile_script_path,
-                &format!("#!/bin/bash\n\n{}", req.compile_script),
+                &script_lint::with_shebang(&req.compile_script),
                 Permissions::from_mode(0o755),
             )
             .await
@@ -201,7 +352,7 @@ pub async fn install_runtime(
         let run_script_path = format!("{runtime_dir}/run");
         crate::fs::write_file_and_set_permissions(
             &run_script_path,
-            &format!("#!/bin/bash\n\n{}", req.run_script),
+            &script_lint::with_shebang(&req.run_script),
             Permissions::from_mode(0o755),
         )
         .await
@@ -210,10 +361,13 @@ pub async fn install_runtime(
             INTERNAL_SERVER_ERROR_RESPONSE.into_response()
         })?;
 
+        let snapshot = env_snapshot::filter(env_entries, &req.env_include, &req.env_exclude);
+        filtered_env_variables = Some(snapshot.filtered_count);
+        warnings = snapshot.warnings;
         let env_script_path = format!("{runtime_dir}/env");
         crate::fs::write_file_and_set_permissions(
             &env_script_path,
-            &stdout,
+            &env_snapshot::serialize(&snapshot.entries),
             Permissions::from_mode(0o755),
         )
         .await
@@ -222,6 +376,19 @@ pub async fn install_runtime(
             INTERNAL_SERVER_ERROR_RESPONSE.into_response()
         })?;
 
+        let shell_snapshot_path =
+            format!("{runtime_dir}/{}", env_snapshot::SHELL_SNAPSHOT_FILE_NAME);
+        crate::fs::write_file_and_set_permissions(
+            &shell_snapshot_path,
+            &env_snapshot::serialize_as_shell(&snapshot.entries),
+            Permissions::from_mode(0o644),
+        )
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to write shell env snapshot: {e}");
+            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+        })?;
+
         fs::write(&(format!("{runtime_dir}/shell.nix")), &req.nix_shell)
             .await
             .map_err(|e| {
@@ -229,45 +396,117 @@ pub async fn install_runtime(
                 INTERNAL_SERVER_ERROR_RESPONSE.into_response()
             })?;
 
+        if let Some(min_memory) = req.min_memory {
+            fs::write(
+                &(format!("{runtime_dir}/{MIN_MEMORY_FILE_NAME}")),
+                min_memory.to_string(),
+            )
+            .await
+            .map_err(|e| {
+                eprintln!("Failed to write the memory floor: {e}");
+                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+            })?;
+        }
+
+        if req.disable_core_dumps {
+            fs::write(
+                &(format!("{runtime_dir}/{CORE_DUMPS_DISABLED_FILE_NAME}")),
+                "",
+            )
+            .await
+            .map_err(|e| {
+                eprintln!("Failed to disable core dumps: {e}");
+                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+            })?;
+        }
+
         let mut metadata_guard = metadata_cache.write().await;
         metadata_guard.insert(
             runtime_id,
             Runtime {
-                name: req.name,
+                name: req.name.clone(),
                 is_compiled,
                 source_file_name: req.source_file_name,
+                aliases: req.aliases,
+                min_memory: req.min_memory,
+                core_dumps: !req.disable_core_dumps,
+                verified: true,
+                health: RuntimeHealth::default(),
             },
         );
         drop(metadata_guard);
         trx.commit();
+        outcome.runtime_id = Some(runtime_id);
+        events.publish(Event::RuntimeInstalled {
+            id: runtime_id,
+            name: req.name.clone(),
+        });
     }
 
-    let status_code = if success {
-        StatusCode::OK
+    let (status_code, failure_category, failure_excerpt) = if success {
+        (StatusCode::OK, None, None)
     } else {
-        StatusCode::BAD_REQUEST
+        let failure_category = nix_errors::classify(&stderr);
+        outcome.error_code = Some(failure_category.as_str());
+        events.publish(Event::RuntimeInstallationFailed {
+            name: req.name,
+            failure_category,
+        });
+        (
+            StatusCode::BAD_REQUEST,
+            Some(failure_category),
+            nix_errors::first_error_block(&stderr),
+        )
     };
-    Ok((status_code, Json(InstallationResponse { stdout, stderr })).into_response())
+    Ok(outcome.attach_to(
+        (
+            status_code,
+            Json(InstallationResponse {
+                stdout,
+                stderr,
+                filtered_env_variables,
+                warnings,
+                failure_category,
+                failure_excerpt,
+                script_warnings,
+                nix_build,
+                exit_signal,
+                exit_signal_name: exit_signal.and_then(signal_name),
+                started_at: clock::rfc3339_millis(started_at),
+                finished_at: clock::rfc3339_millis(finished_at),
+            }),
+        )
+            .into_response(),
+    ))
 }
 
 pub async fn update_nix(
     nix_update_timeout: WholeSeconds,
     installation_lock: Arc<RwLock<u8>>,
+    events: Events,
 ) -> Result<Response<Body>, Response<Body>> {
     let _permit = installation_lock.write().await;
 
+    let nix_build = nix_build::resolve(None, None);
     let mut cmd = Command::new(format!("{NIX_BIN_PATH}/nix-env"));
     cmd.arg("--install")
         .args(["--file", "<nixpkgs>"])
         .args(["--attr", "nix", "cacert"])
         .args(["-I", "nixpkgs=channel:nixpkgs-unstable"])
-        .args(["--timeout".to_string(), nix_update_timeout.to_string()]);
+        .args(["--timeout".to_string(), nix_update_timeout.to_string()])
+        .args(nix_build.args());
 
+    let started_at = clock::now();
     let cmd_res = cmd.output().await.map_err(|e| {
         eprintln!("Failed to get the output of the nix update command: {e}");
         INTERNAL_SERVER_ERROR_RESPONSE.into_response()
     })?;
+    let finished_at = clock::now();
 
+    events.publish(Event::NixUpdated {
+        success: cmd_res.status.success(),
+    });
+    let exit_signal = cmd_res.status.signal().map(|signal| signal as u32);
     let status = if cmd_res.status.success() {
         StatusCode::OK
     } else {
@@ -279,6 +518,16 @@ pub async fn update_nix(
         Json(InstallationResponse {
             stdout: String::from_utf8_lossy(&cmd_res.stdout).to_string(),
             stderr: String::from_utf8_lossy(&cmd_res.stderr).to_string(),
+            filtered_env_variables: None,
+            warnings: Vec::new(),
+            failure_category: None,
+            failure_excerpt: None,
+            script_warnings: Vec::new(),
+            nix_build,
+            exit_signal,
+            exit_signal_name: exit_signal.and_then(signal_name),
+            started_at: clock::rfc3339_millis(started_at),
+            finished_at: clock::rfc3339_millis(finished_at),
         }),
     )
         .into_response())
@@ -0,0 +1,512 @@
+// Executions that are submitted and polled for instead of holding the request
+// open until they finish. Jobs live in memory, their result is dropped once they
+// have been finished for longer than their retention, and the job is forgotten
+// a day later
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, RwLock},
+    task::AbortHandle,
+};
+
+use crate::{
+    api::{
+        common_responses::{Message, StaticMessage},
+        execution::{judged_execution, ExecutionQuery, ExecutionRequest, StreamEvent},
+        json_body::JsonBody,
+    },
+    callbacks::{self, DeliveryState, DeliveryStatus},
+    events::Events,
+    host::HostMonitor,
+    leases::ExecutionSlots,
+    limits::SystemLimits,
+    types::{Metadata, WholeSeconds},
+};
+
+// How long an expired job is answered with 410 before it is forgotten
+const EXPIRED_JOB_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Finished,
+    Cancelled,
+}
+
+impl JobState {
+    fn is_pending(self) -> bool {
+        matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct JobRequest {
+    // Where the result is POSTed once the job finishes
+    pub callback_url: Option<String>,
+    // How long the result is kept once the job is over, up to MAX_JOB_RETENTION
+    pub retention_seconds: Option<WholeSeconds>,
+    #[serde(flatten)]
+    pub execution: ExecutionRequest,
+}
+
+struct Job {
+    state: JobState,
+    // The status and body the execution would have been answered with
+    status: Option<u16>,
+    result: Option<serde_json::Value>,
+    // Of the result as JSON
+    result_bytes: u64,
+    retention: Duration,
+    // Unix seconds, set once the job is over
+    expires_at: Option<u64>,
+    callback_url: Option<String>,
+    callback: Option<DeliveryStatus>,
+    // Aborting the execution drops its boxes, which kills what runs in them
+    // and cleans them up
+    abort: Option<AbortHandle>,
+}
+
+#[derive(Serialize)]
+struct CallbackPayload<'a> {
+    job_id: u64,
+    state: JobState,
+    status: Option<u16>,
+    result: &'a Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+pub struct JobResponse {
+    pub id: u64,
+    pub state: JobState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub callback: Option<DeliveryStatus>,
+    // Unix seconds after which the result is gone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ExpiredJobResponse {
+    message: &'static str,
+    expired_at: u64,
+}
+
+#[derive(Serialize, Default, Clone)]
+pub struct JobResultStats {
+    // Of the results kept now
+    pub stored_bytes: u64,
+    pub reclaimed_bytes_last_purge: u64,
+    pub reclaimed_bytes_total: u64,
+}
+
+pub struct JobStore {
+    jobs: RwLock<HashMap<u64, Job>>,
+    next_id: AtomicU64,
+    retention: Duration,
+    max_retention: Duration,
+    max_pending: usize,
+    stats: RwLock<JobResultStats>,
+}
+
+impl Job {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    // Keeps the job around to tell that it expired
+    fn drop_result(&mut self, now: u64) -> u64 {
+        self.result = None;
+        self.expires_at = Some(
+            self.expires_at
+                .map_or(now, |expires_at| expires_at.min(now)),
+        );
+        std::mem::take(&mut self.result_bytes)
+    }
+
+    fn response(&self, id: u64) -> JobResponse {
+        JobResponse {
+            id,
+            state: self.state,
+            status: self.status,
+            result: self.result.clone(),
+            callback: self.callback.clone(),
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+fn expired_job_response(expired_at: u64) -> Response<Body> {
+    (
+        StatusCode::GONE,
+        Json(ExpiredJobResponse {
+            message: "The result of this job expired",
+            expired_at,
+        }),
+    )
+        .into_response()
+}
+
+fn job_not_found_response() -> Response<Body> {
+    (
+        StatusCode::NOT_FOUND,
+        Json(StaticMessage {
+            message: "No job with this id, it may have been purged",
+        }),
+    )
+        .into_response()
+}
+
+impl JobStore {
+    pub fn new(retention: Duration, max_retention: Duration, max_pending: usize) -> JobStore {
+        JobStore {
+            jobs: RwLock::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            retention,
+            max_retention,
+            max_pending,
+            stats: RwLock::new(JobResultStats::default()),
+        }
+    }
+
+    pub async fn result_stats(&self) -> JobResultStats {
+        self.stats.read().await.clone()
+    }
+
+    async fn start(&self, id: u64) {
+        if let Some(job) = self.jobs.write().await.get_mut(&id) {
+            if job.state == JobState::Queued {
+                job.state = JobState::Running;
+            }
+        }
+    }
+
+    async fn finish(&self, id: u64, status: StatusCode, result: Option<serde_json::Value>) {
+        if let Some(job) = self.jobs.write().await.get_mut(&id) {
+            if !job.state.is_pending() {
+                return;
+            }
+            job.result_bytes = result
+                .as_ref()
+                .map_or(0, |result| result.to_string().len() as u64);
+            job.state = JobState::Finished;
+            job.abort = None;
+            job.status = Some(status.as_u16());
+            job.result = result;
+            job.expires_at = Some(now() + job.retention.as_secs());
+            self.stats.write().await.stored_bytes += job.result_bytes;
+        }
+    }
+
+    async fn update_callback(&self, id: u64, callback: DeliveryStatus) {
+        if let Some(job) = self.jobs.write().await.get_mut(&id) {
+            job.callback = Some(callback);
+        }
+    }
+
+    async fn deliver_result(&self, id: u64, url: &str) {
+        let (state, status, result) = match self.jobs.read().await.get(&id) {
+            Some(job) => (job.state, job.status, job.result.clone()),
+            None => return,
+        };
+        let payload = CallbackPayload {
+            job_id: id,
+            state,
+            status,
+            result: &result,
+        };
+        let callback = callbacks::deliver(url, id, &payload, |callback| {
+            self.update_callback(id, callback)
+        })
+        .await;
+        self.update_callback(id, callback).await;
+    }
+
+    // Drops the results of the jobs that have been over for longer than their
+    // retention, unless they are still being delivered, and forgets the jobs
+    // that expired long ago
+    pub async fn purge(&self) {
+        let now = now();
+        let mut reclaimed = 0;
+        let mut jobs = self.jobs.write().await;
+        jobs.retain(|_, job| {
+            let delivering = job
+                .callback
+                .as_ref()
+                .is_some_and(|callback| callback.state == DeliveryState::Pending);
+            if delivering || !job.is_expired(now) {
+                return true;
+            }
+            reclaimed += job.drop_result(now);
+            job.expires_at
+                .is_some_and(|expires_at| now < expires_at + EXPIRED_JOB_MEMORY.as_secs())
+        });
+        drop(jobs);
+        let mut stats = self.stats.write().await;
+        stats.stored_bytes = stats.stored_bytes.saturating_sub(reclaimed);
+        stats.reclaimed_bytes_last_purge = reclaimed;
+        stats.reclaimed_bytes_total += reclaimed;
+        if reclaimed > 0 {
+            eprintln!("Purged expired job results, reclaiming {reclaimed} bytes");
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn submit_job(
+    jobs: Arc<JobStore>,
+    semaphore: Arc<ExecutionSlots>,
+    box_id: Arc<AtomicU64>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    host_monitor: Arc<HostMonitor>,
+    events: Events,
+    JsonBody(req): JsonBody<JobRequest>,
+    query: Option<Query<ExecutionQuery>>,
+) -> Result<Response<Body>, Response<Body>> {
+    let JobRequest {
+        callback_url,
+        retention_seconds,
+        execution: mut req,
+    } = req;
+    req.unbounded_queue = true;
+    let retention = retention_seconds.map_or(jobs.retention, |retention| {
+        Duration::from_secs(retention.into())
+    });
+    if retention > jobs.max_retention {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(Message {
+                message: format!(
+                    "retention_seconds can't be more than MAX_JOB_RETENTION ({})",
+                    jobs.max_retention.as_secs()
+                ),
+            }),
+        )
+            .into_response());
+    }
+    if let Some(callback_url) = &callback_url {
+        callbacks::validate_callback_url(callback_url).map_err(|message| {
+            (StatusCode::BAD_REQUEST, Json(Message { message })).into_response()
+        })?;
+    }
+    if req.validate_only {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(StaticMessage {
+                message: "Validations can't be submitted as jobs, use /execute/estimate",
+            }),
+        )
+            .into_response());
+    }
+    let callback = callback_url.as_ref().map(|_| DeliveryStatus {
+        state: DeliveryState::Pending,
+        attempts: 0,
+        error: None,
+        dead_letter_id: None,
+    });
+    let id = {
+        let mut pending_jobs = jobs.jobs.write().await;
+        let pending = pending_jobs
+            .values()
+            .filter(|job| job.state.is_pending())
+            .count();
+        if pending >= jobs.max_pending {
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(StaticMessage {
+                    message: "Too many jobs are pending, try again later",
+                }),
+            )
+                .into_response());
+        }
+        let id = jobs.next_id.fetch_add(1, Ordering::Relaxed);
+        pending_jobs.insert(
+            id,
+            Job {
+                state: JobState::Queued,
+                status: None,
+                result: None,
+                result_bytes: 0,
+                retention,
+                expires_at: None,
+                callback_url: callback_url.clone(),
+                callback: callback.clone(),
+                abort: None,
+            },
+        );
+        id
+    };
+
+    let job_store = jobs.clone();
+    let task = tokio::spawn(async move {
+        // The job is running once the execution leaves the queue, its output is
+        // not kept
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let started = {
+            let jobs = job_store.clone();
+            tokio::spawn(async move {
+                while let Some(event) = receiver.recv().await {
+                    if let StreamEvent::Started = event {
+                        jobs.start(id).await;
+                    }
+                }
+            })
+        };
+        let res = judged_execution(
+            semaphore,
+            box_id,
+            metadata_cache,
+            installation_lock,
+            system_limits,
+            &host_monitor,
+            &events,
+            req,
+            query,
+            Some(&sender),
+        )
+        .await;
+        drop(sender);
+        let _ = started.await;
+        match res {
+            Ok((_, _, res)) => {
+                job_store
+                    .finish(id, StatusCode::OK, serde_json::to_value(res).ok())
+                    .await
+            }
+            Err(res) => {
+                let status = res.status();
+                let body = to_bytes(res.into_body(), usize::MAX).await.ok();
+                let result = body.and_then(|body| serde_json::from_slice(&body).ok());
+                job_store.finish(id, status, result).await
+            }
+        }
+        if let Some(callback_url) = callback_url {
+            job_store.deliver_result(id, &callback_url).await;
+        }
+    });
+    // The job may be over already, which leaves nothing to abort
+    if let Some(job) = jobs.jobs.write().await.get_mut(&id) {
+        if job.state.is_pending() {
+            job.abort = Some(task.abort_handle());
+        }
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(JobResponse {
+            id,
+            state: JobState::Queued,
+            status: None,
+            result: None,
+            callback,
+            expires_at: None,
+        }),
+    )
+        .into_response())
+}
+
+pub async fn get_job(
+    Path(id): Path<u64>,
+    jobs: Arc<JobStore>,
+) -> Result<Json<JobResponse>, Response<Body>> {
+    let job_store = jobs.jobs.read().await;
+    let Some(job) = job_store.get(&id) else {
+        return Err(job_not_found_response());
+    };
+    // The purge may not have come by yet
+    if let Some(expires_at) = job.expires_at.filter(|_| job.is_expired(now())) {
+        return Err(expired_job_response(expires_at));
+    }
+    Ok(Json(job.response(id)))
+}
+
+pub async fn cancel_job(
+    Path(id): Path<u64>,
+    jobs: Arc<JobStore>,
+) -> Result<Json<JobResponse>, Response<Body>> {
+    let (res, callback_url) = {
+        let mut job_store = jobs.jobs.write().await;
+        let Some(job) = job_store.get_mut(&id) else {
+            return Err(job_not_found_response());
+        };
+        if !job.state.is_pending() {
+            return Err((
+                StatusCode::CONFLICT,
+                Json(StaticMessage {
+                    message: "The job is already over",
+                }),
+            )
+                .into_response());
+        }
+        if let Some(abort) = job.abort.take() {
+            abort.abort();
+        }
+        job.state = JobState::Cancelled;
+        job.expires_at = Some(now() + job.retention.as_secs());
+        (job.response(id), job.callback_url.clone())
+    };
+    // The aborted task won't deliver the result anymore
+    if let Some(callback_url) = callback_url {
+        tokio::spawn(async move { jobs.deliver_result(id, &callback_url).await });
+    }
+    Ok(Json(res))
+}
+
+// Drops the result of a job that is over before its retention ends
+pub async fn purge_job_result(
+    Path(id): Path<u64>,
+    jobs: Arc<JobStore>,
+) -> Result<StatusCode, Response<Body>> {
+    let reclaimed = {
+        let mut job_store = jobs.jobs.write().await;
+        let Some(job) = job_store.get_mut(&id) else {
+            return Err(job_not_found_response());
+        };
+        if job.state.is_pending() {
+            return Err((
+                StatusCode::CONFLICT,
+                Json(StaticMessage {
+                    message: "The job is not over, cancel it instead",
+                }),
+            )
+                .into_response());
+        }
+        let now = now();
+        if let Some(expires_at) = job.expires_at.filter(|_| job.is_expired(now)) {
+            return Err(expired_job_response(expires_at));
+        }
+        job.drop_result(now)
+    };
+    let mut stats = jobs.stats.write().await;
+    stats.stored_bytes = stats.stored_bytes.saturating_sub(reclaimed);
+    stats.reclaimed_bytes_total += reclaimed;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,119 @@
+use std::error::Error;
+
+use axum::{
+    async_trait,
+    body::{Body, Bytes},
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+// The serde path of the offending field, as axum's `Json` deserializes with it
+type PathError = serde_path_to_error::Error<serde_json::Error>;
+
+#[derive(Serialize)]
+struct JsonBodyError {
+    message: String,
+    // Left out when the body as a whole is at fault, e.g. for a missing field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+}
+
+// `Json` whose rejections have the `{"message": ...}` body of every other error
+// instead of plain text: 400 for a body that doesn't parse or doesn't fit the
+// request type, and 415 without a JSON content type
+pub struct JsonBody<T>(pub T);
+
+// `JsonBody` for requests whose body is optional, the default stands in for
+// an empty body only, a body that can't be read is still refused
+pub struct JsonBodyOrDefault<T>(pub T);
+
+fn find_path_error(rejection: &JsonRejection) -> Option<&PathError> {
+    let mut source = rejection.source();
+    while let Some(error) = source {
+        if let Some(path_error) = error.downcast_ref::<PathError>() {
+            return Some(path_error);
+        }
+        source = error.source();
+    }
+    None
+}
+
+fn rejection_response(rejection: JsonRejection) -> Response<Body> {
+    let path_error = find_path_error(&rejection);
+    let field = path_error
+        .map(|error| error.path().to_string())
+        .filter(|path| path != ".");
+    let (status, message) = match (&rejection, path_error) {
+        (JsonRejection::JsonDataError(_), Some(error)) => (
+            StatusCode::BAD_REQUEST,
+            match &field {
+                Some(field) => format!("Invalid {field}: {}", error.inner()),
+                None => format!("Invalid request body: {}", error.inner()),
+            },
+        ),
+        (JsonRejection::JsonSyntaxError(_), Some(error)) => (
+            StatusCode::BAD_REQUEST,
+            format!("The request body is not valid JSON: {}", error.inner()),
+        ),
+        (JsonRejection::JsonDataError(_) | JsonRejection::JsonSyntaxError(_), None) => {
+            (StatusCode::BAD_REQUEST, rejection.body_text())
+        }
+        (JsonRejection::MissingJsonContentType(_), _) => (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Expected a request with Content-Type: application/json".to_string(),
+        ),
+        // Such as a body over the size limit, which keeps its status
+        _ => (rejection.status(), rejection.body_text()),
+    };
+    (status, Json(JsonBodyError { message, field })).into_response()
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response<Body>;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        Json::<T>::from_request(req, state)
+            .await
+            .map(|Json(value)| JsonBody(value))
+            .map_err(rejection_response)
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonBodyOrDefault<T>
+where
+    T: DeserializeOwned + Default,
+    S: Send + Sync,
+{
+    type Rejection = Response<Body>;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let (parts, body) = req.into_parts();
+        let bytes = Bytes::from_request(Request::from_parts(parts.clone(), body), state)
+            .await
+            .map_err(|rejection| {
+                (
+                    rejection.status(),
+                    Json(JsonBodyError {
+                        message: rejection.body_text(),
+                        field: None,
+                    }),
+                )
+                    .into_response()
+            })?;
+        if bytes.is_empty() {
+            return Ok(JsonBodyOrDefault(T::default()));
+        }
+        JsonBody::from_request(Request::from_parts(parts, Body::from(bytes)), state)
+            .await
+            .map(|JsonBody(value)| JsonBodyOrDefault(value))
+    }
+}
@@ -1,24 +1,45 @@
 use std::sync::Arc;
 
-use axum::{response::IntoResponse, Json};
-use serde::Serialize;
+use axum::{extract::Query, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
-use crate::types::Metadata;
+use crate::{
+    health::HealthSummary,
+    types::{Metadata, RuntimeId},
+};
+
+#[derive(Deserialize)]
+pub struct ListingQuery {
+    // Matches a runtime's name or one of its aliases
+    name: Option<String>,
+}
 
 #[derive(Serialize)]
 pub struct Runtime {
-    id: u32,
+    id: RuntimeId,
     name: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<String>,
+    health: HealthSummary,
 }
 
-pub async fn list_runtimes(metadata_cache: Arc<RwLock<Metadata>>) -> impl IntoResponse {
+pub async fn list_runtimes(
+    metadata_cache: Arc<RwLock<Metadata>>,
+    query: Option<Query<ListingQuery>>,
+) -> impl IntoResponse {
+    let name = query.and_then(|query| query.0.name);
     let mut runtimes: Vec<Runtime> = Vec::new();
     let metadata_guard = metadata_cache.read().await;
     for (key, value) in metadata_guard.iter() {
+        if name.as_ref().is_some_and(|name| !value.is_called(name)) {
+            continue;
+        }
         runtimes.push(Runtime {
             id: *key,
-            name: value.name.clone(),
+            name: value.display_name(),
+            aliases: value.aliases.clone(),
+            health: value.health.summary(),
         });
     }
     Json(runtimes)
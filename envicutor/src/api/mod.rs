@@ -4,3 +4,21 @@ pub mod deletion;
 pub mod common_responses;
 pub mod execution;
 pub mod common_functions;
+pub mod bootstrap;
+pub mod garbage_collection;
+pub mod piston;
+pub mod event_stream;
+pub mod status;
+pub mod suites;
+pub mod aliases;
+pub mod config;
+pub mod piston_import;
+pub mod jobs;
+pub mod box_quarantine;
+pub mod dead_letters;
+pub mod datasets;
+pub mod executions;
+pub mod cores;
+pub mod self_test;
+pub mod validation;
+pub mod json_body;
@@ -0,0 +1,215 @@
+use axum::http::HeaderMap;
+use serde::Serialize;
+
+use crate::{api::execution::ExecutionResponse, isolate::StageResult, signals::signal_name};
+
+pub const DIALECT_HEADER: &str = "x-response-dialect";
+
+#[derive(Clone, Copy)]
+pub enum ResponseDialect {
+    Native,
+    Piston,
+}
+
+impl ResponseDialect {
+    pub fn from_headers(headers: &HeaderMap) -> ResponseDialect {
+        match headers.get(DIALECT_HEADER).map(|value| value.to_str()) {
+            Some(Ok(value)) if value.eq_ignore_ascii_case("piston") => ResponseDialect::Piston,
+            _ => ResponseDialect::Native,
+        }
+    }
+}
+
+// Mirrors the response of Piston's POST /api/v2/execute
+#[derive(Serialize)]
+pub struct PistonResponse {
+    language: String,
+    version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compile: Option<PistonStage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run: Option<PistonStage>,
+}
+
+#[derive(Serialize)]
+pub struct PistonStage {
+    stdout: String,
+    stderr: String,
+    // Piston interleaves both streams, they are captured separately here
+    // so stderr follows stdout
+    output: String,
+    code: Option<u32>,
+    signal: Option<String>,
+    message: Option<String>,
+    status: Option<String>,
+    // Milliseconds and bytes, like Piston
+    cpu_time: Option<u64>,
+    wall_time: Option<u64>,
+    memory: Option<u64>,
+}
+
+impl From<StageResult> for PistonStage {
+    fn from(result: StageResult) -> Self {
+        PistonStage {
+            output: format!("{}{}", result.stdout.text(), result.stderr.text()),
+            code: result.exit_code,
+            signal: result.exit_signal.and_then(signal_name),
+            message: result.exit_message,
+            status: result.exit_status,
+            cpu_time: result.cpu_time.map(|t| (t * 1000.0) as u64),
+            wall_time: result.wall_time.map(|t| (t * 1000.0) as u64),
+            memory: result.memory.map(|m| u64::from(m) * 1024),
+            stdout: result.stdout.text().into_owned(),
+            stderr: result.stderr.text().into_owned(),
+        }
+    }
+}
+
+pub fn from_execution(language: &str, res: ExecutionResponse) -> PistonResponse {
+    // Piston has no extraction stage, a failed extraction is the closest to a failed compilation
+    let compile = match (res.extract, res.compile) {
+        (_, Some(compile)) => Some(compile),
+        (Some(extract), None) if res.run.is_none() => Some(extract),
+        _ => None,
+    };
+    PistonResponse {
+        // Runtimes have no separate version, the name identifies them entirely
+        language: language.to_string(),
+        version: String::new(),
+        compile: compile.map(PistonStage::from),
+        run: res.run.map(PistonStage::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        isolate::{ExitKind, MemorySource, StageTiming},
+        output::{OutputEncoding, StageOutput},
+    };
+
+    use super::*;
+
+    fn stage(
+        stdout: &str,
+        stderr: &str,
+        exit_code: Option<u32>,
+        exit_signal: Option<u32>,
+    ) -> StageResult {
+        StageResult {
+            stage: None,
+            limit_set: None,
+            memory: Some(2048),
+            memory_source: MemorySource::Cgroup,
+            memory_limit_exceeded: false,
+            exit_code,
+            exit_signal,
+            exit_signal_name: exit_signal.and_then(signal_name),
+            exit_message: None,
+            exit_status: exit_signal.map(|_| "SG".to_string()),
+            exit_kind: ExitKind::classify(None, exit_code, exit_signal),
+            stdout: StageOutput::new(stdout.as_bytes().to_vec(), OutputEncoding::Utf8),
+            stderr: StageOutput::new(stderr.as_bytes().to_vec(), OutputEncoding::Utf8),
+            output_encoding: OutputEncoding::Utf8,
+            cpu_time: Some(0.25),
+            cpu_user_ms: None,
+            cpu_system_ms: None,
+            cpu_weight: None,
+            wall_time: Some(0.5),
+            no_output_timeout: false,
+            stdout_truncated: false,
+            stderr_truncated: false,
+            stdout_sha256: String::new(),
+            stderr_sha256: String::new(),
+            returned_stdout_sha256: None,
+            returned_stderr_sha256: None,
+            timing: StageTiming {
+                sandbox_init_ms: None,
+                environment_activation_ms: None,
+                program_ms: 500,
+            },
+            started_at: String::new(),
+            finished_at: String::new(),
+        }
+    }
+
+    fn response(
+        extract: Option<StageResult>,
+        compile: Option<StageResult>,
+        run: Option<StageResult>,
+    ) -> ExecutionResponse {
+        ExecutionResponse {
+            run_skipped: run.is_none(),
+            extract,
+            compile,
+            run,
+            cases: Vec::new(),
+            output_files: Vec::new(),
+            compile_cache: None,
+            core_dump: None,
+            budget_exhausted: false,
+            limits_resolution: None,
+            warnings: Vec::new(),
+            verdict: None,
+            queue_wait: None,
+            box_id: None,
+            planned_stages: Vec::new(),
+            run_limits: None,
+            coalesced: false,
+            sandbox: None,
+        }
+    }
+
+    #[test]
+    fn a_failed_extraction_is_given_as_the_compilation() {
+        let extract = stage("", "not an archive", Some(1), None);
+        let res = from_execution("python", response(Some(extract), None, None));
+        let compile = res.compile.unwrap();
+        assert_eq!(compile.stderr, "not an archive");
+        assert_eq!(compile.code, Some(1));
+        assert!(res.run.is_none());
+    }
+
+    #[test]
+    fn a_successful_extraction_is_not_given() {
+        let extract = stage("extracted", "", Some(0), None);
+        let run = stage("ran", "", Some(0), None);
+        let res = from_execution("python", response(Some(extract), None, Some(run)));
+        assert!(res.compile.is_none());
+        assert_eq!(res.run.unwrap().stdout, "ran");
+
+        let extract = stage("extracted", "", Some(0), None);
+        let compile = stage("compiled", "", Some(0), None);
+        let run = stage("ran", "", Some(0), None);
+        let res = from_execution("gcc", response(Some(extract), Some(compile), Some(run)));
+        assert_eq!(res.compile.unwrap().stdout, "compiled");
+    }
+
+    #[test]
+    fn output_is_stdout_followed_by_stderr() {
+        let run = stage("out\n", "err\n", Some(3), None);
+        let res = from_execution("python", response(None, None, Some(run)));
+        assert_eq!(res.language, "python");
+        assert_eq!(res.version, "");
+        let run = res.run.unwrap();
+        assert_eq!(run.output, "out\nerr\n");
+        assert_eq!(run.code, Some(3));
+        assert_eq!(run.signal, None);
+        // Milliseconds and bytes
+        assert_eq!(run.cpu_time, Some(250));
+        assert_eq!(run.wall_time, Some(500));
+        assert_eq!(run.memory, Some(2048 * 1024));
+    }
+
+    #[test]
+    fn signals_are_given_by_name() {
+        let run = stage("", "", None, Some(11));
+        let res = from_execution("gcc", response(None, None, Some(run)));
+        let run = res.run.unwrap();
+        assert_eq!(run.signal.as_deref(), Some("SIGSEGV"));
+        assert_eq!(run.status.as_deref(), Some("SG"));
+        let run = stage("", "", None, Some(34));
+        let res = from_execution("gcc", response(None, None, Some(run)));
+        assert_eq!(res.run.unwrap().signal.as_deref(), Some("SIG34"));
+    }
+}
@@ -0,0 +1,532 @@
+// Turns the packages of a Piston checkout (packages/<language>/<version>) into
+// runtimes. Piston builds its toolchains itself, here they come from nixpkgs,
+// so only the languages of the table below get a matching toolchain
+use std::{
+    path::Path,
+    sync::{atomic::AtomicU64, Arc},
+};
+
+use anyhow::{anyhow, Error};
+use axum::{body::to_bytes, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::RwLock};
+
+use crate::{
+    api::{
+        installation::{install_runtime, AddRuntimeRequest},
+        json_body::JsonBody,
+    },
+    events::Events,
+    request_limits::RequestLimits,
+    strings::is_valid_name,
+    types::{Metadata, WholeSeconds},
+};
+
+const NIXPKGS: &str =
+    "https://github.com/NixOS/nixpkgs/archive/72da83d9515b43550436891f538ff41d68eecc7f.tar.gz";
+const NIXPKGS_SHA256: &str = "177sws22nqkvv8am76qmy9knham2adfh3gv7hrjf6492z1mvy02y";
+// Unmapped languages still get a shell to run their scripts in
+const FALLBACK_PACKAGES: &[&str] = &["bash", "coreutils"];
+
+struct Toolchain {
+    language: &'static str,
+    packages: &'static [&'static str],
+    source_file_name: &'static str,
+}
+
+static TOOLCHAINS: [Toolchain; 9] = [
+    Toolchain {
+        language: "python",
+        packages: &["python3"],
+        source_file_name: "main.py",
+    },
+    Toolchain {
+        language: "c",
+        packages: &["gcc"],
+        source_file_name: "main.c",
+    },
+    Toolchain {
+        language: "c++",
+        packages: &["gcc"],
+        source_file_name: "main.cpp",
+    },
+    Toolchain {
+        language: "javascript",
+        packages: &["nodejs"],
+        source_file_name: "main.js",
+    },
+    Toolchain {
+        language: "java",
+        packages: &["jdk"],
+        source_file_name: "Main.java",
+    },
+    Toolchain {
+        language: "bash",
+        packages: &["bash"],
+        source_file_name: "main.sh",
+    },
+    Toolchain {
+        language: "go",
+        packages: &["go"],
+        source_file_name: "main.go",
+    },
+    Toolchain {
+        language: "rust",
+        packages: &["rustc"],
+        source_file_name: "main.rs",
+    },
+    Toolchain {
+        language: "ruby",
+        packages: &["ruby"],
+        source_file_name: "main.rb",
+    },
+];
+
+#[derive(Deserialize)]
+struct ProvidedLanguage {
+    language: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+// Piston's metadata.json, packages providing several languages (gcc provides
+// c and c++) pick one in their scripts with $PISTON_LANGUAGE
+#[derive(Deserialize)]
+struct PistonPackage {
+    language: String,
+    version: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    provides: Vec<ProvidedLanguage>,
+}
+
+#[derive(Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    Installed,
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ShellMapping {
+    Mapped,
+    Fallback,
+}
+
+#[derive(Serialize)]
+pub struct ImportResult {
+    // <language>/<version> in the packages directory
+    pub package: String,
+    pub language: Option<String>,
+    pub status: ImportStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell: Option<ShellMapping>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<serde_json::Value>,
+}
+
+impl ImportResult {
+    fn skipped(package: &str, language: Option<&str>, reason: String) -> ImportResult {
+        eprintln!("Skipping Piston package {package}: {reason}");
+        ImportResult {
+            package: package.to_string(),
+            language: language.map(str::to_string),
+            status: ImportStatus::Skipped,
+            shell: None,
+            reason: Some(reason),
+            response: None,
+        }
+    }
+}
+
+fn nix_shell(packages: &[&str]) -> String {
+    let inputs: String = packages
+        .iter()
+        .map(|package| format!("      {package}\n"))
+        .collect();
+    format!(
+        "{{ pkgs ? import (\n  fetchTarball {{\n    url=\"{NIXPKGS}\";\n    sha256=\"{NIXPKGS_SHA256}\";\n  }}\n) {{}} }}:\npkgs.mkShell {{\n  nativeBuildInputs = with pkgs; [\n{inputs}  ];\n}}\n"
+    )
+}
+
+// Keeps the shebang first
+fn with_language(script: &str, language: &str) -> String {
+    let export = format!("export PISTON_LANGUAGE='{language}'\n");
+    match script
+        .strip_prefix("#!")
+        .and_then(|rest| rest.split_once('\n'))
+    {
+        Some((shebang, rest)) => format!("#!{shebang}\n{export}{rest}"),
+        None => format!("{export}{script}"),
+    }
+}
+
+fn fallback_source_file_name(language: &str) -> String {
+    let extension: String = language
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect();
+    format!("main.{extension}")
+}
+
+// With their aliases, a package without `provides` is a single language
+fn provided_languages(package: &PistonPackage) -> Vec<(String, Vec<String>)> {
+    if package.provides.is_empty() {
+        vec![(package.language.clone(), package.aliases.clone())]
+    } else {
+        package
+            .provides
+            .iter()
+            .map(|provided| (provided.language.clone(), provided.aliases.clone()))
+            .collect()
+    }
+}
+
+fn runtime_request(
+    package: &PistonPackage,
+    language: &str,
+    aliases: &[String],
+    compile_script: &str,
+    run_script: &str,
+) -> (AddRuntimeRequest, ShellMapping) {
+    let toolchain = TOOLCHAINS
+        .iter()
+        .find(|toolchain| toolchain.language == language);
+    let (packages, source_file_name, mapping) = match toolchain {
+        Some(toolchain) => (
+            toolchain.packages,
+            toolchain.source_file_name.to_string(),
+            ShellMapping::Mapped,
+        ),
+        None => (
+            FALLBACK_PACKAGES,
+            fallback_source_file_name(language),
+            ShellMapping::Fallback,
+        ),
+    };
+    let req = AddRuntimeRequest {
+        name: format!("{language} {} (Piston)", package.version),
+        nix_shell: nix_shell(packages),
+        compile_script: if compile_script.is_empty() {
+            String::new()
+        } else {
+            with_language(compile_script, language)
+        },
+        run_script: with_language(run_script, language),
+        source_file_name,
+        env_include: Vec::new(),
+        env_exclude: Vec::new(),
+        aliases: aliases
+            .iter()
+            .filter(|alias| is_valid_name(alias))
+            .cloned()
+            .collect(),
+        min_memory: None,
+        normalize_line_endings: true,
+        strict_scripts: false,
+        nix_cores: None,
+        nix_max_jobs: None,
+        disable_core_dumps: false,
+    };
+    (req, mapping)
+}
+
+async fn read_optional(path: &Path) -> Result<String, Error> {
+    match fs::read_to_string(path).await {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(anyhow!("Failed to read {}\nError: {e}", path.display())),
+    }
+}
+
+async fn read_dir_names(dir: &Path) -> Result<Vec<String>, Error> {
+    let mut entries = fs::read_dir(dir)
+        .await
+        .map_err(|e| anyhow!("Failed to read {}\nError: {e}", dir.display()))?;
+    let mut names = Vec::new();
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| anyhow!("Failed to read {}\nError: {e}", dir.display()))?
+    {
+        if entry.path().is_dir() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+// `index` is Piston's packages directory, remote indexes only list tarballs of
+// Piston's own builds, which are of no use here
+async fn read_packages(index: &str) -> Result<Vec<String>, Error> {
+    if index.starts_with("http://") || index.starts_with("https://") {
+        return Err(anyhow!(
+            "Remote indexes are not supported, clone the Piston repository and pass its packages directory"
+        ));
+    }
+    let root = Path::new(index);
+    let mut packages = Vec::new();
+    for language in read_dir_names(root).await? {
+        for version in read_dir_names(&root.join(&language)).await? {
+            packages.push(format!("{language}/{version}"));
+        }
+    }
+    Ok(packages)
+}
+
+pub async fn import_piston_packages(
+    index: &str,
+    installation_timeout: WholeSeconds,
+    box_id: Arc<AtomicU64>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    events: Events,
+    request_limits: RequestLimits,
+) -> Result<Vec<ImportResult>, Error> {
+    let mut results = Vec::new();
+    for package_dir in read_packages(index).await? {
+        let dir = Path::new(index).join(&package_dir);
+        let metadata = read_optional(&dir.join("metadata.json")).await?;
+        if metadata.is_empty() {
+            results.push(ImportResult::skipped(
+                &package_dir,
+                None,
+                "metadata.json is missing".to_string(),
+            ));
+            continue;
+        }
+        let package: PistonPackage = match serde_json::from_str(&metadata) {
+            Ok(package) => package,
+            Err(e) => {
+                results.push(ImportResult::skipped(
+                    &package_dir,
+                    None,
+                    format!("Invalid metadata.json: {e}"),
+                ));
+                continue;
+            }
+        };
+        let run_script = read_optional(&dir.join("run")).await?;
+        let compile_script = read_optional(&dir.join("compile")).await?;
+
+        for (language, aliases) in provided_languages(&package) {
+            if run_script.is_empty() {
+                results.push(ImportResult::skipped(
+                    &package_dir,
+                    Some(&language),
+                    "The package has no run script".to_string(),
+                ));
+                continue;
+            }
+            let (req, mapping) =
+                runtime_request(&package, &language, &aliases, &compile_script, &run_script);
+            if !is_valid_name(&req.name) {
+                results.push(ImportResult::skipped(
+                    &package_dir,
+                    Some(&language),
+                    "The language name is not printable ASCII".to_string(),
+                ));
+                continue;
+            }
+            let exists = metadata_cache
+                .read()
+                .await
+                .values()
+                .any(|runtime| runtime.name == req.name);
+            if exists {
+                results.push(ImportResult::skipped(
+                    &package_dir,
+                    Some(&language),
+                    format!("{} is already installed", req.name),
+                ));
+                continue;
+            }
+
+            eprintln!("Installing Piston package {package_dir} as {}", req.name);
+            let res = match install_runtime(
+                installation_timeout,
+                box_id.clone(),
+                metadata_cache.clone(),
+                installation_lock.clone(),
+                events.clone(),
+                request_limits,
+                JsonBody(req),
+            )
+            .await
+            {
+                Ok(res) => res,
+                Err(res) => res,
+            };
+            let status = if res.status() == StatusCode::OK {
+                ImportStatus::Installed
+            } else {
+                ImportStatus::Failed
+            };
+            let body = to_bytes(res.into_body(), usize::MAX).await.ok();
+            results.push(ImportResult {
+                package: package_dir.clone(),
+                language: Some(language),
+                status,
+                shell: Some(mapping),
+                reason: None,
+                response: body.and_then(|body| serde_json::from_slice(&body).ok()),
+            });
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Piston's packages/<language>/<version>/metadata.json and scripts, as of v3
+    const PYTHON_METADATA: &str = r#"{
+    "language": "python",
+    "version": "3.12.0",
+    "aliases": ["py", "py3", "python3", "python3.12"]
+}"#;
+    const PYTHON_RUN: &str = "#!/bin/bash\n\npython3.12 \"$@\"\n";
+
+    const GCC_METADATA: &str = r#"{
+    "language": "gcc",
+    "version": "10.2.0",
+    "provides": [
+        { "language": "c", "aliases": ["gcc"] },
+        { "language": "c++", "aliases": ["cpp", "g++"] },
+        { "language": "d", "aliases": ["gdc"] },
+        { "language": "fortran", "aliases": ["fortran", "f90"] }
+    ]
+}"#;
+    const GCC_COMPILE: &str = "#!/usr/bin/env bash\n\ncase \"${PISTON_LANGUAGE}\" in\n    c)\n        rename 's/$/\\.c/' \"$@\"\n        gcc -std=c11 *.c -lm\n        ;;\nesac\n";
+    const GCC_RUN: &str = "#!/bin/bash\n\nshift\n./a.out \"$@\"\n";
+
+    const NODE_METADATA: &str = r#"{
+    "language": "node",
+    "version": "20.11.1",
+    "provides": [
+        { "language": "javascript", "aliases": ["node-javascript", "node-js", "javascript", "js"] }
+    ]
+}"#;
+    const NODE_RUN: &str = "#!/bin/bash\n\nnode \"$@\"\n";
+
+    const JAVA_METADATA: &str = r#"{
+    "language": "java",
+    "version": "15.0.2",
+    "aliases": []
+}"#;
+    const JAVA_RUN: &str =
+        "#!/usr/bin/env bash\n\nmv $1 $1.java\nfilename=$1.java\nshift\njava $filename \"$@\"\n";
+
+    fn requests(
+        metadata: &str,
+        compile_script: &str,
+        run_script: &str,
+    ) -> Vec<(String, AddRuntimeRequest, ShellMapping)> {
+        let package: PistonPackage = serde_json::from_str(metadata).unwrap();
+        provided_languages(&package)
+            .into_iter()
+            .map(|(language, aliases)| {
+                let (req, mapping) =
+                    runtime_request(&package, &language, &aliases, compile_script, run_script);
+                (language, req, mapping)
+            })
+            .collect()
+    }
+
+    fn is_mapped(mapping: ShellMapping) -> bool {
+        matches!(mapping, ShellMapping::Mapped)
+    }
+
+    #[test]
+    fn python_maps_to_python3() {
+        let requests = requests(PYTHON_METADATA, "", PYTHON_RUN);
+        assert_eq!(requests.len(), 1);
+        let (language, req, mapping) = &requests[0];
+        assert_eq!(language, "python");
+        assert!(is_mapped(*mapping));
+        assert_eq!(req.name, "python 3.12.0 (Piston)");
+        assert!(req.nix_shell.contains("      python3\n"));
+        assert_eq!(req.source_file_name, "main.py");
+        assert_eq!(req.aliases, ["py", "py3", "python3", "python3.12"]);
+        assert_eq!(req.compile_script, "");
+        assert_eq!(
+            req.run_script,
+            "#!/bin/bash\nexport PISTON_LANGUAGE='python'\n\npython3.12 \"$@\"\n"
+        );
+    }
+
+    #[test]
+    fn gcc_gives_a_runtime_per_language() {
+        let requests = requests(GCC_METADATA, GCC_COMPILE, GCC_RUN);
+        let languages: Vec<&str> = requests
+            .iter()
+            .map(|(language, _, _)| language.as_str())
+            .collect();
+        assert_eq!(languages, ["c", "c++", "d", "fortran"]);
+
+        let (_, c, mapping) = &requests[0];
+        assert!(is_mapped(*mapping));
+        assert!(c.nix_shell.contains("      gcc\n"));
+        assert_eq!(c.source_file_name, "main.c");
+        assert_eq!(c.aliases, ["gcc"]);
+        assert!(c
+            .compile_script
+            .starts_with("#!/usr/bin/env bash\nexport PISTON_LANGUAGE='c'\n"));
+
+        let (_, cpp, mapping) = &requests[1];
+        assert!(is_mapped(*mapping));
+        assert_eq!(cpp.source_file_name, "main.cpp");
+        assert!(cpp.run_script.contains("export PISTON_LANGUAGE='c++'\n"));
+
+        // Not in the table, they only get a shell
+        for (language, req, mapping) in &requests[2..] {
+            assert!(!is_mapped(*mapping), "{language}");
+            assert!(req.nix_shell.contains("      bash\n      coreutils\n"));
+        }
+        assert_eq!(requests[2].1.source_file_name, "main.d");
+        assert_eq!(requests[3].1.source_file_name, "main.fortran");
+    }
+
+    #[test]
+    fn node_provides_javascript() {
+        let requests = requests(NODE_METADATA, "", NODE_RUN);
+        assert_eq!(requests.len(), 1);
+        let (language, req, mapping) = &requests[0];
+        assert_eq!(language, "javascript");
+        assert!(is_mapped(*mapping));
+        assert_eq!(req.name, "javascript 20.11.1 (Piston)");
+        assert!(req.nix_shell.contains("      nodejs\n"));
+        assert_eq!(req.source_file_name, "main.js");
+        assert_eq!(
+            req.aliases,
+            ["node-javascript", "node-js", "javascript", "js"]
+        );
+    }
+
+    #[test]
+    fn java_maps_to_the_jdk() {
+        let requests = requests(JAVA_METADATA, "", JAVA_RUN);
+        assert_eq!(requests.len(), 1);
+        let (language, req, mapping) = &requests[0];
+        assert_eq!(language, "java");
+        assert!(is_mapped(*mapping));
+        assert!(req.nix_shell.contains("      jdk\n"));
+        assert_eq!(req.source_file_name, "Main.java");
+        assert!(req.aliases.is_empty());
+    }
+
+    #[test]
+    fn scripts_without_a_shebang_start_with_the_language() {
+        assert_eq!(
+            with_language("echo hi\n", "bash"),
+            "export PISTON_LANGUAGE='bash'\necho hi\n"
+        );
+    }
+}
@@ -0,0 +1,41 @@
+use axum::{
+    body::Body,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::{
+    api::common_responses::INTERNAL_SERVER_ERROR_RESPONSE,
+    fd_audit::{self, FdCanaryReport},
+};
+
+#[derive(Serialize)]
+pub struct SelfTestResponse {
+    passed: bool,
+    descriptor_canary: FdCanaryReport,
+}
+
+// Checks what sandboxed programs can see of the server. Answers 500 when a
+// check fails, for monitoring to pick it up
+pub async fn run_self_test() -> Result<Response<Body>, Response<Body>> {
+    let descriptor_canary = fd_audit::run_canary().await.map_err(|e| {
+        eprintln!("Failed to run the descriptor canary: {e}");
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    })?;
+    let passed = descriptor_canary.passed;
+    let status = if passed {
+        StatusCode::OK
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    Ok((
+        status,
+        Json(SelfTestResponse {
+            passed,
+            descriptor_canary,
+        }),
+    )
+        .into_response())
+}
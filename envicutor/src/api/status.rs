@@ -0,0 +1,74 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{response::IntoResponse, Json};
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use crate::{
+    api::jobs::{JobResultStats, JobStore},
+    compile_cache::{self, CompileCacheStats},
+    health::{HealthState, HealthSummary},
+    host::{HostMonitor, HostStatus},
+    leases::{ExecutionSlots, LeaseStatus, QueueStatus},
+    quarantine, storage,
+    types::{Metadata, RuntimeId},
+    verification::VerificationStats,
+};
+
+#[derive(Serialize)]
+pub struct UnhealthyRuntime {
+    id: RuntimeId,
+    #[serde(flatten)]
+    health: HealthSummary,
+}
+
+#[derive(Serialize)]
+pub struct StatusResponse {
+    #[serde(flatten)]
+    host: HostStatus,
+    startup_verification: VerificationStats,
+    execution_leases: LeaseStatus,
+    // Only the runtimes that are not healthy
+    runtime_health: Vec<UnhealthyRuntime>,
+    quarantined_boxes: usize,
+    // Set when the runtimes directory is read-only and runtimes can't be changed
+    immutable_deployment: bool,
+    job_results: JobResultStats,
+    compile_cache: CompileCacheStats,
+}
+
+// Public, unlike the admin status, for clients to back off while the queue is full
+pub async fn get_queue_status(execution_slots: Arc<ExecutionSlots>) -> Json<QueueStatus> {
+    Json(execution_slots.queue_status())
+}
+
+pub async fn get_status(
+    host_monitor: Arc<HostMonitor>,
+    verification_stats: VerificationStats,
+    execution_slots: Arc<ExecutionSlots>,
+    stale_lease_age: Duration,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    jobs: Arc<JobStore>,
+) -> impl IntoResponse {
+    let mut runtime_health: Vec<UnhealthyRuntime> = metadata_cache
+        .read()
+        .await
+        .iter()
+        .map(|(id, runtime)| UnhealthyRuntime {
+            id: *id,
+            health: runtime.health.summary(),
+        })
+        .filter(|runtime| runtime.health.state != HealthState::Healthy)
+        .collect();
+    runtime_health.sort_by_key(|runtime| runtime.id);
+    Json(StatusResponse {
+        host: host_monitor.status().await,
+        startup_verification: verification_stats,
+        execution_leases: execution_slots.status(stale_lease_age),
+        runtime_health,
+        quarantined_boxes: quarantine::size(),
+        immutable_deployment: storage::is_immutable(),
+        job_results: jobs.result_stats().await,
+        compile_cache: compile_cache::stats().await,
+    })
+}
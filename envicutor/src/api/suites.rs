@@ -0,0 +1,450 @@
+use std::sync::{atomic::AtomicU64, Arc};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, task};
+
+use crate::{
+    api::{
+        common_functions::internal_error,
+        common_responses::{Message, StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
+        execution::{run_submission, ExecutionQuery, ExecutionRequest},
+        json_body::JsonBody,
+    },
+    events::{Event, Events},
+    host::HostMonitor,
+    isolate::StageResult,
+    leases::ExecutionSlots,
+    limits::{Limits, SystemLimits},
+    output::OutputEncoding,
+    priority::Priority,
+    request_limits::{check_count, RequestLimits},
+    sandbox_env::SandboxEnv,
+    stage, storage,
+    types::{Metadata, RuntimeId},
+    verdict::{self, Comparator, Verdict},
+    verification,
+};
+
+const MAX_SUITE_NAME_LENGTH: usize = 256;
+// Sum of every case's stdin and expected stdout
+const MAX_SUITE_SIZE: usize = 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct SuiteCase {
+    #[serde(default)]
+    stdin: String,
+    expected_stdout: String,
+    points: u32,
+    expected_exit_code: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct SuiteDefinition {
+    name: String,
+    cases: Vec<SuiteCase>,
+    #[serde(default)]
+    comparator: Comparator,
+    limits: Option<Limits>,
+}
+
+#[derive(Serialize)]
+pub struct StoredSuite {
+    name: String,
+    revision: u32,
+}
+
+#[derive(Deserialize)]
+pub struct GradeRequest {
+    source_code: String,
+    // Defaults to the latest revision
+    revision: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct CaseResult {
+    passed: bool,
+    #[serde(flatten)]
+    verdict: Verdict,
+    points: u32,
+    max_points: u32,
+    run: Option<StageResult>,
+}
+
+#[derive(Serialize)]
+pub struct GradeResponse {
+    suite: String,
+    revision: u32,
+    points: u32,
+    max_points: u32,
+    extract: Option<StageResult>,
+    compile: Option<StageResult>,
+    cases: Vec<CaseResult>,
+}
+
+struct LoadedSuite {
+    revision: u32,
+    comparator: Comparator,
+    limits: Option<Limits>,
+    cases: Vec<SuiteCase>,
+}
+
+fn bad_request(message: String) -> Response<Body> {
+    (StatusCode::BAD_REQUEST, Json(Message { message })).into_response()
+}
+
+fn runtime_not_found() -> Response<Body> {
+    (
+        StatusCode::NOT_FOUND,
+        Json(StaticMessage {
+            message: "Could not find the specified runtime",
+        }),
+    )
+        .into_response()
+}
+
+fn validate_suite(
+    suite: &SuiteDefinition,
+    system_limits: &SystemLimits,
+    request_limits: &RequestLimits,
+) -> Result<(), Response<Body>> {
+    if suite.name.is_empty() || suite.name.len() > MAX_SUITE_NAME_LENGTH {
+        return Err(bad_request(format!(
+            "Suite name must have between 1 and {MAX_SUITE_NAME_LENGTH} characters"
+        )));
+    }
+    if suite.cases.is_empty() {
+        return Err(bad_request(
+            "A suite must have at least one case".to_string(),
+        ));
+    }
+    check_count("cases", suite.cases.len(), request_limits.max_suite_cases)
+        .map_err(|e| bad_request(e.to_string()))?;
+    let size: usize = suite
+        .cases
+        .iter()
+        .map(|case| case.stdin.len() + case.expected_stdout.len())
+        .sum();
+    if size > MAX_SUITE_SIZE {
+        return Err(bad_request(format!(
+            "Suite cases can't exceed {MAX_SUITE_SIZE} bytes"
+        )));
+    }
+    for case in &suite.cases {
+        verdict::validate_expected_exit_code(case.expected_exit_code).map_err(bad_request)?;
+    }
+    Limits::resolve(
+        suite.limits.as_ref(),
+        &system_limits.run_defaults,
+        &system_limits.run,
+    )
+    .map_err(|e| bad_request(format!("Invalid limits: {e}")))?;
+    Ok(())
+}
+
+fn insert_suite(runtime_id: RuntimeId, suite: &SuiteDefinition) -> Result<u32, rusqlite::Error> {
+    let mut connection = Connection::open(storage::state_db_path())?;
+    let trx = connection.transaction()?;
+    // Updating a suite stores a new revision, gradings of older revisions stay reproducible
+    let revision: u32 = trx.query_row(
+        "SELECT COALESCE(MAX(revision), 0) + 1 FROM suite WHERE runtime_id = ? AND name = ?",
+        (runtime_id, &suite.name),
+        |row| row.get(0),
+    )?;
+    let limits = suite
+        .limits
+        .as_ref()
+        .map(|limits| serde_json::to_string(limits).unwrap_or_default());
+    trx.execute(
+        "INSERT INTO suite (runtime_id, name, revision, comparator, limits) VALUES (?, ?, ?, ?, ?)",
+        (
+            runtime_id,
+            &suite.name,
+            revision,
+            suite.comparator.name(),
+            limits,
+        ),
+    )?;
+    let suite_id = trx.last_insert_rowid();
+    for (position, case) in suite.cases.iter().enumerate() {
+        trx.execute(
+            "INSERT INTO suite_case (suite_id, position, stdin, expected_stdout, points, expected_exit_code) VALUES (?, ?, ?, ?, ?, ?)",
+            (
+                suite_id,
+                position,
+                &case.stdin,
+                &case.expected_stdout,
+                case.points,
+                case.expected_exit_code,
+            ),
+        )?;
+    }
+    trx.commit()?;
+    Ok(revision)
+}
+
+fn load_suite(
+    runtime_id: RuntimeId,
+    name: &str,
+    revision: Option<u32>,
+) -> Result<Option<LoadedSuite>, rusqlite::Error> {
+    let connection = Connection::open(storage::state_db_path())?;
+    let suite = connection
+        .query_row(
+            "SELECT id, revision, comparator, limits FROM suite
+            WHERE runtime_id = ?1 AND name = ?2 AND (?3 IS NULL OR revision = ?3)
+            ORDER BY revision DESC LIMIT 1",
+            (runtime_id, name, revision),
+            |row| {
+                let id: i64 = row.get(0)?;
+                let revision: u32 = row.get(1)?;
+                let comparator: String = row.get(2)?;
+                let limits: Option<String> = row.get(3)?;
+                Ok((id, revision, comparator, limits))
+            },
+        )
+        .optional()?;
+    let Some((suite_id, revision, comparator, limits)) = suite else {
+        return Ok(None);
+    };
+
+    let mut stmt = connection.prepare(
+        "SELECT stdin, expected_stdout, points, expected_exit_code FROM suite_case WHERE suite_id = ? ORDER BY position",
+    )?;
+    let cases = stmt
+        .query_map([suite_id], |row| {
+            Ok(SuiteCase {
+                stdin: row.get(0)?,
+                expected_stdout: row.get(1)?,
+                points: row.get(2)?,
+                expected_exit_code: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<SuiteCase>, rusqlite::Error>>()?;
+    Ok(Some(LoadedSuite {
+        revision,
+        comparator: Comparator::from_name(&comparator).unwrap_or_else(|| {
+            eprintln!("Unknown comparator {comparator} in suite {suite_id}, comparing exactly");
+            Comparator::Exact
+        }),
+        limits: limits.and_then(|limits| serde_json::from_str(&limits).ok()),
+        cases,
+    }))
+}
+
+pub async fn store_suite(
+    Path(runtime_id): Path<RuntimeId>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    system_limits: SystemLimits,
+    request_limits: RequestLimits,
+    JsonBody(suite): JsonBody<SuiteDefinition>,
+) -> Result<Response<Body>, Response<Body>> {
+    if !metadata_cache.read().await.contains_key(&runtime_id) {
+        return Err(runtime_not_found());
+    }
+    validate_suite(&suite, &system_limits, &request_limits)?;
+
+    let (revision, suite) = task::spawn_blocking(move || {
+        let revision = insert_suite(runtime_id, &suite);
+        (revision, suite)
+    })
+    .await
+    .map_err(|e| {
+        eprintln!("Failed to spawn blocking task: {e}");
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    })?;
+    let revision = revision.map_err(|e| {
+        eprintln!("Failed to store suite: {e}");
+        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    })?;
+
+    Ok(Json(StoredSuite {
+        name: suite.name,
+        revision,
+    })
+    .into_response())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn grade_submission(
+    semaphore: Arc<ExecutionSlots>,
+    box_id: Arc<AtomicU64>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    host_monitor: Arc<HostMonitor>,
+    events: Events,
+    request_limits: RequestLimits,
+    Path((runtime_id, name)): Path<(RuntimeId, String)>,
+    query: Option<Query<ExecutionQuery>>,
+    JsonBody(req): JsonBody<GradeRequest>,
+) -> Result<Response<Body>, Response<Body>> {
+    verification::ensure_verified(&metadata_cache, runtime_id)
+        .await
+        .map_err(|e| {
+            internal_error(
+                &host_monitor,
+                format!("Failed to verify runtime {runtime_id}: {e}"),
+            )
+        })?;
+    let is_compiled = match metadata_cache.read().await.get(&runtime_id) {
+        Some(runtime) => runtime.is_compiled,
+        None => return Err(runtime_not_found()),
+    };
+    let suite_name = name.clone();
+    let suite = task::spawn_blocking(move || load_suite(runtime_id, &suite_name, req.revision))
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to spawn blocking task: {e}");
+            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+        })?
+        .map_err(|e| {
+            eprintln!("Failed to load suite: {e}");
+            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+        })?
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(StaticMessage {
+                    message: "Could not find the specified suite",
+                }),
+            )
+                .into_response()
+        })?;
+    let is_project = query.is_some_and(|query| query.is_project);
+    // Checked before any box is allocated, every case goes through every stage
+    let stages = suite.cases.len() * stage::plan(is_project, is_compiled).len();
+    if stages > request_limits.max_stages_per_request {
+        return Err(bad_request(format!(
+            "Grading would run {stages} sandbox stages, more than the limit of {}",
+            request_limits.max_stages_per_request
+        )));
+    }
+
+    let mut response = GradeResponse {
+        suite: name,
+        revision: suite.revision,
+        points: 0,
+        max_points: suite.cases.iter().map(|case| case.points).sum(),
+        extract: None,
+        compile: None,
+        cases: Vec::new(),
+    };
+    // Every case goes through the whole execution pipeline, so a case can't see
+    // files left behind by the previous one (at the cost of compiling once per case)
+    let mut built = true;
+    for (case_index, case) in suite.cases.into_iter().enumerate() {
+        if !built {
+            response.cases.push(CaseResult {
+                passed: false,
+                verdict: Verdict::CompilationError,
+                points: 0,
+                max_points: case.points,
+                run: None,
+            });
+            continue;
+        }
+        let (_, _, mut res) = run_submission(
+            semaphore.clone(),
+            box_id.clone(),
+            metadata_cache.clone(),
+            installation_lock.clone(),
+            system_limits.clone(),
+            &host_monitor,
+            ExecutionRequest {
+                runtime_id: Some(runtime_id),
+                runtime: None,
+                override_floor: false,
+                args: Vec::new(),
+                expected_exit_code: None,
+                expected_output: None,
+                comparator: Comparator::default(),
+                include_stdout: false,
+                max_stdout_bytes: None,
+                cases: Vec::new(),
+                stop_on_first_failure: false,
+                parallelism: None,
+                max_total_cpu_seconds: None,
+                idle_output_timeout_ms: None,
+                max_output_bytes: None,
+                kill_on_output_limit: false,
+                output_encoding: OutputEncoding::Utf8,
+                env: SandboxEnv::new(),
+                priority: Priority::Interactive,
+                datasets: Vec::new(),
+                output_files: Vec::new(),
+                source_code: req.source_code.clone(),
+                files: Vec::new(),
+                entry: None,
+                entry_file_name: None,
+                transcode: None,
+                capture_core: false,
+                coalesce: false,
+                unbounded_queue: false,
+                input: Some(case.stdin).filter(|stdin| !stdin.is_empty()),
+                compile_limits: None,
+                run_limits: suite.limits.clone(),
+                validate_only: false,
+            },
+            Some(Query(ExecutionQuery {
+                is_project,
+                explain_limits: false,
+            })),
+            None,
+        )
+        .await?;
+        for result in [&mut res.extract, &mut res.compile, &mut res.run]
+            .into_iter()
+            .flatten()
+        {
+            if let Some(stage) = result.stage.as_mut() {
+                stage.case_index = Some(case_index as u32);
+            }
+        }
+        events.publish(Event::execution_finished(runtime_id, &res));
+
+        if response.cases.is_empty() {
+            response.extract = res.extract;
+            response.compile = res.compile;
+        }
+        let Some(run) = res.run else {
+            // The submission did not build, no case can pass
+            built = false;
+            response.cases.push(CaseResult {
+                passed: false,
+                verdict: Verdict::CompilationError,
+                points: 0,
+                max_points: case.points,
+                run: None,
+            });
+            continue;
+        };
+        let verdict = verdict::judge(
+            &run,
+            case.expected_exit_code,
+            Some(
+                suite
+                    .comparator
+                    .matches(&case.expected_stdout, &run.stdout.text()),
+            ),
+        );
+        let passed = verdict == Verdict::Ok;
+        let points = if passed { case.points } else { 0 };
+        response.points += points;
+        response.cases.push(CaseResult {
+            passed,
+            verdict,
+            points,
+            max_points: case.points,
+            run: Some(run),
+        });
+    }
+    Ok(Json(response).into_response())
+}
@@ -0,0 +1,96 @@
+use axum::{
+    body::Body,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+#[derive(Serialize)]
+pub struct FieldError {
+    field: &'static str,
+    message: String,
+    // What a client can act on besides the message, e.g. a code and a bound
+    #[serde(flatten)]
+    detail: Option<Map<String, Value>>,
+}
+
+// `message` and `detail` are the first of the errors, for clients that only
+// read them
+#[derive(Serialize)]
+struct ValidationErrors {
+    message: String,
+    #[serde(flatten)]
+    detail: Option<Map<String, Value>>,
+    errors: Vec<FieldError>,
+}
+
+// Collects every problem of a request, so that they are all answered at once
+// instead of one per attempt
+#[derive(Default)]
+pub struct Violations {
+    errors: Vec<FieldError>,
+}
+
+impl Violations {
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field,
+            message: message.into(),
+            detail: None,
+        });
+    }
+
+    // The fields of `detail` are given next to the message of the error
+    pub fn add_detailed(
+        &mut self,
+        field: &'static str,
+        message: impl Into<String>,
+        detail: impl Serialize,
+    ) {
+        let detail = match serde_json::to_value(detail) {
+            Ok(Value::Object(detail)) => Some(detail),
+            _ => None,
+        };
+        self.errors.push(FieldError {
+            field,
+            message: message.into(),
+            detail,
+        });
+    }
+
+    // The value of a check that passed, None once its error is added
+    pub fn check<T>(&mut self, field: &'static str, res: Result<T, String>) -> Option<T> {
+        res.map_err(|message| self.add(field, message)).ok()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        let (message, detail) = self
+            .errors
+            .first()
+            .map(|error| (error.message.clone(), error.detail.clone()))
+            .unwrap_or_default();
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ValidationErrors {
+                message,
+                detail,
+                errors: self.errors,
+            }),
+        )
+            .into_response()
+    }
+
+    pub fn into_result(self) -> Result<(), Response<Body>> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self.into_response())
+        }
+    }
+}
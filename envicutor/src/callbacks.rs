@@ -0,0 +1,416 @@
+// Results of jobs POSTed to the URL their request gave. Each delivery is signed
+// with the server's secret so that receivers can tell it came from here, and
+// only goes to the hosts the operator allows. Deliveries are kept in the
+// database until they are over, so they resume after a restart, and the ones
+// that run out of attempts are kept as dead letters to be redelivered by hand
+
+use std::{
+    future::Future,
+    process::Stdio,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, process::Command, task};
+
+use crate::{
+    delivery::{FailedAttempt, RetryPolicy},
+    digest, storage,
+    strings::{truncate_at_char_boundary, url_scheme_and_host},
+};
+
+const CURL_PATH: &str = "/usr/bin/curl";
+pub const SIGNATURE_HEADER: &str = "X-Envicutor-Signature";
+// Only the start of larger payloads is kept in dead letters
+const MAX_DEAD_LETTER_PAYLOAD_BYTES: usize = 256 * 1024;
+
+// Set once at startup
+static CALLBACK_CONFIG: OnceLock<CallbackConfig> = OnceLock::new();
+
+#[derive(Clone)]
+pub struct CallbackConfig {
+    pub secret: String,
+    pub allowed_hosts: Vec<String>,
+    pub retry_policy: RetryPolicy,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Serialize, Clone)]
+pub struct DeliveryStatus {
+    pub state: DeliveryState,
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // Set once the delivery failed for good
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dead_letter_id: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct DeadLetter {
+    pub id: i64,
+    pub url: String,
+    pub job_id: u64,
+    pub payload: String,
+    // A truncated payload can't be redelivered
+    pub payload_truncated: bool,
+    pub last_error: String,
+    pub attempts: Vec<FailedAttempt>,
+    // Unix seconds
+    pub failed_at: u64,
+}
+
+pub enum Redelivery {
+    Started,
+    NotFound,
+    PayloadTruncated,
+}
+
+// A delivery that is not over yet
+struct PendingDelivery {
+    id: Option<i64>,
+    url: String,
+    job_id: u64,
+    payload: String,
+}
+
+pub fn use_callback_config(config: CallbackConfig) -> Result<(), Error> {
+    CALLBACK_CONFIG
+        .set(config)
+        .map_err(|_| anyhow!("The callback config was already set"))
+}
+
+// Errors are safe to show in the response
+pub fn validate_callback_url(url: &str) -> Result<(), String> {
+    let Some(config) = CALLBACK_CONFIG.get() else {
+        return Err("Callbacks are not enabled on this server".to_string());
+    };
+    let Some((scheme, host)) = url_scheme_and_host(url) else {
+        return Err(format!("Invalid callback URL: {url}"));
+    };
+    if scheme != "http" && scheme != "https" {
+        return Err(format!("Callback URLs must use http or https: {url}"));
+    }
+    if !config.allowed_hosts.iter().any(|allowed| allowed == host) {
+        return Err(format!("{host} is not an allowed callback host"));
+    }
+    Ok(())
+}
+
+// Redirects are not followed, they could lead off the allowed hosts
+async fn post(url: &str, signature: &str, payload: &[u8], timeout: Duration) -> Result<(), Error> {
+    let mut child = Command::new(CURL_PATH)
+        .args([
+            "--proto",
+            "=http,https",
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--output",
+            "/dev/null",
+            "--max-time",
+            &timeout.as_secs().max(1).to_string(),
+            "--header",
+            "Content-Type: application/json",
+            "--header",
+            &format!("{SIGNATURE_HEADER}: sha256={signature}"),
+            "--data-binary",
+            "@-",
+            "--",
+            url,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to spawn curl\nError: {e}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(payload)
+            .await
+            .map_err(|e| anyhow!("Failed to write to curl\nError: {e}"))?;
+    }
+    let res = child
+        .wait_with_output()
+        .await
+        .map_err(|e| anyhow!("Failed to wait for curl\nError: {e}"))?;
+    if !res.status.success() {
+        return Err(anyhow!("{}", String::from_utf8_lossy(&res.stderr).trim()));
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+async fn with_connection<T: Send + 'static>(
+    f: impl FnOnce(&mut Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+) -> Result<T, Error> {
+    task::spawn_blocking(move || {
+        let mut connection = Connection::open(storage::state_db_path())?;
+        Ok(f(&mut connection)?)
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+}
+
+async fn store_pending(url: &str, job_id: u64, payload: &str) -> Result<i64, Error> {
+    let (url, payload) = (url.to_string(), payload.to_string());
+    with_connection(move |connection| {
+        connection.execute(
+            "INSERT INTO webhook_delivery (url, job_id, payload, created_at) VALUES (?, ?, ?, ?)",
+            (&url, job_id, &payload, now()),
+        )?;
+        Ok(connection.last_insert_rowid())
+    })
+    .await
+}
+
+async fn remove_pending(id: i64) -> Result<(), Error> {
+    with_connection(move |connection| {
+        connection.execute("DELETE FROM webhook_delivery WHERE id = ?", [id])?;
+        Ok(())
+    })
+    .await
+}
+
+// Also removes the pending delivery, returns the id of the dead letter
+async fn store_dead_letter(
+    delivery: &PendingDelivery,
+    attempts: &[FailedAttempt],
+) -> Result<i64, Error> {
+    let mut payload = delivery.payload.clone();
+    let payload_truncated = payload.len() > MAX_DEAD_LETTER_PAYLOAD_BYTES;
+    truncate_at_char_boundary(&mut payload, MAX_DEAD_LETTER_PAYLOAD_BYTES);
+    let last_error = attempts
+        .last()
+        .map(|attempt| attempt.error.clone())
+        .unwrap_or_default();
+    let attempts = serde_json::to_string(attempts)?;
+    let (id, url, job_id) = (delivery.id, delivery.url.clone(), delivery.job_id);
+    with_connection(move |connection| {
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO webhook_dead_letter (url, job_id, payload, payload_truncated, last_error, attempts, failed_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            (&url, job_id, &payload, payload_truncated, &last_error, &attempts, now()),
+        )?;
+        let dead_letter_id = transaction.last_insert_rowid();
+        if let Some(id) = id {
+            transaction.execute("DELETE FROM webhook_delivery WHERE id = ?", [id])?;
+        }
+        transaction.commit()?;
+        Ok(dead_letter_id)
+    })
+    .await
+}
+
+// Awaits `on_retry` with the status after every failed attempt that is retried
+async fn send<F: Future<Output = ()>>(
+    delivery: PendingDelivery,
+    on_retry: impl Fn(DeliveryStatus) -> F,
+) -> DeliveryStatus {
+    let url = &delivery.url;
+    let failed = |attempts, error: String, dead_letter_id| {
+        eprintln!("Failed to deliver a callback to {url}: {error}");
+        DeliveryStatus {
+            state: DeliveryState::Failed,
+            attempts,
+            error: Some(error),
+            dead_letter_id,
+        }
+    };
+    let Some(config) = CALLBACK_CONFIG.get() else {
+        return failed(
+            0,
+            "Callbacks are not enabled on this server".to_string(),
+            None,
+        );
+    };
+    let payload = delivery.payload.as_bytes();
+    let signature = digest::hmac_sha256(config.secret.as_bytes(), payload);
+    let policy = config.retry_policy;
+    let (delivered, attempts) = policy
+        .run(
+            || post(url, &signature, payload, policy.timeout),
+            |attempts| {
+                on_retry(DeliveryStatus {
+                    state: DeliveryState::Pending,
+                    attempts: attempts.len() as u32,
+                    error: attempts.last().map(|attempt| attempt.error.clone()),
+                    dead_letter_id: None,
+                })
+            },
+        )
+        .await;
+    if delivered {
+        if let Some(id) = delivery.id {
+            if let Err(e) = remove_pending(id).await {
+                eprintln!("Failed to remove delivered callback {id}: {e}");
+            }
+        }
+        return DeliveryStatus {
+            state: DeliveryState::Delivered,
+            attempts: attempts.len() as u32 + 1,
+            error: None,
+            dead_letter_id: None,
+        };
+    }
+    let dead_letter_id = store_dead_letter(&delivery, &attempts)
+        .await
+        .map_err(|e| eprintln!("Failed to store the dead letter of a callback to {url}: {e}"))
+        .ok();
+    let error = attempts
+        .last()
+        .map(|attempt| attempt.error.clone())
+        .unwrap_or_default();
+    failed(attempts.len() as u32, error, dead_letter_id)
+}
+
+pub async fn deliver<F: Future<Output = ()>>(
+    url: &str,
+    job_id: u64,
+    payload: &impl Serialize,
+    on_retry: impl Fn(DeliveryStatus) -> F,
+) -> DeliveryStatus {
+    let payload = match serde_json::to_string(payload) {
+        Ok(payload) => payload,
+        Err(e) => {
+            return DeliveryStatus {
+                state: DeliveryState::Failed,
+                attempts: 0,
+                error: Some(format!("Failed to serialize the result: {e}")),
+                dead_letter_id: None,
+            }
+        }
+    };
+    // Still delivered when it can't be stored, it just won't survive a restart
+    let id = store_pending(url, job_id, &payload)
+        .await
+        .map_err(|e| eprintln!("Failed to store a pending callback to {url}: {e}"))
+        .ok();
+    let delivery = PendingDelivery {
+        id,
+        url: url.to_string(),
+        job_id,
+        payload,
+    };
+    send(delivery, on_retry).await
+}
+
+// Called once at startup, resumes the deliveries a previous run left pending
+pub async fn resume_pending() -> Result<usize, Error> {
+    let deliveries = with_connection(|connection| {
+        let mut stmt =
+            connection.prepare("SELECT id, url, job_id, payload FROM webhook_delivery")?;
+        let deliveries = stmt
+            .query_map([], |row| {
+                Ok(PendingDelivery {
+                    id: Some(row.get(0)?),
+                    url: row.get(1)?,
+                    job_id: row.get(2)?,
+                    payload: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(deliveries)
+    })
+    .await?;
+    let resumed = deliveries.len();
+    for delivery in deliveries {
+        tokio::spawn(send(delivery, |_| async {}));
+    }
+    Ok(resumed)
+}
+
+pub async fn dead_letters() -> Result<Vec<DeadLetter>, Error> {
+    let rows = with_connection(|connection| {
+        let mut stmt = connection.prepare(
+            "SELECT id, url, job_id, payload, payload_truncated, last_error, attempts, failed_at FROM webhook_dead_letter ORDER BY id",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    DeadLetter {
+                        id: row.get(0)?,
+                        url: row.get(1)?,
+                        job_id: row.get(2)?,
+                        payload: row.get(3)?,
+                        payload_truncated: row.get(4)?,
+                        last_error: row.get(5)?,
+                        attempts: Vec::new(),
+                        failed_at: row.get(7)?,
+                    },
+                    row.get::<_, String>(6)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    })
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(dead_letter, attempts)| DeadLetter {
+            attempts: serde_json::from_str(&attempts).unwrap_or_default(),
+            ..dead_letter
+        })
+        .collect())
+}
+
+// Moves the dead letter back to the pending deliveries and sends it again, it
+// ends up as a new dead letter if it fails again
+pub async fn redeliver(dead_letter_id: i64) -> Result<Redelivery, Error> {
+    let delivery = with_connection(move |connection| {
+        let transaction = connection.transaction()?;
+        let dead_letter: Option<(String, u64, String, bool)> = transaction
+            .query_row(
+                "SELECT url, job_id, payload, payload_truncated FROM webhook_dead_letter WHERE id = ?",
+                [dead_letter_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()?;
+        let Some((url, job_id, payload, payload_truncated)) = dead_letter else {
+            return Ok(Err(Redelivery::NotFound));
+        };
+        if payload_truncated {
+            return Ok(Err(Redelivery::PayloadTruncated));
+        }
+        transaction.execute(
+            "DELETE FROM webhook_dead_letter WHERE id = ?",
+            [dead_letter_id],
+        )?;
+        transaction.execute(
+            "INSERT INTO webhook_delivery (url, job_id, payload, created_at) VALUES (?, ?, ?, ?)",
+            (&url, job_id, &payload, now()),
+        )?;
+        let id = transaction.last_insert_rowid();
+        transaction.commit()?;
+        Ok(Ok(PendingDelivery {
+            id: Some(id),
+            url,
+            job_id,
+            payload,
+        }))
+    })
+    .await?;
+    match delivery {
+        Ok(delivery) => {
+            tokio::spawn(send(delivery, |_| async {}));
+            Ok(Redelivery::Started)
+        }
+        Err(redelivery) => Ok(redelivery),
+    }
+}
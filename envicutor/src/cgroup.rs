@@ -0,0 +1,210 @@
+// Where isolate puts the control group of a box, read from its config file
+
+use std::{
+    ffi::CString,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error};
+use tokio::{
+    fs,
+    time::{self, Instant},
+};
+
+use crate::isolate;
+
+// Resolved on first use, `None` when the config file has no usable cg_root
+static CG_ROOT: OnceLock<Option<String>> = OnceLock::new();
+static CPU_STAT_FAILURE_LOGGED: AtomicBool = AtomicBool::new(false);
+// Set by the probe at startup
+static CPU_WEIGHT_SUPPORTED: OnceLock<bool> = OnceLock::new();
+
+const CPU_WEIGHT_WAIT: Duration = Duration::from_secs(1);
+const CPU_WEIGHT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CpuUsage {
+    pub user_usec: u64,
+    pub system_usec: u64,
+}
+
+// e.g. "cg_root = /sys/fs/cgroup/isolate", isolate reads the path from a file
+// when the value is "auto:<file>"
+pub fn parse_cg_root(config: &str) -> Option<&str> {
+    config.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == "cg_root").then(|| value.trim())
+    })
+}
+
+fn resolve_cg_root() -> Result<String, Error> {
+    let config_file = isolate::config_file();
+    let config = std::fs::read_to_string(config_file)
+        .map_err(|e| anyhow!("Failed to read {config_file}: {e}"))?;
+    let cg_root =
+        parse_cg_root(&config).ok_or_else(|| anyhow!("{config_file} does not set cg_root"))?;
+    match cg_root.strip_prefix("auto:") {
+        Some(root_file) => std::fs::read_to_string(root_file)
+            .map(|root| root.trim().to_string())
+            .map_err(|e| anyhow!("Failed to read {root_file}: {e}")),
+        None => Ok(cg_root.to_string()),
+    }
+}
+
+fn cg_root() -> Option<&'static str> {
+    CG_ROOT
+        .get_or_init(|| {
+            resolve_cg_root()
+                .map_err(|e| eprintln!("Can't locate the control groups of boxes: {e}"))
+                .ok()
+        })
+        .as_deref()
+}
+
+pub fn box_cgroup_path(box_id: u64) -> Option<String> {
+    cg_root().map(|cg_root| format!("{cg_root}/box-{box_id}"))
+}
+
+// Boxes only have a cpu.weight when the cpu controller is enabled for the
+// children of the root, which has to be writable to create them
+fn check_cpu_weight_support(cg_root: Option<&str>) -> Result<(), Error> {
+    let cg_root = cg_root.ok_or_else(|| anyhow!("the control group root is unknown"))?;
+    let subtree_control_path = format!("{cg_root}/cgroup.subtree_control");
+    let subtree_control = std::fs::read_to_string(&subtree_control_path)
+        .map_err(|e| anyhow!("failed to read {subtree_control_path}: {e}"))?;
+    if !subtree_control
+        .split_whitespace()
+        .any(|controller| controller == "cpu")
+    {
+        return Err(anyhow!(
+            "the cpu controller is not enabled in {subtree_control_path}"
+        ));
+    }
+    let c_root = CString::new(cg_root).map_err(|e| anyhow!("invalid path {cg_root}: {e}"))?;
+    if unsafe { libc::access(c_root.as_ptr(), libc::W_OK) } != 0 {
+        return Err(anyhow!("{cg_root} is not writable"));
+    }
+    Ok(())
+}
+
+// Run once at startup, boxes are left at the default weight when it fails
+pub fn probe_cpu_weight() -> bool {
+    *CPU_WEIGHT_SUPPORTED.get_or_init(|| match check_cpu_weight_support(cg_root()) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Boxes can't be given a CPU weight, priorities will be ignored: {e}");
+            false
+        }
+    })
+}
+
+pub fn can_set_cpu_weight() -> bool {
+    CPU_WEIGHT_SUPPORTED.get().copied().unwrap_or(false)
+}
+
+// isolate creates the control group of a box anew when a run starts, the weight
+// is written once the program is in it so that it isn't lost with the old one
+pub async fn set_cpu_weight(cgroup_path: &str, weight: u32) -> Result<(), Error> {
+    let procs_path = format!("{cgroup_path}/cgroup.procs");
+    let weight_path = format!("{cgroup_path}/cpu.weight");
+    let deadline = Instant::now() + CPU_WEIGHT_WAIT;
+    loop {
+        let populated = fs::read_to_string(&procs_path)
+            .await
+            .is_ok_and(|procs| !procs.trim().is_empty());
+        if populated {
+            return fs::write(&weight_path, weight.to_string())
+                .await
+                .map_err(|e| anyhow!("Failed to write to {weight_path}: {e}"));
+        }
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "{cgroup_path} got no process in {CPU_WEIGHT_WAIT:?}"
+            ));
+        }
+        time::sleep(CPU_WEIGHT_POLL_INTERVAL).await;
+    }
+}
+
+// e.g. "usage_usec 1234\nuser_usec 1000\nsystem_usec 234\n..."
+pub fn parse_cpu_stat(stat: &str) -> Option<CpuUsage> {
+    let field = |name: &str| {
+        stat.lines().find_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            (key == name).then(|| value.trim().parse().ok())?
+        })
+    };
+    Some(CpuUsage {
+        user_usec: field("user_usec")?,
+        system_usec: field("system_usec")?,
+    })
+}
+
+pub async fn read_cpu_usage(cgroup_path: &str) -> Option<CpuUsage> {
+    let stat_path = format!("{cgroup_path}/cpu.stat");
+    match fs::read_to_string(&stat_path).await {
+        Ok(stat) => parse_cpu_stat(&stat),
+        Err(e) => {
+            if !CPU_STAT_FAILURE_LOGGED.swap(true, Ordering::Relaxed) {
+                eprintln!("Failed to read {stat_path}, CPU time won't be split: {e}");
+            }
+            None
+        }
+    }
+}
+
+// Kills every process of the control group, the program of a box, without
+// killing isolate itself so that it still reports how the program ended
+pub async fn kill_all(cgroup_path: &str) -> Result<(), Error> {
+    let kill_path = format!("{cgroup_path}/cgroup.kill");
+    fs::write(&kill_path, "1")
+        .await
+        .map_err(|e| anyhow!("Failed to write to {kill_path}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[tokio::test]
+    async fn cpu_weights_need_the_cpu_controller() {
+        assert!(check_cpu_weight_support(None).is_err());
+
+        let tmp = std::env::temp_dir().to_string_lossy().into_owned();
+        let root = TempDir::new(format!("{tmp}/envicutor-cgroup-{}", std::process::id()))
+            .await
+            .unwrap();
+        let subtree_control_path = format!("{}/cgroup.subtree_control", root.path);
+        assert!(check_cpu_weight_support(Some(&root.path)).is_err());
+        std::fs::write(&subtree_control_path, "memory pids\n").unwrap();
+        assert!(check_cpu_weight_support(Some(&root.path)).is_err());
+        std::fs::write(&subtree_control_path, "cpuset cpu memory pids\n").unwrap();
+        check_cpu_weight_support(Some(&root.path)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_weight_is_written_once_the_box_has_a_process() {
+        let tmp = std::env::temp_dir().to_string_lossy().into_owned();
+        let cgroup = TempDir::new(format!("{tmp}/envicutor-box-cgroup-{}", std::process::id()))
+            .await
+            .unwrap();
+        let procs_path = format!("{}/cgroup.procs", cgroup.path);
+        let weight_path = format!("{}/cpu.weight", cgroup.path);
+        std::fs::write(&procs_path, "").unwrap();
+
+        let set = set_cpu_weight(&cgroup.path, 25);
+        let populate = async {
+            time::sleep(CPU_WEIGHT_POLL_INTERVAL * 4).await;
+            assert!(!std::path::Path::new(&weight_path).exists());
+            std::fs::write(&procs_path, "1234\n").unwrap();
+        };
+        let (res, ()) = tokio::join!(set, populate);
+        res.unwrap();
+        assert_eq!(std::fs::read_to_string(&weight_path).unwrap(), "25");
+    }
+}
@@ -0,0 +1,54 @@
+// Timestamps for correlating stages and installations with external logs. The
+// wall clock is read once and advanced by the monotonic clock, so that the
+// timestamps of a request are ordered like what happened even when the system
+// clock is stepped meanwhile. NTP slews the monotonic clock too, so they don't
+// drift apart
+
+use std::{
+    sync::OnceLock,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+static ANCHOR: OnceLock<(SystemTime, Instant)> = OnceLock::new();
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+pub fn now() -> SystemTime {
+    let (wall, monotonic) = ANCHOR.get_or_init(|| (SystemTime::now(), Instant::now()));
+    *wall + monotonic.elapsed()
+}
+
+// The year, month and day of a day counted from 1970-01-01, see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+// In UTC with milliseconds, e.g. 2024-06-12T08:30:00.250Z
+pub fn rfc3339_millis(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days(seconds / SECONDS_PER_DAY);
+    let seconds_of_day = seconds % SECONDS_PER_DAY;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
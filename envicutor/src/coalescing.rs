@@ -0,0 +1,165 @@
+// Web IDEs saving as the code is typed can send the same execution several
+// times in a row. A request asking to be coalesced waits for the identical
+// execution in flight and gets its result instead of taking a sandbox, or runs
+// and lets the identical ones after it wait for it
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Error;
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use tokio::sync::watch;
+
+use crate::{
+    api::execution::{ExecutionQuery, ExecutionRequest, ExecutionResponse, StreamEvent},
+    digest::Sha256,
+    types::RuntimeId,
+};
+
+// Requests past it run on their own
+const MAX_IN_FLIGHT: usize = 1024;
+
+static IN_FLIGHT: Mutex<BTreeMap<String, watch::Receiver<Progress>>> = Mutex::new(BTreeMap::new());
+
+// An execution that failed, as any of the requests waiting for it is answered
+#[derive(Clone)]
+pub struct FailedExecution {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl FailedExecution {
+    pub async fn from_response(res: Response<Body>) -> (Self, Response<Body>) {
+        let (parts, body) = res.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX)
+            .await
+            .unwrap_or_default();
+        let failed = FailedExecution {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        };
+        (failed, Response::from_parts(parts, Body::from(body)))
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        let mut res = Response::new(Body::from(self.body));
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers;
+        res
+    }
+}
+
+pub type Outcome = Result<(RuntimeId, String, ExecutionResponse), FailedExecution>;
+
+// What the execution sent so far, replayed to the requests that join late,
+// and its outcome once it has one
+#[derive(Default)]
+struct Progress {
+    events: Vec<StreamEvent>,
+    outcome: Option<Arc<Outcome>>,
+}
+
+// Identical when everything but `coalesce` is. The runtime can't be changed
+// while the execution is in flight, installations wait for executions to end
+pub fn key(req: &ExecutionRequest, query: Option<&ExecutionQuery>) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(&serde_json::to_vec(req)?);
+    hasher.update(b"\0");
+    hasher.update(&serde_json::to_vec(&query)?);
+    Ok(hasher.finish_hex())
+}
+
+pub enum Role {
+    Leader(Leader),
+    Follower(Follower),
+}
+
+// None when too many executions are in flight
+pub fn join(key: String) -> Option<Role> {
+    let mut in_flight = IN_FLIGHT.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(progress) = in_flight.get(&key) {
+        return Some(Role::Follower(Follower {
+            progress: progress.clone(),
+            seen: 0,
+        }));
+    }
+    if in_flight.len() >= MAX_IN_FLIGHT {
+        return None;
+    }
+    let (sender, receiver) = watch::channel(Progress::default());
+    in_flight.insert(key.clone(), receiver);
+    Some(Role::Leader(Leader {
+        key,
+        progress: sender,
+    }))
+}
+
+// Runs the execution, it leaves the map once it is done or dropped
+pub struct Leader {
+    key: String,
+    progress: watch::Sender<Progress>,
+}
+
+impl Leader {
+    pub fn send(&self, event: StreamEvent) {
+        self.progress
+            .send_modify(|progress| progress.events.push(event));
+    }
+
+    pub fn finish(self, outcome: Outcome) {
+        self.progress
+            .send_modify(|progress| progress.outcome = Some(Arc::new(outcome)));
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        IN_FLIGHT
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.key);
+    }
+}
+
+pub struct Follower {
+    progress: watch::Receiver<Progress>,
+    seen: usize,
+}
+
+impl Follower {
+    // Gives the events of the execution to `on_event`, those sent so far then
+    // the rest as they come, and returns its outcome. None when it was dropped
+    // without one, e.g. when the client of a streamed execution went away
+    pub async fn follow(mut self, mut on_event: impl FnMut(StreamEvent)) -> Option<Outcome> {
+        loop {
+            let (events, outcome) = {
+                let progress = self.progress.borrow_and_update();
+                (
+                    progress.events[self.seen..].to_vec(),
+                    progress.outcome.clone(),
+                )
+            };
+            self.seen += events.len();
+            events.into_iter().for_each(&mut on_event);
+            if let Some(outcome) = outcome {
+                return Some((*outcome).clone());
+            }
+            if self.progress.changed().await.is_err() {
+                return self
+                    .progress
+                    .borrow()
+                    .outcome
+                    .as_ref()
+                    .map(|outcome| (**outcome).clone());
+            }
+        }
+    }
+}
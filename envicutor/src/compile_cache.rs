@@ -0,0 +1,306 @@
+// What compilations left in the submission directory, so that a submission sent
+// again (rerun against new cases, retried by a flaky client) is not compiled
+// again. Entries are keyed by a hash of everything the compilation depends on
+// and the least recently used ones are evicted once over the size limit
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use tokio::{fs, sync::Mutex};
+
+use crate::{
+    digest::Sha256, globals::RUNTIMES_DIR, limits::MandatoryLimits, storage, types::RuntimeId,
+};
+
+const ENTRIES_DIR_NAME: &str = "entries";
+const STAGING_DIR_NAME: &str = "staging";
+const CACHED_SUBMISSION_NAME: &str = "cached_submission";
+
+// Set once at startup, the cache is disabled without it
+static MAX_BYTES: OnceLock<u64> = OnceLock::new();
+static INDEX: Mutex<Index> = Mutex::const_new(Index::new());
+// Names the directories of entries being stored
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+struct Entry {
+    size: u64,
+    last_used: u64,
+}
+
+struct Index {
+    entries: Option<HashMap<String, Entry>>,
+    stored_bytes: u64,
+    clock: u64,
+}
+
+impl Index {
+    const fn new() -> Self {
+        Index {
+            entries: None,
+            stored_bytes: 0,
+            clock: 0,
+        }
+    }
+
+    fn entries(&mut self) -> &mut HashMap<String, Entry> {
+        self.entries.get_or_insert_with(HashMap::new)
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheResult {
+    Hit,
+    Miss,
+}
+
+#[derive(Serialize)]
+pub struct CompileCacheStats {
+    max_bytes: u64,
+    stored_bytes: u64,
+    entries: usize,
+    hits: u64,
+    misses: u64,
+}
+
+pub fn use_max_bytes(max_bytes: u64) -> Result<(), Error> {
+    MAX_BYTES
+        .set(max_bytes)
+        .map_err(|_| anyhow!("The compile cache size was already set"))
+}
+
+pub fn is_enabled() -> bool {
+    MAX_BYTES.get().is_some_and(|max| *max > 0)
+}
+
+fn entries_dir() -> String {
+    format!("{}/{ENTRIES_DIR_NAME}", storage::compile_cache_dir())
+}
+
+fn entry_path(key: &str) -> String {
+    format!("{}/{key}", entries_dir())
+}
+
+// Entries left by a previous run are not in the index, so they are removed
+pub async fn init() -> Result<(), Error> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    let cache_dir = storage::compile_cache_dir();
+    fs::create_dir_all(cache_dir)
+        .await
+        .map_err(|e| anyhow!("Failed to create {cache_dir}: {e}"))?;
+    for dir_name in [ENTRIES_DIR_NAME, STAGING_DIR_NAME] {
+        crate::fs::create_dir_replacing_existing(&format!("{cache_dir}/{dir_name}")).await?;
+    }
+    Ok(())
+}
+
+async fn hash_runtime_file(hasher: &mut Sha256, path: &str) -> Result<(), Error> {
+    match fs::read(path).await {
+        Ok(content) => hash_field(hasher, &content),
+        Err(e) if e.kind() == ErrorKind::NotFound => hash_field(hasher, b""),
+        Err(e) => return Err(anyhow!("Failed to read {path}: {e}")),
+    }
+    Ok(())
+}
+
+// Every field is prefixed with its length so that they can't run into each other
+fn hash_field(hasher: &mut Sha256, field: &[u8]) {
+    hasher.update(&(field.len() as u64).to_be_bytes());
+    hasher.update(field);
+}
+
+// The submitted files with the runtime's compile script and environment, as
+// an update of the runtime changes what they compile to. The limits are part
+// of it too, a compilation that succeeded may not under lower limits
+pub async fn key<'a>(
+    runtime_id: RuntimeId,
+    limits: &MandatoryLimits,
+    entry: &str,
+    files: impl Iterator<Item = (&'a str, &'a [u8])>,
+    env: impl Iterator<Item = (&'a str, &'a str)>,
+) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    hash_field(&mut hasher, runtime_id.to_string().as_bytes());
+    for file_name in ["compile", "env.sh"] {
+        hash_runtime_file(
+            &mut hasher,
+            &format!("{RUNTIMES_DIR}/{runtime_id}/{file_name}"),
+        )
+        .await?;
+    }
+    let limits = serde_json::to_vec(limits)
+        .map_err(|e| anyhow!("Failed to serialize the compile limits: {e}"))?;
+    hash_field(&mut hasher, &limits);
+    hash_field(&mut hasher, entry.as_bytes());
+    for (name, content) in files {
+        hash_field(&mut hasher, name.as_bytes());
+        hash_field(&mut hasher, content);
+    }
+    // Separates the files from the environment
+    hash_field(&mut hasher, b"");
+    for (name, value) in env {
+        hash_field(&mut hasher, name.as_bytes());
+        hash_field(&mut hasher, value.as_bytes());
+    }
+    Ok(hasher.finish_hex())
+}
+
+async fn remove_dir(path: &str) {
+    match fs::remove_dir_all(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => eprintln!("Failed to remove {path}: {e}"),
+        _ => {}
+    }
+}
+
+// Replaces the submission directory of the box with the cached one, returns
+// whether there was one. Errors leave the submission directory as it was
+async fn seed(key: &str, box_dir: &str) -> Result<bool, Error> {
+    {
+        let mut index = INDEX.lock().await;
+        let clock = index.tick();
+        match index.entries().get_mut(key) {
+            Some(entry) => entry.last_used = clock,
+            None => return Ok(false),
+        }
+    }
+    let cached_submission = format!("{box_dir}/{CACHED_SUBMISSION_NAME}");
+    // The entry could be evicted while it is copied, which fails the copy
+    if let Err(e) = crate::fs::copy_dir_from_box(&entries_dir(), key, &cached_submission).await {
+        remove_dir(&cached_submission).await;
+        return Err(e);
+    }
+    let submission_dir = format!("{box_dir}/submission");
+    fs::remove_dir_all(&submission_dir)
+        .await
+        .map_err(|e| anyhow!("Failed to remove {submission_dir}: {e}"))?;
+    fs::rename(&cached_submission, &submission_dir)
+        .await
+        .map_err(|e| anyhow!("Failed to move {cached_submission} to {submission_dir}: {e}"))?;
+    Ok(true)
+}
+
+pub async fn lookup(key: &str, box_dir: &str) -> CacheResult {
+    let hit = seed(key, box_dir).await.unwrap_or_else(|e| {
+        eprintln!("Failed to use the cached compilation {key}, compiling again: {e}");
+        false
+    });
+    if hit {
+        HITS.fetch_add(1, Ordering::Relaxed);
+        CacheResult::Hit
+    } else {
+        MISSES.fetch_add(1, Ordering::Relaxed);
+        CacheResult::Miss
+    }
+}
+
+async fn dir_size(path: &str) -> Result<u64, Error> {
+    let mut size = 0;
+    let mut pending = vec![path.to_string()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = fs::read_dir(&dir)
+            .await
+            .map_err(|e| anyhow!("Failed to read {dir}: {e}"))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| anyhow!("Failed to read {dir}: {e}"))?
+        {
+            let metadata = fs::symlink_metadata(entry.path())
+                .await
+                .map_err(|e| anyhow!("Failed to stat {}: {e}", entry.path().display()))?;
+            if metadata.is_dir() {
+                pending.push(entry.path().to_string_lossy().into_owned());
+            }
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+// Copies the submission directory of the box, as a successful compilation left it
+pub async fn store(key: &str, box_dir: &str) -> Result<(), Error> {
+    let Some(&max_bytes) = MAX_BYTES.get() else {
+        return Ok(());
+    };
+    let staged = format!(
+        "{}/{STAGING_DIR_NAME}/{}",
+        storage::compile_cache_dir(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    );
+    let size = match crate::fs::copy_dir_from_box(box_dir, "submission", &staged).await {
+        Ok(()) => dir_size(&staged).await,
+        Err(e) => Err(e),
+    };
+    let size = match size {
+        Ok(size) if size <= max_bytes => size,
+        res => {
+            remove_dir(&staged).await;
+            return res.map(|_| ());
+        }
+    };
+
+    let mut evicted = Vec::new();
+    {
+        let mut index = INDEX.lock().await;
+        if index.entries().contains_key(key) {
+            // Stored by another request compiling the same submission meanwhile
+            evicted.push(staged);
+        } else {
+            let path = entry_path(key);
+            fs::rename(&staged, &path)
+                .await
+                .map_err(|e| anyhow!("Failed to move {staged} to {path}: {e}"))?;
+            let last_used = index.tick();
+            index
+                .entries()
+                .insert(key.to_string(), Entry { size, last_used });
+            index.stored_bytes += size;
+            while index.stored_bytes > max_bytes {
+                let Some(oldest) = index
+                    .entries()
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone())
+                else {
+                    break;
+                };
+                if let Some(entry) = index.entries().remove(&oldest) {
+                    index.stored_bytes -= entry.size;
+                }
+                evicted.push(entry_path(&oldest));
+            }
+        }
+    }
+    for path in evicted {
+        remove_dir(&path).await;
+    }
+    Ok(())
+}
+
+pub async fn stats() -> CompileCacheStats {
+    let mut index = INDEX.lock().await;
+    CompileCacheStats {
+        max_bytes: MAX_BYTES.get().copied().unwrap_or(0),
+        stored_bytes: index.stored_bytes,
+        entries: index.entries().len(),
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+    }
+}
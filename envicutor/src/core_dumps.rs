@@ -0,0 +1,221 @@
+// Core dumps of runs killed by a signal, for debugging crashes. A run asking
+// for one gets isolate's `--core`, and the core it leaves in its working
+// directory is copied out of the box. It is only ever referred to by id in the
+// response and downloaded on its own, and it is removed after a while
+
+use std::{
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use tokio::fs;
+
+use crate::{storage, types::Kilobytes};
+
+// Where cores land unless core_pattern says otherwise, core_uses_pid adds ".<pid>"
+const CORE_FILE_NAME: &str = "core";
+const CORE_PATTERN_PATH: &str = "/proc/sys/kernel/core_pattern";
+// A runtime whose directory has it never gets core dumps
+pub const CORE_DUMPS_DISABLED_FILE_NAME: &str = "core_dumps_disabled";
+
+// Set once at startup, core dumps can't be asked for without it
+static CORE_DUMP_CONFIG: OnceLock<CoreDumpConfig> = OnceLock::new();
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+pub struct CoreDumpConfig {
+    pub max_size: Kilobytes,
+    pub retention: Duration,
+}
+
+#[derive(Serialize, Clone)]
+pub struct CoreDump {
+    pub produced: bool,
+    // The signal that killed the run, absent when it exited by itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<u32>,
+    // Downloaded at /cores/:id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    // Why there is no core
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+}
+
+impl CoreDump {
+    fn missing(signal: Option<u32>, reason: &'static str) -> Self {
+        CoreDump {
+            produced: false,
+            signal,
+            id: None,
+            size: None,
+            reason: Some(reason),
+        }
+    }
+}
+
+pub fn use_core_dump_config(config: CoreDumpConfig) -> Result<(), Error> {
+    CORE_DUMP_CONFIG
+        .set(config)
+        .map_err(|_| anyhow!("The core dump config was already set"))
+}
+
+// The most a core can take, bounded by the run's maximum file size. None when
+// core dumps are disabled
+pub fn core_size(max_file_size: Kilobytes) -> Option<Kilobytes> {
+    CORE_DUMP_CONFIG
+        .get()
+        .map(|config| config.max_size.min(max_file_size))
+        .filter(|size| *size > 0)
+}
+
+pub fn is_enabled() -> bool {
+    CORE_DUMP_CONFIG.get().is_some()
+}
+
+// Cores of a previous run are removed. A core_pattern piping cores to a
+// program, or writing them to an absolute path, takes them out of the box
+pub async fn init() -> Result<(), Error> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    let cores_dir = storage::cores_dir();
+    fs::create_dir_all(cores_dir)
+        .await
+        .map_err(|e| anyhow!("Failed to create {cores_dir}: {e}"))?;
+    remove_expired(Duration::ZERO).await;
+    match fs::read_to_string(CORE_PATTERN_PATH).await {
+        Ok(pattern) if pattern.starts_with('|') || pattern.starts_with('/') => eprintln!(
+            "The kernel's core_pattern is {}, cores won't be written in the boxes",
+            pattern.trim()
+        ),
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to read {CORE_PATTERN_PATH}: {e}"),
+    }
+    Ok(())
+}
+
+fn core_path(id: &str) -> String {
+    format!("{}/{id}", storage::cores_dir())
+}
+
+fn is_core_file_name(name: &str) -> bool {
+    name == CORE_FILE_NAME
+        || name
+            .strip_prefix("core.")
+            .is_some_and(|pid| !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// Ids are only made of digits and a dash
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit() || b == b'-')
+}
+
+async fn find_core(box_dir: &str, workdir: &str) -> Result<Option<String>, Error> {
+    let dir = crate::fs::resolve_in_box(box_dir, workdir).await?;
+    let mut entries = fs::read_dir(&dir)
+        .await
+        .map_err(|e| anyhow!("Failed to read {dir}: {e}"))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| anyhow!("Failed to read {dir}: {e}"))?
+    {
+        if let Some(name) = entry
+            .file_name()
+            .to_str()
+            .filter(|name| is_core_file_name(name))
+        {
+            return Ok(Some(format!("{workdir}/{name}")));
+        }
+    }
+    Ok(None)
+}
+
+async fn remove_expired(retention: Duration) {
+    let cores_dir = storage::cores_dir();
+    let Ok(mut entries) = fs::read_dir(cores_dir).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let expired = entry
+            .metadata()
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .is_some_and(|age| age > retention);
+        if expired {
+            if let Err(e) = fs::remove_file(entry.path()).await {
+                eprintln!("Failed to remove {}: {e}", entry.path().display());
+            }
+        }
+    }
+}
+
+// Copies the core the run left in `workdir` (relative to the box directory),
+// the box is cleaned up with the original
+pub async fn capture(box_dir: &str, workdir: &str, signal: Option<u32>) -> CoreDump {
+    let Some(config) = CORE_DUMP_CONFIG.get().copied() else {
+        return CoreDump::missing(signal, "Core dumps are not enabled");
+    };
+    if signal.is_none() {
+        return CoreDump::missing(signal, "The run was not killed by a signal");
+    }
+    remove_expired(config.retention).await;
+    let relative = match find_core(box_dir, workdir).await {
+        Ok(Some(relative)) => relative,
+        Ok(None) => return CoreDump::missing(signal, "The run left no core"),
+        Err(e) => {
+            eprintln!("Failed to look for a core in {box_dir}: {e}");
+            return CoreDump::missing(signal, "Failed to look for the core");
+        }
+    };
+    let id = format!(
+        "{}-{}",
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis()),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    );
+    let path = core_path(&id);
+    let copied = crate::fs::safe_copy_from_box(box_dir, &relative, &path).await;
+    let size = match copied {
+        Ok(size) => size,
+        Err(e) => {
+            eprintln!("Failed to copy the core {relative} out of {box_dir}: {e}");
+            match fs::remove_file(&path).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    eprintln!("Failed to remove {path}: {e}")
+                }
+                _ => {}
+            }
+            return CoreDump::missing(signal, "Failed to copy the core");
+        }
+    };
+    CoreDump {
+        produced: true,
+        signal,
+        id: Some(id),
+        size: Some(size),
+        reason: None,
+    }
+}
+
+// None once the core expired
+pub async fn read(id: &str) -> Result<Option<Vec<u8>>, Error> {
+    let path = core_path(id);
+    match fs::read(&path).await {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!("Failed to read {path}: {e}")),
+    }
+}
@@ -0,0 +1,261 @@
+// Files that executions read but can't change, such as a large CSV every
+// submission of an exercise reads. They are uploaded once, and hard linked into
+// a directory of every execution asking for them that is mounted read-only
+
+use std::{
+    fmt::Display,
+    io::ErrorKind,
+    os::unix::fs::PermissionsExt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use tokio::{fs, io::AsyncWriteExt, time};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{storage, temp_dir::TempDir};
+
+// Where the datasets of an execution are in its box
+pub const MOUNT_POINT: &str = "/datasets";
+pub const MAX_DATASETS_PER_EXECUTION: usize = 16;
+const MAX_NAME_LENGTH: usize = 128;
+// Dataset names can't start with a dot, so these never clash with one
+const MOUNTS_DIR_NAME: &str = ".mounts";
+const UPLOADS_DIR_NAME: &str = ".uploads";
+
+// Set once at startup
+static DATASET_CONFIG: OnceLock<DatasetConfig> = OnceLock::new();
+// Names the directories of executions and uploads in progress
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+pub struct DatasetConfig {
+    pub max_bytes: u64,
+    pub upload_timeout: Duration,
+}
+
+impl Default for DatasetConfig {
+    fn default() -> Self {
+        DatasetConfig {
+            max_bytes: 256 * 1024 * 1024,
+            upload_timeout: Duration::from_secs(600),
+        }
+    }
+}
+
+pub fn use_dataset_config(config: DatasetConfig) -> Result<(), Error> {
+    DATASET_CONFIG
+        .set(config)
+        .map_err(|_| anyhow!("The dataset config was already set"))
+}
+
+fn config() -> DatasetConfig {
+    DATASET_CONFIG.get().copied().unwrap_or_default()
+}
+
+#[derive(Serialize)]
+pub struct Dataset {
+    pub name: String,
+    pub size: u64,
+}
+
+pub enum Upload {
+    Stored(Dataset),
+    TooLarge,
+    TimedOut,
+}
+
+// The datasets of an execution, unless one of them doesn't exist
+pub enum Staging {
+    Staged(TempDir),
+    Missing(String),
+}
+
+// e.g. "sales-2024.csv", a single file name that isn't hidden
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+fn dataset_path(name: &str) -> String {
+    format!("{}/{name}", storage::datasets_dir())
+}
+
+// Leftovers of executions and uploads cut short by a restart are removed
+pub async fn init() -> Result<(), Error> {
+    let datasets_dir = storage::datasets_dir();
+    fs::create_dir_all(datasets_dir)
+        .await
+        .map_err(|e| anyhow!("Failed to create {datasets_dir}: {e}"))?;
+    for dir_name in [MOUNTS_DIR_NAME, UPLOADS_DIR_NAME] {
+        crate::fs::create_dir_replacing_existing(&format!("{datasets_dir}/{dir_name}")).await?;
+    }
+    Ok(())
+}
+
+// Writes an upload, returning its size or none once it goes over `max_bytes`
+async fn write_upload<D, E>(
+    path: &str,
+    mut data: impl Stream<Item = Result<D, E>> + Unpin,
+    max_bytes: u64,
+) -> Result<Option<u64>, Error>
+where
+    D: AsRef<[u8]>,
+    E: Display,
+{
+    let mut file = fs::File::create(path)
+        .await
+        .map_err(|e| anyhow!("Failed to create {path}: {e}"))?;
+    let mut size = 0;
+    while let Some(chunk) = data.next().await {
+        let chunk = chunk.map_err(|e| anyhow!("Failed to read the dataset: {e}"))?;
+        size += chunk.as_ref().len() as u64;
+        if size > max_bytes {
+            return Ok(None);
+        }
+        file.write_all(chunk.as_ref())
+            .await
+            .map_err(|e| anyhow!("Failed to write to {path}: {e}"))?;
+    }
+    file.flush()
+        .await
+        .map_err(|e| anyhow!("Failed to write to {path}: {e}"))?;
+    Ok(Some(size))
+}
+
+// Replaces the dataset at once, executions that already linked the old one
+// keep reading it
+async fn publish(upload_path: &str, name: &str) -> Result<(), Error> {
+    fs::set_permissions(upload_path, std::fs::Permissions::from_mode(0o444))
+        .await
+        .map_err(|e| anyhow!("Failed to make {upload_path} read-only: {e}"))?;
+    let path = dataset_path(name);
+    fs::rename(upload_path, &path)
+        .await
+        .map_err(|e| anyhow!("Failed to move {upload_path} to {path}: {e}"))
+}
+
+pub async fn store<D, E>(
+    name: &str,
+    data: impl Stream<Item = Result<D, E>> + Unpin,
+) -> Result<Upload, Error>
+where
+    D: AsRef<[u8]>,
+    E: Display,
+{
+    let config = config();
+    let upload_path = format!(
+        "{}/{UPLOADS_DIR_NAME}/{}",
+        storage::datasets_dir(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    );
+    let written = time::timeout(
+        config.upload_timeout,
+        write_upload(&upload_path, data, config.max_bytes),
+    )
+    .await;
+    let res = match written {
+        Ok(Ok(Some(size))) => publish(&upload_path, name).await.map(|()| {
+            Upload::Stored(Dataset {
+                name: name.to_string(),
+                size,
+            })
+        }),
+        Ok(Ok(None)) => Ok(Upload::TooLarge),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Ok(Upload::TimedOut),
+    };
+    if !matches!(res, Ok(Upload::Stored(_))) {
+        match fs::remove_file(&upload_path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                eprintln!("Failed to remove {upload_path}: {e}")
+            }
+            _ => {}
+        }
+    }
+    res
+}
+
+pub async fn list() -> Result<Vec<Dataset>, Error> {
+    let datasets_dir = storage::datasets_dir();
+    let mut entries = fs::read_dir(datasets_dir)
+        .await
+        .map_err(|e| anyhow!("Failed to read {datasets_dir}: {e}"))?;
+    let mut datasets = Vec::new();
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| anyhow!("Failed to read {datasets_dir}: {e}"))?
+    {
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if !is_valid_name(&name) {
+            continue;
+        }
+        let metadata = entry
+            .metadata()
+            .await
+            .map_err(|e| anyhow!("Failed to stat dataset {name}: {e}"))?;
+        if metadata.is_file() {
+            datasets.push(Dataset {
+                name,
+                size: metadata.len(),
+            });
+        }
+    }
+    datasets.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(datasets)
+}
+
+// Returns whether there was such a dataset
+pub async fn delete(name: &str) -> Result<bool, Error> {
+    let path = dataset_path(name);
+    match fs::remove_file(&path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(anyhow!("Failed to remove {path}: {e}")),
+    }
+}
+
+// The first of `names` that isn't a dataset
+pub async fn find_missing(names: &[String]) -> Result<Option<&String>, Error> {
+    for name in names {
+        let path = dataset_path(name);
+        let exists = fs::try_exists(&path)
+            .await
+            .map_err(|e| anyhow!("Failed to check if {path} exists: {e}"))?;
+        if !exists {
+            return Ok(Some(name));
+        }
+    }
+    Ok(None)
+}
+
+// Links the datasets into a directory of their own, removed once it is dropped
+pub async fn stage(names: &[String]) -> Result<Staging, Error> {
+    let staged = TempDir::new(format!(
+        "{}/{MOUNTS_DIR_NAME}/{}",
+        storage::datasets_dir(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    ))
+    .await?;
+    for name in names {
+        let link = format!("{}/{name}", staged.path);
+        match fs::hard_link(dataset_path(name), &link).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Staging::Missing(name.clone())),
+            Err(e) => return Err(anyhow!("Failed to link dataset {name} to {link}: {e}")),
+        }
+    }
+    Ok(Staging::Staged(staged))
+}
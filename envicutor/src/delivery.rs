@@ -0,0 +1,81 @@
+// Retrying something sent to another server until it goes through or runs out
+// of attempts, keeping the error of every failed attempt
+
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    // Attempts after the first one
+    pub retries: u32,
+    pub initial_backoff: Duration,
+    // Of every attempt
+    pub timeout: Duration,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FailedAttempt {
+    // Unix seconds
+    pub at: u64,
+    pub error: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// A number between 0 and 1, different on every call
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(now());
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl RetryPolicy {
+    // The wait after the `attempt`th attempt failed: twice the previous one,
+    // plus up to half of it at random so that deliveries failing together
+    // don't all retry together
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        backoff + backoff.mul_f64(random_fraction() / 2.0)
+    }
+
+    // Returns the failed attempts, which are all of them unless the last one
+    // succeeded. `on_retry` is awaited with them before every retry
+    pub async fn run<A, AF, R, RF>(&self, attempt: A, on_retry: R) -> (bool, Vec<FailedAttempt>)
+    where
+        A: Fn() -> AF,
+        AF: Future<Output = Result<(), Error>>,
+        R: Fn(&[FailedAttempt]) -> RF,
+        RF: Future<Output = ()>,
+    {
+        let mut failed = Vec::new();
+        loop {
+            let res = time::timeout(self.timeout, attempt())
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("Timed out after {:?}", self.timeout)));
+            let error = match res {
+                Ok(()) => return (true, failed),
+                Err(e) => e.to_string(),
+            };
+            failed.push(FailedAttempt { at: now(), error });
+            if failed.len() as u32 > self.retries {
+                return (false, failed);
+            }
+            on_retry(&failed).await;
+            time::sleep(self.backoff(failed.len() as u32)).await;
+        }
+    }
+}
@@ -0,0 +1,160 @@
+// Editors on Windows can save source files as UTF-16 or with a UTF-8 byte order
+// mark, which compilers then fail on with errors about stray characters. Files
+// are sniffed for the marks when they are submitted
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Bom {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16_LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16_BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+pub const UTF8_BOM_STRIPPED_WARNING: &str = "utf8_bom_stripped";
+pub const UTF16_TRANSCODED_WARNING: &str = "utf16_transcoded";
+
+impl Bom {
+    fn bytes(self) -> &'static [u8] {
+        match self {
+            Bom::Utf8 => UTF8_BOM,
+            Bom::Utf16Le => UTF16_LE_BOM,
+            Bom::Utf16Be => UTF16_BE_BOM,
+        }
+    }
+
+    pub fn warning(self) -> &'static str {
+        match self {
+            Bom::Utf8 => UTF8_BOM_STRIPPED_WARNING,
+            Bom::Utf16Le | Bom::Utf16Be => UTF16_TRANSCODED_WARNING,
+        }
+    }
+}
+
+pub fn sniff(content: &[u8]) -> Option<Bom> {
+    [Bom::Utf8, Bom::Utf16Le, Bom::Utf16Be]
+        .into_iter()
+        .find(|bom| content.starts_with(bom.bytes()))
+}
+
+fn utf16_to_utf8(content: &[u8], bom: Bom) -> Option<Vec<u8>> {
+    if content.len() % 2 != 0 {
+        return None;
+    }
+    let units = content.chunks_exact(2).map(|pair| match bom {
+        Bom::Utf16Be => u16::from_be_bytes([pair[0], pair[1]]),
+        _ => u16::from_le_bytes([pair[0], pair[1]]),
+    });
+    char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .ok()
+        .map(String::into_bytes)
+}
+
+// Strips the byte order mark of `content` and, if it is UTF-16 and `transcode`
+// is set, makes it UTF-8. Returns the mark that was found, errors are safe to
+// show in the response
+pub fn normalize(
+    name: &str,
+    content: &mut Vec<u8>,
+    transcode: bool,
+) -> Result<Option<Bom>, String> {
+    let Some(bom) = sniff(content) else {
+        return Ok(None);
+    };
+    let without_bom = &content[bom.bytes().len()..];
+    let normalized = match bom {
+        Bom::Utf8 => without_bom.to_vec(),
+        Bom::Utf16Le | Bom::Utf16Be if !transcode => {
+            return Err(format!(
+                "{name} is encoded as UTF-16, save it as UTF-8 or pass transcode: true"
+            ))
+        }
+        Bom::Utf16Le | Bom::Utf16Be => utf16_to_utf8(without_bom, bom).ok_or_else(|| {
+            format!("{name} starts with a UTF-16 byte order mark but is not valid UTF-16")
+        })?,
+    };
+    *content = normalized;
+    Ok(Some(bom))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str, bom: Bom) -> Vec<u8> {
+        let mut content = bom.bytes().to_vec();
+        for unit in text.encode_utf16() {
+            match bom {
+                Bom::Utf16Be => content.extend(unit.to_be_bytes()),
+                _ => content.extend(unit.to_le_bytes()),
+            }
+        }
+        content
+    }
+
+    #[test]
+    fn content_without_a_mark_is_left_alone() {
+        let mut content = b"int main() {}".to_vec();
+        assert_eq!(normalize("main.c", &mut content, true), Ok(None));
+        assert_eq!(content, b"int main() {}");
+    }
+
+    #[test]
+    fn the_utf8_mark_is_stripped() {
+        let mut content = [UTF8_BOM, "print('é')".as_bytes()].concat();
+        assert_eq!(
+            normalize("main.py", &mut content, true),
+            Ok(Some(Bom::Utf8))
+        );
+        assert_eq!(content, "print('é')".as_bytes());
+        // Whatever transcode says, it is already UTF-8
+        let mut content = [UTF8_BOM, b"x"].concat();
+        assert_eq!(
+            normalize("main.py", &mut content, false),
+            Ok(Some(Bom::Utf8))
+        );
+        assert_eq!(content, b"x");
+    }
+
+    #[test]
+    fn utf16_is_transcoded_from_either_byte_order() {
+        for bom in [Bom::Utf16Le, Bom::Utf16Be] {
+            // 𝄞 is a surrogate pair in UTF-16
+            let mut content = utf16("print('é𝄞')", bom);
+            assert_eq!(normalize("main.py", &mut content, true), Ok(Some(bom)));
+            assert_eq!(content, "print('é𝄞')".as_bytes());
+        }
+    }
+
+    #[test]
+    fn utf16_is_refused_without_transcoding() {
+        let mut content = utf16("x", Bom::Utf16Le);
+        let original = content.clone();
+        assert_eq!(
+            normalize("main.py", &mut content, false),
+            Err(
+                "main.py is encoded as UTF-16, save it as UTF-8 or pass transcode: true"
+                    .to_string()
+            )
+        );
+        assert_eq!(content, original);
+    }
+
+    #[test]
+    fn invalid_utf16_is_refused() {
+        let error =
+            Err("main.py starts with a UTF-16 byte order mark but is not valid UTF-16".to_string());
+        // An odd number of bytes can't be UTF-16 code units
+        let mut odd = [UTF16_LE_BOM, b"x\0y"].concat();
+        assert_eq!(normalize("main.py", &mut odd, true), error);
+        // A high surrogate without its low one
+        let mut unpaired = [UTF16_LE_BOM, &[0x3d, 0xd8, b'x', 0]].concat();
+        assert_eq!(normalize("main.py", &mut unpaired, true), error);
+        // A low surrogate on its own
+        let mut lone_low = [UTF16_BE_BOM, &[0xdc, 0x00]].concat();
+        assert_eq!(normalize("main.py", &mut lone_low, true), error);
+    }
+}
@@ -0,0 +1,258 @@
+// The env snapshot is the output of `env` inside the runtime's nix-shell,
+// values spanning multiple lines continue on lines that have no '='
+
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+
+const ALWAYS_KEPT: &str = "PATH";
+// Sourced by every stage inside the sandbox, see `stage_command`
+pub const SHELL_SNAPSHOT_FILE_NAME: &str = "env.sh";
+// Set by the sandbox or by bash itself for the stage, the snapshot's values would be stale
+pub const STAGE_MANAGED: [&str; 5] = ["HOME", "PWD", "OLDPWD", "SHLVL", "_"];
+// The stages of the runtime won't find its programs
+pub const PATH_EXCLUDED_WARNING: &str = "path_excluded";
+
+pub struct FilteredSnapshot {
+    pub entries: Vec<(String, String)>,
+    pub filtered_count: usize,
+    pub warnings: Vec<&'static str>,
+}
+
+pub fn parse(env: &str) -> Vec<(String, String)> {
+    let mut entries: Vec<(String, String)> = Vec::new();
+    for line in env.lines() {
+        match (line.split_once('='), entries.last_mut()) {
+            (Some((key, value)), _) => entries.push((key.to_string(), value.to_string())),
+            (None, Some((_, value))) => {
+                value.push('\n');
+                value.push_str(line);
+            }
+            (None, None) => {}
+        }
+    }
+    entries
+}
+
+pub fn serialize(entries: &[(String, String)]) -> String {
+    let mut env = String::new();
+    for (key, value) in entries {
+        env.push_str(key);
+        env.push('=');
+        env.push_str(value);
+        env.push('\n');
+    }
+    env
+}
+
+pub fn is_shell_name(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// Every value is single-quoted so nothing in it is expanded when sourced.
+// Keys bash can't export (e.g. exported functions like BASH_FUNC_name%%) are skipped
+pub fn serialize_as_shell(entries: &[(String, String)]) -> String {
+    let mut script = String::new();
+    for (key, value) in entries {
+        if !is_shell_name(key) || STAGE_MANAGED.contains(&key.as_str()) {
+            continue;
+        }
+        script.push_str("export ");
+        script.push_str(key);
+        script.push('=');
+        script.push_str(&shell_quote(value));
+        script.push('\n');
+    }
+    script
+}
+
+// Runtimes installed before shell snapshots existed only have the raw `env` file
+pub fn ensure_shell_snapshot(runtime_dir: &str) -> Result<bool, Error> {
+    let shell_snapshot_path = format!("{runtime_dir}/{SHELL_SNAPSHOT_FILE_NAME}");
+    if Path::new(&shell_snapshot_path)
+        .try_exists()
+        .map_err(|e| anyhow!("Failed to check if {shell_snapshot_path} exists\nError: {e}"))?
+    {
+        return Ok(false);
+    }
+    let env_path = format!("{runtime_dir}/env");
+    let env = std::fs::read_to_string(&env_path)
+        .map_err(|e| anyhow!("Failed to read {env_path}\nError: {e}"))?;
+    std::fs::write(&shell_snapshot_path, serialize_as_shell(&parse(&env)))
+        .map_err(|e| anyhow!("Failed to write {shell_snapshot_path}\nError: {e}"))?;
+    Ok(true)
+}
+
+// Supports `*` (any sequence, including empty) and `?` (any single character)
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn matches_any(patterns: &[String], name: &str) -> bool {
+    patterns.iter().any(|pattern| glob_matches(pattern, name))
+}
+
+// Exclusion takes precedence over inclusion, PATH is kept unless explicitly excluded
+pub fn filter(
+    entries: Vec<(String, String)>,
+    include: &[String],
+    exclude: &[String],
+) -> FilteredSnapshot {
+    let total = entries.len();
+    let mut warnings = Vec::new();
+    let entries: Vec<(String, String)> = entries
+        .into_iter()
+        .filter(|(key, _)| {
+            if matches_any(exclude, key) {
+                if key == ALWAYS_KEPT {
+                    warnings.push(PATH_EXCLUDED_WARNING);
+                }
+                return false;
+            }
+            include.is_empty() || key == ALWAYS_KEPT || matches_any(include, key)
+        })
+        .collect();
+    FilteredSnapshot {
+        filtered_count: total - entries.len(),
+        entries,
+        warnings,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn sourcing_the_shell_snapshot_restores_every_value_verbatim() {
+        let values = crate::strings::hostile_strings();
+        let entries: Vec<(String, String)> = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (format!("ENVICUTOR_TEST_{i}"), value.clone()))
+            .collect();
+        let script = serialize_as_shell(&entries);
+        // Each value is printed followed by a NUL so any splitting would show
+        let printer = format!(
+            "for ((i = 0; i < {}; i++)); do v=ENVICUTOR_TEST_$i; printf '%s\\0' \"${{!v}}\"; done",
+            values.len()
+        );
+        let output = Command::new("bash")
+            .args(["-c", &format!("{script}\n{printer}")])
+            .env_clear()
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let printed: Vec<&str> = std::str::from_utf8(&output.stdout)
+            .unwrap()
+            .split_terminator('\0')
+            .collect();
+        assert_eq!(printed, values);
+    }
+
+    fn keys(snapshot: &FilteredSnapshot) -> Vec<&str> {
+        snapshot
+            .entries
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect()
+    }
+
+    fn snapshot() -> Vec<(String, String)> {
+        [
+            "PATH",
+            "PYTHONPATH",
+            "PYTHONHOME",
+            "NIX_CFLAGS",
+            "NIX_LDFLAGS",
+            "LANG",
+        ]
+        .into_iter()
+        .map(|key| (key.to_string(), "x".to_string()))
+        .collect()
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn globs_match_whole_names() {
+        assert!(glob_matches("PYTHON*", "PYTHONPATH"));
+        assert!(glob_matches("PYTHON*", "PYTHON"));
+        assert!(glob_matches("NIX_?FLAGS", "NIX_CFLAGS"));
+        assert!(!glob_matches("NIX_?FLAGS", "NIX_LDFLAGS"));
+        assert!(glob_matches("*FLAGS", "NIX_LDFLAGS"));
+        assert!(glob_matches("N*_*S", "NIX_LDFLAGS"));
+        assert!(!glob_matches("PATH", "PYTHONPATH"));
+        assert!(!glob_matches("PYTHON", "PYTHONPATH"));
+        assert!(glob_matches("*", ""));
+    }
+
+    #[test]
+    fn exclusion_takes_precedence_over_inclusion() {
+        let filtered = filter(
+            snapshot(),
+            &patterns(&["PYTHON*", "NIX_*"]),
+            &patterns(&["PYTHONHOME", "NIX_L*"]),
+        );
+        assert_eq!(keys(&filtered), ["PATH", "PYTHONPATH", "NIX_CFLAGS"]);
+        assert_eq!(filtered.filtered_count, 3);
+        assert!(filtered.warnings.is_empty());
+
+        let filtered = filter(snapshot(), &[], &patterns(&["NIX_*"]));
+        assert_eq!(
+            keys(&filtered),
+            ["PATH", "PYTHONPATH", "PYTHONHOME", "LANG"]
+        );
+    }
+
+    #[test]
+    fn path_is_kept_unless_excluded_explicitly() {
+        let filtered = filter(snapshot(), &patterns(&["LANG"]), &[]);
+        assert_eq!(keys(&filtered), ["PATH", "LANG"]);
+        assert!(filtered.warnings.is_empty());
+
+        let filtered = filter(snapshot(), &patterns(&["LANG"]), &patterns(&["P*"]));
+        assert_eq!(keys(&filtered), ["LANG"]);
+        assert_eq!(filtered.warnings, [PATH_EXCLUDED_WARNING]);
+    }
+
+    #[test]
+    fn keys_that_are_not_shell_names_are_skipped() {
+        let entries = vec![
+            ("BASH_FUNC_f%%".to_string(), "() { id; }".to_string()),
+            ("A;id".to_string(), "x".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+            ("KEPT".to_string(), "x".to_string()),
+        ];
+        assert_eq!(serialize_as_shell(&entries), "export KEPT='x'\n");
+    }
+}
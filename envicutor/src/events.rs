@@ -0,0 +1,108 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{
+    api::execution::ExecutionResponse, isolate::ExitKind, nix_errors::FailureCategory,
+    sandbox_info::SandboxInfo, stage::StageId, types::RuntimeId,
+};
+
+// Slow subscribers miss the oldest events instead of blocking the publishers
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    RuntimeInstalled {
+        id: RuntimeId,
+        name: String,
+    },
+    RuntimeInstallationFailed {
+        name: String,
+        failure_category: FailureCategory,
+    },
+    RuntimeDeleted {
+        id: RuntimeId,
+    },
+    NixUpdated {
+        success: bool,
+    },
+    GarbageCollected {
+        roots: usize,
+        bytes_freed: Option<u64>,
+    },
+    // Describes the last stage that ran, which is the one that decided the outcome
+    ExecutionFinished {
+        runtime_id: RuntimeId,
+        stage: Option<StageId>,
+        exit_code: Option<u32>,
+        exit_signal: Option<u32>,
+        exit_signal_name: Option<String>,
+        exit_status: Option<String>,
+        exit_kind: Option<ExitKind>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sandbox: Option<SandboxInfo>,
+    },
+}
+
+impl Event {
+    pub fn execution_finished(runtime_id: RuntimeId, res: &ExecutionResponse) -> Event {
+        let last_stage = res.last_stage();
+        Event::ExecutionFinished {
+            runtime_id,
+            stage: last_stage.and_then(|result| result.stage),
+            exit_code: last_stage.and_then(|result| result.exit_code),
+            exit_signal: last_stage.and_then(|result| result.exit_signal),
+            exit_signal_name: last_stage.and_then(|result| result.exit_signal_name.clone()),
+            exit_status: last_stage.and_then(|result| result.exit_status.clone()),
+            exit_kind: last_stage.map(|result| result.exit_kind),
+            sandbox: res.sandbox.clone(),
+        }
+    }
+}
+
+// Embedders create one, pass it to the handlers and subscribe to it.
+// Closing it ends every subscription, which lets open event streams finish on shutdown
+#[derive(Clone)]
+pub struct Events {
+    sender: Arc<Mutex<Option<broadcast::Sender<Event>>>>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Events {
+            sender: Arc::new(Mutex::new(Some(sender))),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<broadcast::Sender<Event>>> {
+        // The guarded value is never left half-updated, so a poisoned lock is still usable
+        self.sender.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        match self.lock().as_ref() {
+            Some(sender) => sender.subscribe(),
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    pub fn publish(&self, event: Event) {
+        if let Some(sender) = self.lock().as_ref() {
+            // Having no subscribers is the common case, not an error
+            let _ = sender.send(event);
+        }
+    }
+
+    pub fn close(&self) {
+        self.lock().take();
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events::new()
+    }
+}
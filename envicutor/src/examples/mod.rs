@@ -0,0 +1,77 @@
+use anyhow::{anyhow, Error};
+
+use crate::api::installation::AddRuntimeRequest;
+
+pub struct Example {
+    pub name: &'static str,
+    definition: &'static str,
+}
+
+pub static EXAMPLES: [Example; 3] = [
+    Example {
+        name: "python3",
+        definition: include_str!("python3.json"),
+    },
+    Example {
+        name: "c",
+        definition: include_str!("c.json"),
+    },
+    Example {
+        name: "bash",
+        definition: include_str!("bash.json"),
+    },
+];
+
+impl Example {
+    pub fn request(&self) -> Result<AddRuntimeRequest, Error> {
+        serde_json::from_str(self.definition)
+            .map_err(|e| anyhow!("Invalid definition for example {}: {e}", self.name))
+    }
+}
+
+pub fn get_examples(only: &[String]) -> Result<Vec<&'static Example>, Error> {
+    if let Some(unknown) = only
+        .iter()
+        .find(|name| !EXAMPLES.iter().any(|example| example.name == name.as_str()))
+    {
+        return Err(anyhow!("Unknown example: {unknown}"));
+    }
+    Ok(EXAMPLES
+        .iter()
+        .filter(|example| only.is_empty() || only.iter().any(|name| name == example.name))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api::installation::validate_request, request_limits::RequestLimits};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn every_example_is_a_valid_installation() {
+        for example in &EXAMPLES {
+            let req = example.request().unwrap();
+            if let Err(res) = validate_request(&req, &RequestLimits::default()).await {
+                let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                panic!(
+                    "Example {} is invalid: {}",
+                    example.name,
+                    String::from_utf8_lossy(&body)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn examples_are_picked_by_name() {
+        let all = get_examples(&[]).unwrap();
+        assert_eq!(all.len(), EXAMPLES.len());
+        let picked = get_examples(&["c".to_string()]).unwrap();
+        assert_eq!(picked.len(), 1);
+        assert_eq!(picked[0].name, "c");
+        assert!(get_examples(&["cobol".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+// Keeps the server's descriptors out of the processes it spawns. Everything
+// the standard library, tokio and SQLite open is already close-on-exec, so
+// only the descriptors the server was started with could be inherited.
+// isolate closes whatever it was given besides stdin, stdout and stderr,
+// which the canary checks from inside a box
+
+use std::os::fd::RawFd;
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{globals::MAX_BOX_ID, isolate::Isolate, limits::MandatoryLimits};
+
+// Lists its own descriptors, the directory it reads them from being one of them
+const CANARY_COMMAND: [&str; 3] = ["/bin/ls", "-l", "/proc/self/fd/"];
+
+// The canary runs in the box the startup probe uses, one at a time
+static CANARY_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Serialize, Clone)]
+pub struct InheritedFd {
+    fd: RawFd,
+    target: String,
+}
+
+#[derive(Serialize)]
+pub struct FdCanaryReport {
+    pub passed: bool,
+    pub descriptors: Vec<InheritedFd>,
+    // Beyond stdin, stdout, stderr and the canary's own listing
+    pub unexpected: Vec<InheritedFd>,
+}
+
+// Marks every descriptor above stderr close-on-exec, returning how many weren't
+pub fn close_inherited_on_exec() -> Result<usize, Error> {
+    let entries = std::fs::read_dir("/proc/self/fd")
+        .map_err(|e| anyhow!("Failed to list the open descriptors: {e}"))?;
+    // Collected first, reading the directory takes a descriptor of its own
+    let fds: Vec<RawFd> = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|fd| *fd > 2)
+        .collect();
+    let mut marked = 0;
+    for fd in fds {
+        // A descriptor closed since it was listed only fails with EBADF
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags < 0 || flags & libc::FD_CLOEXEC != 0 {
+            continue;
+        }
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } == 0 {
+            marked += 1;
+        }
+    }
+    Ok(marked)
+}
+
+// `ls -l` lines look like "lr-x------ 1 user user 64 Oct 15 10:00 3 -> /dev/null"
+fn parse_listing(listing: &str) -> Vec<InheritedFd> {
+    listing
+        .lines()
+        .filter_map(|line| {
+            let (entry, target) = line.split_once(" -> ")?;
+            let fd = entry.split_whitespace().last()?.parse().ok()?;
+            Some(InheritedFd {
+                fd,
+                target: target.to_string(),
+            })
+        })
+        .collect()
+}
+
+fn is_expected(descriptor: &InheritedFd) -> bool {
+    descriptor.fd <= 2 || descriptor.target.ends_with("/fd")
+}
+
+// Runs a program in a box that lists the descriptors it was started with
+pub async fn run_canary() -> Result<FdCanaryReport, Error> {
+    let _guard = CANARY_LOCK.lock().await;
+    let limits = MandatoryLimits {
+        wall_time: 5.0,
+        cpu_time: 2.0,
+        memory: 64 * 1024,
+        extra_time: 0.5,
+        max_open_files: 64,
+        max_file_size: 1024,
+        max_number_of_processes: 4,
+    };
+    let mut canary = Isolate::init(MAX_BOX_ID).await?;
+    let res = canary
+        .run(&[], &limits, None, "/box", &CANARY_COMMAND)
+        .await;
+    // Before the lock is released, the next canary initializes the same box
+    if let Err(e) = canary.close().await {
+        eprintln!("Failed to clean up after the descriptor canary: {e}");
+    }
+    let res = res?;
+    if res.exit_code != Some(0) {
+        return Err(anyhow!(
+            "The descriptor canary failed with exit code {:?}: {}",
+            res.exit_code,
+            res.stderr.text()
+        ));
+    }
+    let descriptors = parse_listing(&res.stdout.text());
+    if !descriptors.iter().any(|descriptor| descriptor.fd == 1) {
+        return Err(anyhow!(
+            "The descriptor canary listed no descriptors: {}",
+            res.stdout.text()
+        ));
+    }
+    let unexpected: Vec<InheritedFd> = descriptors
+        .iter()
+        .filter(|descriptor| !is_expected(descriptor))
+        .cloned()
+        .collect();
+    Ok(FdCanaryReport {
+        passed: unexpected.is_empty(),
+        descriptors,
+        unexpected,
+    })
+}
@@ -1,7 +1,10 @@
-use std::fs::Permissions;
+use std::{fs::Permissions, io::ErrorKind, os::unix::fs::PermissionsExt};
 
 use anyhow::{anyhow, Error};
-use tokio::fs;
+use tokio::{
+    fs,
+    io::{self, AsyncReadExt},
+};
 
 pub async fn create_dir_replacing_existing(path: &String) -> Result<(), Error> {
     if fs::try_exists(&path)
@@ -32,3 +35,262 @@ pub async fn write_file_and_set_permissions(
         .map_err(|e| anyhow!("Failed to write permissions on {path}\nError: {e}"))?;
     Ok(())
 }
+
+enum BoxPath {
+    Found(String),
+    Missing(String, std::io::Error),
+    Symlink(String),
+}
+
+// Everything inside a sandbox box is controlled by the submission, so each
+// component of `relative` is checked with lstat and symlinks are never followed
+async fn walk_box(box_dir: &str, relative: &str) -> Result<BoxPath, Error> {
+    let mut path = box_dir.to_string();
+    for component in relative.split('/').filter(|c| !c.is_empty()) {
+        if component == ".." {
+            return Err(anyhow!("Refusing to leave {box_dir} through {relative}"));
+        }
+        path = format!("{path}/{component}");
+        match fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => return Ok(BoxPath::Symlink(path)),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BoxPath::Missing(path, e)),
+            Err(e) => return Err(anyhow!("Failed to stat {path}\nError: {e}")),
+        }
+    }
+    Ok(BoxPath::Found(path))
+}
+
+pub async fn resolve_in_box(box_dir: &str, relative: &str) -> Result<String, Error> {
+    match walk_box(box_dir, relative).await? {
+        BoxPath::Found(path) => Ok(path),
+        BoxPath::Missing(path, e) => Err(anyhow!("Failed to stat {path}\nError: {e}")),
+        BoxPath::Symlink(path) => Err(anyhow!("Refusing to follow symlink at {path}")),
+    }
+}
+
+pub enum BoxFile {
+    Read {
+        // At most the `max_bytes` asked for
+        content: Vec<u8>,
+        size: u64,
+    },
+    Missing,
+    // A symlink, a directory or another special file
+    NotRegular,
+}
+
+// Reads a file the submission left in its box, with the same checks as
+// `resolve_in_box`
+pub async fn read_file_from_box(
+    box_dir: &str,
+    relative: &str,
+    max_bytes: usize,
+) -> Result<BoxFile, Error> {
+    let path = match walk_box(box_dir, relative).await? {
+        BoxPath::Found(path) => path,
+        BoxPath::Missing(..) => return Ok(BoxFile::Missing),
+        BoxPath::Symlink(_) => return Ok(BoxFile::NotRegular),
+    };
+    let file = match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&path)
+        .await
+    {
+        Ok(file) => file,
+        Err(e) if e.raw_os_error() == Some(libc::ELOOP) => return Ok(BoxFile::NotRegular),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BoxFile::Missing),
+        Err(e) => return Err(anyhow!("Failed to open {path}\nError: {e}")),
+    };
+    let metadata = file
+        .metadata()
+        .await
+        .map_err(|e| anyhow!("Failed to stat {path}\nError: {e}"))?;
+    if !metadata.is_file() {
+        return Ok(BoxFile::NotRegular);
+    }
+    let mut content = Vec::new();
+    file.take(max_bytes as u64)
+        .read_to_end(&mut content)
+        .await
+        .map_err(|e| anyhow!("Failed to read {path}\nError: {e}"))?;
+    Ok(BoxFile::Read {
+        content,
+        size: metadata.len(),
+    })
+}
+
+pub async fn safe_copy_from_box(box_dir: &str, relative: &str, dest: &str) -> Result<u64, Error> {
+    let src_path = resolve_in_box(box_dir, relative).await?;
+    // O_NOFOLLOW closes the window between the lstat checks and the open
+    let mut src = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&src_path)
+        .await
+        .map_err(|e| anyhow!("Failed to open {src_path}\nError: {e}"))?;
+    let metadata = src
+        .metadata()
+        .await
+        .map_err(|e| anyhow!("Failed to stat {src_path}\nError: {e}"))?;
+    if !metadata.is_file() {
+        return Err(anyhow!(
+            "Refusing to copy {src_path}, it is not a regular file"
+        ));
+    }
+    let mut dest_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dest)
+        .await
+        .map_err(|e| anyhow!("Failed to create {dest}\nError: {e}"))?;
+    io::copy(&mut src, &mut dest_file)
+        .await
+        .map_err(|e| anyhow!("Failed to copy {src_path} to {dest}\nError: {e}"))
+}
+
+// Copies what a stage left in a box into another box, keeping the permission
+// bits. Symlinks are copied as they are and never followed, other special
+// files are left out
+pub async fn copy_dir_from_box(box_dir: &str, relative: &str, dest: &str) -> Result<(), Error> {
+    let mut pending = vec![(relative.to_string(), dest.to_string())];
+    while let Some((relative, dest)) = pending.pop() {
+        let src_path = resolve_in_box(box_dir, &relative).await?;
+        let metadata = fs::symlink_metadata(&src_path)
+            .await
+            .map_err(|e| anyhow!("Failed to stat {src_path}\nError: {e}"))?;
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            let target = fs::read_link(&src_path)
+                .await
+                .map_err(|e| anyhow!("Failed to read the link {src_path}\nError: {e}"))?;
+            fs::symlink(&target, &dest)
+                .await
+                .map_err(|e| anyhow!("Failed to create the link {dest}\nError: {e}"))?;
+            continue;
+        }
+        if file_type.is_dir() {
+            fs::create_dir(&dest)
+                .await
+                .map_err(|e| anyhow!("Failed to create: {dest}\nError: {e}"))?;
+            let mut entries = fs::read_dir(&src_path)
+                .await
+                .map_err(|e| anyhow!("Failed to read {src_path}\nError: {e}"))?;
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .map_err(|e| anyhow!("Failed to read {src_path}\nError: {e}"))?
+            {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                pending.push((format!("{relative}/{name}"), format!("{dest}/{name}")));
+            }
+        } else if file_type.is_file() {
+            safe_copy_from_box(box_dir, &relative, &dest).await?;
+        } else {
+            continue;
+        }
+        let perms = Permissions::from_mode(metadata.permissions().mode() & 0o7777);
+        fs::set_permissions(&dest, perms)
+            .await
+            .map_err(|e| anyhow!("Failed to write permissions on {dest}\nError: {e}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    async fn fake_box(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().to_string_lossy().into_owned();
+        let fake = TempDir::new(format!("{dir}/envicutor-fs-{name}-{}", std::process::id()))
+            .await
+            .unwrap();
+        fs::create_dir(format!("{}/submission", fake.path))
+            .await
+            .unwrap();
+        fs::write(format!("{}/submission/out.txt", fake.path), "hello")
+            .await
+            .unwrap();
+        fake
+    }
+
+    #[tokio::test]
+    async fn regular_files_are_read_up_to_the_limit() {
+        let fake = fake_box("regular").await;
+        match read_file_from_box(&fake.path, "submission/out.txt", 3)
+            .await
+            .unwrap()
+        {
+            BoxFile::Read { content, size } => {
+                assert_eq!(content, b"hel");
+                assert_eq!(size, 5);
+            }
+            _ => panic!("expected the file to be read"),
+        }
+        assert!(matches!(
+            read_file_from_box(&fake.path, "submission/absent.txt", 3)
+                .await
+                .unwrap(),
+            BoxFile::Missing
+        ));
+        assert_eq!(
+            resolve_in_box(&fake.path, "submission/out.txt")
+                .await
+                .unwrap(),
+            format!("{}/submission/out.txt", fake.path)
+        );
+    }
+
+    #[tokio::test]
+    async fn symlinked_files_are_not_followed() {
+        let fake = fake_box("file-link").await;
+        fs::write(format!("{}/secret", fake.path), "secret")
+            .await
+            .unwrap();
+        fs::symlink(
+            format!("{}/secret", fake.path),
+            format!("{}/submission/link", fake.path),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            read_file_from_box(&fake.path, "submission/link", 64)
+                .await
+                .unwrap(),
+            BoxFile::NotRegular
+        ));
+        assert!(resolve_in_box(&fake.path, "submission/link").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn symlinked_directories_are_not_followed() {
+        let fake = fake_box("dir-link").await;
+        fs::symlink(
+            format!("{}/submission", fake.path),
+            format!("{}/elsewhere", fake.path),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            read_file_from_box(&fake.path, "elsewhere/out.txt", 64)
+                .await
+                .unwrap(),
+            BoxFile::NotRegular
+        ));
+        assert!(resolve_in_box(&fake.path, "elsewhere/out.txt")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn parent_components_are_refused() {
+        let fake = fake_box("parent").await;
+        assert!(read_file_from_box(&fake.path, "submission/../secret", 64)
+            .await
+            .is_err());
+        assert!(resolve_in_box(&fake.path, "../secret").await.is_err());
+    }
+}
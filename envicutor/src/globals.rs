@@ -1,4 +1,12 @@
 pub const RUNTIMES_DIR: &str = "/envicutor/runtimes";
 pub const DB_PATH: &str = "/envicutor/runtimes/runtimes.db";
 pub const MAX_BOX_ID: u64 = 999;
+// The boxes from this one up to MAX_BOX_ID are only given to the CLI subcommands,
+// so that they can install runtimes next to a live server
+pub const FIRST_CLI_BOX_ID: u64 = 960;
 pub const TEMP_DIR: &str = "/envicutor/tmp";
+pub const NIX_BIN_PATH: &str = "/home/envicutor/.nix-profile/bin";
+pub const GC_ROOTS_DIR: &str = "/nix/var/nix/gcroots/envicutor";
+pub const DATASETS_DIR: &str = "/envicutor/datasets";
+pub const COMPILE_CACHE_DIR: &str = "/envicutor/compile_cache";
+pub const CORES_DIR: &str = "/envicutor/cores";
@@ -0,0 +1,234 @@
+// Outcomes of the recent executions of each runtime. A runtime whose executions
+// keep failing in the sandbox while the others are fine has usually lost part
+// of its environment, e.g. to a garbage collection
+
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, MutexGuard, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+
+use crate::{api::garbage_collection::get_runtime_store_paths, types::RuntimeId};
+
+// Set once at startup
+static HEALTH_CONFIG: OnceLock<HealthConfig> = OnceLock::new();
+
+const MAX_OUTCOME_AGE: Duration = Duration::from_secs(60 * 60);
+// With fewer outcomes than this a runtime is healthy whatever they are
+const MIN_OUTCOMES: usize = 5;
+const DEGRADED_ERROR_RATIO: f32 = 0.1;
+const BROKEN_ERROR_RATIO: f32 = 0.5;
+
+#[derive(Clone, Copy)]
+pub struct HealthConfig {
+    // Outcomes kept per runtime, none is kept for longer than an hour
+    pub window: usize,
+    pub disable_broken: bool,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            window: 50,
+            disable_broken: false,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    Healthy,
+    Degraded,
+    Broken,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct HealthSummary {
+    pub state: HealthState,
+    pub executions: usize,
+    pub sandbox_errors: usize,
+}
+
+#[derive(Default)]
+pub struct RuntimeHealth {
+    // Whether each execution failed in the sandbox, oldest first
+    outcomes: Mutex<VecDeque<(Instant, bool)>>,
+}
+
+pub fn use_health_config(config: HealthConfig) -> Result<(), Error> {
+    HEALTH_CONFIG
+        .set(config)
+        .map_err(|_| anyhow!("The runtime health config was already set"))
+}
+
+pub fn health_config() -> HealthConfig {
+    HEALTH_CONFIG.get().copied().unwrap_or_default()
+}
+
+fn summarize(outcomes: &VecDeque<(Instant, bool)>) -> HealthSummary {
+    let executions = outcomes.len();
+    let sandbox_errors = outcomes.iter().filter(|(_, failed)| *failed).count();
+    let ratio = sandbox_errors as f32 / executions.max(1) as f32;
+    let state = if executions < MIN_OUTCOMES || ratio < DEGRADED_ERROR_RATIO {
+        HealthState::Healthy
+    } else if ratio < BROKEN_ERROR_RATIO {
+        HealthState::Degraded
+    } else {
+        HealthState::Broken
+    };
+    HealthSummary {
+        state,
+        executions,
+        sandbox_errors,
+    }
+}
+
+impl RuntimeHealth {
+    fn lock(&self) -> MutexGuard<'_, VecDeque<(Instant, bool)>> {
+        let mut outcomes = self.outcomes.lock().unwrap_or_else(|e| e.into_inner());
+        while outcomes
+            .front()
+            .is_some_and(|(at, _)| at.elapsed() > MAX_OUTCOME_AGE)
+        {
+            outcomes.pop_front();
+        }
+        outcomes
+    }
+
+    // Returns the summary when the runtime just became broken
+    pub fn record(&self, sandbox_error: bool) -> Option<HealthSummary> {
+        let mut outcomes = self.lock();
+        let was_broken = summarize(&outcomes).state == HealthState::Broken;
+        outcomes.push_back((Instant::now(), sandbox_error));
+        while outcomes.len() > health_config().window {
+            outcomes.pop_front();
+        }
+        let summary = summarize(&outcomes);
+        (summary.state == HealthState::Broken && !was_broken).then_some(summary)
+    }
+
+    pub fn summary(&self) -> HealthSummary {
+        summarize(&self.lock())
+    }
+}
+
+// The store paths of the runtime's environment snapshot that are gone
+pub async fn find_missing_store_paths(runtime_id: RuntimeId) -> Result<Vec<String>, Error> {
+    let mut missing = Vec::new();
+    for path in get_runtime_store_paths(&[runtime_id]).await? {
+        if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            missing.push(path);
+        }
+    }
+    Ok(missing)
+}
+
+pub async fn report_broken_runtime(runtime_id: RuntimeId, summary: HealthSummary) {
+    eprintln!(
+        "Runtime {runtime_id} is broken, {} of its last {} executions failed in the sandbox",
+        summary.sandbox_errors, summary.executions
+    );
+    match find_missing_store_paths(runtime_id).await {
+        Ok(missing) if missing.is_empty() => {
+            eprintln!("Every store path of runtime {runtime_id} still exists")
+        }
+        Ok(missing) => eprintln!(
+            "Runtime {runtime_id} references store paths that no longer exist: {}",
+            missing.join(", ")
+        ),
+        Err(e) => eprintln!("Failed to check the store paths of runtime {runtime_id}: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_all(health: &RuntimeHealth, outcomes: &[bool]) -> Vec<HealthSummary> {
+        outcomes
+            .iter()
+            .filter_map(|&sandbox_error| health.record(sandbox_error))
+            .collect()
+    }
+
+    #[test]
+    fn a_few_failures_keep_a_runtime_healthy() {
+        let health = RuntimeHealth::default();
+        assert!(record_all(&health, &[true; MIN_OUTCOMES - 1]).is_empty());
+        let summary = health.summary();
+        assert_eq!(summary.state, HealthState::Healthy);
+        assert_eq!(summary.executions, MIN_OUTCOMES - 1);
+        assert_eq!(summary.sandbox_errors, MIN_OUTCOMES - 1);
+    }
+
+    #[test]
+    fn runtimes_go_from_healthy_to_degraded_to_broken() {
+        let health = RuntimeHealth::default();
+        assert!(record_all(&health, &[false; 9]).is_empty());
+        assert_eq!(health.summary().state, HealthState::Healthy);
+
+        // 1 in 10
+        assert!(health.record(true).is_none());
+        assert_eq!(health.summary().state, HealthState::Degraded);
+
+        // 8 in 17
+        assert!(record_all(&health, &[true; 7]).is_empty());
+        assert_eq!(health.summary().state, HealthState::Degraded);
+
+        // 9 in 18, the broken runtime is reported once
+        let became_broken = health.record(true).unwrap();
+        assert_eq!(became_broken.state, HealthState::Broken);
+        assert_eq!(became_broken.executions, 18);
+        assert_eq!(became_broken.sandbox_errors, 9);
+        assert!(record_all(&health, &[true; 5]).is_empty());
+        assert_eq!(health.summary().state, HealthState::Broken);
+    }
+
+    #[test]
+    fn runtimes_recovering_are_reported_again_when_broken_again() {
+        let health = RuntimeHealth::default();
+        assert_eq!(record_all(&health, &[true; MIN_OUTCOMES]).len(), 1);
+
+        // 5 in 11
+        assert!(record_all(&health, &[false; 6]).is_empty());
+        assert_eq!(health.summary().state, HealthState::Degraded);
+
+        // 6 in 12
+        let became_broken = record_all(&health, &[true]);
+        assert_eq!(became_broken.len(), 1);
+        assert_eq!(became_broken[0].sandbox_errors, 6);
+    }
+
+    #[test]
+    fn only_the_window_is_kept() {
+        let window = health_config().window;
+        let health = RuntimeHealth::default();
+        assert_eq!(record_all(&health, &vec![true; window]).len(), 1);
+        assert!(record_all(&health, &vec![false; window]).is_empty());
+        let summary = health.summary();
+        assert_eq!(summary.state, HealthState::Healthy);
+        assert_eq!(summary.executions, window);
+        assert_eq!(summary.sandbox_errors, 0);
+    }
+
+    #[test]
+    fn outcomes_older_than_an_hour_are_forgotten() {
+        let Some(long_ago) = Instant::now().checked_sub(MAX_OUTCOME_AGE + Duration::from_secs(1))
+        else {
+            return;
+        };
+        let health = RuntimeHealth::default();
+        health
+            .outcomes
+            .lock()
+            .unwrap()
+            .extend([(long_ago, true); 10]);
+        assert!(record_all(&health, &[true; MIN_OUTCOMES - 1]).is_empty());
+        assert_eq!(health.summary().executions, MIN_OUTCOMES - 1);
+        assert_eq!(health.summary().state, HealthState::Healthy);
+    }
+}
@@ -0,0 +1,454 @@
+// What was executed, when, with what limits and with what result, kept in the
+// database for auditing when enabled. The output of the programs is only kept
+// when asked for, cut at a size so that the database doesn't balloon. Old
+// records are pruned by age and by count. The requests themselves can be kept
+// for a while too, for executions to be replayed
+
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use rusqlite::{types::Value, Connection, OptionalExtension};
+use serde::Serialize;
+use tokio::task;
+
+use crate::{
+    api::execution::ExecutionResponse,
+    digest::Sha256,
+    env_snapshot,
+    globals::RUNTIMES_DIR,
+    storage,
+    types::{Kilobytes, RuntimeId, Seconds},
+    verdict::Verdict,
+};
+
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+pub const MAX_PAGE_SIZE: u32 = 500;
+// Records are deleted this many at a time, each batch in its own statement, so
+// that the executions recording theirs meanwhile aren't held up for long
+const PRUNE_BATCH_SIZE: u32 = 1000;
+
+// Set once at startup, nothing is recorded without it
+static HISTORY_CONFIG: OnceLock<HistoryConfig> = OnceLock::new();
+
+#[derive(Clone, Copy)]
+pub struct HistoryConfig {
+    // The bytes kept of stdout and of stderr, none are kept when it is 0
+    pub output_bytes: usize,
+    // Records that finished longer ago are pruned
+    pub max_age: Option<Duration>,
+    // Only the most recent records are kept beyond it
+    pub max_rows: Option<u64>,
+    // Requests up to this size are kept along with their record, none are
+    // kept when it is 0
+    pub request_bytes: usize,
+    // Kept requests are pruned this long after the execution
+    pub request_retention: Duration,
+}
+
+pub fn use_history_config(config: HistoryConfig) -> Result<(), Error> {
+    HISTORY_CONFIG
+        .set(config)
+        .map_err(|_| anyhow!("The execution history config was already set"))
+}
+
+pub fn is_enabled() -> bool {
+    HISTORY_CONFIG.get().is_some()
+}
+
+// Whether there is anything for the scheduled pruning to do
+pub fn has_retention() -> bool {
+    HISTORY_CONFIG.get().is_some_and(|config| {
+        config.max_age.is_some() || config.max_rows.is_some() || config.request_bytes > 0
+    })
+}
+
+// Whether every execution that finished within `window` is still in the history
+pub fn covers(window: Duration) -> bool {
+    HISTORY_CONFIG.get().is_some_and(|config| {
+        config.max_rows.is_none() && config.max_age.map_or(true, |max_age| max_age >= window)
+    })
+}
+
+// Whether requests are worth serializing for `record`
+pub fn keeps_requests() -> bool {
+    HISTORY_CONFIG
+        .get()
+        .is_some_and(|config| config.request_bytes > 0)
+}
+
+#[derive(Serialize)]
+pub struct ExecutionRecord {
+    pub id: i64,
+    pub runtime_id: RuntimeId,
+    // Unix milliseconds
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub limits: Option<serde_json::Value>,
+    pub exit_status: Option<String>,
+    pub exit_code: Option<u32>,
+    pub verdict: Option<String>,
+    pub cpu_time: Option<Seconds>,
+    pub wall_time: Option<Seconds>,
+    pub memory: Option<Kilobytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    // Of everything the last stage wrote
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout_sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr_sha256: Option<String>,
+}
+
+// The request of a recorded execution, as serialized by the handler
+pub struct RetainedRequest {
+    pub request: String,
+    // Of the runtime the execution ran on, see `runtime_fingerprint`
+    pub runtime_fingerprint: Option<String>,
+}
+
+#[derive(Default)]
+pub struct HistoryQuery {
+    pub runtime_id: Option<RuntimeId>,
+    // Unix milliseconds, executions started at or after it
+    pub since: Option<u64>,
+    // Executions with a smaller id, the cursor of the next page
+    pub before: Option<i64>,
+    pub limit: u32,
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+// The tag the verdict is serialized with, e.g. "wrong_answer"
+pub fn verdict_name(verdict: Verdict) -> Option<String> {
+    serde_json::to_value(verdict)
+        .ok()?
+        .get("verdict")?
+        .as_str()
+        .map(str::to_string)
+}
+
+fn capped(bytes: &[u8], max_bytes: usize) -> Option<String> {
+    (max_bytes > 0)
+        .then(|| String::from_utf8_lossy(&bytes[..bytes.len().min(max_bytes)]).into_owned())
+}
+
+// Files of a runtime that decide how its executions go
+const FINGERPRINTED_FILES: [&str; 4] = [
+    "compile",
+    "run",
+    "env",
+    env_snapshot::SHELL_SNAPSHOT_FILE_NAME,
+];
+
+// Changes when the runtime is reinstalled differently or its directory is
+// tampered with, None when the runtime is gone
+pub async fn runtime_fingerprint(runtime_id: RuntimeId) -> Option<String> {
+    let runtime_dir = format!("{RUNTIMES_DIR}/{runtime_id}");
+    if !tokio::fs::try_exists(&runtime_dir).await.unwrap_or(false) {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    for name in FINGERPRINTED_FILES {
+        hasher.update(name.as_bytes());
+        match tokio::fs::read(format!("{runtime_dir}/{name}")).await {
+            Ok(content) => {
+                hasher.update(&(content.len() as u64).to_le_bytes());
+                hasher.update(&content);
+            }
+            Err(_) => hasher.update(b"\0"),
+        }
+    }
+    Some(hasher.finish_hex())
+}
+
+// Written in the background, an execution is never held up or failed by it.
+// The request is kept when given and small enough
+pub fn record(
+    runtime_id: RuntimeId,
+    started_at: u64,
+    res: &ExecutionResponse,
+    request: Option<String>,
+) {
+    let Some(config) = HISTORY_CONFIG.get().copied() else {
+        return;
+    };
+    let last_stage = res.last_stage();
+    let limits = res
+        .run_limits
+        .as_ref()
+        .and_then(|limits| serde_json::to_string(limits).ok());
+    let sandbox = res
+        .sandbox
+        .as_ref()
+        .and_then(|sandbox| serde_json::to_string(sandbox).ok());
+    let exit_status = last_stage.and_then(|stage| stage.exit_status.clone());
+    let exit_code = last_stage.and_then(|stage| stage.exit_code);
+    let verdict = res.verdict.and_then(verdict_name);
+    let cpu_time = last_stage.and_then(|stage| stage.cpu_time);
+    let wall_time = last_stage.and_then(|stage| stage.wall_time);
+    let memory = last_stage.and_then(|stage| stage.memory);
+    let stdout = last_stage.and_then(|stage| capped(stage.stdout.bytes(), config.output_bytes));
+    let stderr = last_stage.and_then(|stage| capped(stage.stderr.bytes(), config.output_bytes));
+    let stdout_sha256 = last_stage.map(|stage| stage.stdout_sha256.clone());
+    let stderr_sha256 = last_stage.map(|stage| stage.stderr_sha256.clone());
+    let request = request.filter(|request| request.len() <= config.request_bytes);
+    let finished_at = now_millis();
+    tokio::spawn(async move {
+        let runtime_fingerprint = match request {
+            Some(_) => runtime_fingerprint(runtime_id).await,
+            None => None,
+        };
+        let res = task::spawn_blocking(move || {
+            let connection = Connection::open(storage::state_db_path())?;
+            connection.execute(
+                "INSERT INTO execution (runtime_id, started_at, finished_at, limits, exit_status, exit_code, verdict, cpu_time, wall_time, memory, stdout, stderr, sandbox, stdout_sha256, stderr_sha256) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                rusqlite::params![
+                    runtime_id,
+                    started_at,
+                    finished_at,
+                    limits,
+                    exit_status,
+                    exit_code,
+                    verdict,
+                    cpu_time,
+                    wall_time,
+                    memory,
+                    stdout,
+                    stderr,
+                    sandbox,
+                    stdout_sha256,
+                    stderr_sha256
+                ],
+            )?;
+            if let Some(request) = request {
+                connection.execute(
+                    "INSERT INTO execution_request (execution_id, request, runtime_fingerprint, stored_at) VALUES (?, ?, ?, ?)",
+                    rusqlite::params![
+                        connection.last_insert_rowid(),
+                        request,
+                        runtime_fingerprint,
+                        finished_at
+                    ],
+                )?;
+            }
+            Ok::<_, rusqlite::Error>(())
+        })
+        .await;
+        match res {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("Failed to record the execution: {e}"),
+            Err(e) => eprintln!("Failed to spawn blocking task: {e}"),
+        }
+    });
+}
+
+const RECORD_COLUMNS: &str = "id, runtime_id, started_at, finished_at, limits, exit_status, exit_code, verdict, cpu_time, wall_time, memory, stdout, stderr, sandbox, stdout_sha256, stderr_sha256";
+
+fn record_from_row(row: &rusqlite::Row) -> rusqlite::Result<ExecutionRecord> {
+    let limits: Option<String> = row.get(4)?;
+    let sandbox: Option<String> = row.get(13)?;
+    Ok(ExecutionRecord {
+        id: row.get(0)?,
+        runtime_id: row.get(1)?,
+        started_at: row.get(2)?,
+        finished_at: row.get(3)?,
+        limits: limits.and_then(|limits| serde_json::from_str(&limits).ok()),
+        exit_status: row.get(5)?,
+        exit_code: row.get(6)?,
+        verdict: row.get(7)?,
+        cpu_time: row.get(8)?,
+        wall_time: row.get(9)?,
+        memory: row.get(10)?,
+        sandbox: sandbox.and_then(|sandbox| serde_json::from_str(&sandbox).ok()),
+        stdout: row.get(11)?,
+        stderr: row.get(12)?,
+        stdout_sha256: row.get(14)?,
+        stderr_sha256: row.get(15)?,
+    })
+}
+
+pub async fn get(id: i64) -> Result<Option<ExecutionRecord>, Error> {
+    task::spawn_blocking(move || {
+        let connection = Connection::open(storage::state_db_path())?;
+        let record = connection
+            .query_row(
+                &format!("SELECT {RECORD_COLUMNS} FROM execution WHERE id = ?"),
+                [id],
+                record_from_row,
+            )
+            .optional()?;
+        Ok(record)
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+}
+
+// None once pruned, or when the request wasn't kept
+pub async fn retained_request(execution_id: i64) -> Result<Option<RetainedRequest>, Error> {
+    task::spawn_blocking(move || {
+        let connection = Connection::open(storage::state_db_path())?;
+        let request = connection
+            .query_row(
+                "SELECT request, runtime_fingerprint FROM execution_request WHERE execution_id = ?",
+                [execution_id],
+                |row| {
+                    Ok(RetainedRequest {
+                        request: row.get(0)?,
+                        runtime_fingerprint: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(request)
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+}
+
+// Most recent first
+pub async fn query(query: HistoryQuery) -> Result<Vec<ExecutionRecord>, Error> {
+    task::spawn_blocking(move || {
+        let connection = Connection::open(storage::state_db_path())?;
+        let mut conditions = Vec::new();
+        let mut params: Vec<Value> = Vec::new();
+        if let Some(runtime_id) = query.runtime_id {
+            conditions.push("runtime_id = ?");
+            params.push(Value::Integer(runtime_id));
+        }
+        if let Some(since) = query.since {
+            conditions.push("started_at >= ?");
+            params.push(Value::Integer(since as i64));
+        }
+        if let Some(before) = query.before {
+            conditions.push("id < ?");
+            params.push(Value::Integer(before));
+        }
+        let filter = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        params.push(Value::Integer(query.limit.into()));
+        let mut stmt = connection.prepare(&format!(
+            "SELECT {RECORD_COLUMNS} FROM execution {filter} ORDER BY id DESC LIMIT ?"
+        ))?;
+        let records = stmt
+            .query_map(rusqlite::params_from_iter(params), record_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(records)
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+}
+
+// Deletes at most a batch of the records with an id below `below_id` or that
+// finished before `finished_before`, returning how many were deleted
+async fn delete_batch(below_id: Option<i64>, finished_before: Option<u64>) -> Result<u64, Error> {
+    task::spawn_blocking(move || {
+        let connection = Connection::open(storage::state_db_path())?;
+        let deleted = connection.execute(
+            "DELETE FROM execution WHERE id IN (SELECT id FROM execution WHERE id < ? OR finished_at < ? ORDER BY id LIMIT ?)",
+            rusqlite::params![
+                below_id.unwrap_or(i64::MIN),
+                finished_before.map_or(i64::MIN, |finished_before| finished_before as i64),
+                PRUNE_BATCH_SIZE
+            ],
+        )?;
+        Ok(deleted as u64)
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+}
+
+// The id of the oldest record kept when only `max_rows` are, None when there
+// aren't more than that
+async fn oldest_kept_id(max_rows: u64) -> Result<Option<i64>, Error> {
+    if max_rows == 0 {
+        return Ok(Some(i64::MAX));
+    }
+    task::spawn_blocking(move || {
+        let connection = Connection::open(storage::state_db_path())?;
+        let id = connection
+            .query_row(
+                "SELECT id FROM execution ORDER BY id DESC LIMIT 1 OFFSET ?",
+                [max_rows - 1],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(id)
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+}
+
+// Deletes the kept requests past their retention and those whose record is gone
+async fn prune_requests(stored_before: u64) -> Result<(), Error> {
+    task::spawn_blocking(move || {
+        let connection = Connection::open(storage::state_db_path())?;
+        connection.execute(
+            "DELETE FROM execution_request WHERE stored_at < ? OR execution_id NOT IN (SELECT id FROM execution)",
+            [stored_before],
+        )?;
+        Ok(())
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+}
+
+// Deletes the records beyond the retention, returning how many were deleted
+pub async fn prune() -> Result<u64, Error> {
+    let Some(config) = HISTORY_CONFIG.get().copied() else {
+        return Ok(0);
+    };
+    if config.request_bytes > 0 {
+        let stored_before =
+            now_millis().saturating_sub(config.request_retention.as_millis() as u64);
+        prune_requests(stored_before).await?;
+    }
+    if config.max_age.is_none() && config.max_rows.is_none() {
+        return Ok(0);
+    }
+    let finished_before = config
+        .max_age
+        .map(|max_age| now_millis().saturating_sub(max_age.as_millis() as u64));
+    let below_id = match config.max_rows {
+        Some(max_rows) => oldest_kept_id(max_rows).await?,
+        None => None,
+    };
+    let mut deleted = 0;
+    loop {
+        let batch = delete_batch(below_id, finished_before).await?;
+        deleted += batch;
+        if batch < PRUNE_BATCH_SIZE.into() {
+            return Ok(deleted);
+        }
+    }
+}
+
+// When each runtime's most recent recorded execution finished, runtimes with
+// no execution left in the history are absent
+pub async fn last_finished_by_runtime() -> Result<HashMap<RuntimeId, u64>, Error> {
+    task::spawn_blocking(move || {
+        let connection = Connection::open(storage::state_db_path())?;
+        let mut stmt = connection
+            .prepare("SELECT runtime_id, MAX(finished_at) FROM execution GROUP BY runtime_id")?;
+        let last_finished = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        Ok(last_finished)
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+}
@@ -0,0 +1,232 @@
+// Operator commands run on the host (outside the sandbox) around executions,
+// given a JSON summary of the execution on stdin
+
+use std::{process::Stdio, str::FromStr, sync::OnceLock, time::Duration};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, process::Command, time};
+
+use crate::types::RuntimeId;
+
+// Set once at startup
+static EXECUTION_HOOKS: OnceLock<ExecutionHooks> = OnceLock::new();
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HookPolicy {
+    // Spawned and not awaited, failures are dropped or logged
+    Ignore,
+    Warn,
+    // Awaited, a failure fails the request
+    FailRequest,
+}
+
+impl FromStr for HookPolicy {
+    type Err = Error;
+
+    fn from_str(policy: &str) -> Result<HookPolicy, Error> {
+        match policy {
+            "ignore" => Ok(HookPolicy::Ignore),
+            "warn" => Ok(HookPolicy::Warn),
+            "fail_request" => Ok(HookPolicy::FailRequest),
+            _ => Err(anyhow!(
+                "Unknown hook policy {policy}, expected ignore, warn or fail_request"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Hook {
+    pub name: &'static str,
+    // Run with `sh -c`
+    pub command: String,
+    pub timeout: Duration,
+    pub policy: HookPolicy,
+}
+
+#[derive(Clone, Default)]
+pub struct ExecutionHooks {
+    pub pre_execution: Option<Hook>,
+    pub post_execution: Option<Hook>,
+}
+
+#[derive(Serialize)]
+pub struct PreExecution<'a> {
+    pub runtime_id: Option<RuntimeId>,
+    pub runtime: Option<&'a str>,
+    pub source_code_bytes: usize,
+    pub files: usize,
+    pub input_bytes: usize,
+    pub args: usize,
+}
+
+pub fn use_execution_hooks(hooks: ExecutionHooks) -> Result<(), Error> {
+    EXECUTION_HOOKS
+        .set(hooks)
+        .map_err(|_| anyhow!("The execution hooks were already set"))
+}
+
+pub fn execution_hooks() -> Option<&'static ExecutionHooks> {
+    EXECUTION_HOOKS.get()
+}
+
+async fn invoke(command: &str, payload: &[u8]) -> Result<(), Error> {
+    let mut child = Command::new("/bin/sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to spawn\nError: {e}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A hook that does not read its input is not a failure
+        if let Err(e) = stdin.write_all(payload).await {
+            if e.kind() != std::io::ErrorKind::BrokenPipe {
+                return Err(anyhow!("Failed to write to stdin\nError: {e}"));
+            }
+        }
+    }
+    let res = child
+        .wait_with_output()
+        .await
+        .map_err(|e| anyhow!("Failed to wait\nError: {e}"))?;
+    if !res.status.success() {
+        return Err(anyhow!(
+            "Exited with {}\nstderr: {}",
+            res.status,
+            String::from_utf8_lossy(&res.stderr)
+        ));
+    }
+    Ok(())
+}
+
+impl Hook {
+    async fn invoke(&self, payload: Vec<u8>) -> Result<(), Error> {
+        time::timeout(self.timeout, invoke(&self.command, &payload))
+            .await
+            .map_err(|_| anyhow!("The {} hook timed out after {:?}", self.name, self.timeout))?
+            .map_err(|e| anyhow!("The {} hook failed: {e}", self.name))
+    }
+
+    // Only waits for the hook when its failure has to fail the request
+    pub async fn run(&self, payload: &impl Serialize) -> Result<(), Error> {
+        let payload = serde_json::to_vec(payload)
+            .map_err(|e| anyhow!("Failed to serialize the {} hook input: {e}", self.name))?;
+        if self.policy == HookPolicy::FailRequest {
+            return self.invoke(payload).await;
+        }
+        let hook = self.clone();
+        tokio::spawn(async move {
+            if let (Err(e), HookPolicy::Warn) = (hook.invoke(payload).await, hook.policy) {
+                eprintln!("{e}");
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    const PAYLOAD: PreExecution = PreExecution {
+        runtime_id: Some(3),
+        runtime: Some("Python 3"),
+        source_code_bytes: 12,
+        files: 1,
+        input_bytes: 0,
+        args: 2,
+    };
+
+    fn hook(command: String, policy: HookPolicy) -> Hook {
+        Hook {
+            name: "pre-execution",
+            command,
+            timeout: Duration::from_secs(5),
+            policy,
+        }
+    }
+
+    async fn records_dir(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().to_string_lossy().into_owned();
+        TempDir::new(format!(
+            "{dir}/envicutor-hooks-{name}-{}",
+            std::process::id()
+        ))
+        .await
+        .unwrap()
+    }
+
+    // Hooks that are not awaited are only seen through what they leave behind
+    async fn wait_for_record(path: &str) -> String {
+        for _ in 0..100 {
+            if let Ok(record) = tokio::fs::read_to_string(path).await {
+                if !record.is_empty() {
+                    return record;
+                }
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("{path} was never written");
+    }
+
+    #[test]
+    fn policies_are_parsed() {
+        assert_eq!("ignore".parse::<HookPolicy>().unwrap(), HookPolicy::Ignore);
+        assert_eq!("warn".parse::<HookPolicy>().unwrap(), HookPolicy::Warn);
+        assert_eq!(
+            "fail_request".parse::<HookPolicy>().unwrap(),
+            HookPolicy::FailRequest
+        );
+        assert!("fail".parse::<HookPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn the_payload_is_given_on_stdin_under_every_policy() {
+        let records = records_dir("stdin").await;
+        let expected = serde_json::to_string(&PAYLOAD).unwrap();
+        for policy in [
+            HookPolicy::Ignore,
+            HookPolicy::Warn,
+            HookPolicy::FailRequest,
+        ] {
+            let record = format!("{}/{policy:?}", records.path);
+            let hook = hook(
+                format!("cat > {record}.partial && mv {record}.partial {record}"),
+                policy,
+            );
+            hook.run(&PAYLOAD).await.unwrap();
+            assert_eq!(wait_for_record(&record).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failing_hook_only_fails_the_request_when_asked_to() {
+        let records = records_dir("exit").await;
+        for policy in [HookPolicy::Ignore, HookPolicy::Warn] {
+            let record = format!("{}/{policy:?}", records.path);
+            let hook = hook(format!("echo ran > {record}; exit 3"), policy);
+            hook.run(&PAYLOAD).await.unwrap();
+            assert_eq!(wait_for_record(&record).await, "ran\n");
+        }
+        let hook = hook(
+            "echo refused >&2; exit 3".to_string(),
+            HookPolicy::FailRequest,
+        );
+        let e = hook.run(&PAYLOAD).await.unwrap_err().to_string();
+        assert!(e.starts_with("The pre-execution hook failed"), "{e}");
+        assert!(e.contains("exit status: 3"), "{e}");
+        assert!(e.contains("stderr: refused"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn a_hook_that_outlives_its_timeout_fails_the_request() {
+        let mut hook = hook("sleep 5".to_string(), HookPolicy::FailRequest);
+        hook.timeout = Duration::from_millis(50);
+        let e = hook.run(&PAYLOAD).await.unwrap_err().to_string();
+        assert!(e.starts_with("The pre-execution hook timed out"), "{e}");
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    ffi::CString,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicBool, Ordering},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use tokio::{
+    fs,
+    sync::{Notify, RwLock},
+};
+
+use crate::globals::RUNTIMES_DIR;
+
+const LOADAVG_PATH: &str = "/proc/loadavg";
+const MEMORY_PRESSURE_PATH: &str = "/proc/pressure/memory";
+const NIX_DIR: &str = "/nix";
+pub const HOST_SATURATED_WARNING: &str = "host_saturated";
+// ENOSPC and EDQUOT as formatted by std::io::Error, and SQLITE_FULL as formatted by rusqlite
+const DISK_FULL_PATTERNS: [&str; 3] = [
+    "No space left on device",
+    "Disk quota exceeded",
+    "database or disk is full",
+];
+
+#[derive(Serialize, Clone)]
+pub struct FsUsage {
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct HostSample {
+    pub sampled_at: u64,
+    pub load_average: Option<[f32; 3]>,
+    // `some avg10` of the memory PSI, absent on kernels without PSI
+    pub memory_pressure: Option<f32>,
+    pub runtimes_fs: Option<FsUsage>,
+    pub nix_fs: Option<FsUsage>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct HostThresholds {
+    pub load: Option<f32>,
+    pub memory_pressure: Option<f32>,
+    // Free space both filesystems need before a full disk is considered resolved
+    pub disk_free_bytes: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct HostStatus {
+    pub sample: Option<HostSample>,
+    pub saturated: bool,
+    pub disk_full: bool,
+    pub thresholds: HostThresholds,
+}
+
+pub struct HostMonitor {
+    status: RwLock<HostStatus>,
+    // Permits an execution takes from the execution semaphore while the host is saturated
+    saturated_execution_permits: u32,
+    // Set when a write fails for lack of space, cleared by a sample with enough free space
+    disk_full: AtomicBool,
+    disk_full_reported: Notify,
+}
+
+pub fn parse_loadavg(loadavg: &str) -> Option<[f32; 3]> {
+    let mut values = loadavg.split_whitespace().map(|value| value.parse().ok());
+    Some([values.next()??, values.next()??, values.next()??])
+}
+
+// e.g. "some avg10=0.31 avg60=0.12 avg300=0.02 total=123456"
+pub fn parse_memory_pressure(pressure: &str) -> Option<f32> {
+    let some = pressure.lines().find(|line| line.starts_with("some "))?;
+    some.split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+pub fn fs_usage(path: &str) -> Result<FsUsage, Error> {
+    let c_path = CString::new(path).map_err(|e| anyhow!("Invalid path {path}: {e}"))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: c_path is NUL-terminated and stat is only read after statvfs succeeded
+    let res = unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) };
+    if res != 0 {
+        return Err(anyhow!(
+            "Failed to statvfs {path}: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let stat = unsafe { stat.assume_init() };
+    let fragment_size = stat.f_frsize;
+    Ok(FsUsage {
+        total_bytes: stat.f_blocks * fragment_size,
+        available_bytes: stat.f_bavail * fragment_size,
+    })
+}
+
+// Every source is optional, a host missing one of them still gets sampled
+pub async fn sample_host() -> HostSample {
+    let load_average = match fs::read_to_string(LOADAVG_PATH).await {
+        Ok(loadavg) => parse_loadavg(&loadavg),
+        Err(e) => {
+            eprintln!("Failed to read {LOADAVG_PATH}: {e}");
+            None
+        }
+    };
+    let memory_pressure = fs::read_to_string(MEMORY_PRESSURE_PATH)
+        .await
+        .ok()
+        .and_then(|pressure| parse_memory_pressure(&pressure));
+    let runtimes_fs = fs_usage(RUNTIMES_DIR).map_err(|e| eprintln!("{e}")).ok();
+    let nix_fs = fs_usage(NIX_DIR).map_err(|e| eprintln!("{e}")).ok();
+    HostSample {
+        sampled_at: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        load_average,
+        memory_pressure,
+        runtimes_fs,
+        nix_fs,
+    }
+}
+
+pub fn is_disk_full_error(message: &str) -> bool {
+    DISK_FULL_PATTERNS
+        .iter()
+        .any(|pattern| message.contains(pattern))
+}
+
+fn has_free_space(sample: &HostSample, free_bytes: u64) -> bool {
+    let filesystems = [&sample.runtimes_fs, &sample.nix_fs];
+    // Without any usage known, only a restart clears the flag
+    filesystems.iter().any(|fs| fs.is_some())
+        && filesystems
+            .iter()
+            .filter_map(|fs| fs.as_ref())
+            .all(|fs| fs.available_bytes >= free_bytes)
+}
+
+fn exceeds(value: Option<f32>, threshold: Option<f32>) -> bool {
+    matches!((value, threshold), (Some(value), Some(threshold)) if value > threshold)
+}
+
+pub fn is_saturated(sample: &HostSample, thresholds: &HostThresholds) -> bool {
+    exceeds(sample.load_average.map(|l| l[0]), thresholds.load)
+        || exceeds(sample.memory_pressure, thresholds.memory_pressure)
+}
+
+impl HostMonitor {
+    pub fn new(thresholds: HostThresholds, saturated_execution_permits: u32) -> HostMonitor {
+        HostMonitor {
+            status: RwLock::new(HostStatus {
+                sample: None,
+                saturated: false,
+                disk_full: false,
+                thresholds,
+            }),
+            saturated_execution_permits,
+            disk_full: AtomicBool::new(false),
+            disk_full_reported: Notify::new(),
+        }
+    }
+
+    pub async fn record(&self, sample: HostSample) {
+        let mut status = self.status.write().await;
+        let saturated = is_saturated(&sample, &status.thresholds);
+        if saturated && !status.saturated {
+            eprintln!(
+                "Host is saturated, load: {:?}, memory pressure: {:?}",
+                sample.load_average, sample.memory_pressure
+            );
+        }
+        status.saturated = saturated;
+        if self.is_disk_full() && has_free_space(&sample, status.thresholds.disk_free_bytes) {
+            eprintln!("Disk space was freed, accepting writes again");
+            self.disk_full.store(false, Ordering::Relaxed);
+        }
+        status.sample = Some(sample);
+    }
+
+    pub async fn status(&self) -> HostStatus {
+        let mut status = self.status.read().await.clone();
+        status.disk_full = self.is_disk_full();
+        status
+    }
+
+    pub fn is_disk_full(&self) -> bool {
+        self.disk_full.load(Ordering::Relaxed)
+    }
+
+    pub fn report_disk_full(&self) {
+        if !self.disk_full.swap(true, Ordering::Relaxed) {
+            eprintln!("The disk is full, refusing writes until space is freed");
+            self.disk_full_reported.notify_one();
+        }
+    }
+
+    // Resolves once per report, for whatever frees space
+    pub async fn disk_full_reported(&self) {
+        self.disk_full_reported.notified().await
+    }
+
+    pub async fn is_saturated(&self) -> bool {
+        self.status.read().await.saturated
+    }
+
+    // Attached to the responses of executions run while the host is saturated
+    pub async fn warning(&self) -> Option<&'static str> {
+        self.is_saturated().await.then_some(HOST_SATURATED_WARNING)
+    }
+
+    pub async fn execution_permits(&self) -> u32 {
+        if self.is_saturated().await {
+            self.saturated_execution_permits
+        } else {
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(load: Option<f32>, memory_pressure: Option<f32>) -> HostSample {
+        HostSample {
+            sampled_at: 0,
+            load_average: load.map(|load| [load, 0.0, 0.0]),
+            memory_pressure,
+            runtimes_fs: None,
+            nix_fs: None,
+        }
+    }
+
+    fn usage(available_bytes: u64) -> Option<FsUsage> {
+        Some(FsUsage {
+            total_bytes: 1 << 30,
+            available_bytes,
+        })
+    }
+
+    #[test]
+    fn host_files_are_parsed() {
+        assert_eq!(
+            parse_loadavg("0.52 1.25 3.00 2/345 6789\n"),
+            Some([0.52, 1.25, 3.0])
+        );
+        assert_eq!(parse_loadavg("0.52 1.25"), None);
+        assert_eq!(
+            parse_memory_pressure(
+                "some avg10=0.31 avg60=0.12 avg300=0.02 total=123456\nfull avg10=0.10 avg60=0.00 avg300=0.00 total=1\n"
+            ),
+            Some(0.31)
+        );
+        assert_eq!(parse_memory_pressure("full avg10=0.10"), None);
+    }
+
+    #[test]
+    fn the_host_is_saturated_past_a_threshold() {
+        let thresholds = HostThresholds {
+            load: Some(8.0),
+            memory_pressure: Some(20.0),
+            disk_free_bytes: 0,
+        };
+        assert!(!is_saturated(&sample(Some(8.0), Some(20.0)), &thresholds));
+        assert!(is_saturated(&sample(Some(8.5), Some(0.0)), &thresholds));
+        assert!(is_saturated(&sample(Some(0.5), Some(20.5)), &thresholds));
+        // Unknown values and unset thresholds never saturate the host
+        assert!(!is_saturated(&sample(None, None), &thresholds));
+        assert!(!is_saturated(
+            &sample(Some(100.0), Some(100.0)),
+            &HostThresholds::default()
+        ));
+    }
+
+    #[test]
+    fn disks_are_full_until_both_filesystems_have_space() {
+        let mut sample = sample(None, None);
+        assert!(!has_free_space(&sample, 0));
+        sample.runtimes_fs = usage(100);
+        assert!(has_free_space(&sample, 100));
+        sample.nix_fs = usage(99);
+        assert!(!has_free_space(&sample, 100));
+        assert!(is_disk_full_error(
+            "Failed to write: No space left on device (os error 28)"
+        ));
+        assert!(!is_disk_full_error("Permission denied (os error 13)"));
+    }
+
+    #[tokio::test]
+    async fn saturated_hosts_take_more_permits_and_warn() {
+        let thresholds = HostThresholds {
+            load: Some(4.0),
+            memory_pressure: None,
+            disk_free_bytes: 0,
+        };
+        let monitor = HostMonitor::new(thresholds, 3);
+        assert_eq!(monitor.execution_permits().await, 1);
+        assert_eq!(monitor.warning().await, None);
+
+        monitor.record(sample(Some(6.0), None)).await;
+        assert!(monitor.status().await.saturated);
+        assert_eq!(monitor.execution_permits().await, 3);
+        assert_eq!(monitor.warning().await, Some(HOST_SATURATED_WARNING));
+
+        monitor.record(sample(Some(2.0), None)).await;
+        assert_eq!(monitor.execution_permits().await, 1);
+        assert_eq!(monitor.warning().await, None);
+    }
+
+    #[tokio::test]
+    async fn a_sample_with_free_space_clears_a_full_disk() {
+        let thresholds = HostThresholds {
+            disk_free_bytes: 1000,
+            ..HostThresholds::default()
+        };
+        let monitor = HostMonitor::new(thresholds, 2);
+        monitor.report_disk_full();
+        assert!(monitor.status().await.disk_full);
+
+        let mut low = sample(None, None);
+        low.runtimes_fs = usage(10);
+        monitor.record(low).await;
+        assert!(monitor.is_disk_full());
+
+        let mut freed = sample(None, None);
+        freed.runtimes_fs = usage(5000);
+        freed.nix_fs = usage(1000);
+        monitor.record(freed).await;
+        assert!(!monitor.is_disk_full());
+    }
+}
@@ -1,17 +1,35 @@
-use std::{process::Stdio, time::Duration};
+use std::{
+    fmt,
+    io::ErrorKind,
+    process::Stdio,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{anyhow, Error};
 use tokio::{
     fs,
-    io::{self, AsyncWriteExt},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt},
     process::Command,
-    task::yield_now,
+    sync::Notify,
     time,
 };
 
 use crate::{
-    globals::TEMP_DIR,
-    limits::MandatoryLimits,
+    cgroup, clock,
+    digest::{self, Sha256},
+    fs::BoxFile,
+    globals::{MAX_BOX_ID, TEMP_DIR},
+    limits::{LimitSet, MandatoryLimits},
+    output::{OutputEncoding, StageOutput},
+    quarantine,
+    sandbox_env::{self, SandboxEnv},
+    signals::signal_name,
+    stage::StageId,
     types::{Kilobytes, Seconds},
 };
 
@@ -19,23 +37,354 @@ pub struct Isolate {
     box_id: u64,
     metadata_file_path: String,
     run_pid: Option<u32>,
+    // Given to the timing of the first stage run in the box
+    init_duration: Option<Duration>,
+    // Set once closed, so that dropping it doesn't clean up the box again
+    closed: bool,
     pub box_dir: String,
 }
 
-#[derive(serde::Serialize)]
+// Only cgroup numbers account for the whole stage, max-rss is the peak of a single process
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MemorySource {
+    Cgroup,
+    Rss,
+    Unavailable,
+}
+
+// How the program ended, read from isolate's two-letter status, its exit code
+// and its signal, so that clients don't each interpret the status themselves
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitKind {
+    Ok,
+    TimedOut,
+    Signalled,
+    NonZeroExit,
+    SandboxError,
+}
+
+impl ExitKind {
+    pub fn classify(
+        status: Option<&str>,
+        exit_code: Option<u32>,
+        exit_signal: Option<u32>,
+    ) -> ExitKind {
+        match status {
+            // Over the CPU or wall time, whether isolate killed it with a signal or not
+            Some("TO") => ExitKind::TimedOut,
+            Some("XX") => ExitKind::SandboxError,
+            Some("SG") => ExitKind::Signalled,
+            _ if exit_signal.is_some() => ExitKind::Signalled,
+            Some("RE") => ExitKind::NonZeroExit,
+            _ if exit_code.is_some_and(|exit_code| exit_code != 0) => ExitKind::NonZeroExit,
+            _ => ExitKind::Ok,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Clone)]
 pub struct StageResult {
+    // Set by the orchestrator, a bare isolate run does not know its place in a request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage: Option<StageId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit_set: Option<LimitSet>,
     pub memory: Option<Kilobytes>,
+    pub memory_source: MemorySource,
+    // Set when the program was killed for going over its memory limit, which
+    // otherwise only shows as a SIGKILL
+    pub memory_limit_exceeded: bool,
     pub exit_code: Option<u32>,
     pub exit_signal: Option<u32>,
+    // SIGSEGV for 11, SIG34 for a real-time signal, null when unknown
+    pub exit_signal_name: Option<String>,
     pub exit_message: Option<String>,
+    // isolate's own status (TO, SG, RE), kept as is for older clients
     pub exit_status: Option<String>,
-    pub stdout: String,
-    pub stderr: String,
+    pub exit_kind: ExitKind,
+    pub stdout: StageOutput,
+    pub stderr: StageOutput,
+    // How stdout and stderr are given, left out when they are UTF-8
+    #[serde(skip_serializing_if = "OutputEncoding::is_utf8")]
+    pub output_encoding: OutputEncoding,
     pub cpu_time: Option<Seconds>,
+    // From the box's cpu.stat, absent when its control group can't be found
+    pub cpu_user_ms: Option<u64>,
+    pub cpu_system_ms: Option<u64>,
+    // The cpu.weight of the box, absent when it was left at the default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_weight: Option<u32>,
     pub wall_time: Option<Seconds>,
+    // Set when the program was killed for producing no output for too long
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub no_output_timeout: bool,
+    // Set when the stream went over the output limit, only its start is kept
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stdout_truncated: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stderr_truncated: bool,
+    // Of everything the program wrote, hashed as it was read
+    pub stdout_sha256: String,
+    pub stderr_sha256: String,
+    // Of the part that is returned, when it was truncated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub returned_stdout_sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub returned_stderr_sha256: Option<String>,
+    pub timing: StageTiming,
+    // Around the isolate invocation, see `clock`
+    pub started_at: String,
+    pub finished_at: String,
+}
+
+// Where the wall time of a stage went, in milliseconds as measured by the
+// server. The environment activation covers isolate setting up the run and
+// the runtime's environment being loaded, up to the program being started
+#[derive(serde::Serialize, Clone, Copy)]
+pub struct StageTiming {
+    // Only for the first stage run in a box
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sandbox_init_ms: Option<u64>,
+    // Unknown when the stage didn't get to start the program
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub environment_activation_ms: Option<u64>,
+    pub program_ms: u64,
+}
+
+impl StageResult {
+    // Keeps only the first `max_bytes` of stdout
+    pub fn truncate_stdout(&mut self, max_bytes: usize) {
+        if self.stdout.truncate(max_bytes) {
+            self.stdout_truncated = true;
+            self.returned_stdout_sha256 = Some(digest::sha256(self.stdout.bytes()));
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+pub type OutputSink<'a> = dyn Fn(OutputStream, &str) + Sync + 'a;
+
+// The program is killed once it has produced no output for `timeout`, though
+// never before it has run for `grace`
+#[derive(Clone, Copy)]
+pub struct IdleOutputTimeout {
+    pub timeout: Duration,
+    pub grace: Duration,
+}
+
+// How a run is set up and what is done with its output besides collecting it
+// into the result
+#[derive(Clone, Copy, Default)]
+pub struct RunOptions<'a> {
+    // Set over the default environment, below what the sandbox manages
+    pub env: Option<&'a SandboxEnv>,
+    // Given the output as it is produced
+    pub on_output: Option<&'a OutputSink<'a>>,
+    pub idle_timeout: Option<IdleOutputTimeout>,
+    // Bytes kept of each stream, the rest is read and thrown away
+    pub max_bytes: Option<usize>,
+    // Kills the program once either stream goes over `max_bytes`, instead of
+    // letting it run to its end
+    pub kill_on_limit: bool,
+    // Of the output in the result and the one given to `on_output`
+    pub encoding: OutputEncoding,
+    // Written to the cpu.weight of the box once the program has started
+    pub cpu_weight: Option<u32>,
+    // Lets the program dump a core up to this size
+    pub core_size: Option<Kilobytes>,
+}
+
+// Why a program was killed before it ended by itself
+enum KillReason {
+    Silence,
+    OutputLimit,
+}
+
+// What was kept of a stream
+struct CapturedOutput {
+    bytes: Vec<u8>,
+    truncated: bool,
+    // Of the whole stream
+    sha256: String,
+}
+
+// When the last output of a run arrived, in milliseconds since it started
+struct OutputClock {
+    started: Instant,
+    last_output_ms: AtomicU64,
+}
+
+impl OutputClock {
+    fn new() -> OutputClock {
+        OutputClock {
+            started: Instant::now(),
+            last_output_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn tick(&self) {
+        self.last_output_ms
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // Returns once the run has been silent for longer than allowed
+    async fn wait_for_silence(&self, idle_timeout: IdleOutputTimeout) {
+        loop {
+            let running_for = self.started.elapsed();
+            let silent_for = running_for.saturating_sub(Duration::from_millis(
+                self.last_output_ms.load(Ordering::Relaxed),
+            ));
+            if silent_for >= idle_timeout.timeout && running_for >= idle_timeout.grace {
+                return;
+            }
+            let wait = (idle_timeout.timeout.saturating_sub(silent_for))
+                .max(idle_timeout.grace.saturating_sub(running_for))
+                .max(MIN_SILENCE_CHECK_INTERVAL);
+            time::sleep(wait).await;
+        }
+    }
 }
 
+const OUTPUT_CHUNK_SIZE: usize = 8192;
+const MIN_SILENCE_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
 const ISOLATE_PATH: &str = "/usr/local/bin/isolate";
+const CONFIG_FILE_ENV_VAR: &str = "ISOLATE_CONFIG_FILE";
+const DEFAULT_CONFIG_FILE: &str = "/usr/local/etc/isolate";
+const DEFAULT_SANDBOX_ERROR_RETRIES: u32 = 2;
+
+// Set once at startup, lets several instances on one host use separate box roots
+static CONFIG_FILE: OnceLock<String> = OnceLock::new();
+static RSS_FALLBACK_LOGGED: AtomicBool = AtomicBool::new(false);
+// Set once at startup, what every stage starts with
+static DEFAULT_ENV: OnceLock<SandboxEnv> = OnceLock::new();
+// Set once at startup, how many times a stage is run again when isolate fails
+static SANDBOX_ERROR_RETRIES: OnceLock<u32> = OnceLock::new();
+// Set once at startup, how the output of runs that aren't streamed is captured
+static OUTPUT_CAPTURE: OnceLock<OutputCapture> = OnceLock::new();
+
+// Where the output of a run goes, in its working directory, when captured to
+// files. They are removed once read, no listing of the box ever has them
+pub const STDOUT_CAPTURE_FILE: &str = ".envicutor_stdout";
+pub const STDERR_CAPTURE_FILE: &str = ".envicutor_stderr";
+// Where the stage loader writes when it starts the program, as Unix seconds
+pub const ACTIVATION_MARKER_FILE: &str = ".envicutor_activated";
+
+// A program killed mid-run (e.g. by the OOM killer) may leave output in a pipe
+// that is lost with it, what it wrote to a file is kept. Files can't be read
+// as they are written, so runs streaming their output or watching it for
+// silence or its size always use pipes. Being files, the output is bound by
+// the stage's max_file_size, the program is stopped by SIGXFSZ past it, so
+// pipes are the default
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutputCapture {
+    Pipes,
+    Files,
+}
+
+impl FromStr for OutputCapture {
+    type Err = Error;
+
+    fn from_str(capture: &str) -> Result<OutputCapture, Error> {
+        match capture {
+            "pipes" => Ok(OutputCapture::Pipes),
+            "files" => Ok(OutputCapture::Files),
+            _ => Err(anyhow!(
+                "Unknown output capture {capture}, expected pipes or files"
+            )),
+        }
+    }
+}
+
+// The files the server leaves in the working directory of a stage
+pub fn is_reserved_file(name: &str) -> bool {
+    [
+        STDOUT_CAPTURE_FILE,
+        STDERR_CAPTURE_FILE,
+        ACTIVATION_MARKER_FILE,
+    ]
+    .contains(&name)
+}
+
+// isolate failing by itself (status XX), a busy box or a control group that
+// couldn't be set up, which says nothing about the program it was to run
+#[derive(Debug)]
+pub struct SandboxError {
+    pub message: String,
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "isolate failed: {}", self.message)
+    }
+}
+
+impl std::error::Error for SandboxError {}
+
+pub fn is_sandbox_error(error: &Error) -> bool {
+    error.downcast_ref::<SandboxError>().is_some()
+}
+
+pub fn use_config_file(path: String) -> Result<(), Error> {
+    CONFIG_FILE
+        .set(path)
+        .map_err(|_| anyhow!("The isolate config file was already set"))
+}
+
+pub fn use_default_env(env: SandboxEnv) -> Result<(), Error> {
+    DEFAULT_ENV
+        .set(env)
+        .map_err(|_| anyhow!("The default sandbox environment was already set"))
+}
+
+pub fn use_sandbox_error_retries(retries: u32) -> Result<(), Error> {
+    SANDBOX_ERROR_RETRIES
+        .set(retries)
+        .map_err(|_| anyhow!("The sandbox error retries were already set"))
+}
+
+pub fn sandbox_error_retries() -> u32 {
+    SANDBOX_ERROR_RETRIES
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_SANDBOX_ERROR_RETRIES)
+}
+
+pub fn use_output_capture(capture: OutputCapture) -> Result<(), Error> {
+    OUTPUT_CAPTURE
+        .set(capture)
+        .map_err(|_| anyhow!("The output capture was already set"))
+}
+
+fn output_capture() -> OutputCapture {
+    OUTPUT_CAPTURE
+        .get()
+        .copied()
+        .unwrap_or(OutputCapture::Pipes)
+}
+
+pub fn config_file() -> &'static str {
+    CONFIG_FILE
+        .get()
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_CONFIG_FILE)
+}
+
+fn isolate_command() -> Command {
+    let mut cmd = Command::new(ISOLATE_PATH);
+    cmd.env_clear();
+    if let Some(config_file) = CONFIG_FILE.get() {
+        cmd.env(CONFIG_FILE_ENV_VAR, config_file);
+    }
+    cmd
+}
 
 fn split_metadata_line(line: &str) -> (Result<&str, ()>, Result<&str, ()>) {
     let mut entry: Vec<&str> = line.split(':').collect();
@@ -51,85 +400,129 @@ fn split_metadata_line(line: &str) -> (Result<&str, ()>, Result<&str, ()>) {
     (key, value)
 }
 
-async fn add_env_vars_from_file(cmd: &mut Command, file_path: &str) -> Result<(), Error> {
-    let env = fs::read_to_string(file_path)
-        .await
-        .map_err(|e| anyhow!("Failed to read environment variables from: {file_path}: {e}"))?;
-    let lines = env.lines();
-
-    let mut line_count = 0;
-    let mut key = String::new();
-    let mut value = String::new();
-    for line in lines {
-        if line.contains('=') {
-            if !key.is_empty() {
-                cmd.env(&key, &value);
-            }
-            let mut entry: Vec<&str> = line.split('=').collect();
-            value = match entry.pop() {
-                Some(e) => e.to_string(),
-                None => {
-                    return Err(anyhow!("Found a bad line in the env file: {file_path}"));
-                }
-            };
-            key = match entry.pop() {
-                Some(e) => e.to_string(),
-                None => {
-                    return Err(anyhow!("Found a bad line in the env file: {file_path}"));
-                }
-            };
-        } else {
-            value.push('\n');
-            value.push_str(line);
-        }
-        line_count += 1;
-        if line_count % 500 == 0 {
-            yield_now().await;
-        }
+impl Isolate {
+    // Initializes and cleans up a box that executions never use (get_next_box_id stays
+    // below MAX_BOX_ID), to fail at startup if the configured box root is unusable
+    pub async fn probe() -> Result<(), Error> {
+        let probe = Isolate::init(MAX_BOX_ID).await?;
+        let res = fs::metadata(&probe.box_dir)
+            .await
+            .map_err(|e| anyhow!("The box directory {} is unusable: {e}", probe.box_dir));
+        probe.close().await?;
+        res.map(|_| ())
     }
-    cmd.env(&key, &value);
-    Ok(())
-}
 
-impl Isolate {
+    // Failures count towards quarantining the box id
     pub async fn init(box_id: u64) -> Result<Self, Error> {
-        let res = Command::new(ISOLATE_PATH)
+        let started = Instant::now();
+        let res = isolate_command()
             .args(["--init", "--cg", &format!("-b{}", box_id)])
             .output()
             .await
             .map_err(|e| anyhow!("Failed to get `isolate --init` output\nError: {e}"))?;
         if !res.status.success() {
-            return Err(anyhow!(
+            let error = anyhow!(
                 "`isolate --init` failed with\nstderr: {}\nstdout: {}",
                 String::from_utf8_lossy(&res.stderr),
                 String::from_utf8_lossy(&res.stdout),
-            ));
+            );
+            quarantine::record_init_failure(box_id, &error.to_string()).await;
+            return Err(error);
         }
+        quarantine::record_init_success(box_id);
         Ok(Isolate {
             box_id,
             metadata_file_path: format!("{TEMP_DIR}/{box_id}-metadata.txt"),
             run_pid: None,
+            init_duration: Some(started.elapsed()),
+            closed: false,
             box_dir: format!("{}/box", String::from_utf8_lossy(&res.stdout).trim()),
         })
     }
 
+    pub fn box_id(&self) -> u64 {
+        self.box_id
+    }
+
+    // Cleans up the box before returning, for the boxes that are used again
+    // right away. Dropping an Isolate only cleans up in the background
+    pub async fn close(mut self) -> Result<(), Error> {
+        self.closed = true;
+        if let Some(run_pid) = self.run_pid.take() {
+            kill_run(run_pid).await;
+        }
+        let res = Isolate::cleanup(self.box_id).await;
+        remove_metadata_file(&self.metadata_file_path).await;
+        res
+    }
+
+    pub async fn cleanup(box_id: u64) -> Result<(), Error> {
+        let res = isolate_command()
+            .args(["--cleanup", "--cg", &format!("-b{}", box_id)])
+            .output()
+            .await
+            .map_err(|e| anyhow!("Failed to run `isolate --cleanup`\nError: {e}"))?;
+        if !res.status.success() {
+            return Err(anyhow!(
+                "`isolate --cleanup` failed with\nstderr: {}\nstdout: {}",
+                String::from_utf8_lossy(&res.stderr),
+                String::from_utf8_lossy(&res.stdout)
+            ));
+        }
+        Ok(())
+    }
+
     pub async fn run(
         &mut self,
         mounts: &[&str],
         limits: &MandatoryLimits,
         stdin: Option<&str>,
         workdir: &str,
-        env_file: Option<&str>,
         cmd_args: &[&str],
     ) -> Result<StageResult, Error> {
-        let mut cmd = Command::new(ISOLATE_PATH);
+        self.run_with_output(
+            mounts,
+            limits,
+            stdin,
+            workdir,
+            cmd_args,
+            RunOptions::default(),
+        )
+        .await
+    }
+
+    pub async fn run_with_output(
+        &mut self,
+        mounts: &[&str],
+        limits: &MandatoryLimits,
+        stdin: Option<&str>,
+        workdir: &str,
+        cmd_args: &[&str],
+        run_options: RunOptions<'_>,
+    ) -> Result<StageResult, Error> {
+        // Stages load their environment themselves, nothing is inherited from the server's
+        // The working directory within the box, where the server's files go
+        let box_workdir = workdir
+            .strip_prefix("/box")
+            .filter(|relative| relative.is_empty() || relative.starts_with('/'));
+        let capture_to_files = box_workdir.is_some()
+            && output_capture() == OutputCapture::Files
+            && run_options.on_output.is_none()
+            && run_options.idle_timeout.is_none()
+            && !run_options.kill_on_limit;
+        let mut cmd = isolate_command();
         cmd.arg("--run")
-            .arg(&format!("--meta={}", self.metadata_file_path))
+            .arg(format!("--meta={}", self.metadata_file_path))
             .arg("--cg")
             .arg("-s")
-            .args(["-c", workdir])
-            .arg("-e")
-            .args(["-E", "HOME=/tmp"]);
+            .args(["-c", workdir]);
+
+        let sandbox_managed = SandboxEnv::from([("HOME".to_string(), "/tmp".to_string())]);
+        let default_env = DEFAULT_ENV.get().cloned().unwrap_or_default();
+        let request_env = run_options.env.cloned().unwrap_or_default();
+        for (key, value) in sandbox_env::stage_env(&default_env, &request_env, &sandbox_managed) {
+            cmd.arg("-E").arg(format!("{key}={value}"));
+        }
 
         for dir in mounts {
             cmd.arg(format!("--dir={}", dir));
@@ -142,14 +535,15 @@ impl Isolate {
             .arg(format!("--open-files={}", limits.max_open_files))
             .arg(format!("--fsize={}", limits.max_file_size))
             .arg(format!("--processes={}", limits.max_number_of_processes))
+            .args(run_options.core_size.map(|size| format!("--core={size}")))
+            .args(capture_to_files.then(|| format!("--stdout={STDOUT_CAPTURE_FILE}")))
+            .args(capture_to_files.then(|| format!("--stderr={STDERR_CAPTURE_FILE}")))
             .arg(format!("-b{}", self.box_id))
             .arg("--")
             .args(cmd_args);
 
-        if let Some(env_file) = env_file {
-            add_env_vars_from_file(cmd.env_clear(), env_file).await?;
-        }
-
+        let spawned_at = SystemTime::now();
+        let started_at = clock::now();
         let mut child = cmd
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
@@ -160,29 +554,163 @@ impl Isolate {
         if let Some(pid) = child.id() {
             self.run_pid = Some(pid);
         }
-        if let Some(stdin) = stdin {
-            if let Some(mut stdin_handle) = child.stdin.take() {
-                stdin_handle
-                    .write_all(stdin.as_bytes())
-                    .await
-                    .map_err(|e| anyhow!("Failed to write to child process stdin: {e}"))?;
+        // Written while the output is read, a program writing more than the pipe
+        // holds before reading all of its input would otherwise never finish.
+        // The handle is dropped once written, so the program sees the end of its input
+        let stdin_handle = child.stdin.take();
+        let write_stdin = async move {
+            match (stdin, stdin_handle) {
+                (Some(stdin), Some(mut stdin_handle)) => {
+                    match stdin_handle.write_all(stdin.as_bytes()).await {
+                        // The program exited or closed its stdin without reading all of it
+                        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+                        res => res,
+                    }
+                }
+                _ => Ok(()),
             }
-        }
-        let cmd_res = child
-            .wait_with_output()
-            .await
+        };
+        let clock = OutputClock::new();
+        let limit_reached = Notify::new();
+        let (stdout_handle, stderr_handle) = (child.stdout.take(), child.stderr.take());
+        let box_id = self.box_id;
+        let weigh = async {
+            let weight = run_options.cpu_weight?;
+            let cgroup_path = cgroup::box_cgroup_path(box_id)?;
+            match cgroup::set_cpu_weight(&cgroup_path, weight).await {
+                Ok(()) => Some(weight),
+                Err(e) => {
+                    eprintln!("Failed to set the CPU weight of box {box_id}: {e}");
+                    None
+                }
+            }
+        };
+        let pipes = async {
+            tokio::join!(
+                write_stdin,
+                read_output(
+                    stdout_handle,
+                    OutputStream::Stdout,
+                    &run_options,
+                    &clock,
+                    &limit_reached
+                ),
+                read_output(
+                    stderr_handle,
+                    OutputStream::Stderr,
+                    &run_options,
+                    &clock,
+                    &limit_reached
+                )
+            )
+        };
+        // A program ending before its control group was seen runs at the default weight
+        let io = async {
+            tokio::pin!(pipes, weigh);
+            tokio::select! {
+                res = &mut pipes => (res, None),
+                cpu_weight = &mut weigh => (pipes.await, cpu_weight),
+            }
+        };
+        tokio::pin!(io);
+        let watchdog = async {
+            let silence = async {
+                match run_options.idle_timeout {
+                    Some(idle_timeout) => clock.wait_for_silence(idle_timeout).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = silence => KillReason::Silence,
+                _ = limit_reached.notified() => KillReason::OutputLimit,
+            }
+        };
+        let mut no_output_timeout = false;
+        let ((write_res, stdout_res, stderr_res), cpu_weight) = tokio::select! {
+            res = &mut io => res,
+            reason = watchdog => {
+                // The pipes close once the program is gone
+                let killed = match cgroup::box_cgroup_path(self.box_id) {
+                    Some(cgroup_path) => cgroup::kill_all(&cgroup_path).await,
+                    None => Err(anyhow!("The control group of box {} is unknown", self.box_id)),
+                };
+                match (reason, killed) {
+                    (KillReason::Silence, Ok(())) => no_output_timeout = true,
+                    (KillReason::OutputLimit, Ok(())) => {}
+                    (KillReason::Silence, Err(e)) => {
+                        eprintln!("Failed to kill a program that went silent: {e}")
+                    }
+                    (KillReason::OutputLimit, Err(e)) => {
+                        eprintln!("Failed to kill a program that went over the output limit: {e}")
+                    }
+                }
+                io.await
+            }
+        };
+        write_res.map_err(|e| anyhow!("Failed to write to child process stdin: {e}"))?;
+        let (mut stdout, mut stderr) = stdout_res
+            .and_then(|stdout| Ok((stdout, stderr_res?)))
             .map_err(|e| anyhow!("Failed to get `isolate --run` output\nError: {e}"))?;
+        child
+            .wait()
+            .await
+            .map_err(|e| anyhow!("Failed to wait for `isolate --run`\nError: {e}"))?;
         self.run_pid = None;
+        let ended_at = SystemTime::now();
+        let finished_at = clock::now();
+        // The program could have removed its working directory or swapped it
+        // for a symlink, its output and when it started are then lost
+        let box_workdir = match box_workdir {
+            Some(relative) => crate::fs::resolve_in_box(&self.box_dir, relative)
+                .await
+                .map_err(|e| eprintln!("Failed to find the working directory of the stage: {e}"))
+                .ok(),
+            None => None,
+        };
+        // The pipes then only have what isolate itself wrote, which it also
+        // reports in the metadata file
+        if capture_to_files {
+            let dir = box_workdir.as_deref();
+            stdout = read_capture_file(dir, STDOUT_CAPTURE_FILE, limits, &run_options).await?;
+            stderr = read_capture_file(dir, STDERR_CAPTURE_FILE, limits, &run_options).await?;
+        }
+        let activated_at = match &box_workdir {
+            Some(dir) => read_activation_marker(dir).await,
+            None => None,
+        };
+        let total = ended_at.duration_since(spawned_at).unwrap_or_default();
+        let activation = activated_at.map(|activated_at| {
+            activated_at
+                .duration_since(spawned_at)
+                .unwrap_or_default()
+                .min(total)
+        });
+        let timing = StageTiming {
+            sandbox_init_ms: self
+                .init_duration
+                .take()
+                .map(|duration| duration.as_millis() as u64),
+            environment_activation_ms: activation.map(|duration| duration.as_millis() as u64),
+            program_ms: (total - activation.unwrap_or_default()).as_millis() as u64,
+        };
 
         let mut memory: Option<Kilobytes> = None;
+        let mut max_rss: Option<Kilobytes> = None;
         let mut exit_code: Option<u32> = None;
         let mut exit_signal: Option<u32> = None;
         let mut exit_message: Option<String> = None;
         let mut exit_status: Option<String> = None;
+        let mut oom_killed = false;
         let mut cpu_time: Option<Seconds> = None;
         let mut wall_time: Option<Seconds> = None;
-        let stdout = String::from_utf8_lossy(&cmd_res.stdout).to_string();
-        let stderr = String::from_utf8_lossy(&cmd_res.stderr).to_string();
+        let (stdout_truncated, stderr_truncated) = (stdout.truncated, stderr.truncated);
+        let returned_sha256 =
+            |output: &CapturedOutput| output.truncated.then(|| digest::sha256(&output.bytes));
+        let (returned_stdout_sha256, returned_stderr_sha256) =
+            (returned_sha256(&stdout), returned_sha256(&stderr));
+        let (stdout_sha256, stderr_sha256) = (stdout.sha256, stderr.sha256);
+        let stdout = StageOutput::new(stdout.bytes, run_options.encoding);
+        let stderr = StageOutput::new(stderr.bytes, run_options.encoding);
 
         let metadata_str = fs::read_to_string(&self.metadata_file_path)
             .await
@@ -191,8 +719,8 @@ impl Isolate {
                     "Error reading metadata file: {}\nError: {}\nIsolate run stdout: {}\nIsolate run stderr: {}",
                     self.metadata_file_path,
                     e,
-                    stdout,
-                    stderr
+                    stdout.text(),
+                    stderr.text()
                 )
             })?;
         let metadata_lines = metadata_str.lines();
@@ -208,6 +736,13 @@ impl Isolate {
                         anyhow!("Failed to parse memory usage, received value: {value}")
                     })?)
                 }
+                "cg-oom-killed" => oom_killed = value == "1",
+                "max-rss" => {
+                    max_rss =
+                        Some(value.parse().map_err(|_| {
+                            anyhow!("Failed to parse max rss, received value: {value}")
+                        })?)
+                }
                 "exitcode" => {
                     exit_code = Some(value.parse().map_err(|_| {
                         anyhow!("Failed to parse exit code, received value: {value}")
@@ -235,71 +770,348 @@ impl Isolate {
         }
 
         if exit_status == Some("XX".to_string()) {
-            return Err(anyhow!(
-                "Failed to run isolate --run\nstdout: {}\nstderr: {}",
-                stdout,
-                stderr
-            ));
+            return Err(SandboxError {
+                message: format!(
+                    "{}\nstdout: {}\nstderr: {}",
+                    exit_message.as_deref().unwrap_or("no message"),
+                    stdout.text(),
+                    stderr.text()
+                ),
+            }
+            .into());
         }
+        // Older isolate versions don't report OOM kills, a SIGKILL with the
+        // control group at its limit is taken as one
+        let memory_limit_exceeded = oom_killed
+            || (exit_signal == Some(libc::SIGKILL as u32)
+                && memory.is_some_and(|memory| memory >= limits.memory));
+        let memory_source = match (memory, max_rss) {
+            (Some(_), _) => MemorySource::Cgroup,
+            (None, Some(_)) => {
+                if !RSS_FALLBACK_LOGGED.swap(true, Ordering::Relaxed) {
+                    eprintln!("isolate reports no cgroup memory usage, falling back to max-rss");
+                }
+                MemorySource::Rss
+            }
+            (None, None) => MemorySource::Unavailable,
+        };
+        // isolate keeps the control group of the box until the next run or cleanup
+        let cpu_usage = match cgroup::box_cgroup_path(self.box_id) {
+            Some(cgroup_path) => cgroup::read_cpu_usage(&cgroup_path).await,
+            None => None,
+        };
         let result = StageResult {
+            stage: None,
+            limit_set: None,
             cpu_time,
+            cpu_user_ms: cpu_usage.map(|usage| usage.user_usec / 1000),
+            cpu_system_ms: cpu_usage.map(|usage| usage.system_usec / 1000),
+            cpu_weight,
             exit_code,
             exit_message,
             exit_signal,
+            exit_signal_name: exit_signal.and_then(signal_name),
+            exit_kind: ExitKind::classify(exit_status.as_deref(), exit_code, exit_signal),
             exit_status,
-            memory,
+            memory: memory.or(max_rss),
+            memory_source,
+            memory_limit_exceeded,
             stderr,
             stdout,
+            output_encoding: run_options.encoding,
             wall_time,
+            no_output_timeout,
+            stdout_truncated,
+            stderr_truncated,
+            stdout_sha256,
+            stderr_sha256,
+            returned_stdout_sha256,
+            returned_stderr_sha256,
+            timing,
+            started_at: clock::rfc3339_millis(started_at),
+            finished_at: clock::rfc3339_millis(finished_at),
         };
 
         Ok(result)
     }
 }
 
+// Reads a pipe to its end. Chunks are given to `on_output` as they come, up to
+// the last complete UTF-8 character, so characters are never split between them.
+// Only the first `max_bytes` are kept and given, `limit_reached` is notified once
+// they are exceeded when the program is to be killed for it
+async fn read_output(
+    pipe: Option<impl AsyncRead + Unpin>,
+    stream: OutputStream,
+    options: &RunOptions<'_>,
+    clock: &OutputClock,
+    limit_reached: &Notify,
+) -> Result<CapturedOutput, io::Error> {
+    let mut output = Vec::new();
+    let mut truncated = false;
+    let mut hasher = Sha256::new();
+    let Some(mut pipe) = pipe else {
+        return Ok(CapturedOutput {
+            bytes: output,
+            truncated,
+            sha256: hasher.finish_hex(),
+        });
+    };
+    let max_bytes = options.max_bytes.unwrap_or(usize::MAX);
+    let mut chunk = vec![0; OUTPUT_CHUNK_SIZE];
+    let mut sent = 0;
+    loop {
+        let read = pipe.read(&mut chunk).await?;
+        if read > 0 {
+            clock.tick();
+            hasher.update(&chunk[..read]);
+        }
+        let kept = read.min(max_bytes - output.len());
+        if kept < read && !truncated {
+            truncated = true;
+            if options.kill_on_limit {
+                limit_reached.notify_one();
+            }
+        }
+        output.extend_from_slice(&chunk[..kept]);
+        if let Some(on_output) = options.on_output {
+            let unsent = &output[sent..];
+            // An incomplete character at the end waits for the next chunk, unless none comes
+            let end = match (options.encoding, std::str::from_utf8(unsent)) {
+                (OutputEncoding::Utf8, Err(e))
+                    if read != 0 && !truncated && e.error_len().is_none() =>
+                {
+                    e.valid_up_to()
+                }
+                _ => unsent.len(),
+            };
+            if end > 0 {
+                on_output(stream, &options.encoding.encode(&unsent[..end]));
+                sent += end;
+            }
+        }
+        if read == 0 {
+            break;
+        }
+    }
+    Ok(CapturedOutput {
+        bytes: output,
+        truncated,
+        sha256: hasher.finish_hex(),
+    })
+}
+
+// Reads and removes the time the stage loader started the program at. A
+// program could write anything there, but only to skew its own timing
+async fn read_activation_marker(dir: &str) -> Option<SystemTime> {
+    let path = format!("{dir}/{ACTIVATION_MARKER_FILE}");
+    let content = match crate::fs::read_file_from_box(dir, ACTIVATION_MARKER_FILE, 64).await {
+        Ok(BoxFile::Read { content, .. }) => Some(content),
+        Ok(_) => None,
+        Err(e) => {
+            eprintln!("Failed to read {path}: {e}");
+            None
+        }
+    };
+    if let Err(e) = fs::remove_file(&path).await {
+        if e.kind() != ErrorKind::NotFound {
+            eprintln!("Failed to remove {path}\nError: {e}");
+        }
+    }
+    // $EPOCHREALTIME follows the locale's decimal separator
+    let secs: f64 = String::from_utf8(content?)
+        .ok()?
+        .trim()
+        .replace(',', ".")
+        .parse()
+        .ok()?;
+    (secs.is_finite() && secs >= 0.0).then(|| UNIX_EPOCH + Duration::from_secs_f64(secs))
+}
+
+// Reads and removes a file the output of a run was captured to. The program
+// could have replaced it with anything, symlinks are not followed and what
+// isn't a regular file counts as no output. A file as large as the stage's
+// max_file_size is where the program was stopped writing, so it's truncated
+async fn read_capture_file(
+    dir: Option<&str>,
+    name: &str,
+    limits: &MandatoryLimits,
+    options: &RunOptions<'_>,
+) -> Result<CapturedOutput, Error> {
+    let mut output = Vec::new();
+    let mut truncated = false;
+    let mut size: u64 = 0;
+    let mut hasher = Sha256::new();
+    let Some(dir) = dir else {
+        return Ok(CapturedOutput {
+            bytes: output,
+            truncated,
+            sha256: hasher.finish_hex(),
+        });
+    };
+    let path = format!("{dir}/{name}");
+    let opened = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(&path)
+        .await;
+    let file = match opened {
+        Ok(file) => Some(file),
+        Err(e) if e.kind() == ErrorKind::NotFound || e.raw_os_error() == Some(libc::ELOOP) => None,
+        Err(e) => return Err(anyhow!("Failed to open {path}\nError: {e}")),
+    };
+    let file = match file {
+        Some(file) => file
+            .metadata()
+            .await
+            .map_err(|e| anyhow!("Failed to stat {path}\nError: {e}"))?
+            .is_file()
+            .then_some(file),
+        None => None,
+    };
+    if let Some(mut file) = file {
+        let max_bytes = options.max_bytes.unwrap_or(usize::MAX);
+        let mut chunk = vec![0; OUTPUT_CHUNK_SIZE];
+        loop {
+            let read = file
+                .read(&mut chunk)
+                .await
+                .map_err(|e| anyhow!("Failed to read {path}\nError: {e}"))?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&chunk[..read]);
+            size += read as u64;
+            let kept = read.min(max_bytes - output.len());
+            truncated |= kept < read;
+            output.extend_from_slice(&chunk[..kept]);
+        }
+        truncated |= size >= u64::from(limits.max_file_size) * 1024;
+    }
+    match fs::remove_file(&path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(anyhow!("Failed to remove {path}\nError: {e}"))
+        }
+        _ => {}
+    }
+    Ok(CapturedOutput {
+        bytes: output,
+        truncated,
+        sha256: hasher.finish_hex(),
+    })
+}
+
+// Of a run that didn't finish, e.g. its request went away
+async fn kill_run(run_pid: u32) {
+    let kill_res = Command::new("/bin/kill")
+        .arg("-SIGABRT")
+        .arg(run_pid.to_string())
+        .output()
+        .await;
+    if let Err(e) = kill_res {
+        eprintln!("Could not kill `isolate --run` process. Maybe it has already exited: {e}");
+    }
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+async fn remove_metadata_file(path: &str) {
+    if let Err(e) = fs::remove_file(path).await {
+        if e.kind() != io::ErrorKind::NotFound {
+            eprintln!("Failed to remove: {path}\nError: {e}");
+        }
+    }
+}
+
 impl Drop for Isolate {
     fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
         let box_id = self.box_id;
         let metadata_file_path = self.metadata_file_path.clone();
         let run_pid_opt = self.run_pid;
         tokio::spawn(async move {
             if let Some(run_pid) = run_pid_opt {
-                let kill_res = Command::new("/bin/kill")
-                    .arg("-SIGABRT")
-                    .arg(run_pid.to_string())
-                    .output()
-                    .await;
-                if let Err(e) = kill_res {
-                    eprintln!(
-                        "Could not kill `isolate --run` process. Maybe it has already exited: {e}"
-                    );
-                }
-                time::sleep(Duration::from_millis(50)).await;
-            }
-            let res = Command::new(ISOLATE_PATH)
-                .args(["--cleanup", "--cg", &format!("-b{}", box_id)])
-                .output()
-                .await;
-            match res {
-                Ok(res) => {
-                    if !res.status.success() {
-                        eprintln!(
-                            "`isolate --cleanup` failed with\nstderr: {}\nstdout: {}",
-                            String::from_utf8_lossy(&res.stderr),
-                            String::from_utf8_lossy(&res.stdout)
-                        );
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Failed to run `isolate --cleanup`\nError: {e}");
-                }
+                kill_run(run_pid).await;
             }
-            let res = fs::remove_file(&metadata_file_path).await;
-            if let Err(e) = res {
-                if e.kind() != io::ErrorKind::NotFound {
-                    eprintln!("Failed to remove: {metadata_file_path}\nError: {e}");
-                }
+            if let Err(e) = Isolate::cleanup(box_id).await {
+                eprintln!("{e}");
             }
+            remove_metadata_file(&metadata_file_path).await;
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_clean_exit_is_ok() {
+        assert_eq!(ExitKind::classify(None, Some(0), None), ExitKind::Ok);
+        assert_eq!(ExitKind::classify(None, None, None), ExitKind::Ok);
+    }
+
+    #[test]
+    fn time_limits_are_timeouts_however_the_program_ended() {
+        assert_eq!(
+            ExitKind::classify(Some("TO"), None, None),
+            ExitKind::TimedOut
+        );
+        assert_eq!(
+            ExitKind::classify(Some("TO"), None, Some(9)),
+            ExitKind::TimedOut
+        );
+        assert_eq!(
+            ExitKind::classify(Some("TO"), Some(1), None),
+            ExitKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn isolate_failing_is_a_sandbox_error() {
+        assert_eq!(
+            ExitKind::classify(Some("XX"), None, None),
+            ExitKind::SandboxError
+        );
+        assert_eq!(
+            ExitKind::classify(Some("XX"), Some(1), Some(9)),
+            ExitKind::SandboxError
+        );
+    }
+
+    #[test]
+    fn signals_come_before_exit_codes() {
+        assert_eq!(
+            ExitKind::classify(Some("SG"), None, Some(11)),
+            ExitKind::Signalled
+        );
+        assert_eq!(
+            ExitKind::classify(Some("SG"), None, None),
+            ExitKind::Signalled
+        );
+        // A signal without a status, e.g. the program killed itself
+        assert_eq!(ExitKind::classify(None, None, Some(9)), ExitKind::Signalled);
+        assert_eq!(
+            ExitKind::classify(Some("RE"), Some(1), Some(6)),
+            ExitKind::Signalled
+        );
+    }
+
+    #[test]
+    fn non_zero_exits() {
+        assert_eq!(
+            ExitKind::classify(Some("RE"), Some(1), None),
+            ExitKind::NonZeroExit
+        );
+        // RE without an exit code still means the program failed
+        assert_eq!(
+            ExitKind::classify(Some("RE"), None, None),
+            ExitKind::NonZeroExit
+        );
+        assert_eq!(
+            ExitKind::classify(None, Some(3), None),
+            ExitKind::NonZeroExit
+        );
+    }
+}
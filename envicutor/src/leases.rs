@@ -0,0 +1,353 @@
+// The execution semaphore, along with who holds its permits and since when. A
+// permit held far longer than any execution can take is a leak, which can't be
+// taken back but is logged and reported in the status. Permits are given in
+// order of priority, then of arrival. Batch executions that waited longer than
+// the aging period are served as interactive ones, so they are never starved.
+// With a maximum queue length, requests arriving to a full queue are turned
+// away rather than left waiting for the client to time out
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use tokio::sync::{Notify, Semaphore, SemaphorePermit, TryAcquireError};
+
+use crate::priority::Priority;
+
+struct Waiter {
+    priority: Priority,
+    queued_at: Instant,
+}
+
+struct LeaseRecord {
+    permits: u32,
+    box_id: Option<u64>,
+    acquired_at: Instant,
+    reported: bool,
+}
+
+pub struct ExecutionSlots {
+    semaphore: Semaphore,
+    permits: usize,
+    max_queue_length: Option<usize>,
+    leases: Mutex<HashMap<u64, LeaseRecord>>,
+    next_lease_id: AtomicU64,
+    // By arrival
+    waiters: Mutex<BTreeMap<u64, Waiter>>,
+    next_waiter_id: AtomicU64,
+    // Notified when permits are returned or a waiter leaves
+    changed: Notify,
+    aging: Duration,
+}
+
+// Takes its place out of the queue when the request stops waiting
+struct QueuePlace<'a> {
+    slots: &'a ExecutionSlots,
+    id: u64,
+}
+
+impl Drop for QueuePlace<'_> {
+    fn drop(&mut self) {
+        self.slots.lock_waiters().remove(&self.id);
+        self.slots.changed.notify_waiters();
+    }
+}
+
+// Returns its permits and forgets its record when dropped
+pub struct Lease<'a> {
+    slots: &'a ExecutionSlots,
+    id: u64,
+    // Taken back on drop, before the waiters are told
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+// The queue was full when the request arrived
+#[derive(Debug)]
+pub struct QueueFull {
+    pub queue_depth: usize,
+    pub permits: usize,
+}
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requests are already waiting for the {} execution permits",
+            self.queue_depth, self.permits
+        )
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+#[derive(Serialize)]
+pub struct QueueStatus {
+    pub permits: usize,
+    pub permits_in_use: usize,
+    pub queue_depth: usize,
+    pub max_queue_length: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct StaleLease {
+    pub permits: u32,
+    pub box_id: Option<u64>,
+    pub age_secs: u64,
+}
+
+#[derive(Serialize)]
+pub struct LeaseStatus {
+    pub active: usize,
+    pub permits_held: u32,
+    pub available_permits: usize,
+    pub waiting_interactive: usize,
+    pub waiting_batch: usize,
+    pub max_queue_length: Option<usize>,
+    pub stale: Vec<StaleLease>,
+}
+
+impl ExecutionSlots {
+    pub fn new(permits: usize, max_queue_length: Option<usize>, aging: Duration) -> ExecutionSlots {
+        ExecutionSlots {
+            semaphore: Semaphore::new(permits),
+            permits,
+            max_queue_length,
+            leases: Mutex::new(HashMap::new()),
+            next_lease_id: AtomicU64::new(0),
+            waiters: Mutex::new(BTreeMap::new()),
+            next_waiter_id: AtomicU64::new(0),
+            changed: Notify::new(),
+            aging,
+        }
+    }
+
+    fn lock_waiters(&self) -> MutexGuard<'_, BTreeMap<u64, Waiter>> {
+        self.waiters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // The waiter served next: the first interactive one, counting batch ones
+    // that waited past the aging period, else the first batch one
+    fn next_waiter(&self, waiters: &BTreeMap<u64, Waiter>) -> Option<u64> {
+        waiters
+            .iter()
+            .find(|(_, waiter)| {
+                waiter.priority == Priority::Interactive || waiter.queued_at.elapsed() >= self.aging
+            })
+            .or_else(|| waiters.iter().next())
+            .map(|(id, _)| *id)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, LeaseRecord>> {
+        // Records are inserted and removed whole, a poisoned lock is still usable
+        self.leases.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lease<'a>(&'a self, permits: u32, permit: SemaphorePermit<'a>) -> Lease<'a> {
+        let id = self.next_lease_id.fetch_add(1, Ordering::Relaxed);
+        self.lock().insert(
+            id,
+            LeaseRecord {
+                permits,
+                box_id: None,
+                acquired_at: Instant::now(),
+                reported: false,
+            },
+        );
+        Lease {
+            slots: self,
+            id,
+            permit: Some(permit),
+        }
+    }
+
+    // A bounded request is turned away with QueueFull when the queue is full
+    pub async fn acquire(
+        &self,
+        permits: u32,
+        priority: Priority,
+        bounded: bool,
+    ) -> Result<Lease<'_>, Error> {
+        let id = self.next_waiter_id.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut waiters = self.lock_waiters();
+            // Requests that can be served right away don't queue
+            if bounded
+                && self
+                    .max_queue_length
+                    .is_some_and(|max| waiters.len() >= max)
+                && (!waiters.is_empty() || self.semaphore.available_permits() < permits as usize)
+            {
+                return Err(QueueFull {
+                    queue_depth: waiters.len(),
+                    permits: self.permits,
+                }
+                .into());
+            }
+            waiters.insert(
+                id,
+                Waiter {
+                    priority,
+                    queued_at: Instant::now(),
+                },
+            );
+        }
+        let _place = QueuePlace { slots: self, id };
+        loop {
+            // Registered before checking, a change in between isn't missed
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            {
+                let waiters = self.lock_waiters();
+                if self.next_waiter(&waiters) == Some(id) {
+                    match self.semaphore.try_acquire_many(permits) {
+                        Ok(permit) => {
+                            drop(waiters);
+                            return Ok(self.lease(permits, permit));
+                        }
+                        Err(TryAcquireError::Closed) => {
+                            return Err(anyhow!("The execution semaphore was closed"))
+                        }
+                        Err(TryAcquireError::NoPermits) => {}
+                    }
+                }
+            }
+            // Batch waiters age without anything else changing, with no aging
+            // period every waiter is already served in order of arrival
+            if self.aging.is_zero() {
+                changed.await;
+            } else {
+                let _ = tokio::time::timeout(self.aging, changed).await;
+            }
+        }
+    }
+
+    // For work that can do without the permits instead of waiting for them,
+    // never ahead of the waiting requests
+    pub fn try_acquire(&self, permits: u32) -> Option<Lease<'_>> {
+        if !self.lock_waiters().is_empty() {
+            return None;
+        }
+        let permit = self.semaphore.try_acquire_many(permits).ok()?;
+        Some(self.lease(permits, permit))
+    }
+
+    pub fn queue_status(&self) -> QueueStatus {
+        let queue_depth = self.lock_waiters().len();
+        QueueStatus {
+            permits: self.permits,
+            permits_in_use: self.permits - self.semaphore.available_permits(),
+            queue_depth,
+            max_queue_length: self.max_queue_length,
+        }
+    }
+
+    pub fn status(&self, max_age: Duration) -> LeaseStatus {
+        let (waiting_interactive, waiting_batch) = {
+            let waiters = self.lock_waiters();
+            let interactive = waiters
+                .values()
+                .filter(|waiter| waiter.priority == Priority::Interactive)
+                .count();
+            (interactive, waiters.len() - interactive)
+        };
+        let leases = self.lock();
+        LeaseStatus {
+            active: leases.len(),
+            permits_held: leases.values().map(|lease| lease.permits).sum(),
+            available_permits: self.semaphore.available_permits(),
+            waiting_interactive,
+            waiting_batch,
+            max_queue_length: self.max_queue_length,
+            stale: leases
+                .values()
+                .filter(|lease| lease.acquired_at.elapsed() > max_age)
+                .map(|lease| StaleLease {
+                    permits: lease.permits,
+                    box_id: lease.box_id,
+                    age_secs: lease.acquired_at.elapsed().as_secs(),
+                })
+                .collect(),
+        }
+    }
+
+    // The leases found older than `max_age` for the first time, to be logged
+    pub fn newly_stale(&self, max_age: Duration) -> Vec<StaleLease> {
+        let mut stale = Vec::new();
+        for lease in self.lock().values_mut() {
+            if lease.reported || lease.acquired_at.elapsed() <= max_age {
+                continue;
+            }
+            lease.reported = true;
+            stale.push(StaleLease {
+                permits: lease.permits,
+                box_id: lease.box_id,
+                age_secs: lease.acquired_at.elapsed().as_secs(),
+            });
+        }
+        stale
+    }
+}
+
+impl Lease<'_> {
+    pub fn set_box_id(&self, box_id: u64) {
+        if let Some(lease) = self.slots.lock().get_mut(&self.id) {
+            lease.box_id = Some(box_id);
+        }
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.slots.lock().remove(&self.id);
+        self.permit.take();
+        self.slots.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn leases_held_past_the_maximum_age_are_reported_once() {
+        let max_age = Duration::from_millis(20);
+        let slots = ExecutionSlots::new(3, None, Duration::ZERO);
+        let stale = slots
+            .acquire(2, Priority::Interactive, false)
+            .await
+            .unwrap();
+        stale.set_box_id(7);
+        tokio::time::sleep(max_age * 3).await;
+        let fresh = slots.acquire(1, Priority::Batch, false).await.unwrap();
+
+        let reported = slots.newly_stale(max_age);
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].permits, 2);
+        assert_eq!(reported[0].box_id, Some(7));
+        assert!(slots.newly_stale(max_age).is_empty());
+
+        // The status lists it for as long as it is held
+        let status = slots.status(max_age);
+        assert_eq!(status.active, 2);
+        assert_eq!(status.permits_held, 3);
+        assert_eq!(status.available_permits, 0);
+        assert_eq!(status.stale.len(), 1);
+        assert_eq!(status.stale[0].box_id, Some(7));
+
+        drop(stale);
+        let status = slots.status(max_age);
+        assert_eq!(status.active, 1);
+        assert_eq!(status.available_permits, 2);
+        assert!(status.stale.is_empty());
+        drop(fresh);
+    }
+}
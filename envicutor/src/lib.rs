@@ -7,3 +7,39 @@ pub mod globals;
 pub mod types;
 pub mod strings;
 pub mod api;
+pub mod examples;
+pub mod nix_errors;
+pub mod env_snapshot;
+pub mod server;
+pub mod signals;
+pub mod events;
+pub mod host;
+pub mod request_limits;
+pub mod stage;
+pub mod access_log;
+pub mod script_lint;
+pub mod sandbox_env;
+pub mod cgroup;
+pub mod verdict;
+pub mod verification;
+pub mod hooks;
+pub mod leases;
+pub mod health;
+pub mod test_data;
+pub mod digest;
+pub mod callbacks;
+pub mod quarantine;
+pub mod output;
+pub mod delivery;
+pub mod priority;
+pub mod storage;
+pub mod datasets;
+pub mod compile_cache;
+pub mod nix_build;
+pub mod encoding;
+pub mod history;
+pub mod core_dumps;
+pub mod coalescing;
+pub mod sandbox_info;
+pub mod fd_audit;
+pub mod clock;
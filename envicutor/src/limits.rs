@@ -1,13 +1,11 @@
+use std::fmt::Display;
+
 use anyhow::{anyhow, Error};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::types::{Kilobytes, Seconds};
 
-pub trait GetLimits {
-    fn get(&self, system_limits: &MandatoryLimits) -> Result<MandatoryLimits, Error>;
-}
-
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Limits {
     pub wall_time: Option<Seconds>,
     pub cpu_time: Option<Seconds>,
@@ -18,88 +16,165 @@ pub struct Limits {
     pub max_number_of_processes: Option<u32>,
 }
 
-impl GetLimits for Option<Limits> {
-    fn get(&self, system_limits: &MandatoryLimits) -> Result<MandatoryLimits, Error> {
-        match &self {
-            Some(req_limits) => {
-                if let Some(wall_time) = req_limits.wall_time {
-                    if wall_time > system_limits.wall_time {
-                        return Err(anyhow!(
-                            "wall_time can't exceed {} seconds",
-                            system_limits.wall_time
-                        ));
-                    }
-                }
-                if let Some(cpu_time) = req_limits.cpu_time {
-                    if cpu_time > system_limits.cpu_time {
-                        return Err(anyhow!(
-                            "cpu_time can't exceed {} seconds",
-                            system_limits.cpu_time
-                        ));
-                    }
-                }
-                if let Some(memory) = req_limits.memory {
-                    if memory > system_limits.memory {
-                        return Err(anyhow!(
-                            "memory can't exceed {} kilobytes",
-                            system_limits.memory
-                        ));
-                    }
-                }
-                if let Some(extra_time) = req_limits.extra_time {
-                    if extra_time > system_limits.extra_time {
-                        return Err(anyhow!(
-                            "extra_time can't exceed {} seconds",
-                            system_limits.extra_time
-                        ));
-                    }
-                }
-                if let Some(max_open_files) = req_limits.max_open_files {
-                    if max_open_files > system_limits.max_open_files {
-                        return Err(anyhow!(
-                            "max_open_files can't exceed {}",
-                            system_limits.max_open_files
-                        ));
-                    }
-                }
-                if let Some(max_file_size) = req_limits.max_file_size {
-                    if max_file_size > system_limits.max_file_size {
-                        return Err(anyhow!(
-                            "max_file_size can't exceed {} kilobytes",
-                            system_limits.max_file_size
-                        ));
-                    }
-                }
-                if let Some(max_number_of_processes) = req_limits.max_number_of_processes {
-                    if max_number_of_processes > system_limits.max_number_of_processes {
-                        return Err(anyhow!(
-                            "max_number_of_processes can't exceed {}",
-                            system_limits.max_number_of_processes
-                        ));
-                    }
-                }
-                Ok(MandatoryLimits {
-                    wall_time: req_limits.wall_time.unwrap_or(system_limits.wall_time),
-                    cpu_time: req_limits.cpu_time.unwrap_or(system_limits.cpu_time),
-                    memory: req_limits.memory.unwrap_or(system_limits.memory),
-                    extra_time: req_limits.extra_time.unwrap_or(system_limits.extra_time),
-                    max_open_files: req_limits
-                        .max_open_files
-                        .unwrap_or(system_limits.max_open_files),
-                    max_file_size: req_limits
-                        .max_file_size
-                        .unwrap_or(system_limits.max_file_size),
-                    max_number_of_processes: req_limits
-                        .max_number_of_processes
-                        .unwrap_or(system_limits.max_number_of_processes),
-                })
-            }
-            None => Ok(system_limits.clone()),
+// The limits a stage ran with, extractions run with the compile limits
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitSet {
+    Compile,
+    Run,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitLayer {
+    Request,
+    Default,
+}
+
+#[derive(Serialize, Clone)]
+pub struct LimitResolution<T> {
+    pub requested: Option<T>,
+    pub default: T,
+    pub system: T,
+    pub effective: T,
+    pub source: LimitLayer,
+}
+
+#[derive(Serialize, Clone)]
+pub struct LimitsResolution {
+    pub wall_time: LimitResolution<Seconds>,
+    pub cpu_time: LimitResolution<Seconds>,
+    pub memory: LimitResolution<Kilobytes>,
+    pub extra_time: LimitResolution<Seconds>,
+    pub max_open_files: LimitResolution<u32>,
+    pub max_file_size: LimitResolution<Kilobytes>,
+    pub max_number_of_processes: LimitResolution<u32>,
+}
+
+impl LimitsResolution {
+    pub fn effective(&self) -> MandatoryLimits {
+        MandatoryLimits {
+            wall_time: self.wall_time.effective,
+            cpu_time: self.cpu_time.effective,
+            memory: self.memory.effective,
+            extra_time: self.extra_time.effective,
+            max_open_files: self.max_open_files.effective,
+            max_file_size: self.max_file_size.effective,
+            max_number_of_processes: self.max_number_of_processes.effective,
         }
     }
 }
 
-#[derive(Clone)]
+// `system` is the maximum a request may ask for, `default` is used when it asks for nothing
+fn resolve<T>(
+    name: &str,
+    unit: &str,
+    requested: Option<T>,
+    default: T,
+    system: T,
+) -> Result<LimitResolution<T>, Error>
+where
+    T: PartialOrd + Copy + Display,
+{
+    match requested {
+        Some(requested) if requested > system => Err(anyhow!("{name} can't exceed {system}{unit}")),
+        Some(requested) => Ok(LimitResolution {
+            requested: Some(requested),
+            default,
+            system,
+            effective: requested,
+            source: LimitLayer::Request,
+        }),
+        None => Ok(LimitResolution {
+            requested: None,
+            default,
+            system,
+            effective: default,
+            source: LimitLayer::Default,
+        }),
+    }
+}
+
+impl Limits {
+    // The same limits, none above the maximums
+    pub fn clamped_to(&self, maximums: &MandatoryLimits) -> Limits {
+        fn clamp<T: PartialOrd>(limit: Option<T>, maximum: T) -> Option<T> {
+            limit.map(|limit| if limit > maximum { maximum } else { limit })
+        }
+        Limits {
+            wall_time: clamp(self.wall_time, maximums.wall_time),
+            cpu_time: clamp(self.cpu_time, maximums.cpu_time),
+            memory: clamp(self.memory, maximums.memory),
+            extra_time: clamp(self.extra_time, maximums.extra_time),
+            max_open_files: clamp(self.max_open_files, maximums.max_open_files),
+            max_file_size: clamp(self.max_file_size, maximums.max_file_size),
+            max_number_of_processes: clamp(
+                self.max_number_of_processes,
+                maximums.max_number_of_processes,
+            ),
+        }
+    }
+
+    pub fn resolve(
+        req_limits: Option<&Limits>,
+        default_limits: &MandatoryLimits,
+        system_limits: &MandatoryLimits,
+    ) -> Result<LimitsResolution, Error> {
+        Ok(LimitsResolution {
+            wall_time: resolve(
+                "wall_time",
+                " seconds",
+                req_limits.and_then(|l| l.wall_time),
+                default_limits.wall_time,
+                system_limits.wall_time,
+            )?,
+            cpu_time: resolve(
+                "cpu_time",
+                " seconds",
+                req_limits.and_then(|l| l.cpu_time),
+                default_limits.cpu_time,
+                system_limits.cpu_time,
+            )?,
+            memory: resolve(
+                "memory",
+                " kilobytes",
+                req_limits.and_then(|l| l.memory),
+                default_limits.memory,
+                system_limits.memory,
+            )?,
+            extra_time: resolve(
+                "extra_time",
+                " seconds",
+                req_limits.and_then(|l| l.extra_time),
+                default_limits.extra_time,
+                system_limits.extra_time,
+            )?,
+            max_open_files: resolve(
+                "max_open_files",
+                "",
+                req_limits.and_then(|l| l.max_open_files),
+                default_limits.max_open_files,
+                system_limits.max_open_files,
+            )?,
+            max_file_size: resolve(
+                "max_file_size",
+                " kilobytes",
+                req_limits.and_then(|l| l.max_file_size),
+                default_limits.max_file_size,
+                system_limits.max_file_size,
+            )?,
+            max_number_of_processes: resolve(
+                "max_number_of_processes",
+                "",
+                req_limits.and_then(|l| l.max_number_of_processes),
+                default_limits.max_number_of_processes,
+                system_limits.max_number_of_processes,
+            )?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
 pub struct MandatoryLimits {
     pub wall_time: Seconds,
     pub cpu_time: Seconds,
@@ -110,8 +185,85 @@ pub struct MandatoryLimits {
     pub max_number_of_processes: u32,
 }
 
+// `compile` and `run` are the maximums, the defaults apply to requests that don't set limits
 #[derive(Clone)]
 pub struct SystemLimits {
     pub compile: MandatoryLimits,
     pub run: MandatoryLimits,
+    pub compile_defaults: MandatoryLimits,
+    pub run_defaults: MandatoryLimits,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAXIMUMS: MandatoryLimits = MandatoryLimits {
+        wall_time: 10.0,
+        cpu_time: 5.0,
+        memory: 512_000,
+        extra_time: 1.0,
+        max_open_files: 64,
+        max_file_size: 10_000,
+        max_number_of_processes: 64,
+    };
+    const DEFAULTS: MandatoryLimits = MandatoryLimits {
+        memory: 128_000,
+        ..MAXIMUMS
+    };
+
+    fn requesting_memory(memory: Kilobytes) -> Limits {
+        Limits {
+            wall_time: None,
+            cpu_time: None,
+            memory: Some(memory),
+            extra_time: None,
+            max_open_files: None,
+            max_file_size: None,
+            max_number_of_processes: None,
+        }
+    }
+
+    #[test]
+    fn unrequested_limits_come_from_the_defaults() {
+        let resolution = Limits::resolve(None, &DEFAULTS, &MAXIMUMS).unwrap();
+        assert!(resolution.memory.source == LimitLayer::Default);
+        assert_eq!(resolution.memory.requested, None);
+        assert_eq!(resolution.memory.default, 128_000);
+        assert_eq!(resolution.memory.system, 512_000);
+        assert_eq!(resolution.memory.effective, 128_000);
+    }
+
+    #[test]
+    fn requested_limits_are_used_up_to_the_maximums() {
+        let limits = requesting_memory(256_000);
+        let resolution = Limits::resolve(Some(&limits), &DEFAULTS, &MAXIMUMS).unwrap();
+        assert!(resolution.memory.source == LimitLayer::Request);
+        assert_eq!(resolution.memory.effective, 256_000);
+        assert!(resolution.wall_time.source == LimitLayer::Default);
+
+        let limits = requesting_memory(512_000);
+        let resolution = Limits::resolve(Some(&limits), &DEFAULTS, &MAXIMUMS).unwrap();
+        assert_eq!(resolution.memory.effective, 512_000);
+
+        let limits = requesting_memory(512_001);
+        let error = Limits::resolve(Some(&limits), &DEFAULTS, &MAXIMUMS)
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "memory can't exceed 512000 kilobytes");
+    }
+
+    #[test]
+    fn clamping_keeps_what_is_unset_and_below_the_maximums() {
+        let limits = Limits {
+            wall_time: Some(20.0),
+            cpu_time: Some(2.0),
+            ..requesting_memory(1_000_000)
+        };
+        let clamped = limits.clamped_to(&MAXIMUMS);
+        assert_eq!(clamped.wall_time, Some(10.0));
+        assert_eq!(clamped.cpu_time, Some(2.0));
+        assert_eq!(clamped.memory, Some(512_000));
+        assert_eq!(clamped.max_open_files, None);
+    }
 }
@@ -1,35 +1,123 @@
 use std::{
     collections::HashMap,
+    convert::Infallible,
     env,
-    path::Path,
+    fmt::Display,
     str::FromStr,
     sync::{atomic::AtomicU64, Arc},
+    time::Duration,
 };
 
 use axum::{
     body::Body,
+    error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
+    http::HeaderMap,
+    middleware,
     response::{IntoResponse, Response},
-    routing::{delete, get, post},
+    routing::{delete, get, patch, post, put, MethodRouter},
     Router,
 };
 use envicutor::{
+    access_log::log_request,
     api::{
-        deletion::delete_runtime,
-        execution::execute,
+        aliases::update_aliases,
+        bootstrap::{bootstrap, bootstrap_runtimes},
+        box_quarantine::{clear_quarantined_box, list_quarantined_boxes},
+        common_functions::use_box_ids,
+        config::{get_public_config, PublicConfig},
+        cores::get_core,
+        datasets::{delete_dataset, list_datasets, upload_dataset},
+        dead_letters::{list_dead_letters, redeliver_dead_letter},
+        deletion::{delete_runtime, delete_runtimes},
+        event_stream::stream_events,
+        execution::{
+            estimate_execution, execute, stream_execution, use_cpu_budget,
+            use_max_case_parallelism, use_max_output_bytes, CpuBudget,
+        },
+        executions::{list_executions, prune_executions, replay_execution},
+        garbage_collection::{collect_garbage, run_garbage_collection},
         installation::{install_runtime, update_nix},
+        jobs::{cancel_job, get_job, purge_job_result, submit_job, JobStore},
         listing::list_runtimes,
+        piston::ResponseDialect,
+        piston_import::import_piston_packages,
+        self_test::run_self_test,
+        status::{get_queue_status, get_status},
+        suites::{grade_submission, store_suite},
     },
-    globals::{DB_PATH, RUNTIMES_DIR},
+    callbacks::{self, CallbackConfig},
+    cgroup, compile_cache,
+    core_dumps::{self, CoreDumpConfig},
+    datasets::{self, DatasetConfig},
+    delivery::RetryPolicy,
+    events::Events,
+    fd_audit,
+    globals::{FIRST_CLI_BOX_ID, MAX_BOX_ID, RUNTIMES_DIR},
+    health::{self, HealthConfig, RuntimeHealth},
+    history::{self, HistoryConfig},
+    hooks::{self, ExecutionHooks, Hook, HookPolicy},
+    host::{sample_host, HostMonitor, HostThresholds},
+    isolate::{self, Isolate},
+    leases::ExecutionSlots,
     limits::{MandatoryLimits, SystemLimits},
-    types::{Metadata, Runtime, WholeSeconds},
+    nix_build::{self, NixBuildConfig},
+    priority::{self, CpuWeights, MAX_CPU_WEIGHT, MIN_CPU_WEIGHT},
+    quarantine,
+    request_limits::RequestLimits,
+    sandbox_env, sandbox_info,
+    server::{
+        self, add_retry_hints, compress_response, decompress_request, handle_route_error,
+        read_body_with_timeout, refuse_when_immutable, refuse_writes_when_disk_full, ServerLimits,
+        MAX_BODY_SIZE,
+    },
+    storage, strings,
+    test_data::{self, TestDataConfig},
+    types::{Kilobytes, Metadata, Runtime, RuntimeId, WholeSeconds},
+    verification::{verify_runtime_dirs, RuntimeDirInfo, VerificationStats},
 };
 use rusqlite::Connection;
 use tokio::{
     signal::{self, unix::SignalKind},
-    sync::{RwLock, Semaphore},
+    sync::RwLock,
+    time,
 };
+use tower::ServiceBuilder;
 
 const DEFAULT_PORT: &str = "5000";
+const DEFAULT_GC_TIMEOUT: WholeSeconds = 600;
+const DEFAULT_REQUEST_TIMEOUT: WholeSeconds = 30;
+const DEFAULT_LONG_REQUEST_TIMEOUT: WholeSeconds = 3600;
+const DEFAULT_REQUEST_HEADER_TIMEOUT: WholeSeconds = 10;
+const DEFAULT_REQUEST_BODY_TIMEOUT: WholeSeconds = 30;
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+// The least time between two collections started by a full disk
+const EMERGENCY_GC_COOLDOWN: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_PRIORITY_AGING: WholeSeconds = 30;
+const DEFAULT_HOST_SAMPLE_INTERVAL: WholeSeconds = 5;
+const DEFAULT_DISK_FREE_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_STARTUP_VERIFICATION_CONCURRENCY: usize = 16;
+const DEFAULT_STARTUP_VERIFICATION_TIMEOUT: WholeSeconds = 30;
+const DEFAULT_HOOK_TIMEOUT: WholeSeconds = 5;
+const DEFAULT_TEST_DATA_FETCH_TIMEOUT: WholeSeconds = 30;
+const DEFAULT_DATASET_UPLOAD_TIMEOUT: WholeSeconds = 600;
+const DEFAULT_STALE_LEASE_FACTOR: u32 = 2;
+const DEFAULT_MAX_CASE_PARALLELISM: u32 = 4;
+const DEFAULT_MAX_OUTPUT_BYTES: usize = 16 * 1024 * 1024;
+const DEFAULT_COMPRESSION_MIN_BYTES: usize = 8 * 1024;
+const DEFAULT_MAX_DECOMPRESSED_BODY_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_DENIED_REQUEST_ENV: &str = "PATH,LD_PRELOAD,LD_LIBRARY_PATH,LD_AUDIT,BASH_ENV,ENV";
+const LEASE_WATCHDOG_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_JOB_RETENTION: WholeSeconds = 600;
+const DEFAULT_CORE_DUMP_RETENTION: WholeSeconds = 600;
+const DEFAULT_MAX_JOB_RETENTION: WholeSeconds = 24 * 60 * 60;
+const DEFAULT_MAX_PENDING_JOBS: usize = 1000;
+const JOB_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_EXECUTION_HISTORY_PRUNE_INTERVAL: WholeSeconds = 3600;
+const DEFAULT_EXECUTION_HISTORY_REQUEST_RETENTION: WholeSeconds = 7 * 24 * 60 * 60;
+const DEFAULT_CALLBACK_RETRIES: u32 = 3;
+const DEFAULT_CALLBACK_BACKOFF: WholeSeconds = 1;
+const DEFAULT_CALLBACK_TIMEOUT: WholeSeconds = 10;
 
 fn get_mandatory_parsed_env_var<T>(var_name: &str) -> T
 where
@@ -43,33 +131,195 @@ where
         })
 }
 
-fn get_limits_from_env_var(prefix: &str) -> MandatoryLimits {
+fn get_optional_parsed_env_var<T>(var_name: &str) -> Option<T>
+where
+    T: FromStr,
+{
+    env::var(var_name).ok().map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            panic!("Invalid {var_name} environment variable");
+        })
+    })
+}
+
+fn get_limit<T>(var_name: &str, fallback: Option<T>) -> T
+where
+    T: FromStr,
+{
+    match fallback {
+        Some(fallback) => get_optional_parsed_env_var(var_name).unwrap_or(fallback),
+        None => get_mandatory_parsed_env_var(var_name),
+    }
+}
+
+// Limits missing from the environment are taken from `fallback`, mandatory without one
+fn get_limits_from_env_var(prefix: &str, fallback: Option<&MandatoryLimits>) -> MandatoryLimits {
+    let var_name = |name: &str| format!("{prefix}_{name}");
+    MandatoryLimits {
+        wall_time: get_limit(&var_name("WALL_TIME"), fallback.map(|l| l.wall_time)),
+        cpu_time: get_limit(&var_name("CPU_TIME"), fallback.map(|l| l.cpu_time)),
+        memory: get_limit(&var_name("MEMORY"), fallback.map(|l| l.memory)),
+        extra_time: get_limit(&var_name("EXTRA_TIME"), fallback.map(|l| l.extra_time)),
+        max_open_files: get_limit(
+            &var_name("MAX_OPEN_FILES"),
+            fallback.map(|l| l.max_open_files),
+        ),
+        max_file_size: get_limit(
+            &var_name("MAX_FILE_SIZE"),
+            fallback.map(|l| l.max_file_size),
+        ),
+        max_number_of_processes: get_limit(
+            &var_name("MAX_NUMBER_OF_PROCESSES"),
+            fallback.map(|l| l.max_number_of_processes),
+        ),
+    }
+}
+
+fn get_default_limit<T>(var_name: &str, maximum: T) -> T
+where
+    T: FromStr + PartialOrd + Display + Copy,
+{
+    let default = get_optional_parsed_env_var(var_name).unwrap_or(maximum);
+    if default > maximum {
+        panic!("{var_name} can't exceed the maximum of {maximum}");
+    }
+    default
+}
+
+// e.g. RUN_DEFAULT_MEMORY, defaults to the maximum (RUN_MEMORY)
+fn get_default_limits_from_env_var(prefix: &str, maximums: &MandatoryLimits) -> MandatoryLimits {
+    let var_name = |name: &str| format!("{prefix}_DEFAULT_{name}");
     MandatoryLimits {
-        wall_time: get_mandatory_parsed_env_var(&format!("{prefix}_WALL_TIME")),
-        cpu_time: get_mandatory_parsed_env_var(&format!("{prefix}_CPU_TIME")),
-        memory: get_mandatory_parsed_env_var(&format!("{prefix}_MEMORY")),
-        extra_time: get_mandatory_parsed_env_var(&format!("{prefix}_EXTRA_TIME")),
-        max_open_files: get_mandatory_parsed_env_var(&format!("{prefix}_MAX_OPEN_FILES")),
-        max_file_size: get_mandatory_parsed_env_var(&format!("{prefix}_MAX_FILE_SIZE")),
-        max_number_of_processes: get_mandatory_parsed_env_var(&format!(
-            "{prefix}_MAX_NUMBER_OF_PROCESSES"
-        )),
+        wall_time: get_default_limit(&var_name("WALL_TIME"), maximums.wall_time),
+        cpu_time: get_default_limit(&var_name("CPU_TIME"), maximums.cpu_time),
+        memory: get_default_limit(&var_name("MEMORY"), maximums.memory),
+        extra_time: get_default_limit(&var_name("EXTRA_TIME"), maximums.extra_time),
+        max_open_files: get_default_limit(&var_name("MAX_OPEN_FILES"), maximums.max_open_files),
+        max_file_size: get_default_limit(&var_name("MAX_FILE_SIZE"), maximums.max_file_size),
+        max_number_of_processes: get_default_limit(
+            &var_name("MAX_NUMBER_OF_PROCESSES"),
+            maximums.max_number_of_processes,
+        ),
     }
 }
 
 fn check_and_get_system_limits() -> SystemLimits {
+    let run = get_limits_from_env_var("RUN", None);
+    let compile = get_limits_from_env_var("COMPILE", Some(&run));
     SystemLimits {
-        compile: get_limits_from_env_var("COMPILE"),
-        run: get_limits_from_env_var("RUN"),
+        compile_defaults: get_default_limits_from_env_var("COMPILE", &compile),
+        run_defaults: get_default_limits_from_env_var("RUN", &run),
+        compile,
+        run,
     }
 }
 
+fn get_duration_from_env_var(var_name: &str, default: WholeSeconds) -> Duration {
+    Duration::from_secs(
+        get_optional_parsed_env_var::<WholeSeconds>(var_name)
+            .unwrap_or(default)
+            .into(),
+    )
+}
+
+// e.g. POST_EXECUTION_HOOK, with POST_EXECUTION_HOOK_TIMEOUT and POST_EXECUTION_HOOK_POLICY
+fn get_hook_from_env_var(var_name: &str, name: &'static str) -> Option<Hook> {
+    let command: String = get_optional_parsed_env_var(var_name)?;
+    Some(Hook {
+        name,
+        command,
+        timeout: get_duration_from_env_var(&format!("{var_name}_TIMEOUT"), DEFAULT_HOOK_TIMEOUT),
+        policy: get_optional_parsed_env_var(&format!("{var_name}_POLICY"))
+            .unwrap_or(HookPolicy::Warn),
+    })
+}
+
+fn with_timeout(route: MethodRouter, timeout: Duration) -> MethodRouter {
+    route.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(handle_route_error))
+            .timeout(timeout),
+    )
+}
+
+fn with_compression(route: MethodRouter, min_bytes: usize) -> MethodRouter {
+    route.layer(middleware::from_fn(move |req, next| {
+        compress_response(min_bytes, req, next)
+    }))
+}
+
+fn changing_runtimes(route: MethodRouter) -> MethodRouter {
+    route.layer(middleware::from_fn(refuse_when_immutable))
+}
+
+// Decompressed bodies may be larger than the compressed ones the server accepts
+fn with_decompression(route: MethodRouter, max_size: usize) -> MethodRouter {
+    route
+        .layer::<_, Infallible>(middleware::from_fn(move |req, next| {
+            decompress_request(max_size, req, next)
+        }))
+        .layer(DefaultBodyLimit::max(max_size.max(MAX_BODY_SIZE)))
+}
+
 async fn get_health() -> Response<Body> {
     "Up and running\n".into_response()
 }
 
-fn get_runtimes() -> Metadata {
-    let connection = Connection::open(DB_PATH)
+// db.sql only creates missing tables, columns added to existing ones are added here
+fn migrate_database() {
+    let connection = Connection::open(storage::state_db_path())
+        .unwrap_or_else(|e| panic!("Failed to open SQLite connection: {e}"));
+    let has_expected_exit_code: bool = connection
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('suite_case') WHERE name = 'expected_exit_code'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap_or_else(|e| panic!("Failed to inspect the suite_case table: {e}"));
+    if !has_expected_exit_code {
+        connection
+            .execute(
+                "ALTER TABLE suite_case ADD COLUMN expected_exit_code INTEGER",
+                [],
+            )
+            .unwrap_or_else(|e| panic!("Failed to add suite_case.expected_exit_code: {e}"));
+        eprintln!("Added expected exit codes to suite cases");
+    }
+    let has_sandbox: bool = connection
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('execution') WHERE name = 'sandbox'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap_or_else(|e| panic!("Failed to inspect the execution table: {e}"));
+    if !has_sandbox {
+        connection
+            .execute("ALTER TABLE execution ADD COLUMN sandbox TEXT", [])
+            .unwrap_or_else(|e| panic!("Failed to add execution.sandbox: {e}"));
+        eprintln!("Added the sandbox to the execution history");
+    }
+    let has_output_hashes: bool = connection
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('execution') WHERE name = 'stdout_sha256'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap_or_else(|e| panic!("Failed to inspect the execution table: {e}"));
+    if !has_output_hashes {
+        connection
+            .execute_batch(
+                "ALTER TABLE execution ADD COLUMN stdout_sha256 TEXT; ALTER TABLE execution ADD COLUMN stderr_sha256 TEXT;",
+            )
+            .unwrap_or_else(|e| panic!("Failed to add the output hashes of executions: {e}"));
+        eprintln!("Added the output hashes to the execution history");
+    }
+}
+
+async fn get_runtimes(
+    verification_concurrency: usize,
+    verification_timeout: Duration,
+) -> (Metadata, VerificationStats) {
+    let connection = storage::open_runtimes_db()
         .unwrap_or_else(|e| panic!("Failed to open SQLite connection: {e}"));
     let mut stmt = connection
         .prepare("SELECT id, name, source_file_name FROM runtime")
@@ -77,7 +327,7 @@ fn get_runtimes() -> Metadata {
     let mut metadata_cache = HashMap::new();
     let runtime_iter = stmt
         .query_map([], |row| {
-            let id: u32 = row.get(0)?;
+            let id: RuntimeId = row.get(0)?;
             let name: String = row.get(1)?;
             let source_file_name: String = row.get(2)?;
             Ok((id, name, source_file_name))
@@ -86,120 +336,1121 @@ fn get_runtimes() -> Metadata {
             panic!("Failed to get id and name from the row: {e}");
         });
 
-    for runtime in runtime_iter {
-        let (id, name, source_file_name) = runtime.unwrap_or_else(|e| {
-            panic!("Failed to get runtime from database: {e}");
-        });
-        eprintln!("Loading {id}: {name}");
+    let runtimes: Vec<(RuntimeId, String, String)> = runtime_iter
+        .map(|runtime| {
+            runtime.unwrap_or_else(|e| {
+                panic!("Failed to get runtime from database: {e}");
+            })
+        })
+        .collect();
+    let mut verification_results = verify_runtime_dirs(
+        runtimes.iter().map(|(id, _, _)| *id).collect(),
+        verification_concurrency,
+        verification_timeout,
+    )
+    .await;
+    let mut stats = VerificationStats::default();
+    for (id, name, source_file_name) in runtimes {
+        // Ids name the runtime directories, a non-positive one means the database is corrupt
+        if id <= 0 {
+            panic!("Runtime {name} has a non-positive id: {id}");
+        }
+        eprintln!("Loading {id}: {}", strings::display_name(&name));
+        // Broken and deferred runtimes are checked again on their first use
+        let (info, verified) = match verification_results.remove(&id) {
+            Some(Ok(info)) => {
+                stats.checked += 1;
+                (info, true)
+            }
+            Some(Err(e)) => {
+                eprintln!("Runtime {} is broken: {e}", strings::display_name(&name));
+                stats.broken += 1;
+                (RuntimeDirInfo::default(), false)
+            }
+            None => {
+                stats.deferred += 1;
+                (RuntimeDirInfo::default(), false)
+            }
+        };
         metadata_cache.insert(
             id,
             Runtime {
                 name,
                 source_file_name,
-                is_compiled: Path::new(&format!("{RUNTIMES_DIR}/{id}/compile"))
-                    .try_exists()
-                    .unwrap_or_else(|e| {
-                        panic!("Could not check if compile script exists: {e}");
-                    }),
+                is_compiled: info.is_compiled,
+                aliases: Vec::new(),
+                min_memory: info.min_memory,
+                core_dumps: !info.core_dumps_disabled,
+                verified,
+                health: RuntimeHealth::default(),
             },
         );
     }
-    metadata_cache
+    eprintln!(
+        "Verified runtime directories: checked={} deferred={} broken={}",
+        stats.checked, stats.deferred, stats.broken
+    );
+
+    let mut stmt = connection
+        .prepare("SELECT alias, runtime_id FROM runtime_alias ORDER BY alias")
+        .unwrap_or_else(|e| panic!("Failed to prepare SQL statement: {}", e));
+    let alias_iter = stmt
+        .query_map([], |row| {
+            let alias: String = row.get(0)?;
+            let runtime_id: RuntimeId = row.get(1)?;
+            Ok((alias, runtime_id))
+        })
+        .unwrap_or_else(|e| {
+            panic!("Failed to get aliases from the database: {e}");
+        });
+    for alias in alias_iter {
+        let (alias, runtime_id) = alias.unwrap_or_else(|e| {
+            panic!("Failed to get alias from database: {e}");
+        });
+        match metadata_cache.get_mut(&runtime_id) {
+            Some(runtime) => runtime.aliases.push(alias),
+            None => eprintln!("Ignoring alias {alias} of missing runtime {runtime_id}"),
+        }
+    }
+    (metadata_cache.into_iter().collect(), stats)
+}
+
+fn get_only_filter(args: &[String]) -> Vec<String> {
+    match args.iter().position(|arg| arg == "--only") {
+        Some(i) => args
+            .get(i + 1)
+            .unwrap_or_else(|| panic!("Missing value for --only"))
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+fn get_index_arg(args: &[String]) -> String {
+    match args.iter().position(|arg| arg == "--index") {
+        Some(i) => args
+            .get(i + 1)
+            .unwrap_or_else(|| panic!("Missing value for --index"))
+            .clone(),
+        None => panic!("import-piston needs --index <packages directory>"),
+    }
 }
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = env::args().collect();
+    match fd_audit::close_inherited_on_exec() {
+        Ok(0) => {}
+        Ok(marked) => eprintln!("Marked {marked} inherited descriptors close-on-exec"),
+        Err(e) => eprintln!("Failed to mark inherited descriptors close-on-exec: {e}"),
+    }
     let installation_timeout: WholeSeconds = get_mandatory_parsed_env_var("INSTALLATION_TIMEOUT");
     let update_timeout: WholeSeconds = get_mandatory_parsed_env_var("UPDATE_TIMEOUT");
+    let gc_timeout: WholeSeconds =
+        get_optional_parsed_env_var("GC_TIMEOUT").unwrap_or(DEFAULT_GC_TIMEOUT);
+    let gc_interval: Option<WholeSeconds> = get_optional_parsed_env_var("GC_INTERVAL");
     let system_limits = check_and_get_system_limits();
+    // Reads and other quick operations get the short budget, installations and executions the long one
+    let short_timeout = get_duration_from_env_var("REQUEST_TIMEOUT", DEFAULT_REQUEST_TIMEOUT);
+    let long_timeout =
+        get_duration_from_env_var("LONG_REQUEST_TIMEOUT", DEFAULT_LONG_REQUEST_TIMEOUT);
+    let body_timeout =
+        get_duration_from_env_var("REQUEST_BODY_TIMEOUT", DEFAULT_REQUEST_BODY_TIMEOUT);
+    let max_decompressed_body_size: usize =
+        get_optional_parsed_env_var("MAX_DECOMPRESSED_BODY_SIZE")
+            .unwrap_or(DEFAULT_MAX_DECOMPRESSED_BODY_SIZE);
+    let compression_min_bytes: usize = get_optional_parsed_env_var("COMPRESSION_MIN_BYTES")
+        .unwrap_or(DEFAULT_COMPRESSION_MIN_BYTES);
+    let server_limits = ServerLimits {
+        header_timeout: get_duration_from_env_var(
+            "REQUEST_HEADER_TIMEOUT",
+            DEFAULT_REQUEST_HEADER_TIMEOUT,
+        ),
+        max_connections: get_optional_parsed_env_var("MAX_CONNECTIONS")
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS),
+    };
+    let default_request_limits = RequestLimits::default();
+    let request_limits = RequestLimits {
+        max_env_patterns: get_optional_parsed_env_var("MAX_ENV_PATTERNS")
+            .unwrap_or(default_request_limits.max_env_patterns),
+        max_suite_cases: get_optional_parsed_env_var("MAX_SUITE_CASES")
+            .unwrap_or(default_request_limits.max_suite_cases),
+        max_aliases: get_optional_parsed_env_var("MAX_ALIASES")
+            .unwrap_or(default_request_limits.max_aliases),
+        max_stages_per_request: get_optional_parsed_env_var("MAX_STAGES_PER_REQUEST")
+            .unwrap_or(default_request_limits.max_stages_per_request),
+    };
     let max_concurrent_submissions: usize =
         get_mandatory_parsed_env_var("MAX_CONCURRENT_SUBMISSIONS");
-    let execution_semaphore = Arc::new(Semaphore::new(max_concurrent_submissions));
+    let priority_aging = get_duration_from_env_var("PRIORITY_AGING", DEFAULT_PRIORITY_AGING);
+    let max_queue_length: Option<usize> = get_optional_parsed_env_var("MAX_QUEUE_LENGTH");
+    let execution_semaphore = Arc::new(ExecutionSlots::new(
+        max_concurrent_submissions,
+        max_queue_length,
+        priority_aging,
+    ));
+    let saturated_execution_permits: u32 =
+        get_optional_parsed_env_var("SATURATED_EXECUTION_PERMITS").unwrap_or(1);
+    if saturated_execution_permits == 0
+        || saturated_execution_permits as usize > max_concurrent_submissions
+    {
+        panic!("SATURATED_EXECUTION_PERMITS must be between 1 and MAX_CONCURRENT_SUBMISSIONS");
+    }
+    let host_monitor = Arc::new(HostMonitor::new(
+        HostThresholds {
+            load: get_optional_parsed_env_var("SATURATION_LOAD"),
+            memory_pressure: get_optional_parsed_env_var("SATURATION_MEMORY_PRESSURE"),
+            disk_free_bytes: get_optional_parsed_env_var("DISK_FREE_BYTES")
+                .unwrap_or(DEFAULT_DISK_FREE_BYTES),
+        },
+        saturated_execution_permits,
+    ));
+    // Every stage of an execution (extraction, compilation and run) using up its wall time
+    let longest_execution = Duration::from_secs_f32(
+        2.0 * (system_limits.compile.wall_time + system_limits.compile.extra_time)
+            + system_limits.run.wall_time
+            + system_limits.run.extra_time,
+    );
+    let stale_lease_age = longest_execution
+        * get_optional_parsed_env_var("STALE_LEASE_FACTOR").unwrap_or(DEFAULT_STALE_LEASE_FACTOR);
+    let host_sample_interval =
+        get_duration_from_env_var("HOST_SAMPLE_INTERVAL", DEFAULT_HOST_SAMPLE_INTERVAL);
+    if let Some(secret) = get_optional_parsed_env_var::<String>("CALLBACK_SECRET") {
+        let allowed_hosts: Vec<String> = env::var("CALLBACK_HOSTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|host| host.trim().to_string())
+            .filter(|host| !host.is_empty())
+            .collect();
+        if allowed_hosts.is_empty() {
+            panic!("CALLBACK_HOSTS must list the hosts callbacks may go to");
+        }
+        callbacks::use_callback_config(CallbackConfig {
+            secret,
+            allowed_hosts,
+            retry_policy: RetryPolicy {
+                retries: get_optional_parsed_env_var("CALLBACK_RETRIES")
+                    .unwrap_or(DEFAULT_CALLBACK_RETRIES),
+                initial_backoff: get_duration_from_env_var(
+                    "CALLBACK_BACKOFF",
+                    DEFAULT_CALLBACK_BACKOFF,
+                ),
+                timeout: get_duration_from_env_var("CALLBACK_TIMEOUT", DEFAULT_CALLBACK_TIMEOUT),
+            },
+        })
+        .unwrap_or_else(|e| panic!("Failed to configure callbacks: {e}"));
+    }
+    let job_retention = get_duration_from_env_var("JOB_RETENTION", DEFAULT_JOB_RETENTION);
+    let max_job_retention =
+        get_duration_from_env_var("MAX_JOB_RETENTION", DEFAULT_MAX_JOB_RETENTION);
+    if job_retention > max_job_retention {
+        panic!("JOB_RETENTION can't be more than MAX_JOB_RETENTION");
+    }
+    let jobs = Arc::new(JobStore::new(
+        job_retention,
+        max_job_retention,
+        get_optional_parsed_env_var("MAX_PENDING_JOBS").unwrap_or(DEFAULT_MAX_PENDING_JOBS),
+    ));
+
+    if let Some(config_file) = get_optional_parsed_env_var::<String>("ISOLATE_CONFIG_FILE") {
+        eprintln!("Using isolate config file: {config_file}");
+        isolate::use_config_file(config_file)
+            .unwrap_or_else(|e| panic!("Failed to configure isolate: {e}"));
+    }
+    let default_sandbox_env = get_optional_parsed_env_var::<String>("DEFAULT_SANDBOX_ENV")
+        .map(|json| {
+            sandbox_env::parse_default_env(&json)
+                .unwrap_or_else(|e| panic!("Invalid DEFAULT_SANDBOX_ENV: {e}"))
+        })
+        .unwrap_or_default();
+    isolate::use_default_env(default_sandbox_env.clone())
+        .unwrap_or_else(|e| panic!("Failed to configure isolate: {e}"));
+    if get_optional_parsed_env_var("SANDBOX_INFO").unwrap_or(true) {
+        let host = get_optional_parsed_env_var("SANDBOX_HOST")
+            .or_else(|| sandbox_info::hostname().map_err(|e| eprintln!("{e}")).ok())
+            .unwrap_or_else(|| "unknown".to_string());
+        sandbox_info::use_sandbox_host(host)
+            .unwrap_or_else(|e| panic!("Failed to configure the sandbox info: {e}"));
+    }
+    if let Some(retries) = get_optional_parsed_env_var("SANDBOX_ERROR_RETRIES") {
+        isolate::use_sandbox_error_retries(retries)
+            .unwrap_or_else(|e| panic!("Failed to configure isolate: {e}"));
+    }
+    if let Some(capture) = get_optional_parsed_env_var("OUTPUT_CAPTURE") {
+        isolate::use_output_capture(capture)
+            .unwrap_or_else(|e| panic!("Failed to configure isolate: {e}"));
+    }
+    let denied_request_env: Vec<String> = env::var("DENIED_REQUEST_ENV")
+        .unwrap_or_else(|_| DEFAULT_DENIED_REQUEST_ENV.to_string())
+        .split(',')
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect();
+    sandbox_env::use_denied_request_env(denied_request_env)
+        .unwrap_or_else(|e| panic!("Failed to configure the request environment: {e}"));
+    hooks::use_execution_hooks(ExecutionHooks {
+        pre_execution: get_hook_from_env_var("PRE_EXECUTION_HOOK", "pre-execution"),
+        post_execution: get_hook_from_env_var("POST_EXECUTION_HOOK", "post-execution"),
+    })
+    .unwrap_or_else(|e| panic!("Failed to configure execution hooks: {e}"));
+    let max_case_parallelism: u32 = get_optional_parsed_env_var("MAX_CASE_PARALLELISM")
+        .unwrap_or(DEFAULT_MAX_CASE_PARALLELISM.min(max_concurrent_submissions as u32));
+    if max_case_parallelism == 0 || max_case_parallelism as usize > max_concurrent_submissions {
+        panic!("MAX_CASE_PARALLELISM must be between 1 and MAX_CONCURRENT_SUBMISSIONS");
+    }
+    use_max_case_parallelism(max_case_parallelism)
+        .unwrap_or_else(|e| panic!("Failed to configure batches: {e}"));
+    let cpu_budget = CpuBudget {
+        default: get_optional_parsed_env_var("DEFAULT_MAX_TOTAL_CPU_SECONDS"),
+        max: get_optional_parsed_env_var("MAX_TOTAL_CPU_SECONDS"),
+    };
+    if let (Some(default), Some(max)) = (cpu_budget.default, cpu_budget.max) {
+        if default > max {
+            panic!("DEFAULT_MAX_TOTAL_CPU_SECONDS can't be more than MAX_TOTAL_CPU_SECONDS");
+        }
+    }
+    use_cpu_budget(cpu_budget).unwrap_or_else(|e| panic!("Failed to configure CPU budgets: {e}"));
+    let max_output_bytes: usize =
+        get_optional_parsed_env_var("MAX_OUTPUT_BYTES").unwrap_or(DEFAULT_MAX_OUTPUT_BYTES);
+    use_max_output_bytes(max_output_bytes)
+        .unwrap_or_else(|e| panic!("Failed to configure output limits: {e}"));
+    let default_cpu_weights = CpuWeights::default();
+    let cpu_weights = CpuWeights {
+        interactive: get_optional_parsed_env_var("CPU_WEIGHT_INTERACTIVE")
+            .unwrap_or(default_cpu_weights.interactive),
+        batch: get_optional_parsed_env_var("CPU_WEIGHT_BATCH").unwrap_or(default_cpu_weights.batch),
+    };
+    if [cpu_weights.interactive, cpu_weights.batch]
+        .iter()
+        .any(|weight| !(MIN_CPU_WEIGHT..=MAX_CPU_WEIGHT).contains(weight))
+    {
+        panic!(
+            "CPU_WEIGHT_INTERACTIVE and CPU_WEIGHT_BATCH must be between {MIN_CPU_WEIGHT} and {MAX_CPU_WEIGHT}"
+        );
+    }
+    priority::use_cpu_weights(cpu_weights)
+        .unwrap_or_else(|e| panic!("Failed to configure CPU weights: {e}"));
+    cgroup::probe_cpu_weight();
+    let default_health_config = HealthConfig::default();
+    let runtime_health_window: usize = get_optional_parsed_env_var("RUNTIME_HEALTH_WINDOW")
+        .unwrap_or(default_health_config.window);
+    if runtime_health_window == 0 {
+        panic!("RUNTIME_HEALTH_WINDOW must be positive");
+    }
+    health::use_health_config(HealthConfig {
+        window: runtime_health_window,
+        disable_broken: get_optional_parsed_env_var("DISABLE_BROKEN_RUNTIMES")
+            .unwrap_or(default_health_config.disable_broken),
+    })
+    .unwrap_or_else(|e| panic!("Failed to configure runtime health: {e}"));
+    let default_test_data_config = TestDataConfig::default();
+    let test_data_fetch_concurrency: usize =
+        get_optional_parsed_env_var("TEST_DATA_FETCH_CONCURRENCY")
+            .unwrap_or(default_test_data_config.fetch_concurrency);
+    if test_data_fetch_concurrency == 0 {
+        panic!("TEST_DATA_FETCH_CONCURRENCY must be positive");
+    }
+    test_data::use_test_data_config(TestDataConfig {
+        allowed_hosts: env::var("TEST_DATA_HOSTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|host| host.trim().to_string())
+            .filter(|host| !host.is_empty())
+            .collect(),
+        max_bytes: get_optional_parsed_env_var("TEST_DATA_MAX_BYTES")
+            .unwrap_or(default_test_data_config.max_bytes),
+        max_request_bytes: get_optional_parsed_env_var("TEST_DATA_MAX_REQUEST_BYTES")
+            .unwrap_or(default_test_data_config.max_request_bytes),
+        ca_file: env::var("TEST_DATA_CA_FILE").ok(),
+        fetch_concurrency: test_data_fetch_concurrency,
+        fetch_timeout: get_duration_from_env_var(
+            "TEST_DATA_FETCH_TIMEOUT",
+            DEFAULT_TEST_DATA_FETCH_TIMEOUT,
+        ),
+        cache_max_bytes: get_optional_parsed_env_var("TEST_DATA_CACHE_SIZE")
+            .unwrap_or(default_test_data_config.cache_max_bytes),
+    })
+    .unwrap_or_else(|e| panic!("Failed to configure test data: {e}"));
+    let default_dataset_config = DatasetConfig::default();
+    datasets::use_dataset_config(DatasetConfig {
+        max_bytes: get_optional_parsed_env_var("MAX_DATASET_SIZE")
+            .unwrap_or(default_dataset_config.max_bytes),
+        upload_timeout: get_duration_from_env_var(
+            "DATASET_UPLOAD_TIMEOUT",
+            DEFAULT_DATASET_UPLOAD_TIMEOUT,
+        ),
+    })
+    .unwrap_or_else(|e| panic!("Failed to configure datasets: {e}"));
+    let default_nix_build_config = NixBuildConfig::default();
+    nix_build::use_nix_build_config(NixBuildConfig {
+        max_cores: get_optional_parsed_env_var("NIX_MAX_CORES")
+            .unwrap_or(default_nix_build_config.max_cores),
+        max_jobs: get_optional_parsed_env_var("NIX_MAX_JOBS")
+            .unwrap_or(default_nix_build_config.max_jobs),
+    })
+    .unwrap_or_else(|e| panic!("Failed to configure nix builds: {e}"));
+    let compile_cache_size: u64 = get_optional_parsed_env_var("COMPILE_CACHE_SIZE").unwrap_or(0);
+    compile_cache::use_max_bytes(compile_cache_size)
+        .unwrap_or_else(|e| panic!("Failed to configure the compile cache: {e}"));
+    if get_optional_parsed_env_var("EXECUTION_HISTORY").unwrap_or(false) {
+        history::use_history_config(HistoryConfig {
+            output_bytes: get_optional_parsed_env_var("EXECUTION_HISTORY_OUTPUT_BYTES")
+                .unwrap_or(0),
+            max_age: get_optional_parsed_env_var::<WholeSeconds>("EXECUTION_HISTORY_MAX_AGE")
+                .map(|max_age| Duration::from_secs(max_age.into())),
+            max_rows: get_optional_parsed_env_var("EXECUTION_HISTORY_MAX_ROWS"),
+            request_bytes: get_optional_parsed_env_var("EXECUTION_HISTORY_REQUEST_BYTES")
+                .unwrap_or(0),
+            request_retention: get_duration_from_env_var(
+                "EXECUTION_HISTORY_REQUEST_RETENTION",
+                DEFAULT_EXECUTION_HISTORY_REQUEST_RETENTION,
+            ),
+        })
+        .unwrap_or_else(|e| panic!("Failed to configure the execution history: {e}"));
+    }
+    let core_dump_max_size: Kilobytes =
+        get_optional_parsed_env_var("CORE_DUMP_MAX_SIZE").unwrap_or(0);
+    if core_dump_max_size > 0 {
+        core_dumps::use_core_dump_config(CoreDumpConfig {
+            max_size: core_dump_max_size,
+            retention: get_duration_from_env_var(
+                "CORE_DUMP_RETENTION",
+                DEFAULT_CORE_DUMP_RETENTION,
+            ),
+        })
+        .unwrap_or_else(|e| panic!("Failed to configure core dumps: {e}"));
+    }
+    let public_config = Arc::new(PublicConfig {
+        default_sandbox_env,
+    });
+    if !storage::is_writable(RUNTIMES_DIR) {
+        let state_dir = env::var("STATE_DIR").unwrap_or_else(|_| {
+            panic!("{RUNTIMES_DIR} is read-only, STATE_DIR has to be set to a writable directory")
+        });
+        storage::use_immutable_storage(&state_dir)
+            .unwrap_or_else(|e| panic!("Failed to keep the server's state in {state_dir}: {e}"));
+        eprintln!("{RUNTIMES_DIR} is read-only, runtimes can't be changed");
+    }
+
+    let verification_concurrency: usize =
+        get_optional_parsed_env_var("STARTUP_VERIFICATION_CONCURRENCY")
+            .unwrap_or(DEFAULT_STARTUP_VERIFICATION_CONCURRENCY);
+    let verification_timeout = get_duration_from_env_var(
+        "STARTUP_VERIFICATION_TIMEOUT",
+        DEFAULT_STARTUP_VERIFICATION_TIMEOUT,
+    );
+
+    // Subcommands run next to a live server, so none of the server's state is set up,
+    // cleared or resumed and their boxes are ones the server never uses
+    let command = args.get(1).map(String::as_str);
+    if matches!(command, Some("bootstrap" | "import-piston")) {
+        if storage::is_immutable() {
+            panic!("{RUNTIMES_DIR} is read-only, runtimes can't be installed");
+        }
+        use_box_ids(FIRST_CLI_BOX_ID..MAX_BOX_ID)
+            .unwrap_or_else(|e| panic!("Failed to configure box ids: {e}"));
+        migrate_database();
+        quarantine::load().unwrap_or_else(|e| panic!("Failed to load the box quarantine: {e}"));
+        let (metadata, _) = get_runtimes(verification_concurrency, verification_timeout).await;
+        let box_id = Arc::new(AtomicU64::new(0));
+        let metadata_cache = Arc::new(RwLock::new(metadata));
+        let installation_lock = Arc::new(RwLock::new(0));
+        let events = Events::new();
+        let report = if command == Some("bootstrap") {
+            let results = bootstrap_runtimes(
+                &get_only_filter(&args[2..]),
+                installation_timeout,
+                box_id,
+                metadata_cache,
+                installation_lock,
+                events,
+                request_limits,
+            )
+            .await
+            .unwrap_or_else(|e| panic!("Failed to bootstrap example runtimes: {e}"));
+            serde_json::to_string_pretty(&results)
+        } else {
+            let results = import_piston_packages(
+                &get_index_arg(&args[2..]),
+                installation_timeout,
+                box_id,
+                metadata_cache,
+                installation_lock,
+                events,
+                request_limits,
+            )
+            .await
+            .unwrap_or_else(|e| panic!("Failed to import Piston packages: {e}"));
+            serde_json::to_string_pretty(&results)
+        };
+        println!(
+            "{}",
+            report.unwrap_or_else(|e| panic!("Failed to serialize the results: {e}"))
+        );
+        return;
+    }
+
+    Isolate::probe()
+        .await
+        .unwrap_or_else(|e| panic!("Isolate preflight check failed: {e}"));
+    match fd_audit::run_canary().await {
+        Ok(report) if !report.passed => eprintln!(
+            "Sandboxed programs inherit unexpected descriptors: {}",
+            serde_json::to_string(&report.unexpected).unwrap_or_default()
+        ),
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to run the descriptor canary: {e}"),
+    }
+
+    datasets::init()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to set up the datasets directory: {e}"));
+    compile_cache::init()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to set up the compile cache: {e}"));
+    test_data::init()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to set up the test data cache: {e}"));
+    core_dumps::init()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to set up the cores directory: {e}"));
 
     let box_id = Arc::new(AtomicU64::new(0));
-    let metadata_cache = Arc::new(RwLock::new(get_runtimes()));
+    migrate_database();
+    let quarantined_boxes =
+        quarantine::load().unwrap_or_else(|e| panic!("Failed to load the box quarantine: {e}"));
+    if quarantined_boxes > 0 {
+        eprintln!("{quarantined_boxes} box(es) are quarantined");
+    }
+    let (metadata, verification_stats) =
+        get_runtimes(verification_concurrency, verification_timeout).await;
+    let metadata_cache = Arc::new(RwLock::new(metadata));
     let installation_lock = Arc::new(RwLock::new(0));
+    let events = Events::new();
+
+    // The Nix store comes with the runtimes in immutable deployments, nothing is collected
+    let gc_interval = gc_interval.filter(|_| !storage::is_immutable());
+    if let Some(gc_interval) = gc_interval {
+        let metadata_cache = metadata_cache.clone();
+        let installation_lock = installation_lock.clone();
+        let events = events.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(gc_interval.into()));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = collect_garbage(
+                    gc_timeout,
+                    metadata_cache.clone(),
+                    installation_lock.clone(),
+                    events.clone(),
+                )
+                .await
+                {
+                    eprintln!("Scheduled garbage collection failed: {e}");
+                }
+            }
+        });
+    }
+
+    if !storage::is_immutable() {
+        let host_monitor = host_monitor.clone();
+        let metadata_cache = metadata_cache.clone();
+        let installation_lock = installation_lock.clone();
+        let events = events.clone();
+        tokio::spawn(async move {
+            loop {
+                host_monitor.disk_full_reported().await;
+                eprintln!("Collecting garbage to free disk space");
+                if let Err(e) = collect_garbage(
+                    gc_timeout,
+                    metadata_cache.clone(),
+                    installation_lock.clone(),
+                    events.clone(),
+                )
+                .await
+                {
+                    eprintln!("Emergency garbage collection failed: {e}");
+                }
+                // Reports meanwhile are kept, for one more collection at most
+                time::sleep(EMERGENCY_GC_COOLDOWN).await;
+            }
+        });
+    }
+
+    {
+        let host_monitor = host_monitor.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(host_sample_interval);
+            loop {
+                interval.tick().await;
+                host_monitor.record(sample_host().await).await;
+            }
+        });
+    }
+
+    {
+        let execution_semaphore = execution_semaphore.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(LEASE_WATCHDOG_INTERVAL);
+            loop {
+                interval.tick().await;
+                for lease in execution_semaphore.newly_stale(stale_lease_age) {
+                    eprintln!(
+                        "An execution has held {} permit(s) for {}s (box {}), longer than any execution can take",
+                        lease.permits,
+                        lease.age_secs,
+                        lease
+                            .box_id
+                            .map_or_else(|| "none".to_string(), |id| id.to_string())
+                    );
+                }
+            }
+        });
+    }
+
+    {
+        let jobs = jobs.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(JOB_PURGE_INTERVAL);
+            loop {
+                interval.tick().await;
+                jobs.purge().await;
+            }
+        });
+    }
+
+    if history::has_retention() {
+        let prune_interval = get_duration_from_env_var(
+            "EXECUTION_HISTORY_PRUNE_INTERVAL",
+            DEFAULT_EXECUTION_HISTORY_PRUNE_INTERVAL,
+        );
+        tokio::spawn(async move {
+            let mut interval = time::interval(prune_interval);
+            loop {
+                interval.tick().await;
+                match history::prune().await {
+                    Ok(0) => {}
+                    Ok(removed) => eprintln!("Pruned {removed} execution(s) from the history"),
+                    Err(e) => eprintln!("Scheduled execution history pruning failed: {e}"),
+                }
+            }
+        });
+    }
+
     let app = Router::new()
-        .route("/health", get(get_health))
+        .route("/health", with_timeout(get(get_health), short_timeout))
         .route(
             "/runtimes",
-            get({
-                let metadata_cache = metadata_cache.clone();
-                move || list_runtimes(metadata_cache)
-            }),
+            with_compression(
+                with_timeout(
+                    get({
+                        let metadata_cache = metadata_cache.clone();
+                        move |query| list_runtimes(metadata_cache, query)
+                    }),
+                    short_timeout,
+                ),
+                compression_min_bytes,
+            ),
         )
         .route(
             "/runtimes",
-            post({
-                let box_id = box_id.clone();
-                let metadata_cache = metadata_cache.clone();
-                let installation_lock = installation_lock.clone();
-                move |req| {
-                    install_runtime(
-                        installation_timeout,
-                        box_id,
-                        metadata_cache,
-                        installation_lock,
-                        req,
-                    )
-                }
-            }),
+            changing_runtimes(with_decompression(
+                with_timeout(
+                    post({
+                        let box_id = box_id.clone();
+                        let metadata_cache = metadata_cache.clone();
+                        let installation_lock = installation_lock.clone();
+                        let events = events.clone();
+                        move |req| {
+                            install_runtime(
+                                installation_timeout,
+                                box_id,
+                                metadata_cache,
+                                installation_lock,
+                                events,
+                                request_limits,
+                                req,
+                            )
+                        }
+                    }),
+                    long_timeout,
+                ),
+                max_decompressed_body_size,
+            )),
+        )
+        .route(
+            "/runtimes",
+            changing_runtimes(with_timeout(
+                delete({
+                    let metadata_cache = metadata_cache.clone();
+                    let events = events.clone();
+                    move |req| delete_runtimes(metadata_cache, events, req)
+                }),
+                long_timeout,
+            )),
         )
         .route(
             "/runtimes/:id",
-            delete({
-                let metadata_cache = metadata_cache.clone();
-                move |req| delete_runtime(req, metadata_cache)
-            }),
+            changing_runtimes(with_timeout(
+                delete({
+                    let metadata_cache = metadata_cache.clone();
+                    let events = events.clone();
+                    move |req| delete_runtime(req, metadata_cache, events)
+                }),
+                short_timeout,
+            )),
+        )
+        .route(
+            "/runtimes/:id/aliases",
+            changing_runtimes(with_timeout(
+                patch({
+                    let metadata_cache = metadata_cache.clone();
+                    let installation_lock = installation_lock.clone();
+                    move |path, req| {
+                        update_aliases(path, metadata_cache, installation_lock, request_limits, req)
+                    }
+                }),
+                short_timeout,
+            )),
+        )
+        .route(
+            "/runtimes/:id/suites",
+            with_timeout(
+                post({
+                    let metadata_cache = metadata_cache.clone();
+                    let system_limits = system_limits.clone();
+                    move |path, req| {
+                        store_suite(path, metadata_cache, system_limits, request_limits, req)
+                    }
+                }),
+                short_timeout,
+            ),
+        )
+        .route(
+            "/runtimes/:id/suites/:suite/grade",
+            with_compression(
+                with_timeout(
+                    post({
+                        let metadata_cache = metadata_cache.clone();
+                        let installation_lock = installation_lock.clone();
+                        let box_id = box_id.clone();
+                        let system_limits = system_limits.clone();
+                        let execution_semaphore = execution_semaphore.clone();
+                        let host_monitor = host_monitor.clone();
+                        let events = events.clone();
+                        move |path, query, req| {
+                            grade_submission(
+                                execution_semaphore,
+                                box_id,
+                                metadata_cache,
+                                installation_lock,
+                                system_limits,
+                                host_monitor,
+                                events,
+                                request_limits,
+                                path,
+                                query,
+                                req,
+                            )
+                        }
+                    }),
+                    long_timeout,
+                ),
+                compression_min_bytes,
+            ),
         )
         .route(
             "/update",
-            post({
-                let installation_lock = installation_lock.clone();
-                move || update_nix(update_timeout, installation_lock)
-            }),
+            changing_runtimes(with_timeout(
+                post({
+                    let installation_lock = installation_lock.clone();
+                    let events = events.clone();
+                    move || update_nix(update_timeout, installation_lock, events)
+                }),
+                long_timeout,
+            )),
+        )
+        .route(
+            "/admin/bootstrap",
+            changing_runtimes(with_timeout(
+                post({
+                    let box_id = box_id.clone();
+                    let metadata_cache = metadata_cache.clone();
+                    let installation_lock = installation_lock.clone();
+                    let events = events.clone();
+                    move |req| {
+                        bootstrap(
+                            installation_timeout,
+                            box_id,
+                            metadata_cache,
+                            installation_lock,
+                            events,
+                            request_limits,
+                            req,
+                        )
+                    }
+                }),
+                long_timeout,
+            )),
+        )
+        .route(
+            "/admin/gc",
+            changing_runtimes(with_timeout(
+                post({
+                    let metadata_cache = metadata_cache.clone();
+                    let installation_lock = installation_lock.clone();
+                    let events = events.clone();
+                    move || {
+                        run_garbage_collection(
+                            gc_timeout,
+                            metadata_cache,
+                            installation_lock,
+                            events,
+                        )
+                    }
+                }),
+                long_timeout,
+            )),
+        )
+        .route("/datasets", with_timeout(get(list_datasets), short_timeout))
+        .route(
+            "/executions",
+            with_timeout(get(list_executions), short_timeout),
+        )
+        .route("/cores/:id", with_timeout(get(get_core), short_timeout))
+        .route(
+            "/admin/prune",
+            with_timeout(post(prune_executions), long_timeout),
+        )
+        .route(
+            "/datasets/:name",
+            with_timeout(delete(delete_dataset), short_timeout),
+        )
+        .route(
+            "/config/public",
+            with_timeout(get(move || get_public_config(public_config)), short_timeout),
+        )
+        .route(
+            "/status",
+            with_timeout(
+                get({
+                    let execution_semaphore = execution_semaphore.clone();
+                    move || get_queue_status(execution_semaphore)
+                }),
+                short_timeout,
+            ),
+        )
+        .route(
+            "/admin/self-test",
+            with_timeout(post(run_self_test), long_timeout),
+        )
+        .route(
+            "/admin/status",
+            with_timeout(
+                get({
+                    let host_monitor = host_monitor.clone();
+                    let execution_semaphore = execution_semaphore.clone();
+                    let metadata_cache = metadata_cache.clone();
+                    let jobs = jobs.clone();
+                    move || {
+                        get_status(
+                            host_monitor,
+                            verification_stats,
+                            execution_semaphore,
+                            stale_lease_age,
+                            metadata_cache,
+                            jobs,
+                        )
+                    }
+                }),
+                short_timeout,
+            ),
+        )
+        .route(
+            "/admin/quarantine",
+            with_timeout(get(list_quarantined_boxes), short_timeout),
+        )
+        .route(
+            "/admin/quarantine/:box_id",
+            with_timeout(delete(clear_quarantined_box), short_timeout),
+        )
+        .route(
+            "/admin/dead-letters",
+            with_timeout(get(list_dead_letters), short_timeout),
+        )
+        .route(
+            "/admin/dead-letters/:id/redeliver",
+            with_timeout(post(redeliver_dead_letter), short_timeout),
+        )
+        .route(
+            "/admin/events",
+            with_timeout(
+                get({
+                    let events = events.clone();
+                    move || stream_events(events)
+                }),
+                short_timeout,
+            ),
+        )
+        .route(
+            "/executions/:id/replay",
+            with_timeout(
+                post({
+                    let metadata_cache = metadata_cache.clone();
+                    let installation_lock = installation_lock.clone();
+                    let box_id = box_id.clone();
+                    let system_limits = system_limits.clone();
+                    let execution_semaphore = execution_semaphore.clone();
+                    let host_monitor = host_monitor.clone();
+                    let events = events.clone();
+                    move |path, req| {
+                        replay_execution(
+                            execution_semaphore,
+                            box_id,
+                            metadata_cache,
+                            installation_lock,
+                            system_limits,
+                            host_monitor,
+                            events,
+                            path,
+                            req,
+                        )
+                    }
+                }),
+                long_timeout,
+            ),
         )
         .route(
             "/execute",
-            post({
-                let metadata_cache = metadata_cache.clone();
-                let installation_lock = installation_lock.clone();
-                let box_id = box_id.clone();
-                let system_limits = system_limits.clone();
-                let execution_semaphore = execution_semaphore.clone();
-                move |query, req| {
-                    execute(
-                        execution_semaphore,
-                        box_id,
-                        metadata_cache,
-                        installation_lock,
-                        system_limits,
-                        req,
-                        query,
-                    )
-                }
-            }),
+            with_decompression(
+                with_compression(
+                    with_timeout(
+                        post({
+                            let metadata_cache = metadata_cache.clone();
+                            let installation_lock = installation_lock.clone();
+                            let box_id = box_id.clone();
+                            let system_limits = system_limits.clone();
+                            let execution_semaphore = execution_semaphore.clone();
+                            let host_monitor = host_monitor.clone();
+                            let events = events.clone();
+                            move |headers: HeaderMap, query, req| {
+                                execute(
+                                    execution_semaphore,
+                                    box_id,
+                                    metadata_cache,
+                                    installation_lock,
+                                    system_limits,
+                                    host_monitor,
+                                    events,
+                                    ResponseDialect::from_headers(&headers),
+                                    req,
+                                    query,
+                                )
+                            }
+                        }),
+                        long_timeout,
+                    ),
+                    compression_min_bytes,
+                ),
+                max_decompressed_body_size,
+            ),
+        )
+        .route(
+            "/execute/stream",
+            with_decompression(
+                with_timeout(
+                    post({
+                        let metadata_cache = metadata_cache.clone();
+                        let installation_lock = installation_lock.clone();
+                        let box_id = box_id.clone();
+                        let system_limits = system_limits.clone();
+                        let execution_semaphore = execution_semaphore.clone();
+                        let host_monitor = host_monitor.clone();
+                        let events = events.clone();
+                        move |query, req| {
+                            stream_execution(
+                                execution_semaphore,
+                                box_id,
+                                metadata_cache,
+                                installation_lock,
+                                system_limits,
+                                host_monitor,
+                                events,
+                                req,
+                                query,
+                            )
+                        }
+                    }),
+                    short_timeout,
+                ),
+                max_decompressed_body_size,
+            ),
+        )
+        .route(
+            "/jobs",
+            with_decompression(
+                with_timeout(
+                    post({
+                        let jobs = jobs.clone();
+                        let metadata_cache = metadata_cache.clone();
+                        let installation_lock = installation_lock.clone();
+                        let box_id = box_id.clone();
+                        let system_limits = system_limits.clone();
+                        let execution_semaphore = execution_semaphore.clone();
+                        let host_monitor = host_monitor.clone();
+                        let events = events.clone();
+                        move |query, req| {
+                            submit_job(
+                                jobs,
+                                execution_semaphore,
+                                box_id,
+                                metadata_cache,
+                                installation_lock,
+                                system_limits,
+                                host_monitor,
+                                events,
+                                req,
+                                query,
+                            )
+                        }
+                    }),
+                    short_timeout,
+                ),
+                max_decompressed_body_size,
+            ),
+        )
+        .route(
+            "/jobs/:id",
+            with_timeout(
+                get({
+                    let jobs = jobs.clone();
+                    move |path| get_job(path, jobs)
+                })
+                .delete({
+                    let jobs = jobs.clone();
+                    move |path| cancel_job(path, jobs)
+                }),
+                short_timeout,
+            ),
+        )
+        .route(
+            "/jobs/:id/result",
+            with_timeout(
+                delete({
+                    let jobs = jobs.clone();
+                    move |path| purge_job_result(path, jobs)
+                }),
+                short_timeout,
+            ),
+        )
+        .route(
+            "/execute/estimate",
+            with_timeout(
+                post({
+                    let metadata_cache = metadata_cache.clone();
+                    let installation_lock = installation_lock.clone();
+                    let box_id = box_id.clone();
+                    let system_limits = system_limits.clone();
+                    let execution_semaphore = execution_semaphore.clone();
+                    let host_monitor = host_monitor.clone();
+                    move |query, req| {
+                        estimate_execution(
+                            execution_semaphore,
+                            box_id,
+                            metadata_cache,
+                            installation_lock,
+                            system_limits,
+                            host_monitor,
+                            req,
+                            query,
+                        )
+                    }
+                }),
+                short_timeout,
+            ),
+        )
+        .route(
+            "/piston/execute",
+            with_decompression(
+                with_compression(
+                    with_timeout(
+                        post({
+                            let metadata_cache = metadata_cache.clone();
+                            let installation_lock = installation_lock.clone();
+                            let box_id = box_id.clone();
+                            let system_limits = system_limits.clone();
+                            let execution_semaphore = execution_semaphore.clone();
+                            let host_monitor = host_monitor.clone();
+                            let events = events.clone();
+                            move |query, req| {
+                                execute(
+                                    execution_semaphore,
+                                    box_id,
+                                    metadata_cache,
+                                    installation_lock,
+                                    system_limits,
+                                    host_monitor,
+                                    events,
+                                    ResponseDialect::Piston,
+                                    req,
+                                    query,
+                                )
+                            }
+                        }),
+                        long_timeout,
+                    ),
+                    compression_min_bytes,
+                ),
+                max_decompressed_body_size,
+            ),
         );
 
+    // Uploads are streamed to disk by their handler under DATASET_UPLOAD_TIMEOUT,
+    // so they are merged in after the other bodies are given `body_timeout`
+    let uploads = Router::new().route("/datasets/:name", put(upload_dataset));
+
     let port = env::var("PORT").unwrap_or_else(|_| {
         eprintln!("Could not find PORT environment variable, defaulting to 5000");
         DEFAULT_PORT.into()
     });
 
-    let signal = async {
+    let signal = async move {
         signal::unix::signal(SignalKind::terminate())
             .expect("Failed to install SIGTERM handler")
             .recv()
             .await;
         eprintln!("Received SIGTERM, shutting down...");
+        events.close();
     };
 
+    // Only the server delivers callbacks, a second process would send each one again
+    // and race it for the delivery rows
+    match callbacks::resume_pending().await {
+        Ok(0) => {}
+        Ok(resumed) => eprintln!("Resumed {resumed} pending callback(s)"),
+        Err(e) => eprintln!("Failed to resume pending callbacks: {e}"),
+    }
+
     eprintln!("Listening on port {port}");
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{port}"))
         .await
         .expect("Failed to bind to address");
-    axum::serve(listener, app)
-        .with_graceful_shutdown(signal)
-        .await
-        .expect("Failed to start server");
+    server::serve(
+        listener,
+        app.layer(middleware::from_fn(move |req, next| {
+            read_body_with_timeout(body_timeout, req, next)
+        }))
+        .merge(uploads)
+        .layer(middleware::from_fn(move |req, next| {
+            refuse_writes_when_disk_full(host_monitor.clone(), req, next)
+        }))
+        .layer(middleware::from_fn(add_retry_hints))
+        .layer(middleware::from_fn(log_request)),
+        server_limits,
+        signal,
+    )
+    .await;
 }
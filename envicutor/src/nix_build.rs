@@ -0,0 +1,73 @@
+// How much of the host the nix builds of an installation may use. A request can
+// ask for its own `--cores` and `--max-jobs`, which are clamped to the maximums
+
+use std::{sync::OnceLock, thread};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+
+#[derive(Clone, Copy)]
+pub struct NixBuildConfig {
+    pub max_cores: u32,
+    pub max_jobs: u32,
+}
+
+impl Default for NixBuildConfig {
+    fn default() -> Self {
+        let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get() as u32);
+        NixBuildConfig {
+            max_cores: cpus,
+            max_jobs: cpus,
+        }
+    }
+}
+
+// What an installation was built with, given back in its response
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct NixBuildOptions {
+    pub cores: u32,
+    pub max_jobs: u32,
+}
+
+static NIX_BUILD_CONFIG: OnceLock<NixBuildConfig> = OnceLock::new();
+
+pub fn use_nix_build_config(config: NixBuildConfig) -> Result<(), Error> {
+    NIX_BUILD_CONFIG
+        .set(config)
+        .map_err(|_| anyhow!("The nix build config was already set"))
+}
+
+impl NixBuildConfig {
+    // Every build gets all the cores allowed, and the jobs share them: a build
+    // can run as many derivations at once as there are cores for each to have
+    // at least one
+    pub fn resolve(&self, cores: Option<u32>, max_jobs: Option<u32>) -> NixBuildOptions {
+        let cores = cores
+            .unwrap_or(self.max_cores)
+            .clamp(1, self.max_cores.max(1));
+        let max_jobs = max_jobs
+            .unwrap_or(self.max_cores.max(1) / cores)
+            .clamp(1, self.max_jobs.max(1));
+        NixBuildOptions { cores, max_jobs }
+    }
+}
+
+pub fn resolve(cores: Option<u32>, max_jobs: Option<u32>) -> NixBuildOptions {
+    NIX_BUILD_CONFIG
+        .get()
+        .copied()
+        .unwrap_or_default()
+        .resolve(cores, max_jobs)
+}
+
+impl NixBuildOptions {
+    // Passed to nix-shell, NIX_BUILD_CORES in the builds follows `--cores`
+    pub fn args(&self) -> [String; 4] {
+        [
+            "--cores".to_string(),
+            self.cores.to_string(),
+            "--max-jobs".to_string(),
+            self.max_jobs.to_string(),
+        ]
+    }
+}
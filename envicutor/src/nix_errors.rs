@@ -0,0 +1,209 @@
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureCategory {
+    MissingAttribute,
+    EvalError,
+    FetchFailure,
+    HashMismatch,
+    Timeout,
+    Unknown,
+}
+
+impl FailureCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureCategory::MissingAttribute => "missing_attribute",
+            FailureCategory::EvalError => "eval_error",
+            FailureCategory::FetchFailure => "fetch_failure",
+            FailureCategory::HashMismatch => "hash_mismatch",
+            FailureCategory::Timeout => "timeout",
+            FailureCategory::Unknown => "unknown",
+        }
+    }
+}
+
+// Checked in order, the first category with a matching pattern wins
+// (e.g. a hash mismatch also mentions the fetch that produced it, and a failed
+// download can mention a connection that timed out)
+const PATTERNS: &[(FailureCategory, &[&str])] = &[
+    (
+        FailureCategory::HashMismatch,
+        &[
+            "hash mismatch in fixed-output derivation",
+            "hash mismatch in file downloaded",
+        ],
+    ),
+    (
+        FailureCategory::FetchFailure,
+        &[
+            "unable to download",
+            "could not resolve host",
+            "couldn't resolve host",
+            "failed to connect to",
+            "connection timed out",
+            "unable to fetch",
+        ],
+    ),
+    (
+        FailureCategory::Timeout,
+        &["timed out after", "time limit exceeded"],
+    ),
+    (
+        FailureCategory::MissingAttribute,
+        &["' missing", "undefined variable '"],
+    ),
+    (
+        FailureCategory::EvalError,
+        &[
+            "infinite recursion encountered",
+            "syntax error",
+            "while evaluating",
+            "evaluation aborted",
+            "called without required argument",
+            "cannot coerce",
+            "value is a",
+        ],
+    ),
+];
+
+pub fn classify(stderr: &str) -> FailureCategory {
+    let lowercase = stderr.to_lowercase();
+    PATTERNS
+        .iter()
+        .find(|(_, patterns)| patterns.iter().any(|pattern| lowercase.contains(pattern)))
+        .map(|(category, _)| *category)
+        .unwrap_or(FailureCategory::Unknown)
+}
+
+// The first "error:" line along with its indented continuation lines
+pub fn first_error_block(stderr: &str) -> Option<String> {
+    let mut lines = stderr.lines().skip_while(|line| !line.contains("error:"));
+    let mut block = vec![lines.next()?];
+    for line in lines {
+        if !line.is_empty() && !line.starts_with(char::is_whitespace) {
+            break;
+        }
+        block.push(line);
+    }
+    while block.last().is_some_and(|line| line.trim().is_empty()) {
+        block.pop();
+    }
+    Some(block.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_MISMATCH: &str = "\
+error: hash mismatch in fixed-output derivation '/nix/store/9kd4z7vq6x0m-source.drv':
+         specified: sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+            got:    sha256-3rf0sKj0rWZ8dGxzJDpJr6Or1yDPo4iRk+KhsC5URE8=
+error: 1 dependencies of derivation '/nix/store/pq2l8r1n5c3w-nix-shell.drv' failed to build
+";
+
+    const FETCH_FAILURE: &str = "\
+warning: error: unable to download 'https://cache.nixos.org/nix-cache-info': Couldn't resolve host name (6); retrying in 281 ms
+error: unable to download 'https://github.com/NixOS/nixpkgs/archive/nixos-23.11.tar.gz': Couldn't resolve host name (6)
+";
+
+    const TIMEOUT: &str = "\
+building '/nix/store/a1b2c3d4-python3-3.11.6.drv'...
+error: builder for '/nix/store/a1b2c3d4-python3-3.11.6.drv' timed out after 120 seconds
+";
+
+    const MISSING_ATTRIBUTE: &str = "\
+error:
+       … while evaluating the attribute 'buildInputs' of the derivation 'nix-shell'
+
+       error: attribute 'pythn3' missing
+
+       at «string»:1:48:
+
+            1| with import <nixpkgs> {}; mkShell { packages = [ pkgs.pythn3 ]; }
+             |                                                ^
+       Did you mean python3?
+";
+
+    const UNDEFINED_VARIABLE: &str = "\
+error: undefined variable 'pythn3'
+
+       at «string»:1:48:
+
+            1| with import <nixpkgs> {}; mkShell { packages = [ pythn3 ]; }
+             |                                                ^
+";
+
+    const SYNTAX_ERROR: &str = "\
+error: syntax error, unexpected ']', expecting ';'
+
+       at «string»:1:58:
+
+            1| with import <nixpkgs> {}; mkShell { packages = [ python3 ]]; }
+             |                                                          ^
+";
+
+    const INFINITE_RECURSION: &str = "\
+error: infinite recursion encountered
+
+       at /nix/store/k9v2-source/lib/fixed-points.nix:19:5:
+";
+
+    const UNKNOWN: &str = "\
+error: cannot connect to socket at '/nix/var/nix/daemon-socket/socket': Connection refused
+";
+
+    #[test]
+    fn failures_are_classified_by_their_messages() {
+        for (stderr, category) in [
+            (HASH_MISMATCH, FailureCategory::HashMismatch),
+            (FETCH_FAILURE, FailureCategory::FetchFailure),
+            (TIMEOUT, FailureCategory::Timeout),
+            (MISSING_ATTRIBUTE, FailureCategory::MissingAttribute),
+            (UNDEFINED_VARIABLE, FailureCategory::MissingAttribute),
+            (SYNTAX_ERROR, FailureCategory::EvalError),
+            (INFINITE_RECURSION, FailureCategory::EvalError),
+            (UNKNOWN, FailureCategory::Unknown),
+            ("", FailureCategory::Unknown),
+        ] {
+            assert_eq!(classify(stderr), category, "{stderr}");
+        }
+    }
+
+    #[test]
+    fn the_first_error_is_kept_with_its_continuation_lines() {
+        assert_eq!(
+            first_error_block(HASH_MISMATCH).as_deref(),
+            Some(
+                "\
+error: hash mismatch in fixed-output derivation '/nix/store/9kd4z7vq6x0m-source.drv':
+         specified: sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+            got:    sha256-3rf0sKj0rWZ8dGxzJDpJr6Or1yDPo4iRk+KhsC5URE8="
+            )
+        );
+        // Blank lines inside the block are kept, the trailing ones are not
+        assert_eq!(
+            first_error_block(SYNTAX_ERROR).as_deref(),
+            Some(SYNTAX_ERROR.trim_end())
+        );
+    }
+
+    #[test]
+    fn what_comes_before_the_first_error_is_skipped() {
+        assert_eq!(
+            first_error_block(TIMEOUT).as_deref(),
+            Some("error: builder for '/nix/store/a1b2c3d4-python3-3.11.6.drv' timed out after 120 seconds")
+        );
+        // A warning quoting an error counts as the first error
+        assert_eq!(
+            first_error_block(FETCH_FAILURE).as_deref(),
+            FETCH_FAILURE.lines().next()
+        );
+        assert_eq!(
+            first_error_block("building...\nwarning: dirty tree\n"),
+            None
+        );
+    }
+}
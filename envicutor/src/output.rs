@@ -0,0 +1,71 @@
+// The stdout or stderr of a stage. The bytes are kept as the program wrote
+// them and only turned into text when serialized, in the encoding its request
+// asked for
+
+use std::borrow::Cow;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::{Deserialize, Serialize, Serializer};
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputEncoding {
+    // Invalid UTF-8 is replaced with U+FFFD
+    #[default]
+    Utf8,
+    Base64,
+}
+
+impl OutputEncoding {
+    pub fn is_utf8(&self) -> bool {
+        *self == OutputEncoding::Utf8
+    }
+
+    pub fn encode<'a>(self, bytes: &'a [u8]) -> Cow<'a, str> {
+        match self {
+            OutputEncoding::Utf8 => String::from_utf8_lossy(bytes),
+            OutputEncoding::Base64 => Cow::Owned(BASE64_STANDARD.encode(bytes)),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct StageOutput {
+    bytes: Vec<u8>,
+    encoding: OutputEncoding,
+}
+
+impl StageOutput {
+    pub fn new(bytes: Vec<u8>, encoding: OutputEncoding) -> StageOutput {
+        StageOutput { bytes, encoding }
+    }
+
+    // What the program wrote as text, whatever the encoding of the response
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.bytes)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // Cuts the output to at most `max_bytes`, without splitting a UTF-8
+    // character when it is returned as text. Returns whether anything was cut
+    pub fn truncate(&mut self, max_bytes: usize) -> bool {
+        if self.bytes.len() <= max_bytes {
+            return false;
+        }
+        let end = match (self.encoding, std::str::from_utf8(&self.bytes[..max_bytes])) {
+            (OutputEncoding::Utf8, Err(e)) if e.error_len().is_none() => e.valid_up_to(),
+            _ => max_bytes,
+        };
+        self.bytes.truncate(end);
+        true
+    }
+}
+
+impl Serialize for StageOutput {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.encoding.encode(&self.bytes))
+    }
+}
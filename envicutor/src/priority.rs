@@ -0,0 +1,93 @@
+// How urgent an execution is. Batch executions get a lower cpu.weight than
+// interactive ones, so they only take the CPU time interactive ones leave
+
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::cgroup;
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Interactive,
+    Batch,
+}
+
+// cpu.weight of the box of every priority, between 1 and 10000
+#[derive(Clone, Copy)]
+pub struct CpuWeights {
+    pub interactive: u32,
+    pub batch: u32,
+}
+
+impl Default for CpuWeights {
+    fn default() -> Self {
+        CpuWeights {
+            interactive: 100,
+            batch: 25,
+        }
+    }
+}
+
+pub const MIN_CPU_WEIGHT: u32 = 1;
+pub const MAX_CPU_WEIGHT: u32 = 10000;
+
+static CPU_WEIGHTS: OnceLock<CpuWeights> = OnceLock::new();
+
+pub fn use_cpu_weights(weights: CpuWeights) -> Result<(), Error> {
+    CPU_WEIGHTS
+        .set(weights)
+        .map_err(|_| anyhow!("CPU weights were already set"))
+}
+
+impl CpuWeights {
+    pub fn of(&self, priority: Priority) -> u32 {
+        match priority {
+            Priority::Interactive => self.interactive,
+            Priority::Batch => self.batch,
+        }
+    }
+}
+
+// The weight given to the boxes of `priority`, none when boxes can't be weighted
+pub fn cpu_weight(priority: Priority) -> Option<u32> {
+    weight_of(
+        cgroup::can_set_cpu_weight(),
+        CPU_WEIGHTS.get().copied().unwrap_or_default(),
+        priority,
+    )
+}
+
+fn weight_of(supported: bool, weights: CpuWeights, priority: Priority) -> Option<u32> {
+    supported.then(|| weights.of(priority))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boxes_are_weighted_by_priority() {
+        let weights = CpuWeights {
+            interactive: 400,
+            batch: 10,
+        };
+        assert_eq!(weight_of(true, weights, Priority::Interactive), Some(400));
+        assert_eq!(weight_of(true, weights, Priority::Batch), Some(10));
+        let defaults = CpuWeights::default();
+        assert!(defaults.of(Priority::Batch) < defaults.of(Priority::Interactive));
+    }
+
+    #[test]
+    fn boxes_are_left_alone_without_cpu_weights() {
+        let weights = CpuWeights::default();
+        assert_eq!(weight_of(false, weights, Priority::Interactive), None);
+        assert_eq!(weight_of(false, weights, Priority::Batch), None);
+        // The probe never ran
+        assert!(!cgroup::can_set_cpu_weight());
+        assert_eq!(cpu_weight(Priority::Interactive), None);
+    }
+}
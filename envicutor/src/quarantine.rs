@@ -0,0 +1,276 @@
+// Box ids whose box failed to initialize repeatedly, which usually means it was
+// left in a state isolate can't recover from by itself. They are skipped when
+// allocating boxes, kept in the database across restarts, and tried again on
+// every garbage collection
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use rusqlite::Connection;
+use serde::Serialize;
+use tokio::task;
+
+use crate::{isolate::Isolate, storage};
+
+// Failed initializations in a row before a box id is quarantined
+const QUARANTINE_THRESHOLD: u32 = 2;
+
+static QUARANTINE: OnceLock<Quarantine> = OnceLock::new();
+
+#[derive(Serialize, Clone)]
+pub struct QuarantineEntry {
+    pub box_id: u64,
+    // The last initialization error
+    pub reason: String,
+    // Unix seconds
+    pub quarantined_at: u64,
+    pub failures: u32,
+}
+
+#[derive(Default)]
+struct Boxes {
+    // Failed initializations in a row of the box ids that are not quarantined
+    failures: HashMap<u64, u32>,
+    entries: HashMap<u64, QuarantineEntry>,
+}
+
+struct Quarantine {
+    db_path: String,
+    boxes: Mutex<Boxes>,
+}
+
+fn quarantine() -> &'static Quarantine {
+    QUARANTINE.get_or_init(|| Quarantine::new(storage::state_db_path().to_string()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+impl Quarantine {
+    fn new(db_path: String) -> Quarantine {
+        Quarantine {
+            db_path,
+            boxes: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Boxes> {
+        self.boxes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn persist(&self, entry: QuarantineEntry) -> Result<(), Error> {
+        let db_path = self.db_path.clone();
+        task::spawn_blocking(move || {
+            let connection = Connection::open(db_path)?;
+            connection.execute(
+                "INSERT OR REPLACE INTO box_quarantine (box_id, reason, quarantined_at, failures) VALUES (?, ?, ?, ?)",
+                (entry.box_id, &entry.reason, entry.quarantined_at, entry.failures),
+            )?;
+            Ok(())
+        })
+        .await
+        .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+    }
+
+    fn load(&self) -> Result<usize, Error> {
+        let connection = Connection::open(&self.db_path)?;
+        let mut stmt = connection
+            .prepare("SELECT box_id, reason, quarantined_at, failures FROM box_quarantine")?;
+        let entries = stmt
+            .query_map([], |row| {
+                Ok(QuarantineEntry {
+                    box_id: row.get(0)?,
+                    reason: row.get(1)?,
+                    quarantined_at: row.get(2)?,
+                    failures: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut boxes = self.lock();
+        for entry in entries {
+            boxes.entries.insert(entry.box_id, entry);
+        }
+        Ok(boxes.entries.len())
+    }
+
+    fn entries(&self) -> Vec<QuarantineEntry> {
+        let mut entries: Vec<QuarantineEntry> = self.lock().entries.values().cloned().collect();
+        entries.sort_by_key(|entry| entry.box_id);
+        entries
+    }
+
+    async fn record_init_failure(&self, box_id: u64, reason: &str) {
+        let entry = {
+            let mut boxes = self.lock();
+            if let Some(entry) = boxes.entries.get_mut(&box_id) {
+                entry.failures += 1;
+                entry.reason = reason.to_string();
+                entry.clone()
+            } else {
+                let failures = boxes.failures.entry(box_id).or_default();
+                *failures += 1;
+                if *failures < QUARANTINE_THRESHOLD {
+                    return;
+                }
+                let entry = QuarantineEntry {
+                    box_id,
+                    reason: reason.to_string(),
+                    quarantined_at: now(),
+                    failures: *failures,
+                };
+                boxes.failures.remove(&box_id);
+                boxes.entries.insert(box_id, entry.clone());
+                eprintln!(
+                    "Quarantined box {box_id} after {} failed initializations",
+                    entry.failures
+                );
+                entry
+            }
+        };
+        if let Err(e) = self.persist(entry).await {
+            eprintln!("Failed to persist the quarantine of box {box_id}: {e}");
+        }
+    }
+
+    fn record_init_success(&self, box_id: u64) {
+        self.lock().failures.remove(&box_id);
+    }
+
+    async fn clear(&self, box_id: u64) -> Result<bool, Error> {
+        let removed = self.lock().entries.remove(&box_id).is_some();
+        let db_path = self.db_path.clone();
+        task::spawn_blocking(move || {
+            let connection = Connection::open(db_path)?;
+            connection.execute("DELETE FROM box_quarantine WHERE box_id = ?", [box_id])?;
+            Ok::<_, Error>(())
+        })
+        .await
+        .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))??;
+        if removed {
+            eprintln!("Box {box_id} is no longer quarantined");
+        }
+        Ok(removed)
+    }
+}
+
+// Called once at startup, before any box is allocated
+pub fn load() -> Result<usize, Error> {
+    quarantine().load()
+}
+
+pub fn is_quarantined(box_id: u64) -> bool {
+    quarantine().lock().entries.contains_key(&box_id)
+}
+
+pub fn size() -> usize {
+    quarantine().lock().entries.len()
+}
+
+pub fn entries() -> Vec<QuarantineEntry> {
+    quarantine().entries()
+}
+
+pub async fn record_init_failure(box_id: u64, reason: &str) {
+    quarantine().record_init_failure(box_id, reason).await
+}
+
+pub fn record_init_success(box_id: u64) {
+    quarantine().record_init_success(box_id)
+}
+
+// Returns whether the box id was quarantined
+pub async fn clear(box_id: u64) -> Result<bool, Error> {
+    quarantine().clear(box_id).await
+}
+
+// Cleans up every quarantined box and clears the ones that can then be
+// initialized, returns how many were cleared
+pub async fn rehabilitate() -> usize {
+    let mut cleared = 0;
+    for entry in entries() {
+        if let Err(e) = Isolate::cleanup(entry.box_id).await {
+            eprintln!("Failed to clean up quarantined box {}: {e}", entry.box_id);
+        }
+        match Isolate::init(entry.box_id).await {
+            // Cleaned up here instead of when dropped, so that it is done before
+            // the box id can be allocated again
+            Ok(test_box) => std::mem::forget(test_box),
+            Err(_) => continue,
+        }
+        if let Err(e) = Isolate::cleanup(entry.box_id).await {
+            eprintln!("Failed to clean up quarantined box {}: {e}", entry.box_id);
+            continue;
+        }
+        match clear(entry.box_id).await {
+            Ok(_) => cleared += 1,
+            Err(e) => eprintln!(
+                "Failed to clear the quarantine of box {}: {e}",
+                entry.box_id
+            ),
+        }
+    }
+    cleared
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    fn create_state_db(db_path: &str) {
+        Connection::open(db_path)
+            .unwrap()
+            .execute_batch(include_str!("../db.sql"))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn boxes_failing_repeatedly_are_quarantined_across_reloads() {
+        let tmp = std::env::temp_dir().to_string_lossy().into_owned();
+        let dir = TempDir::new(format!("{tmp}/envicutor-quarantine-{}", std::process::id()))
+            .await
+            .unwrap();
+        let db_path = format!("{}/state.db", dir.path);
+        create_state_db(&db_path);
+
+        let quarantine = Quarantine::new(db_path.clone());
+        assert_eq!(quarantine.load().unwrap(), 0);
+        quarantine.record_init_failure(3, "first").await;
+        assert!(quarantine.entries().is_empty());
+        quarantine.record_init_failure(3, "second").await;
+        quarantine.record_init_failure(3, "third").await;
+        // Failures in a row only
+        quarantine.record_init_failure(5, "first").await;
+        quarantine.record_init_success(5);
+        quarantine.record_init_failure(5, "after a success").await;
+
+        let entries = quarantine.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].box_id, 3);
+        assert_eq!(entries[0].failures, 3);
+        assert_eq!(entries[0].reason, "third");
+
+        let reloaded = Quarantine::new(db_path.clone());
+        assert_eq!(reloaded.load().unwrap(), 1);
+        let entries = reloaded.entries();
+        assert_eq!(entries[0].box_id, 3);
+        assert_eq!(entries[0].failures, 3);
+        assert_eq!(entries[0].reason, "third");
+        assert_eq!(
+            entries[0].quarantined_at,
+            quarantine.entries()[0].quarantined_at
+        );
+
+        assert!(reloaded.clear(3).await.unwrap());
+        assert!(!reloaded.clear(3).await.unwrap());
+        assert!(reloaded.entries().is_empty());
+        assert_eq!(Quarantine::new(db_path).load().unwrap(), 0);
+    }
+}
@@ -0,0 +1,30 @@
+use anyhow::{anyhow, Error};
+
+// Caps on how many entries a single request may carry, entries are cheap one
+// by one but thousands of them are not
+#[derive(Clone, Copy)]
+pub struct RequestLimits {
+    pub max_env_patterns: usize,
+    pub max_suite_cases: usize,
+    pub max_aliases: usize,
+    // Sandbox runs (extraction, compilation and run stages) one request may trigger
+    pub max_stages_per_request: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_env_patterns: 128,
+            max_suite_cases: 512,
+            max_aliases: 32,
+            max_stages_per_request: 1024,
+        }
+    }
+}
+
+pub fn check_count(field: &str, count: usize, max: usize) -> Result<(), Error> {
+    if count > max {
+        return Err(anyhow!("{field} can't have more than {max} entries"));
+    }
+    Ok(())
+}
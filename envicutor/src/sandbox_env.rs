@@ -0,0 +1,163 @@
+// Variables every stage starts with, below the runtime's environment snapshot
+// (sourced by the stage itself, see `stage_command`) and what the sandbox sets
+
+use std::{collections::BTreeMap, sync::OnceLock};
+
+use anyhow::{anyhow, Error};
+
+use crate::env_snapshot::{is_shell_name, STAGE_MANAGED};
+
+pub type SandboxEnv = BTreeMap<String, String>;
+
+pub const MAX_REQUEST_ENV_VARS: usize = 128;
+// The names of the request's variables, which the stage sets again after
+// sourcing the runtime's environment so that they take precedence over it
+pub const REQUEST_ENV_KEYS: &str = "ENVICUTOR_REQUEST_ENV";
+
+// Set once at startup, variables requests can't set
+static DENIED_REQUEST_ENV: OnceLock<Vec<String>> = OnceLock::new();
+
+pub fn use_denied_request_env(denied: Vec<String>) -> Result<(), Error> {
+    DENIED_REQUEST_ENV
+        .set(denied)
+        .map_err(|_| anyhow!("The denied request environment was already set"))
+}
+
+// Given as a JSON object, e.g. {"TZ": "UTC", "ENVICUTOR": "1"}
+pub fn parse_default_env(json: &str) -> Result<SandboxEnv, Error> {
+    let env: SandboxEnv = serde_json::from_str(json)
+        .map_err(|e| anyhow!("Expected a JSON object of strings: {e}"))?;
+    for (key, value) in &env {
+        if !is_shell_name(key) {
+            return Err(anyhow!("{key} is not a valid variable name"));
+        }
+        if STAGE_MANAGED.contains(&key.as_str()) {
+            return Err(anyhow!("{key} is set by the sandbox for every stage"));
+        }
+        if value.contains('\0') {
+            return Err(anyhow!("The value of {key} contains a NUL byte"));
+        }
+    }
+    Ok(env)
+}
+
+// Errors are safe to show in the response
+pub fn validate_request_env(env: &SandboxEnv) -> Result<(), String> {
+    if env.len() > MAX_REQUEST_ENV_VARS {
+        return Err(format!(
+            "env can't have more than {MAX_REQUEST_ENV_VARS} entries"
+        ));
+    }
+    let denied = DENIED_REQUEST_ENV.get().map_or(&[][..], Vec::as_slice);
+    for (key, value) in env {
+        if !is_shell_name(key) {
+            return Err(format!(
+                "{key} is not a valid variable name, expected [A-Za-z_][A-Za-z0-9_]*"
+            ));
+        }
+        if STAGE_MANAGED.contains(&key.as_str()) || key == REQUEST_ENV_KEYS || denied.contains(key)
+        {
+            return Err(format!("{key} can't be set by requests"));
+        }
+        if value.contains('\0') {
+            return Err(format!("The value of {key} contains a NUL byte"));
+        }
+    }
+    Ok(())
+}
+
+// Later layers take precedence over earlier ones
+pub fn merge(layers: &[&SandboxEnv]) -> SandboxEnv {
+    let mut merged = SandboxEnv::new();
+    for layer in layers {
+        merged.extend(
+            layer
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+    }
+    merged
+}
+
+// What isolate passes to a stage, from lowest to highest precedence: the defaults,
+// the request's variables and those the sandbox manages. The runtime's
+// environment comes between the defaults and the request, see REQUEST_ENV_KEYS
+pub fn stage_env(
+    default_env: &SandboxEnv,
+    request_env: &SandboxEnv,
+    sandbox_managed: &SandboxEnv,
+) -> SandboxEnv {
+    let mut env = merge(&[default_env, request_env, sandbox_managed]);
+    if !request_env.is_empty() {
+        let keys: Vec<&str> = request_env.keys().map(String::as_str).collect();
+        env.insert(REQUEST_ENV_KEYS.to_string(), keys.join(" "));
+    }
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(entries: &[(&str, &str)]) -> SandboxEnv {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn later_layers_take_precedence() {
+        let default_env = env(&[("TZ", "UTC"), ("LANG", "C"), ("ENVICUTOR", "1")]);
+        let runtime_env = env(&[("TZ", "Asia/Tokyo"), ("LANG", "C.UTF-8"), ("CC", "gcc")]);
+        let request_env = env(&[("TZ", "Europe/Paris"), ("CC", "clang"), ("DEBUG", "1")]);
+        let sandbox_managed = env(&[("HOME", "/tmp"), ("DEBUG", "0")]);
+
+        let merged = merge(&[&default_env, &runtime_env, &request_env, &sandbox_managed]);
+        assert_eq!(
+            merged,
+            env(&[
+                ("CC", "clang"),
+                ("DEBUG", "0"),
+                ("ENVICUTOR", "1"),
+                ("HOME", "/tmp"),
+                ("LANG", "C.UTF-8"),
+                ("TZ", "Europe/Paris"),
+            ])
+        );
+        assert_eq!(merge(&[]), SandboxEnv::new());
+        assert_eq!(merge(&[&default_env]), default_env);
+    }
+
+    #[test]
+    fn the_stage_env_lists_the_request_keys() {
+        let default_env = env(&[("TZ", "UTC"), ("ENVICUTOR", "1")]);
+        let request_env = env(&[("TZ", "Europe/Paris"), ("RUST_BACKTRACE", "1")]);
+        let sandbox_managed = env(&[("HOME", "/tmp")]);
+
+        let stage = stage_env(&default_env, &request_env, &sandbox_managed);
+        assert_eq!(
+            stage,
+            env(&[
+                ("ENVICUTOR", "1"),
+                (REQUEST_ENV_KEYS, "RUST_BACKTRACE TZ"),
+                ("HOME", "/tmp"),
+                ("RUST_BACKTRACE", "1"),
+                ("TZ", "Europe/Paris"),
+            ])
+        );
+
+        let without_request = stage_env(&default_env, &SandboxEnv::new(), &sandbox_managed);
+        assert!(!without_request.contains_key(REQUEST_ENV_KEYS));
+        assert_eq!(without_request["TZ"], "UTC");
+    }
+
+    #[test]
+    fn requests_cant_set_what_the_sandbox_manages() {
+        assert!(validate_request_env(&env(&[("TZ", "UTC")])).is_ok());
+        assert!(validate_request_env(&env(&[("HOME", "/root")])).is_err());
+        assert!(validate_request_env(&env(&[(REQUEST_ENV_KEYS, "PATH")])).is_err());
+        assert!(validate_request_env(&env(&[("1TZ", "UTC")])).is_err());
+        assert!(validate_request_env(&env(&[("TZ", "U\0TC")])).is_err());
+    }
+}
@@ -0,0 +1,44 @@
+// Which sandbox ran an execution, so that an execution reported as behaving
+// oddly can be looked into without going through the logs. Left out of the
+// responses, the history and the events of deployments that don't enable it
+
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+
+const BACKEND: &str = "isolate";
+const HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
+
+// Set once at startup, nothing is exposed without it
+static SANDBOX_HOST: OnceLock<String> = OnceLock::new();
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SandboxInfo {
+    pub backend: &'static str,
+    // The box the last stage ran in, boxes are renewed between stages
+    pub box_id: u64,
+    pub host: String,
+}
+
+pub fn use_sandbox_host(host: String) -> Result<(), Error> {
+    SANDBOX_HOST
+        .set(host)
+        .map_err(|_| anyhow!("The sandbox host was already set"))
+}
+
+pub fn hostname() -> Result<String, Error> {
+    std::fs::read_to_string(HOSTNAME_PATH)
+        .map(|hostname| hostname.trim().to_string())
+        .map_err(|e| anyhow!("Failed to read {HOSTNAME_PATH}: {e}"))
+}
+
+// None when it isn't exposed or nothing ran in a box
+pub fn describe(box_id: Option<u64>) -> Option<SandboxInfo> {
+    let host = SANDBOX_HOST.get()?;
+    Some(SandboxInfo {
+        backend: BACKEND,
+        box_id: box_id?,
+        host: host.clone(),
+    })
+}
@@ -0,0 +1,294 @@
+// Static checks of the compile and run scripts of a runtime, done at installation
+// for the usual reasons a freshly installed runtime fails its first execution
+
+use std::path::Path;
+
+use serde::Serialize;
+
+// Prepended to scripts that do not pick their own interpreter
+pub const DEFAULT_SHEBANG: &str = "#!/bin/bash\n\n";
+const ENV_PATH: &str = "/usr/bin/env";
+// Compilations only get the source file name as $1, see `stage_command`
+const PROVIDED_POSITIONAL_PARAMETERS: u32 = 1;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FindingKind {
+    MissingInterpreter,
+    CrlfLineEndings,
+    UnprovidedPositionalParameter,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ScriptFinding {
+    pub script: &'static str,
+    pub kind: FindingKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl ScriptFinding {
+    // Only these block the installation, and only when it asks for strict scripts
+    pub fn is_hard_failure(&self) -> bool {
+        self.kind == FindingKind::MissingInterpreter
+    }
+}
+
+// Without strict scripts every finding is only a warning
+pub fn blocks_installation(findings: &[ScriptFinding], strict_scripts: bool) -> bool {
+    strict_scripts && findings.iter().any(ScriptFinding::is_hard_failure)
+}
+
+pub fn with_shebang(script: &str) -> String {
+    if script.starts_with("#!") {
+        script.to_string()
+    } else {
+        format!("{DEFAULT_SHEBANG}{script}")
+    }
+}
+
+pub fn has_crlf(script: &str) -> bool {
+    script.contains("\r\n")
+}
+
+pub fn normalize_line_endings(
+    script_name: &'static str,
+    script: &mut String,
+) -> Option<ScriptFinding> {
+    if !has_crlf(script) {
+        return None;
+    }
+    *script = script.replace("\r\n", "\n");
+    Some(ScriptFinding {
+        script: script_name,
+        kind: FindingKind::CrlfLineEndings,
+        line: None,
+        message: "CRLF line endings were converted to LF".to_string(),
+    })
+}
+
+// Finds $N and ${N} with N past the parameters stages are given
+fn unprovided_parameter(line: &str) -> Option<u32> {
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            // Nothing is expanded inside single quotes or comments
+            b'\'' => match line[i + 1..].find('\'') {
+                Some(end) => i += end + 2,
+                None => return None,
+            },
+            b'#' if i == 0 || bytes[i - 1].is_ascii_whitespace() => return None,
+            b'\\' => i += 2,
+            b'$' => {
+                let rest = &line[i + 1..];
+                let digits = rest.strip_prefix('{').unwrap_or(rest);
+                let end = digits
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(digits.len());
+                // $10 is ${1}0, only braces make multi-digit parameters
+                let end = if digits.len() == rest.len() {
+                    end.min(1)
+                } else {
+                    end
+                };
+                if let Ok(n) = digits[..end].parse::<u32>() {
+                    if n > PROVIDED_POSITIONAL_PARAMETERS {
+                        return Some(n);
+                    }
+                }
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+pub fn find_unprovided_parameters(script_name: &'static str, script: &str) -> Vec<ScriptFinding> {
+    script
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            unprovided_parameter(line).map(|n| ScriptFinding {
+                script: script_name,
+                kind: FindingKind::UnprovidedPositionalParameter,
+                line: Some(i + 1),
+                message: format!(
+                    "${n} is always empty, compilations only get the source file name as $1"
+                ),
+            })
+        })
+        .collect()
+}
+
+fn find_in_path(program: &str, path_var: &str) -> bool {
+    path_var
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .any(|dir| Path::new(dir).join(program).is_file())
+}
+
+// The interpreter has to exist in the base image or in the runtime's closure,
+// `path_var` is the PATH of the runtime's environment snapshot
+pub fn check_interpreter(
+    script_name: &'static str,
+    script: &str,
+    path_var: &str,
+) -> Option<ScriptFinding> {
+    let shebang = script.lines().next()?.strip_prefix("#!")?;
+    let mut words = shebang.split_whitespace();
+    let interpreter = words.next().unwrap_or("");
+    let missing = if interpreter == ENV_PATH {
+        match words.find(|word| !word.starts_with('-')) {
+            Some(program) if find_in_path(program, path_var) => None,
+            Some(program) => Some(format!(
+                "{program} is not in the PATH of the runtime's environment"
+            )),
+            None => Some(format!("{ENV_PATH} is not given a program to run")),
+        }
+    } else if !interpreter.starts_with('/') {
+        Some(format!(
+            "The interpreter must be an absolute path, got: {interpreter}"
+        ))
+    } else if !Path::new(interpreter).is_file() {
+        Some(format!("{interpreter} does not exist"))
+    } else {
+        None
+    };
+    missing.map(|message| ScriptFinding {
+        script: script_name,
+        kind: FindingKind::MissingInterpreter,
+        line: Some(1),
+        message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    fn kinds(findings: &[ScriptFinding]) -> Vec<(FindingKind, Option<usize>)> {
+        findings
+            .iter()
+            .map(|finding| (finding.kind, finding.line))
+            .collect()
+    }
+
+    #[test]
+    fn crlf_line_endings_are_converted() {
+        let mut script = "#!/bin/bash\r\npython3 \"$1\"\r\n".to_string();
+        assert!(has_crlf(&script));
+        let finding = normalize_line_endings("run", &mut script).unwrap();
+        assert_eq!(finding.kind, FindingKind::CrlfLineEndings);
+        assert_eq!(script, "#!/bin/bash\npython3 \"$1\"\n");
+        assert!(normalize_line_endings("run", &mut script).is_none());
+        // A lone carriage return is not a line ending
+        let mut script = "printf 'a\rb'\n".to_string();
+        assert!(normalize_line_endings("run", &mut script).is_none());
+    }
+
+    #[test]
+    fn positional_parameters_past_the_source_file_are_found() {
+        let script = [
+            "#!/bin/bash",
+            "gcc \"$1\" -o main",
+            "gcc \"$2\" -o main",
+            "echo ${3}",
+            "echo $10",
+            "echo ${10}",
+            "echo '$2' # $3",
+            "echo \\$2",
+            "echo $# $@ $0",
+        ]
+        .join("\n");
+        let findings = find_unprovided_parameters("compile", &script);
+        assert_eq!(
+            kinds(&findings),
+            [
+                (FindingKind::UnprovidedPositionalParameter, Some(3)),
+                (FindingKind::UnprovidedPositionalParameter, Some(4)),
+                (FindingKind::UnprovidedPositionalParameter, Some(6)),
+            ]
+        );
+        assert!(findings[2].message.starts_with("$10 is always empty"));
+    }
+
+    #[tokio::test]
+    async fn interpreters_are_looked_up_in_the_runtime_path() {
+        let tmp = std::env::temp_dir().to_string_lossy().into_owned();
+        let bin = TempDir::new(format!(
+            "{tmp}/envicutor-script-lint-{}",
+            std::process::id()
+        ))
+        .await
+        .unwrap();
+        let python = format!("{}/python3", bin.path);
+        std::fs::write(&python, "").unwrap();
+        std::fs::set_permissions(&python, Permissions::from_mode(0o755)).unwrap();
+        let path_var = format!("/nonexistent:{}", bin.path);
+
+        let present = [
+            "python3 \"$1\"",
+            "#!/usr/bin/env python3\nprint()",
+            "#!/usr/bin/env -S python3 -u\nprint()",
+            "#!/bin/sh\necho",
+        ];
+        for script in present {
+            assert!(
+                check_interpreter("run", script, &path_var).is_none(),
+                "{script}"
+            );
+        }
+        let missing = [
+            (
+                "#!/usr/bin/env ruby\nputs 1",
+                "ruby is not in the PATH of the runtime's environment",
+            ),
+            (
+                "#!/usr/bin/env\n",
+                "/usr/bin/env is not given a program to run",
+            ),
+            (
+                "#!bash\necho",
+                "The interpreter must be an absolute path, got: bash",
+            ),
+            (
+                "#!/nonexistent/bash\necho",
+                "/nonexistent/bash does not exist",
+            ),
+        ];
+        for (script, message) in missing {
+            let finding = check_interpreter("run", script, &path_var).unwrap();
+            assert_eq!(finding.kind, FindingKind::MissingInterpreter);
+            assert_eq!(finding.line, Some(1));
+            assert_eq!(finding.message, message);
+        }
+    }
+
+    #[test]
+    fn only_strict_scripts_block_on_a_missing_interpreter() {
+        let mut crlf = "echo\r\n".to_string();
+        let warnings: Vec<ScriptFinding> = normalize_line_endings("run", &mut crlf)
+            .into_iter()
+            .chain(find_unprovided_parameters("compile", "echo $2"))
+            .collect();
+        assert!(!blocks_installation(&warnings, true));
+        assert!(!blocks_installation(&warnings, false));
+
+        let mut findings = warnings;
+        findings.extend(check_interpreter("run", "#!/nonexistent/bash", ""));
+        assert!(blocks_installation(&findings, true));
+        assert!(!blocks_installation(&findings, false));
+    }
+
+    #[test]
+    fn scripts_without_a_shebang_get_bash() {
+        assert_eq!(with_shebang("echo"), "#!/bin/bash\n\necho");
+        assert_eq!(with_shebang("#!/bin/sh\necho"), "#!/bin/sh\necho");
+    }
+}
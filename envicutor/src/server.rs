@@ -0,0 +1,436 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    process::Stdio,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error};
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError, Json, Router,
+};
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use serde_json::{json, Map, Value};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    process::Command,
+    sync::{watch, Semaphore},
+    time,
+};
+use tower::ServiceExt;
+
+use crate::{
+    api::common_responses::{StaticMessage, INSUFFICIENT_STORAGE_RESPONSE},
+    host::HostMonitor,
+    storage::{self, IMMUTABLE_DEPLOYMENT},
+};
+
+// Same as axum's default body limit, so buffering does not change what is accepted
+pub const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+const SERVICE_UNAVAILABLE_RETRY_AFTER: Duration = Duration::from_secs(1);
+// Space is freed by deletions and garbage collection, which are not quick to come
+const DISK_FULL_RETRY_AFTER: Duration = Duration::from_secs(30);
+const GZIP_PATH: &str = "/bin/gzip";
+// Every gzip process holds a copy of its body, on top of being a process
+const MAX_GZIP_PROCESSES: usize = 8;
+
+static GZIP_PROCESSES: OnceLock<Semaphore> = OnceLock::new();
+
+pub struct ServerLimits {
+    pub header_timeout: Duration,
+    pub max_connections: usize,
+}
+
+fn static_error(status: StatusCode, message: &'static str) -> Response<Body> {
+    (status, Json(StaticMessage { message })).into_response()
+}
+
+pub async fn handle_route_error(e: BoxError) -> Response<Body> {
+    if e.is::<tower::timeout::error::Elapsed>() {
+        static_error(StatusCode::REQUEST_TIMEOUT, "Request timed out")
+    } else {
+        eprintln!("Unhandled route error: {e}");
+        static_error(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+    }
+}
+
+// Reads the whole body under a deadline, so a client that never sends it
+// does not hold the handler (and whatever it acquired) indefinitely
+pub async fn read_body_with_timeout(
+    body_timeout: Duration,
+    req: Request,
+    next: Next,
+) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let collected = time::timeout(body_timeout, Limited::new(body, MAX_BODY_SIZE).collect()).await;
+    let bytes: Bytes = match collected {
+        Ok(Ok(collected)) => collected.to_bytes(),
+        Ok(Err(e)) if e.is::<LengthLimitError>() => {
+            return static_error(StatusCode::PAYLOAD_TOO_LARGE, "Request body is too large");
+        }
+        Ok(Err(_)) => {
+            return static_error(StatusCode::BAD_REQUEST, "Failed to read the request body");
+        }
+        Err(_) => {
+            return static_error(
+                StatusCode::REQUEST_TIMEOUT,
+                "Timed out reading the request body",
+            );
+        }
+    };
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+// Requests that free space (deletions and garbage collection) and reads still go through
+pub async fn refuse_writes_when_disk_full(
+    host_monitor: Arc<HostMonitor>,
+    req: Request,
+    next: Next,
+) -> Response<Body> {
+    let frees_space = req.method() == Method::DELETE || req.uri().path() == "/admin/gc";
+    if host_monitor.is_disk_full() && !req.method().is_safe() && !frees_space {
+        return INSUFFICIENT_STORAGE_RESPONSE.into_response();
+    }
+    next.run(req).await
+}
+
+// Runtimes can't be installed, changed or removed when their directory is read-only
+pub async fn refuse_when_immutable(req: Request, next: Next) -> Response<Body> {
+    if storage::is_immutable() {
+        return (
+            StatusCode::NOT_IMPLEMENTED,
+            Json(json!({
+                "code": IMMUTABLE_DEPLOYMENT,
+                "message": "Runtimes can't be changed, they are part of this deployment",
+            })),
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+// Whether a client may send the same request again, and how long it should wait first
+pub fn retry_hint(status: StatusCode) -> (bool, Option<Duration>) {
+    match status {
+        StatusCode::SERVICE_UNAVAILABLE => (true, Some(SERVICE_UNAVAILABLE_RETRY_AFTER)),
+        StatusCode::INSUFFICIENT_STORAGE => (true, Some(DISK_FULL_RETRY_AFTER)),
+        // Worth another try, though not an unlimited number of them
+        StatusCode::REQUEST_TIMEOUT | StatusCode::INTERNAL_SERVER_ERROR => (true, None),
+        _ => (false, None),
+    }
+}
+
+fn set_retry_after(res: &mut Response<Body>, retry_after: Duration) {
+    let secs = retry_after.as_millis().div_ceil(1000);
+    if let Ok(value) = header::HeaderValue::from_str(&secs.to_string()) {
+        res.headers_mut().insert(header::RETRY_AFTER, value);
+    }
+}
+
+fn accepts_gzip(req: &Request) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            params
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case("gzip"))
+                && !params.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .is_some_and(|q| q.parse::<f32>() == Ok(0.0))
+                })
+        })
+}
+
+fn gzip_processes() -> &'static Semaphore {
+    GZIP_PROCESSES.get_or_init(|| Semaphore::new(MAX_GZIP_PROCESSES))
+}
+
+// Runs gzip with `args` on `data`, None when its output is longer than `max_output`.
+// The caller holds a permit of gzip_processes()
+async fn gzip(args: &[&str], data: &[u8], max_output: usize) -> Result<Option<Vec<u8>>, Error> {
+    let mut child = Command::new(GZIP_PATH)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to spawn gzip\nError: {e}"))?;
+    // Written while the output is read, gzip stops reading once its output pipe is full
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("Failed to open the stdin of gzip"))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("Failed to open the stdout of gzip"))?;
+    let write = async move {
+        match stdin.write_all(data).await {
+            // gzip stopped reading, its exit status tells why
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            res => res,
+        }
+    };
+    let mut output = Vec::new();
+    let read = async {
+        let res = stdout
+            .take(max_output as u64 + 1)
+            .read_to_end(&mut output)
+            .await;
+        // Left running, gzip would wait for its output to be read forever and
+        // never read the rest of its input
+        if output.len() > max_output {
+            let _ = child.start_kill();
+        }
+        res
+    };
+    let (write_res, read_res) = tokio::join!(write, read);
+    read_res.map_err(|e| anyhow!("Failed to read from gzip\nError: {e}"))?;
+    if output.len() > max_output {
+        return Ok(None);
+    }
+    write_res.map_err(|e| anyhow!("Failed to write to gzip\nError: {e}"))?;
+    let res = child
+        .wait_with_output()
+        .await
+        .map_err(|e| anyhow!("Failed to wait for gzip\nError: {e}"))?;
+    if !res.status.success() {
+        return Err(anyhow!(
+            "gzip failed: {}",
+            String::from_utf8_lossy(&res.stderr).trim()
+        ));
+    }
+    Ok(Some(output))
+}
+
+// Decompresses gzip request bodies, up to `max_size` bytes once decompressed.
+// Bodies without a Content-Encoding go through untouched
+pub async fn decompress_request(max_size: usize, req: Request, next: Next) -> Response<Body> {
+    let Some(encoding) = req.headers().get(header::CONTENT_ENCODING) else {
+        return next.run(req).await;
+    };
+    if encoding.as_bytes().eq_ignore_ascii_case(b"identity") {
+        return next.run(req).await;
+    }
+    if !encoding.as_bytes().eq_ignore_ascii_case(b"gzip") {
+        return static_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Only gzip request bodies are supported",
+        );
+    }
+    let (mut parts, body) = req.into_parts();
+    // Already buffered by read_body_with_timeout
+    let bytes = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(_) => return static_error(StatusCode::BAD_REQUEST, "Failed to read the request body"),
+    };
+    let Ok(_permit) = gzip_processes().acquire().await else {
+        return static_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to decompress the request body",
+        );
+    };
+    let decompressed = match gzip(&["-d", "-c"], &bytes, max_size).await {
+        Ok(Some(decompressed)) => decompressed,
+        Ok(None) => {
+            return static_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request body is too large once decompressed",
+            )
+        }
+        Err(e) => {
+            eprintln!("Failed to decompress a request body: {e}");
+            return static_error(StatusCode::BAD_REQUEST, "Malformed gzip request body");
+        }
+    };
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    next.run(Request::from_parts(parts, Body::from(decompressed)))
+        .await
+}
+
+// Compresses successful responses of at least `min_size` bytes for clients that
+// accept gzip. Error bodies are left alone, they are small and get retry hints
+pub async fn compress_response(min_size: usize, req: Request, next: Next) -> Response<Body> {
+    let accepts_gzip = accepts_gzip(&req);
+    let mut res = next.run(req).await;
+    if !res.status().is_success() || res.headers().contains_key(header::CONTENT_ENCODING) {
+        return res;
+    }
+    res.headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    if !accepts_gzip {
+        return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    let bytes = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(e) => {
+            eprintln!("Failed to read a response to compress: {e}");
+            return static_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read the response",
+            );
+        }
+    };
+    if bytes.len() < min_size {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+    // Sent as it is rather than waiting for the other compressions
+    let Ok(_permit) = gzip_processes().try_acquire() else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    // Compressing makes no sense once it would not make the body smaller
+    match gzip(&["-c", "-n"], &bytes, bytes.len()).await {
+        Ok(Some(compressed)) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            parts
+                .headers
+                .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        Ok(None) => Response::from_parts(parts, Body::from(bytes)),
+        Err(e) => {
+            eprintln!("Failed to compress a response: {e}");
+            Response::from_parts(parts, Body::from(bytes))
+        }
+    }
+}
+
+// Adds `retryable` and `retry_after_ms` to JSON error bodies, and the
+// Retry-After header along with the latter
+pub async fn add_retry_hints(req: Request, next: Next) -> Response<Body> {
+    let res = next.run(req).await;
+    let status = res.status();
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/json");
+    if !(status.is_client_error() || status.is_server_error()) || !is_json {
+        return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    let bytes = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(e) => {
+            eprintln!("Failed to read an error response: {e}");
+            return static_error(status, "Failed to read the error response");
+        }
+    };
+    let Ok(mut fields) = serde_json::from_slice::<Map<String, Value>>(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    let (retryable, retry_after) = retry_hint(status);
+    fields.insert("retryable".to_string(), Value::Bool(retryable));
+    if let Some(retry_after) = retry_after {
+        fields.insert(
+            "retry_after_ms".to_string(),
+            Value::from(retry_after.as_millis() as u64),
+        );
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let mut res = Response::from_parts(parts, Body::from(Value::Object(fields).to_string()));
+    if let Some(retry_after) = retry_after {
+        set_retry_after(&mut res, retry_after);
+    }
+    res
+}
+
+async fn reject_connection(_: hyper::Request<Incoming>) -> Result<Response<Body>, Infallible> {
+    // Answered before any routing, so the retry hints are added here
+    let mut res = (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({
+            "message": "Too many open connections",
+            "retryable": true,
+            "retry_after_ms": SERVICE_UNAVAILABLE_RETRY_AFTER.as_millis() as u64,
+        })),
+    )
+        .into_response();
+    set_retry_after(&mut res, SERVICE_UNAVAILABLE_RETRY_AFTER);
+    res.headers_mut().insert(
+        header::CONNECTION,
+        header::HeaderValue::from_static("close"),
+    );
+    Ok(res)
+}
+
+pub async fn serve<F>(listener: TcpListener, app: Router, limits: ServerLimits, signal: F)
+where
+    F: Future<Output = ()>,
+{
+    let connections = Arc::new(Semaphore::new(limits.max_connections));
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    tokio::pin!(signal);
+
+    loop {
+        let stream = tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {e}");
+                    continue;
+                }
+            },
+            _ = &mut signal => break,
+        };
+        let io = TokioIo::new(stream);
+        let mut builder = http1::Builder::new();
+        builder
+            .timer(TokioTimer::new())
+            .header_read_timeout(limits.header_timeout);
+
+        let permit = match connections.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                tokio::spawn(async move {
+                    builder.keep_alive(false);
+                    let _ = builder
+                        .serve_connection(io, service_fn(reject_connection))
+                        .await;
+                });
+                continue;
+            }
+        };
+
+        let app = app.clone();
+        let mut shutdown_rx = shutdown_rx.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req: hyper::Request<Incoming>| app.clone().oneshot(req));
+            let conn = builder.serve_connection(io, service);
+            tokio::pin!(conn);
+            // Connection errors (e.g. header read timeouts) only concern that client
+            let _ = tokio::select! {
+                res = conn.as_mut() => res,
+                _ = shutdown_rx.changed() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            drop(permit);
+        });
+    }
+
+    drop(shutdown_rx);
+    let _ = shutdown_tx.send(());
+    // Every connection holds a permit until it is done
+    let _ = connections
+        .acquire_many(limits.max_connections as u32)
+        .await;
+}
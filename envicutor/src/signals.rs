@@ -0,0 +1,71 @@
+const SIGNAL_NAMES: [&str; 31] = [
+    "SIGHUP",
+    "SIGINT",
+    "SIGQUIT",
+    "SIGILL",
+    "SIGTRAP",
+    "SIGABRT",
+    "SIGBUS",
+    "SIGFPE",
+    "SIGKILL",
+    "SIGUSR1",
+    "SIGSEGV",
+    "SIGUSR2",
+    "SIGPIPE",
+    "SIGALRM",
+    "SIGTERM",
+    "SIGSTKFLT",
+    "SIGCHLD",
+    "SIGCONT",
+    "SIGSTOP",
+    "SIGTSTP",
+    "SIGTTIN",
+    "SIGTTOU",
+    "SIGURG",
+    "SIGXCPU",
+    "SIGXFSZ",
+    "SIGVTALRM",
+    "SIGPROF",
+    "SIGWINCH",
+    "SIGIO",
+    "SIGPWR",
+    "SIGSYS",
+];
+// Linux real-time signals have no fixed names (glibc reserves the first ones)
+const FIRST_REALTIME_SIGNAL: u32 = 32;
+const LAST_REALTIME_SIGNAL: u32 = 64;
+
+pub fn signal_name(signal: u32) -> Option<String> {
+    match signal {
+        1..=31 => Some(SIGNAL_NAMES[signal as usize - 1].to_string()),
+        FIRST_REALTIME_SIGNAL..=LAST_REALTIME_SIGNAL => Some(format!("SIG{signal}")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_with_names() {
+        assert_eq!(signal_name(1).as_deref(), Some("SIGHUP"));
+        assert_eq!(signal_name(9).as_deref(), Some("SIGKILL"));
+        assert_eq!(signal_name(11).as_deref(), Some("SIGSEGV"));
+        assert_eq!(signal_name(31).as_deref(), Some("SIGSYS"));
+    }
+
+    #[test]
+    fn realtime_signals_are_named_by_number() {
+        assert_eq!(signal_name(32).as_deref(), Some("SIG32"));
+        assert_eq!(signal_name(34).as_deref(), Some("SIG34"));
+        assert_eq!(signal_name(64).as_deref(), Some("SIG64"));
+    }
+
+    #[test]
+    fn unknown_signals_have_no_name() {
+        assert_eq!(signal_name(0), None);
+        assert_eq!(signal_name(65), None);
+        assert_eq!(signal_name(u32::MAX), None);
+    }
+}
@@ -0,0 +1,44 @@
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StageName {
+    Extract,
+    Compile,
+    Run,
+}
+
+// Identifies a stage within a request: `index` is its position in the pipeline,
+// `attempt` counts reruns of the same stage and `case_index` is set when grading
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct StageId {
+    pub name: StageName,
+    pub index: u32,
+    pub attempt: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub case_index: Option<u32>,
+}
+
+// The stages a submission goes through, in order, when none of them fails
+pub fn plan(is_project: bool, is_compiled: bool) -> Vec<StageName> {
+    let mut stages = Vec::new();
+    if is_project {
+        stages.push(StageName::Extract);
+    }
+    if is_compiled {
+        stages.push(StageName::Compile);
+    }
+    stages.push(StageName::Run);
+    stages
+}
+
+impl StageId {
+    pub fn new(name: StageName, index: u32) -> StageId {
+        StageId {
+            name,
+            index,
+            attempt: 1,
+            case_index: None,
+        }
+    }
+}
@@ -0,0 +1,84 @@
+// Deployments can bake their runtimes into an image and mount the runtimes
+// directory read-only. Runtimes then can't be installed, changed or removed, and
+// what the server keeps of its own (suites, the box quarantine, callbacks,
+// datasets, the compile cache, core dumps) goes to a separate writable directory
+
+use std::{ffi::CString, sync::OnceLock};
+
+use anyhow::{anyhow, Error};
+use rusqlite::{Connection, OpenFlags};
+
+use crate::globals::{COMPILE_CACHE_DIR, CORES_DIR, DATASETS_DIR, DB_PATH};
+
+pub const IMMUTABLE_DEPLOYMENT: &str = "immutable_deployment";
+pub const STATE_DB_FILE_NAME: &str = "state.db";
+
+// Where the server's own state goes, set when the deployment is immutable
+static STATE_PATHS: OnceLock<StatePaths> = OnceLock::new();
+
+struct StatePaths {
+    db: String,
+    datasets_dir: String,
+    compile_cache_dir: String,
+    cores_dir: String,
+}
+
+pub fn is_writable(dir: &str) -> bool {
+    let Ok(c_dir) = CString::new(dir) else {
+        return false;
+    };
+    // SAFETY: c_dir is NUL-terminated
+    unsafe { libc::access(c_dir.as_ptr(), libc::W_OK) == 0 }
+}
+
+pub fn use_immutable_storage(state_dir: &str) -> Result<(), Error> {
+    if !is_writable(state_dir) {
+        return Err(anyhow!("{state_dir} is not writable"));
+    }
+    STATE_PATHS
+        .set(StatePaths {
+            db: format!("{state_dir}/{STATE_DB_FILE_NAME}"),
+            datasets_dir: format!("{state_dir}/datasets"),
+            compile_cache_dir: format!("{state_dir}/compile_cache"),
+            cores_dir: format!("{state_dir}/cores"),
+        })
+        .map_err(|_| anyhow!("The storage was already set up"))
+}
+
+pub fn is_immutable() -> bool {
+    STATE_PATHS.get().is_some()
+}
+
+pub fn state_db_path() -> &'static str {
+    STATE_PATHS.get().map_or(DB_PATH, |paths| &paths.db)
+}
+
+pub fn datasets_dir() -> &'static str {
+    STATE_PATHS
+        .get()
+        .map_or(DATASETS_DIR, |paths| &paths.datasets_dir)
+}
+
+pub fn compile_cache_dir() -> &'static str {
+    STATE_PATHS
+        .get()
+        .map_or(COMPILE_CACHE_DIR, |paths| &paths.compile_cache_dir)
+}
+
+pub fn cores_dir() -> &'static str {
+    STATE_PATHS
+        .get()
+        .map_or(CORES_DIR, |paths| &paths.cores_dir)
+}
+
+// The database the runtimes are registered in, only read from when it is bundled
+pub fn open_runtimes_db() -> rusqlite::Result<Connection> {
+    if is_immutable() {
+        Connection::open_with_flags(
+            DB_PATH,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+    } else {
+        Connection::open(DB_PATH)
+    }
+}
@@ -9,3 +9,92 @@ impl NewLine for String {
         }
     }
 }
+
+pub const MAX_NAME_LENGTH: usize = 256;
+
+// What runtime names and aliases may be, checked when they are given
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name.bytes().all(|byte| (b' '..=b'~').contains(&byte))
+}
+
+fn is_bidi_control(c: char) -> bool {
+    matches!(
+        c,
+        '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}'
+    )
+}
+
+// Names stored before they were validated may hold escape sequences or bidi
+// controls, which would spoof the log lines and messages they end up in
+pub fn display_name(name: &str) -> String {
+    name.chars()
+        .filter(|&c| !c.is_control() && !is_bidi_control(c))
+        .take(MAX_NAME_LENGTH)
+        .collect()
+}
+
+// Cuts `text` to at most `max_bytes` without splitting a character
+pub fn truncate_at_char_boundary(text: &mut String, max_bytes: usize) {
+    if text.len() <= max_bytes {
+        return;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+}
+
+// The scheme and host of an absolute URL, None when it is malformed or has
+// credentials, which could make it look like it is on another host
+pub fn url_scheme_and_host(url: &str) -> Option<(&str, &str)> {
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return None;
+    }
+    let (scheme, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    if authority.contains('@') {
+        return None;
+    }
+    let host = authority.split(':').next().unwrap_or("");
+    (!host.is_empty()).then_some((scheme, host))
+}
+
+// Every combination of two fragments a shell would treat specially. NUL is left
+// out, it can't be in an environment variable or an argument
+#[cfg(test)]
+pub fn hostile_strings() -> Vec<String> {
+    let fragments = [
+        "",
+        " ",
+        "main.c",
+        "'",
+        "\"",
+        "\\",
+        "$",
+        "$(touch pwned)",
+        "`touch pwned`",
+        ";",
+        "&&",
+        "|",
+        "\n",
+        "\nexport PATH=",
+        "\t",
+        "'\\''",
+        "--",
+        "-c",
+        "*",
+        "~",
+        "${IFS}",
+        "!",
+    ];
+    let mut strings = Vec::new();
+    for a in fragments {
+        for b in fragments {
+            strings.push(format!("{a}{b}"));
+        }
+    }
+    strings
+}
@@ -0,0 +1,510 @@
+// Stdin and expected outputs that test cases give as URLs instead of inline.
+// They are fetched by the server, before anything runs in a sandbox, and
+// only from the hosts the operator allows, up to a total per request. Data given with its sha256 is kept
+// by that hash, so the next request giving it does not fetch it again, and the
+// least recently used data is evicted once the cache is over its size limit
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error};
+use tokio::{
+    fs,
+    io::AsyncReadExt,
+    process::Command,
+    sync::{Mutex, Semaphore},
+    time,
+};
+
+use crate::{
+    digest::{self, is_sha256},
+    globals::TEMP_DIR,
+    strings::url_scheme_and_host,
+};
+
+const CURL_PATH: &str = "/usr/bin/curl";
+// curl's exit code when the server announces more than --max-filesize
+const CURL_FILE_TOO_LARGE: i32 = 63;
+
+// Set once at startup
+static TEST_DATA_CONFIG: OnceLock<TestDataConfig> = OnceLock::new();
+static FETCH_SLOTS: OnceLock<Semaphore> = OnceLock::new();
+static CACHE_DIR: OnceLock<String> = OnceLock::new();
+static INDEX: Mutex<Index> = Mutex::const_new(Index::new());
+// Names the files of data being stored, two requests can fetch the same data
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Entry {
+    size: u64,
+    last_used: u64,
+}
+
+struct Index {
+    entries: Option<HashMap<String, Entry>>,
+    stored_bytes: u64,
+    clock: u64,
+}
+
+impl Index {
+    const fn new() -> Self {
+        Index {
+            entries: None,
+            stored_bytes: 0,
+            clock: 0,
+        }
+    }
+
+    fn entries(&mut self) -> &mut HashMap<String, Entry> {
+        self.entries.get_or_insert_with(HashMap::new)
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[derive(Clone)]
+pub struct TestDataConfig {
+    // Without any, test data can't be given as URLs
+    pub allowed_hosts: Vec<String>,
+    // Of each URL
+    pub max_bytes: u64,
+    // Of all the URLs of a request, cached or not
+    pub max_request_bytes: u64,
+    // Trusted on top of the system's certificates, for hosts with a private CA
+    pub ca_file: Option<String>,
+    pub fetch_concurrency: usize,
+    pub fetch_timeout: Duration,
+    // Of the data kept by its sha256, nothing is kept with 0
+    pub cache_max_bytes: u64,
+}
+
+impl Default for TestDataConfig {
+    fn default() -> Self {
+        TestDataConfig {
+            allowed_hosts: Vec::new(),
+            max_bytes: 64 * 1024 * 1024,
+            max_request_bytes: 256 * 1024 * 1024,
+            ca_file: None,
+            fetch_concurrency: 4,
+            fetch_timeout: Duration::from_secs(30),
+            cache_max_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+pub fn use_test_data_config(config: TestDataConfig) -> Result<(), Error> {
+    FETCH_SLOTS
+        .set(Semaphore::new(config.fetch_concurrency))
+        .map_err(|_| anyhow!("The test data config was already set"))?;
+    TEST_DATA_CONFIG
+        .set(config)
+        .map_err(|_| anyhow!("The test data config was already set"))
+}
+
+fn config() -> Option<&'static TestDataConfig> {
+    TEST_DATA_CONFIG.get()
+}
+
+// Nothing is cached before `init`
+fn cache_dir() -> Option<&'static str> {
+    CACHE_DIR.get().map(String::as_str)
+}
+
+// Data left by a previous run is not in the index, so it is removed
+pub async fn init() -> Result<(), Error> {
+    fs::create_dir_all(TEMP_DIR)
+        .await
+        .map_err(|e| anyhow!("Failed to create {TEMP_DIR}\nError: {e}"))?;
+    init_in(format!("{TEMP_DIR}/test-data")).await
+}
+
+async fn init_in(dir: String) -> Result<(), Error> {
+    crate::fs::create_dir_replacing_existing(&dir).await?;
+    CACHE_DIR
+        .set(dir)
+        .map_err(|_| anyhow!("The test data cache was already set up"))
+}
+
+// The bytes a request may still fetch, shared by its fetches
+pub struct FetchBudget {
+    remaining: AtomicU64,
+}
+
+impl FetchBudget {
+    pub fn new() -> Self {
+        FetchBudget {
+            remaining: AtomicU64::new(config().map_or(0, |config| config.max_request_bytes)),
+        }
+    }
+
+    fn remaining(&self) -> u64 {
+        self.remaining.load(Ordering::SeqCst)
+    }
+
+    fn spend(&self, bytes: u64) -> Result<(), Error> {
+        self.remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| {
+                remaining.checked_sub(bytes)
+            })
+            .map(|_| ())
+            .map_err(|_| over_request_budget())
+    }
+}
+
+impl Default for FetchBudget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn over_request_budget() -> Error {
+    let max_request_bytes = config().map_or(0, |config| config.max_request_bytes);
+    anyhow!("The test data of the request is larger than {max_request_bytes} bytes in total")
+}
+
+// Errors are safe to show in the response
+pub fn validate_url(url: &str, sha256: Option<&str>) -> Result<(), String> {
+    let allowed_hosts = config().map_or(&[][..], |config| &config.allowed_hosts[..]);
+    if allowed_hosts.is_empty() {
+        return Err("Test data can't be given as URLs on this server".to_string());
+    }
+    let Some((scheme, host)) = url_scheme_and_host(url) else {
+        return Err(format!("Invalid test data URL: {url}"));
+    };
+    if scheme != "https" {
+        return Err(format!("Test data URLs must use https: {url}"));
+    }
+    if !allowed_hosts.iter().any(|allowed| allowed == host) {
+        return Err(format!("{host} is not an allowed test data host"));
+    }
+    if let Some(sha256) = sha256 {
+        if !is_sha256(sha256) {
+            return Err(format!(
+                "Invalid sha256 for {url}, expected 64 lowercase hex digits"
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Redirects are not followed, they could lead off the allowed hosts. Data
+// larger than `limit` is not kept
+async fn download(
+    url: &str,
+    config: &TestDataConfig,
+    limit: u64,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut cmd = Command::new(CURL_PATH);
+    cmd.args([
+        "--proto",
+        "=https",
+        "--fail",
+        "--silent",
+        "--show-error",
+        "--max-filesize",
+        &limit.to_string(),
+    ]);
+    if let Some(ca_file) = &config.ca_file {
+        cmd.arg("--cacert").arg(ca_file);
+    }
+    let mut child = cmd
+        .args(["--", url])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to spawn curl\nError: {e}"))?;
+    // The size is only known in advance when the server sends it
+    let mut data = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        stdout
+            .take(limit + 1)
+            .read_to_end(&mut data)
+            .await
+            .map_err(|e| anyhow!("Failed to read from curl\nError: {e}"))?;
+    }
+    if data.len() as u64 > limit {
+        return Ok(None);
+    }
+    let res = child
+        .wait_with_output()
+        .await
+        .map_err(|e| anyhow!("Failed to wait for curl\nError: {e}"))?;
+    if res.status.code() == Some(CURL_FILE_TOO_LARGE) {
+        return Ok(None);
+    }
+    if !res.status.success() {
+        return Err(anyhow!("{}", String::from_utf8_lossy(&res.stderr).trim()));
+    }
+    Ok(Some(data))
+}
+
+async fn fetch_uncached(
+    url: &str,
+    sha256: Option<&str>,
+    budget: &FetchBudget,
+) -> Result<String, Error> {
+    let Some(config) = config() else {
+        return Err(anyhow!("Test data can't be given as URLs on this server"));
+    };
+    let _permit = match FETCH_SLOTS.get() {
+        Some(slots) => Some(
+            slots
+                .acquire()
+                .await
+                .map_err(|e| anyhow!("Failed to acquire a fetch slot: {e}"))?,
+        ),
+        None => None,
+    };
+    let remaining = budget.remaining();
+    let limit = config.max_bytes.min(remaining);
+    let data = time::timeout(config.fetch_timeout, download(url, config, limit))
+        .await
+        .map_err(|_| anyhow!("Timed out after {:?}", config.fetch_timeout))??;
+    let data = match data {
+        Some(data) => data,
+        None if limit < config.max_bytes => return Err(over_request_budget()),
+        None => return Err(anyhow!("It is larger than {} bytes", config.max_bytes)),
+    };
+    budget.spend(data.len() as u64)?;
+    if let Some(expected) = sha256 {
+        let actual = digest::sha256(&data);
+        if actual != expected {
+            return Err(anyhow!("Its sha256 is {actual}, not {expected}"));
+        }
+        if let Err(e) = store(expected, &data).await {
+            eprintln!("Failed to cache the test data of {url}: {e}");
+        }
+    }
+    String::from_utf8(data).map_err(|_| anyhow!("It is not valid UTF-8"))
+}
+
+async fn store(sha256: &str, data: &[u8]) -> Result<(), Error> {
+    let max_bytes = config().map_or(0, |config| config.cache_max_bytes);
+    let size = data.len() as u64;
+    let Some(dir) = cache_dir().filter(|_| size <= max_bytes) else {
+        return Ok(());
+    };
+    // Renamed into place so that readers never see a partial file
+    let path = format!("{dir}/{sha256}");
+    let partial_path = format!("{path}.partial-{}", NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    fs::write(&partial_path, data)
+        .await
+        .map_err(|e| anyhow!("Failed to write {partial_path}\nError: {e}"))?;
+
+    let mut evicted = Vec::new();
+    {
+        let mut index = INDEX.lock().await;
+        if index.entries().contains_key(sha256) {
+            // Stored by another request fetching the same data meanwhile
+            evicted.push(partial_path);
+        } else {
+            fs::rename(&partial_path, &path)
+                .await
+                .map_err(|e| anyhow!("Failed to rename {partial_path} to {path}\nError: {e}"))?;
+            let last_used = index.tick();
+            index
+                .entries()
+                .insert(sha256.to_string(), Entry { size, last_used });
+            index.stored_bytes += size;
+            while index.stored_bytes > max_bytes {
+                let Some(oldest) = index
+                    .entries()
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(sha256, _)| sha256.clone())
+                else {
+                    break;
+                };
+                if let Some(entry) = index.entries().remove(&oldest) {
+                    index.stored_bytes -= entry.size;
+                }
+                evicted.push(format!("{dir}/{oldest}"));
+            }
+        }
+    }
+    for path in evicted {
+        if let Err(e) = fs::remove_file(&path).await {
+            if e.kind() != ErrorKind::NotFound {
+                eprintln!("Failed to remove {path}\nError: {e}");
+            }
+        }
+    }
+    Ok(())
+}
+
+// Data evicted while it is read is only fetched again
+async fn lookup(sha256: &str) -> Option<String> {
+    let dir = cache_dir()?;
+    {
+        let mut index = INDEX.lock().await;
+        let last_used = index.tick();
+        index.entries().get_mut(sha256)?.last_used = last_used;
+    }
+    fs::read_to_string(format!("{dir}/{sha256}")).await.ok()
+}
+
+// Errors are safe to show in the response
+pub async fn fetch(
+    url: &str,
+    sha256: Option<&str>,
+    budget: &FetchBudget,
+) -> Result<String, String> {
+    if let Some(sha256) = sha256 {
+        if let Some(data) = lookup(sha256).await {
+            return budget
+                .spend(data.len() as u64)
+                .map(|_| data)
+                .map_err(|e| format!("Failed to fetch {url}: {e}"));
+        }
+    }
+    fetch_uncached(url, sha256, budget)
+        .await
+        .map_err(|e| format!("Failed to fetch {url}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        process::{Child, Command as StdCommand},
+    };
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    // `openssl s_server` serving the files of a directory over https
+    struct LocalServer {
+        child: Child,
+        port: u16,
+    }
+
+    impl Drop for LocalServer {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+
+    fn serve(dir: &str) -> LocalServer {
+        let status = StdCommand::new("openssl")
+            .args(["req", "-x509", "-newkey", "rsa:2048", "-nodes"])
+            .args(["-keyout", "key.pem", "-out", "cert.pem", "-days", "1"])
+            .args([
+                "-subj",
+                "/CN=localhost",
+                "-addext",
+                "subjectAltName=DNS:localhost",
+            ])
+            .current_dir(dir)
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let child = StdCommand::new("openssl")
+            .args([
+                "s_server", "-quiet", "-WWW", "-cert", "cert.pem", "-key", "key.pem",
+            ])
+            .args(["-accept", &format!("127.0.0.1:{port}")])
+            .current_dir(dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = LocalServer { child, port };
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                return server;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        panic!("openssl s_server did not start");
+    }
+
+    #[tokio::test]
+    async fn test_data_is_fetched_from_allowed_hosts_and_cached_by_its_sha256() {
+        let tmp = std::env::temp_dir().to_string_lossy().into_owned();
+        let dir = TempDir::new(format!("{tmp}/envicutor-test-data-{}", std::process::id()))
+            .await
+            .unwrap();
+        let server = serve(&dir.path);
+        fs::write(format!("{}/1.in", dir.path), "1 2\n")
+            .await
+            .unwrap();
+        fs::write(format!("{}/big.in", dir.path), "x".repeat(80))
+            .await
+            .unwrap();
+        fs::write(format!("{}/half.in", dir.path), "x".repeat(60))
+            .await
+            .unwrap();
+        use_test_data_config(TestDataConfig {
+            allowed_hosts: vec!["localhost".to_string()],
+            max_bytes: 64,
+            max_request_bytes: 100,
+            ca_file: Some(format!("{}/cert.pem", dir.path)),
+            cache_max_bytes: 1024,
+            ..Default::default()
+        })
+        .unwrap();
+        init_in(format!("{}/cache", dir.path)).await.unwrap();
+        let url = |name: &str| format!("https://localhost:{}/{name}", server.port);
+
+        // The allowlist
+        assert!(validate_url(&url("1.in"), None).is_ok());
+        let e = validate_url("https://example.com/1.in", None).unwrap_err();
+        assert_eq!(e, "example.com is not an allowed test data host");
+        let e = validate_url(&format!("http://localhost:{}/1.in", server.port), None).unwrap_err();
+        assert!(e.starts_with("Test data URLs must use https"), "{e}");
+
+        // A checksum mismatch, which is not cached
+        let wrong = digest::sha256(b"1 3\n");
+        let e = fetch(&url("1.in"), Some(&wrong), &FetchBudget::new())
+            .await
+            .unwrap_err();
+        assert!(
+            e.contains(&format!("Its sha256 is {}", digest::sha256(b"1 2\n"))),
+            "{e}"
+        );
+        assert!(lookup(&wrong).await.is_none());
+
+        // A cache hit, the data changed on the server is not fetched again
+        let sha256 = digest::sha256(b"1 2\n");
+        let data = fetch(&url("1.in"), Some(&sha256), &FetchBudget::new()).await;
+        assert_eq!(data.unwrap(), "1 2\n");
+        fs::write(format!("{}/1.in", dir.path), "2 2\n")
+            .await
+            .unwrap();
+        let data = fetch(&url("1.in"), Some(&sha256), &FetchBudget::new()).await;
+        assert_eq!(data.unwrap(), "1 2\n");
+        let data = fetch(&url("1.in"), None, &FetchBudget::new()).await;
+        assert_eq!(data.unwrap(), "2 2\n");
+
+        // The limits of a URL and of a request
+        let e = fetch(&url("big.in"), None, &FetchBudget::new())
+            .await
+            .unwrap_err();
+        assert!(e.ends_with("It is larger than 64 bytes"), "{e}");
+        let budget = FetchBudget::new();
+        assert!(fetch(&url("half.in"), None, &budget).await.is_ok());
+        let e = fetch(&url("half.in"), None, &budget).await.unwrap_err();
+        assert!(e.ends_with("larger than 100 bytes in total"), "{e}");
+        let e = fetch(&url("1.in"), Some(&sha256), &budget).await;
+        assert_eq!(e.unwrap(), "1 2\n");
+    }
+}
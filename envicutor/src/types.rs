@@ -1,11 +1,118 @@
 use std::collections::HashMap;
 
+use crate::{health::RuntimeHealth, strings};
+
 pub struct Runtime {
     pub name: String,
     pub source_file_name: String,
     pub is_compiled: bool,
+    pub aliases: Vec<String>,
+    // The least memory the runtime can start with
+    pub min_memory: Option<Kilobytes>,
+    // Whether runs may ask for core dumps, when the server allows them
+    pub core_dumps: bool,
+    // Unset for runtimes whose directory was not checked at startup yet
+    pub verified: bool,
+    pub health: RuntimeHealth,
 }
 pub type Seconds = f32;
 pub type WholeSeconds = u32;
 pub type Kilobytes = u32;
-pub type Metadata = HashMap<u32, Runtime>;
+pub type RuntimeId = i64;
+
+pub const MIN_MEMORY_FILE_NAME: &str = "min_memory";
+
+impl Runtime {
+    pub fn display_name(&self) -> String {
+        strings::display_name(&self.name)
+    }
+
+    pub fn is_called(&self, name: &str) -> bool {
+        self.name == name || self.aliases.iter().any(|alias| alias == name)
+    }
+}
+
+// Runtimes by id, with the ids of their names and aliases (which share one namespace)
+#[derive(Default)]
+pub struct Metadata {
+    runtimes: HashMap<RuntimeId, Runtime>,
+    names: HashMap<String, RuntimeId>,
+}
+
+impl Metadata {
+    pub fn get(&self, id: &RuntimeId) -> Option<&Runtime> {
+        self.runtimes.get(id)
+    }
+
+    // Names and aliases are indexed, change them with `set_aliases` or `insert`
+    pub fn get_mut(&mut self, id: &RuntimeId) -> Option<&mut Runtime> {
+        self.runtimes.get_mut(id)
+    }
+
+    pub fn contains_key(&self, id: &RuntimeId) -> bool {
+        self.runtimes.contains_key(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&RuntimeId, &Runtime)> {
+        self.runtimes.iter()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &RuntimeId> {
+        self.runtimes.keys()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Runtime> {
+        self.runtimes.values()
+    }
+
+    fn unindex(&mut self, id: RuntimeId) {
+        if let Some(runtime) = self.runtimes.get(&id) {
+            for name in std::iter::once(&runtime.name).chain(&runtime.aliases) {
+                self.names.remove(name);
+            }
+        }
+    }
+
+    pub fn insert(&mut self, id: RuntimeId, runtime: Runtime) {
+        self.unindex(id);
+        for name in std::iter::once(&runtime.name).chain(&runtime.aliases) {
+            self.names.insert(name.clone(), id);
+        }
+        self.runtimes.insert(id, runtime);
+    }
+
+    pub fn remove(&mut self, id: &RuntimeId) -> Option<Runtime> {
+        self.unindex(*id);
+        self.runtimes.remove(id)
+    }
+
+    pub fn set_aliases(&mut self, id: RuntimeId, aliases: Vec<String>) {
+        if let Some(runtime) = self.runtimes.get(&id) {
+            for alias in &runtime.aliases {
+                self.names.remove(alias);
+            }
+        }
+        let Some(runtime) = self.runtimes.get_mut(&id) else {
+            return;
+        };
+        for alias in &aliases {
+            self.names.insert(alias.clone(), id);
+        }
+        runtime.aliases = aliases;
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<(RuntimeId, &Runtime)> {
+        let id = *self.names.get(name)?;
+        self.runtimes.get(&id).map(|runtime| (id, runtime))
+    }
+}
+
+impl FromIterator<(RuntimeId, Runtime)> for Metadata {
+    fn from_iter<I: IntoIterator<Item = (RuntimeId, Runtime)>>(iter: I) -> Metadata {
+        let mut metadata = Metadata::default();
+        for (id, runtime) in iter {
+            metadata.insert(id, runtime);
+        }
+        metadata
+    }
+}
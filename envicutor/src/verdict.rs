@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+
+use crate::isolate::{ExitKind, StageResult};
+
+// What an exit code can be expected to be, it is a byte once the process exits
+pub const MAX_EXIT_CODE: i32 = 255;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case", tag = "verdict")]
+pub enum Verdict {
+    Ok,
+    WrongAnswer,
+    TimeLimitExceeded,
+    MemoryLimitExceeded,
+    RuntimeError,
+    WrongExitCode {
+        expected_exit_code: i32,
+        exit_code: u32,
+    },
+    CompilationError,
+    // The test data of a batch case couldn't be fetched, so it was not run
+    FetchFailed,
+    // The request used up its CPU budget before the run (or case) could start
+    BudgetExhausted,
+    // The run was killed for writing no output for longer than its request allowed
+    NoOutputTimeout,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparator {
+    #[default]
+    Exact,
+    IgnoreTrailingWhitespace,
+    IgnoreWhitespace,
+}
+
+impl Comparator {
+    pub fn name(self) -> &'static str {
+        match self {
+            Comparator::Exact => "exact",
+            Comparator::IgnoreTrailingWhitespace => "ignore_trailing_whitespace",
+            Comparator::IgnoreWhitespace => "ignore_whitespace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Comparator> {
+        [
+            Comparator::Exact,
+            Comparator::IgnoreTrailingWhitespace,
+            Comparator::IgnoreWhitespace,
+        ]
+        .into_iter()
+        .find(|comparator| comparator.name() == name)
+    }
+
+    pub fn matches(self, expected: &str, actual: &str) -> bool {
+        match self {
+            Comparator::Exact => expected == actual,
+            Comparator::IgnoreTrailingWhitespace => {
+                trimmed_lines(expected) == trimmed_lines(actual)
+            }
+            Comparator::IgnoreWhitespace => {
+                expected.split_whitespace().eq(actual.split_whitespace())
+            }
+        }
+    }
+}
+
+fn trimmed_lines(output: &str) -> Vec<&str> {
+    let mut lines: Vec<&str> = output.lines().map(str::trim_end).collect();
+    while lines.last() == Some(&"") {
+        lines.pop();
+    }
+    lines
+}
+
+// A program that did not exit by itself (killed by a signal or a limit) is a runtime
+// error whatever exit code was expected, unless it ran out of time. Without an
+// expected exit code, only 0 is fine. A program killed for going silent is judged
+// by that alone
+pub fn judge(
+    run: &StageResult,
+    expected_exit_code: Option<i32>,
+    output_matches: Option<bool>,
+) -> Verdict {
+    if run.no_output_timeout {
+        return Verdict::NoOutputTimeout;
+    }
+    if run.exit_kind == ExitKind::TimedOut {
+        return Verdict::TimeLimitExceeded;
+    }
+    if run.memory_limit_exceeded {
+        return Verdict::MemoryLimitExceeded;
+    }
+    let exit_code = match (run.exit_code, run.exit_signal) {
+        (Some(exit_code), None) => exit_code,
+        _ => return Verdict::RuntimeError,
+    };
+    match expected_exit_code {
+        None if exit_code != 0 => return Verdict::RuntimeError,
+        Some(expected_exit_code) if i64::from(exit_code) != i64::from(expected_exit_code) => {
+            return Verdict::WrongExitCode {
+                expected_exit_code,
+                exit_code,
+            }
+        }
+        _ => {}
+    }
+    if output_matches == Some(false) {
+        return Verdict::WrongAnswer;
+    }
+    Verdict::Ok
+}
+
+pub fn validate_expected_exit_code(expected_exit_code: Option<i32>) -> Result<(), String> {
+    match expected_exit_code {
+        Some(code) if !(0..=MAX_EXIT_CODE).contains(&code) => Err(format!(
+            "expected_exit_code must be between 0 and {MAX_EXIT_CODE}"
+        )),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,135 @@
+// Checks of the runtime directories done at startup. They run concurrently and
+// within a time budget, runtimes left over are loaded as they are and checked
+// on their first use
+
+use std::{collections::HashMap, os::unix::fs::PermissionsExt, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use tokio::{
+    sync::{RwLock, Semaphore},
+    task::{self, JoinSet},
+    time::{self, Duration, Instant},
+};
+
+use crate::{
+    core_dumps::CORE_DUMPS_DISABLED_FILE_NAME,
+    env_snapshot,
+    globals::RUNTIMES_DIR,
+    types::{Kilobytes, Metadata, RuntimeId, MIN_MEMORY_FILE_NAME},
+};
+
+#[derive(Default)]
+pub struct RuntimeDirInfo {
+    pub is_compiled: bool,
+    pub min_memory: Option<Kilobytes>,
+    pub core_dumps_disabled: bool,
+}
+
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct VerificationStats {
+    pub checked: usize,
+    pub deferred: usize,
+    pub broken: usize,
+}
+
+fn check_script(path: &str) -> Result<bool, Error> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(anyhow!("Failed to stat {path}\nError: {e}")),
+    };
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        return Err(anyhow!("{path} is not an executable file"));
+    }
+    Ok(true)
+}
+
+fn read_min_memory(runtime_dir: &str) -> Result<Option<Kilobytes>, Error> {
+    let path = format!("{runtime_dir}/{MIN_MEMORY_FILE_NAME}");
+    match std::fs::read_to_string(&path) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow!("Invalid memory floor in {path}: {e}")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!("Failed to read {path}\nError: {e}")),
+    }
+}
+
+pub fn verify_runtime_dir(runtime_dir: &str) -> Result<RuntimeDirInfo, Error> {
+    if !check_script(&format!("{runtime_dir}/run"))? {
+        return Err(anyhow!("{runtime_dir}/run does not exist"));
+    }
+    let is_compiled = check_script(&format!("{runtime_dir}/compile"))?;
+    if env_snapshot::ensure_shell_snapshot(runtime_dir)? {
+        eprintln!("Generated the shell environment snapshot in {runtime_dir}");
+    }
+    Ok(RuntimeDirInfo {
+        is_compiled,
+        min_memory: read_min_memory(runtime_dir)?,
+        core_dumps_disabled: Path::new(&format!("{runtime_dir}/{CORE_DUMPS_DISABLED_FILE_NAME}"))
+            .exists(),
+    })
+}
+
+// Runtimes missing from the results were not checked within the budget
+pub async fn verify_runtime_dirs(
+    runtime_ids: Vec<RuntimeId>,
+    concurrency: usize,
+    budget: Duration,
+) -> HashMap<RuntimeId, Result<RuntimeDirInfo, Error>> {
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let mut tasks = JoinSet::new();
+    for id in runtime_ids {
+        let semaphore = semaphore.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            let runtime_dir = format!("{RUNTIMES_DIR}/{id}");
+            let res = task::spawn_blocking(move || verify_runtime_dir(&runtime_dir))
+                .await
+                .unwrap_or_else(|e| Err(anyhow!("Failed to spawn blocking task: {e}")));
+            (id, res)
+        });
+    }
+
+    let deadline = Instant::now() + budget;
+    let mut results = HashMap::new();
+    loop {
+        match time::timeout_at(deadline, tasks.join_next()).await {
+            Ok(Some(Ok((id, res)))) => {
+                results.insert(id, res);
+            }
+            Ok(Some(Err(e))) => eprintln!("Runtime verification task failed: {e}"),
+            Ok(None) => break,
+            Err(_) => {
+                tasks.abort_all();
+                break;
+            }
+        }
+    }
+    results
+}
+
+// Fills in what the startup checks would have for runtimes they deferred
+pub async fn ensure_verified(
+    metadata_cache: &RwLock<Metadata>,
+    runtime_id: RuntimeId,
+) -> Result<(), Error> {
+    match metadata_cache.read().await.get(&runtime_id) {
+        Some(runtime) if !runtime.verified => {}
+        _ => return Ok(()),
+    }
+    let runtime_dir = format!("{RUNTIMES_DIR}/{runtime_id}");
+    let info = task::spawn_blocking(move || verify_runtime_dir(&runtime_dir))
+        .await
+        .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))??;
+    if let Some(runtime) = metadata_cache.write().await.get_mut(&runtime_id) {
+        runtime.is_compiled = info.is_compiled;
+        runtime.min_memory = info.min_memory;
+        runtime.core_dumps = !info.core_dumps_disabled;
+        runtime.verified = true;
+    }
+    Ok(())
+}
@@ -12,6 +12,7 @@ module.exports.sendRequest = (method, url, body, signal) => {
 };
 
 module.exports.BASE_URL = 'http://envicutor:5000';
+module.exports.COMPILE_MEMORY = parseInt(process.env['COMPILE_MEMORY']);
 module.exports.RUN_WALL_TIME = parseFloat(process.env['RUN_WALL_TIME']);
 module.exports.RUN_CPU_TIME = parseFloat(process.env['RUN_CPU_TIME']);
 module.exports.RUN_EXTRA_TIME = parseFloat(process.env['RUN_EXTRA_TIME']);
@@ -20,5 +21,35 @@ module.exports.RUN_MAX_OPEN_FILES = parseInt(process.env['RUN_MAX_OPEN_FILES']);
 module.exports.RUN_MAX_FILE_SIZE = parseInt(process.env['RUN_MAX_FILE_SIZE']);
 module.exports.RUN_MAX_NUMBER_OF_PROCESSES = parseInt(process.env['RUN_MAX_NUMBER_OF_PROCESSES']);
 module.exports.MAX_CONCURRENT_SUBMISSIONS = parseInt(process.env['MAX_CONCURRENT_SUBMISSIONS']);
+module.exports.REQUEST_BODY_TIMEOUT = parseInt(process.env['REQUEST_BODY_TIMEOUT']);
+module.exports.OUTPUT_CAPTURE = process.env['OUTPUT_CAPTURE'] || 'pipes';
 
 module.exports.sleep = async (t) => await new Promise((res) => setTimeout(res, t));
+
+// Reads the events of a server-sent event stream until one named `lastEvent`
+module.exports.readServerSentEvents = async (res, lastEvent) => {
+  const reader = res.body.getReader();
+  const decoder = new TextDecoder();
+  const received = [];
+  let data = '';
+  while (!received.some(({ event }) => event === lastEvent)) {
+    const { value, done } = await reader.read();
+    if (done) throw new Error(`The stream ended before a ${lastEvent} event`);
+    data += decoder.decode(value, { stream: true });
+    let end;
+    while ((end = data.indexOf('\n\n')) !== -1) {
+      const lines = data.slice(0, end).split('\n');
+      data = data.slice(end + 2);
+      const event = lines.find((line) => line.startsWith('event:'));
+      const payload = lines.find((line) => line.startsWith('data:'));
+      if (event && payload) {
+        received.push({
+          event: event.slice('event:'.length).trim(),
+          data: JSON.parse(payload.slice('data:'.length)),
+          at: Date.now()
+        });
+      }
+    }
+  }
+  return received;
+};
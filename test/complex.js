@@ -1,7 +1,11 @@
 const assert = require('assert');
+const net = require('net');
+const zlib = require('zlib');
+const crypto = require('crypto');
 const {
   sendRequest,
   BASE_URL,
+  COMPILE_MEMORY,
   RUN_WALL_TIME,
   RUN_CPU_TIME,
   RUN_MEMORY,
@@ -9,7 +13,10 @@ const {
   RUN_MAX_OPEN_FILES,
   RUN_MAX_FILE_SIZE,
   RUN_MAX_NUMBER_OF_PROCESSES,
-  MAX_CONCURRENT_SUBMISSIONS
+  MAX_CONCURRENT_SUBMISSIONS,
+  REQUEST_BODY_TIMEOUT,
+  OUTPUT_CAPTURE,
+  readServerSentEvents
 } = require('./common');
 
 (async () => {
@@ -137,6 +144,7 @@ int main()x {
     assert.equal(res.status, 200);
     const body = JSON.parse(text);
     assert.equal(body.run, null);
+    assert.equal(body.run_skipped, true);
     assert.equal(body.compile.exit_code, 1);
   }
 
@@ -172,8 +180,9 @@ pkgs.mkShell {
   ];
 }`,
       compile_script: '',
-      run_script: 'bash main.sh',
-      source_file_name: 'main.sh'
+      run_script: 'bash main.sh "${@:2}"',
+      source_file_name: 'main.sh',
+      min_memory: 4096
     });
 
     console.log(await res.text());
@@ -231,6 +240,10 @@ int main()
 	return 0;
 }
 `,
+      // The run limits would apply to the compilation too
+      compile_limits: {
+        memory: COMPILE_MEMORY
+      },
       run_limits: {
         memory: 13000
       }
@@ -241,6 +254,7 @@ int main()
     assert.equal(res.status, 200);
     const body = JSON.parse(text);
     assert.equal(body.run.exit_signal, 9);
+    assert.equal(body.run.memory_limit_exceeded, true);
   }
 
   {
@@ -257,6 +271,10 @@ int main()
 	return 0;
 }
 `,
+      // The run limits would apply to the compilation too
+      compile_limits: {
+        memory: COMPILE_MEMORY
+      },
       run_limits: {
         memory: 13000
       }
@@ -803,4 +821,1860 @@ pkgs.mkShell {
     const body = JSON.parse(text);
     assert.equal(body.extract.exit_code, 9);
   }
+
+  {
+    console.log('Collecting nix garbage (installed runtimes must keep working)');
+    const res = await sendRequest('POST', `${BASE_URL}/admin/gc`);
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.ok(body.roots > 0);
+    assert.equal(body.rehabilitated_boxes, 0);
+  }
+
+  {
+    console.log('Executing Python code after garbage collection');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print(input())',
+      input: 'Hello world'
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.equal(body.run.stdout, 'Hello world\n');
+  }
+
+  {
+    console.log('Installing Python with a filtered environment');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Python (filtered environment)',
+      nix_shell: `
+{ pkgs ? import (
+  fetchTarball {
+    url="https://github.com/NixOS/nixpkgs/archive/72da83d9515b43550436891f538ff41d68eecc7f.tar.gz";
+    sha256="177sws22nqkvv8am76qmy9knham2adfh3gv7hrjf6492z1mvy02y";
+  }
+) {} }:
+pkgs.mkShell {
+  shellHook = ''
+export KEPT_VAR="kept"
+export DROPPED_VAR="dropped"
+  '';
+  nativeBuildInputs = with pkgs; [
+      python3
+  ];
+}`,
+      compile_script: '',
+      run_script: 'python3 main.py',
+      source_file_name: 'main.py',
+      env_include: ['KEPT_*', 'DROPPED_*'],
+      env_exclude: ['DROPPED_VAR']
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.ok(body.filtered_env_variables > 0);
+  }
+
+  {
+    console.log('Checking the filtered environment variables in Python');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 7,
+      source_code: `import os
+print(os.environ.get("KEPT_VAR"))
+print(os.environ.get("DROPPED_VAR"))
+`
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.equal(body.run.stdout, 'kept\nNone\n');
+  }
+
+  {
+    console.log('Validating a submission and explaining its limits without running it');
+    const res = await sendRequest('POST', `${BASE_URL}/execute?explain_limits=true`, {
+      runtime_id: 2,
+      source_code: 'print(input())',
+      run_limits: {
+        memory: RUN_MEMORY - 1
+      },
+      validate_only: true
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.equal(body.run, null);
+    assert.deepEqual(body.limits_resolution.run.memory, {
+      requested: RUN_MEMORY - 1,
+      default: RUN_MEMORY,
+      system: RUN_MEMORY,
+      effective: RUN_MEMORY - 1,
+      source: 'request'
+    });
+    assert.equal(body.limits_resolution.run.wall_time.source, 'default');
+    assert.equal(body.limits_resolution.run.wall_time.effective, RUN_WALL_TIME);
+  }
+
+  {
+    console.log('Sending requests whose bodies never arrive (should time out)');
+    const sendStalledRequest = () =>
+      new Promise((resolve, reject) => {
+        const { hostname, port } = new URL(BASE_URL);
+        const socket = net.connect(parseInt(port), hostname, () => {
+          socket.write(
+            'POST /execute HTTP/1.1\r\nHost: envicutor\r\nContent-Type: application/json\r\nContent-Length: 100\r\n\r\n'
+          );
+        });
+        let data = '';
+        socket.on('data', (chunk) => {
+          data += chunk;
+          if (data.includes('}')) {
+            socket.destroy();
+            resolve(data);
+          }
+        });
+        socket.on('error', reject);
+      });
+    // As many as there are execution slots, none of which they should keep
+    const before = new Date();
+    const responses = await Promise.all(
+      Array.from({ length: MAX_CONCURRENT_SUBMISSIONS }, sendStalledRequest)
+    );
+    const after = new Date();
+    for (const response of responses) {
+      console.log(response);
+      assert.ok(response.startsWith('HTTP/1.1 408'));
+      assert.ok(response.includes('"message":"Timed out reading the request body"'));
+    }
+    assert.ok(after - before >= REQUEST_BODY_TIMEOUT * 1000);
+
+    console.log('Executing right after the timed out requests (should get a slot)');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print("slot")'
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.equal(body.run.stdout, 'slot\n');
+  }
+
+  {
+    console.log('Executing Python through the Piston-compatible route');
+    const res = await sendRequest('POST', `${BASE_URL}/piston/execute`, {
+      runtime_id: 2,
+      source_code: 'import os, sys\nprint("out", flush=True)\nprint("err", file=sys.stderr, flush=True)\nos.kill(os.getpid(), 9)'
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.equal(body.language, 'Python');
+    assert.equal(body.version, '');
+    assert.equal(body.compile, undefined);
+    assert.equal(body.run.stdout, 'out\n');
+    assert.equal(body.run.stderr, 'err\n');
+    assert.equal(body.run.output, 'out\nerr\n');
+    assert.equal(body.run.code, null);
+    assert.equal(body.run.signal, 'SIGKILL');
+  }
+
+  {
+    console.log('Receiving the event of a finished execution');
+    const controller = new AbortController();
+    const events = await fetch(`${BASE_URL}/admin/events`, { signal: controller.signal });
+    assert.equal(events.status, 200);
+    const reader = events.body.getReader();
+    const decoder = new TextDecoder();
+
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'exit(3)'
+    });
+    assert.equal(res.status, 200);
+
+    let data = '';
+    while (!data.includes('execution_finished')) {
+      const { value } = await reader.read();
+      data += decoder.decode(value);
+    }
+    controller.abort();
+    const line = data.split('\n').find((line) => line.includes('execution_finished'));
+    const event = JSON.parse(line.slice('data: '.length));
+    console.log(event);
+    assert.deepEqual(event, {
+      type: 'execution_finished',
+      runtime_id: 2,
+      stage: { name: 'run', index: 0, attempt: 1 },
+      exit_code: 3,
+      exit_signal: null,
+      exit_status: 'RE'
+    });
+  }
+
+  {
+    console.log('Checking the sampled host status');
+    const res = await sendRequest('GET', `${BASE_URL}/admin/status`);
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.equal(body.saturated, false);
+    assert.equal(body.disk_full, false);
+    assert.equal(body.startup_verification.broken, 0);
+    assert.equal(body.startup_verification.deferred, 0);
+    assert.deepEqual(body.execution_leases.stale, []);
+    assert.deepEqual(body.runtime_health, []);
+    assert.equal(body.quarantined_boxes, 0);
+    assert.equal(body.immutable_deployment, false);
+    assert.equal(body.sample.load_average.length, 3);
+    assert.ok(body.sample.runtimes_fs.total_bytes >= body.sample.runtimes_fs.available_bytes);
+  }
+
+  {
+    console.log('Storing a grading suite and grading a submission against it');
+    let res = await sendRequest('POST', `${BASE_URL}/runtimes/2/suites`, {
+      name: 'doubling',
+      comparator: 'ignore_trailing_whitespace',
+      cases: [
+        { stdin: '2', expected_stdout: '4', points: 3 },
+        { stdin: '-3', expected_stdout: '-6', points: 2 }
+      ]
+    });
+    let text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.deepEqual(JSON.parse(text), { name: 'doubling', revision: 1 });
+
+    // Only right for positive numbers
+    const source_code = 'print(abs(int(input())) * 2)';
+    res = await sendRequest('POST', `${BASE_URL}/runtimes/2/suites/doubling/grade`, {
+      source_code
+    });
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    let body = JSON.parse(text);
+    assert.equal(body.revision, 1);
+    assert.equal(body.points, 3);
+    assert.equal(body.max_points, 5);
+    assert.deepEqual(
+      body.cases.map((c) => c.passed),
+      [true, false]
+    );
+    assert.deepEqual(body.cases[1].run.stage, { name: 'run', index: 0, attempt: 1, case_index: 1 });
+
+    console.log('Updating the suite creates a new revision');
+    res = await sendRequest('POST', `${BASE_URL}/runtimes/2/suites`, {
+      name: 'doubling',
+      cases: [{ stdin: '5', expected_stdout: '10\n', points: 1 }]
+    });
+    text = await res.text();
+    console.log(text);
+    assert.deepEqual(JSON.parse(text), { name: 'doubling', revision: 2 });
+
+    res = await sendRequest('POST', `${BASE_URL}/runtimes/2/suites/doubling/grade`, {
+      source_code
+    });
+    body = JSON.parse(await res.text());
+    assert.equal(body.revision, 2);
+    assert.equal(body.points, 1);
+
+    res = await sendRequest('POST', `${BASE_URL}/runtimes/2/suites/doubling/grade`, {
+      source_code,
+      revision: 1
+    });
+    body = JSON.parse(await res.text());
+    assert.equal(body.revision, 1);
+    assert.equal(body.points, 3);
+  }
+
+  {
+    console.log('Aliasing Bash and executing it by alias');
+    let res = await sendRequest('PATCH', `${BASE_URL}/runtimes/4/aliases`, {
+      aliases: ['sh', 'shell']
+    });
+    let text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.deepEqual(JSON.parse(text), { id: 4, name: 'Bash', aliases: ['sh', 'shell'] });
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime: 'sh',
+      source_code: 'echo aliased'
+    });
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, 'aliased\n');
+
+    res = await sendRequest('GET', `${BASE_URL}/runtimes?name=shell`);
+    text = await res.text();
+    console.log(text);
+    const [{ health, ...listed }] = JSON.parse(text);
+    assert.deepEqual(listed, { id: 4, name: 'Bash', aliases: ['sh', 'shell'] });
+    assert.equal(health.state, 'healthy');
+    assert.equal(health.sandbox_errors, 0);
+
+    console.log('Rejecting an alias used by another runtime');
+    res = await sendRequest('PATCH', `${BASE_URL}/runtimes/2/aliases`, { aliases: ['sh'] });
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 409);
+
+    res = await sendRequest('PATCH', `${BASE_URL}/runtimes/4/aliases`, { aliases: [] });
+    assert.equal(res.status, 200);
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime: 'sh',
+      source_code: 'echo aliased'
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 404);
+  }
+
+  {
+    console.log('Rejecting a memory limit below the runtime floor');
+    let res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: 'echo started',
+      run_limits: { memory: 2048 }
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 400);
+    const body = JSON.parse(text);
+    assert.equal(body.code, 'memory_below_runtime_floor');
+    assert.equal(body.memory, 2048);
+    assert.equal(body.min_memory, 4096);
+
+    console.log('Overriding the runtime floor');
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: 'echo started',
+      run_limits: { memory: 2048 },
+      override_floor: true
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 200);
+  }
+
+  {
+    console.log('Accepting runtime ids beyond the 32-bit range');
+    const res = await sendRequest('DELETE', `${BASE_URL}/runtimes/4294967297`);
+    console.log(await res.text());
+    assert.equal(res.status, 404);
+  }
+
+  {
+    console.log('Injecting the default sandbox environment below the runtime environment');
+    let res = await sendRequest('GET', `${BASE_URL}/config/public`);
+    let text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.deepEqual(JSON.parse(text).default_sandbox_env, {
+      ENVICUTOR: '1',
+      spaces: 'overridden by the runtime'
+    });
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: `import os
+print(os.environ["ENVICUTOR"])
+print(os.environ["spaces"])
+`
+    });
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, '1\nthese spaces\n');
+  }
+
+  {
+    console.log('Executing in a runtime that does not exist');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4294967297,
+      source_code: 'print("hi")'
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 404);
+  }
+
+  {
+    console.log('Echoing a large stdin without deadlocking against the output');
+    const line = 'x'.repeat(99) + '\n';
+    const stdin = line.repeat(15000);
+    let res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: `import sys
+for line in sys.stdin:
+    sys.stdout.write(line)
+`,
+      stdin
+    });
+    let text = await res.text();
+    assert.equal(res.status, 200);
+    let body = JSON.parse(text);
+    assert.equal(body.run.exit_code, 0);
+    assert.equal(body.run.stdout, stdin);
+
+    console.log('Treating an empty stdin as closed');
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'import sys\nprint(repr(sys.stdin.read()))',
+      stdin: ''
+    });
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    body = JSON.parse(text);
+    assert.equal(body.run.stdout, "''\n");
+  }
+
+  {
+    console.log('Passing arguments to the run script without expanding them');
+    const args = ['"; rm -rf /', 'two words', '$HOME', "'quoted'"];
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: 'printf "%s\\n" "$@"',
+      args
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, args.map((arg) => `${arg}\n`).join(''));
+  }
+
+  {
+    console.log('Judging cases by their expected exit codes');
+    let res = await sendRequest('POST', `${BASE_URL}/runtimes/2/suites`, {
+      name: 'exit_codes',
+      cases: [
+        { stdin: '3', expected_stdout: '', points: 1, expected_exit_code: 3 },
+        { stdin: '4', expected_stdout: '', points: 1, expected_exit_code: 3 },
+        { stdin: '9', expected_stdout: '', points: 1, expected_exit_code: 9 },
+        { stdin: '0', expected_stdout: '', points: 1 }
+      ]
+    });
+    let text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+
+    const source_code = `import os, sys
+n = int(input())
+if n == 9:
+    os.kill(os.getpid(), 9)
+sys.exit(n)
+`;
+    res = await sendRequest('POST', `${BASE_URL}/runtimes/2/suites/exit_codes/grade`, {
+      source_code
+    });
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    let body = JSON.parse(text);
+    assert.equal(body.points, 2);
+    assert.deepEqual(
+      body.cases.map((c) => c.verdict),
+      ['ok', 'wrong_exit_code', 'runtime_error', 'ok']
+    );
+    assert.equal(body.cases[1].expected_exit_code, 3);
+    assert.equal(body.cases[1].exit_code, 4);
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code,
+      stdin: '3',
+      expected_exit_code: 3
+    });
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).verdict, 'ok');
+  }
+
+  {
+    console.log('Executing a submission made of several files');
+    let res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      files: [
+        { name: 'main.py', content: 'from lib.helper import greet\nprint(greet())\n' },
+        { name: 'lib/helper.py', content: 'def greet():\n    return "hello from helper"\n' }
+      ]
+    });
+    let text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, 'hello from helper\n');
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      files: [{ name: '../main.py', content: 'print(1)' }]
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 400);
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      files: [{ name: 'helper.py', content: 'print(1)' }]
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 400);
+  }
+
+  {
+    console.log('Round-tripping a binary file through base64');
+    const png =
+      'iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR4nGP4z8DwHwAFAAH/iZk9HQAAAABJRU5ErkJggg==';
+    let res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      files: [
+        {
+          name: 'main.py',
+          content: "import hashlib\nprint(hashlib.sha256(open('pixel.png', 'rb').read()).hexdigest())\n"
+        },
+        { name: 'pixel.png', content: png, encoding: 'base64' }
+      ]
+    });
+    let text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(
+      JSON.parse(text).run.stdout,
+      '4ff6ab670a58c14270e034e2090d9a432caa263a14e0a25785386b0c12f880b5\n'
+    );
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      files: [
+        { name: 'main.py', content: 'print(1)' },
+        { name: 'pixel.png', content: 'not base64!', encoding: 'base64' }
+      ]
+    });
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 400);
+    assert.ok(JSON.parse(text).message.includes('pixel.png'));
+  }
+
+  {
+    console.log('Estimating an execution before running it');
+    const req = {
+      runtime_id: 3,
+      source_code: 'int main() { return 0; }',
+      run_limits: { memory: RUN_MEMORY - 1 }
+    };
+    let res = await sendRequest('POST', `${BASE_URL}/execute/estimate`, req);
+    let text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const estimate = JSON.parse(text);
+    assert.deepEqual(
+      estimate.stages.map((stage) => stage.stage),
+      ['compile', 'run']
+    );
+    // Without compile_limits, the compilation runs with the run limits
+    assert.equal(estimate.stages[0].memory, RUN_MEMORY - 1);
+    assert.equal(estimate.stages[1].memory, RUN_MEMORY - 1);
+    assert.equal(estimate.stages[1].wall_time, RUN_WALL_TIME + RUN_EXTRA_TIME);
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, req);
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.equal(body.run_skipped, false);
+    assert.equal(body.compile.limit_set, 'compile');
+    assert.equal(body.run.limit_set, 'run');
+    const ranStages = ['extract', 'compile', 'run'].filter((stage) => body[stage] !== null);
+    assert.deepEqual(
+      ranStages,
+      estimate.stages.map((stage) => stage.stage)
+    );
+
+    res = await sendRequest('POST', `${BASE_URL}/execute/estimate`, {
+      runtime_id: 3,
+      source_code: '',
+      run_limits: { memory: RUN_MEMORY + 1 }
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 400);
+  }
+
+  {
+    console.log('Executing by runtime name');
+    let res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_name: 'Bash',
+      source_code: 'echo by name'
+    });
+    let text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, 'by name\n');
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      runtime_name: 'Bash',
+      source_code: 'echo by both'
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 200);
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      runtime_name: 'Bash',
+      source_code: 'echo by both'
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 400);
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_name: 'No such runtime',
+      source_code: 'echo nothing'
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 404);
+  }
+
+  {
+    console.log('Rejecting runtime names and aliases with control characters');
+    let res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Spoofed\u001b[31m',
+      nix_shell: 'unused',
+      compile_script: '',
+      run_script: 'unused',
+      source_file_name: 'main.sh'
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 400);
+
+    res = await sendRequest('PATCH', `${BASE_URL}/runtimes/4/aliases`, {
+      aliases: ['hsab\u202e']
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 400);
+  }
+
+  {
+    console.log('Judging an execution by its output');
+    let res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print(input())  ',
+      stdin: 'judged',
+      expected_output: 'judged  \n\n',
+      comparator: 'ignore_trailing_whitespace'
+    });
+    let text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    let body = JSON.parse(text);
+    assert.equal(body.verdict, 'ok');
+    assert.equal(body.run.stdout, '');
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print(input())',
+      stdin: 'judged',
+      expected_output: 'something else\n',
+      include_stdout: true,
+      max_stdout_bytes: 3
+    });
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    body = JSON.parse(text);
+    assert.equal(body.verdict, 'wrong_answer');
+    assert.equal(body.run.stdout, 'jud');
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'while True:\n    pass',
+      expected_output: '',
+      run_limits: { cpu_time: 1 }
+    });
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).verdict, 'time_limit_exceeded');
+  }
+
+  {
+    console.log('Running a batch of cases');
+    let res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print(int(input()) * 2)',
+      cases: [
+        { stdin: '1', expected_output: '2\n' },
+        { stdin: '2', expected_output: '5\n' },
+        { stdin: 'x' },
+        { stdin: '4', expected_output: '8\n', cpu_time: 1 }
+      ]
+    });
+    let text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    let body = JSON.parse(text);
+    assert.equal(body.run, undefined);
+    assert.equal(body.cases.length, 4);
+    assert.equal(body.cases[0].verdict, 'ok');
+    assert.equal(body.cases[1].verdict, 'wrong_answer');
+    assert.equal(body.cases[2].verdict, undefined);
+    assert.notEqual(body.cases[2].run.exit_code, 0);
+    assert.equal(body.cases[3].verdict, 'ok');
+    assert.equal(body.verdict, 'wrong_answer');
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print(int(input()) * 2)',
+      cases: [{ stdin: '1', expected_output: '3\n' }, { stdin: '2', expected_output: '4\n' }],
+      stop_on_first_failure: true
+    });
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).cases.length, 1);
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print(input())',
+      stdin: 'both',
+      cases: [{ stdin: '1' }]
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 400);
+  }
+
+  {
+    console.log('Streaming the cases of a batch as they finish');
+    const res = await fetch(`${BASE_URL}/execute/stream`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({
+        runtime_id: 2,
+        source_code: 'import time\nn = int(input())\ntime.sleep(n)\nprint(n)',
+        cases: [
+          { stdin: '0', expected_output: '0\n' },
+          { stdin: '2', expected_output: '2\n' },
+          { stdin: '1', expected_output: '0\n' }
+        ]
+      })
+    });
+    assert.equal(res.status, 200);
+    const received = await readServerSentEvents(res, 'summary');
+    console.log(received);
+    const cases = received.filter(({ event }) => event === 'case_result');
+    const summary = received.find(({ event }) => event === 'summary');
+    assert.equal(cases.length, 3);
+    assert(summary.at - cases[0].at >= 2000);
+    assert.deepEqual(
+      cases.map(({ data }) => data.verdict),
+      summary.data.cases.map(({ verdict }) => verdict)
+    );
+    assert.equal(cases[2].data.case_index, 2);
+    assert.equal(cases[2].data.verdict, 'wrong_answer');
+    assert.equal(summary.data.verdict, 'wrong_answer');
+
+    const rejected = await sendRequest('POST', `${BASE_URL}/execute/stream`, {
+      runtime_id: 2,
+      source_code: 'print(1)',
+      validate_only: true
+    });
+    console.log(await rejected.text());
+    assert.equal(rejected.status, 400);
+  }
+
+  {
+    console.log('Running the cases of a batch in parallel');
+    const startedAt = Date.now();
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'import time\nn = int(input())\ntime.sleep(1)\nprint(n)',
+      cases: [0, 1, 2, 3].map((n) => ({ stdin: `${n}`, expected_output: `${n}\n` })),
+      parallelism: 4
+    });
+    const elapsed = Date.now() - startedAt;
+    const text = await res.text();
+    console.log(text, elapsed);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.deepEqual(
+      body.cases.map(({ case_index }) => case_index),
+      [0, 1, 2, 3]
+    );
+    assert.equal(body.verdict, 'ok');
+    assert(elapsed < 3500);
+  }
+
+  {
+    console.log('Telling clients whether to retry');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print(1)',
+      args: ['\u0000']
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 400);
+    const body = JSON.parse(text);
+    assert.equal(body.retryable, false);
+    assert.equal(body.retry_after_ms, undefined);
+    assert.equal(res.headers.get('retry-after'), null);
+  }
+
+  {
+    console.log('Streaming the output of an execution');
+    const res = await fetch(`${BASE_URL}/execute/stream`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({
+        runtime_id: 2,
+        source_code:
+          'import sys, time\nprint("first", flush=True)\nprint("é", file=sys.stderr, flush=True)\ntime.sleep(2)\nprint("second")'
+      })
+    });
+    assert.equal(res.status, 200);
+    const received = await readServerSentEvents(res, 'result');
+    console.log(received);
+    const stdout = received.filter(({ event }) => event === 'run_stdout');
+    const stderr = received.filter(({ event }) => event === 'run_stderr');
+    const result = received.find(({ event }) => event === 'result');
+    assert.equal(stdout[0].data, 'first\n');
+    assert(result.at - stdout[0].at >= 1500);
+    assert.equal(stdout.map(({ data }) => data).join(''), 'first\nsecond\n');
+    assert.equal(stderr.map(({ data }) => data).join(''), 'é\n');
+    assert.equal(result.data.run.stdout, 'first\nsecond\n');
+    assert.equal(result.data.run.exit_code, 0);
+  }
+
+  {
+    console.log('Refusing test data from hosts that are not allowed');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print(input())',
+      cases: [{ stdin_url: 'https://example.com/1.in', expected_output: '1\n' }]
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 400);
+    assert(JSON.parse(text).message.startsWith('Case 0:'));
+  }
+
+  {
+    console.log('Polling for the result of a job');
+    const res = await sendRequest('POST', `${BASE_URL}/jobs`, {
+      runtime_id: 2,
+      source_code: 'import time\ntime.sleep(1)\nprint("done")'
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 202);
+    const { id, state } = JSON.parse(text);
+    assert.equal(state, 'queued');
+    let job;
+    do {
+      await new Promise((resolve) => setTimeout(resolve, 200));
+      job = await (await fetch(`${BASE_URL}/jobs/${id}`)).json();
+      assert(['queued', 'running', 'finished'].includes(job.state));
+    } while (job.state !== 'finished');
+    console.log(job);
+    assert.equal(job.status, 200);
+    assert.equal(job.result.run.stdout, 'done\n');
+    const missing = await fetch(`${BASE_URL}/jobs/${id + 1000}`);
+    assert.equal(missing.status, 404);
+  }
+
+  {
+    console.log('Not running cases once the CPU budget is used up');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'import time\nwhile time.process_time() < 0.3:\n    pass\nprint(1)',
+      cases: [{}, {}, {}],
+      max_total_cpu_seconds: 0.2
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.equal(body.budget_exhausted, true);
+    assert.equal(body.cases[0].run.exit_code, 0);
+    assert.deepEqual(
+      body.cases.slice(1).map(({ verdict }) => verdict),
+      ['budget_exhausted', 'budget_exhausted']
+    );
+  }
+
+  {
+    console.log('Refusing job callbacks when they are not enabled');
+    const res = await sendRequest('POST', `${BASE_URL}/jobs`, {
+      runtime_id: 2,
+      source_code: 'print(1)',
+      callback_url: 'https://example.com/results'
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 400);
+    assert.equal(JSON.parse(text).message, 'Callbacks are not enabled on this server');
+  }
+
+  {
+    console.log('Cancelling a running job');
+    const res = await sendRequest('POST', `${BASE_URL}/jobs`, {
+      runtime_id: 2,
+      source_code: 'import time\ntime.sleep(10)',
+      run_limits: { wall_time: RUN_WALL_TIME }
+    });
+    assert.equal(res.status, 202);
+    const { id } = await res.json();
+    let job;
+    do {
+      await new Promise((resolve) => setTimeout(resolve, 200));
+      job = await (await fetch(`${BASE_URL}/jobs/${id}`)).json();
+    } while (job.state === 'queued');
+    assert.equal(job.state, 'running');
+    const cancelled = await fetch(`${BASE_URL}/jobs/${id}`, { method: 'DELETE' });
+    assert.equal(cancelled.status, 200);
+    assert.equal((await cancelled.json()).state, 'cancelled');
+    job = await (await fetch(`${BASE_URL}/jobs/${id}`)).json();
+    assert.equal(job.state, 'cancelled');
+    assert.equal(job.result, undefined);
+    const again = await fetch(`${BASE_URL}/jobs/${id}`, { method: 'DELETE' });
+    assert.equal(again.status, 409);
+  }
+
+  {
+    console.log('Listing and clearing quarantined boxes');
+    const res = await fetch(`${BASE_URL}/admin/quarantine`);
+    assert.equal(res.status, 200);
+    assert.deepEqual(await res.json(), []);
+    const cleared = await fetch(`${BASE_URL}/admin/quarantine/3`, { method: 'DELETE' });
+    assert.equal(cleared.status, 404);
+  }
+
+  {
+    console.log('Killing a run that stops writing output');
+    const started = Date.now();
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'import time\nprint("waiting", flush=True)\ntime.sleep(10)',
+      idle_output_timeout_ms: 500,
+      run_limits: { wall_time: RUN_WALL_TIME }
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.equal(body.verdict, 'no_output_timeout');
+    assert.equal(body.run.no_output_timeout, true);
+    assert.equal(body.run.stdout, 'waiting\n');
+    assert(Date.now() - started < 8000);
+  }
+
+  {
+    console.log('Truncating output over the limit');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'import sys\nsys.stdout.write("a" * 100000)\nsys.stderr.write("b" * 10)\nsys.exit(3)',
+      max_output_bytes: 1000
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const { run } = JSON.parse(text);
+    assert.equal(run.stdout, 'a'.repeat(1000));
+    assert.equal(run.stdout_truncated, true);
+    assert.equal(run.stderr, 'b'.repeat(10));
+    assert.equal(run.stderr_truncated, undefined);
+    assert.equal(run.exit_code, 3);
+  }
+
+  {
+    console.log('Killing a run over the output limit');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'while True:\n    print("a" * 1000)',
+      max_output_bytes: 1000,
+      kill_on_output_limit: true,
+      run_limits: { wall_time: RUN_WALL_TIME }
+    });
+    const text = await res.text();
+    assert.equal(res.status, 200);
+    const { run } = JSON.parse(text);
+    assert.equal(run.stdout.length, 1000);
+    assert.equal(run.stdout_truncated, true);
+    assert.equal(run.exit_status, 'SG');
+  }
+
+  {
+    console.log('Refusing an output limit over the maximum');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print(1)',
+      max_output_bytes: 1024 * 1024 * 1024
+    });
+    assert.equal(res.status, 400);
+    assert.equal((await res.json()).message, `max_output_bytes can't be more than ${16 * 1024 * 1024}`);
+  }
+
+  {
+    console.log('Returning binary output as base64');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'import sys\nsys.stdout.buffer.write(bytes([0, 255, 128, 10]))',
+      output_encoding: 'base64'
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const { run } = JSON.parse(text);
+    assert.equal(run.output_encoding, 'base64');
+    assert.deepEqual([...Buffer.from(run.stdout, 'base64')], [0, 255, 128, 10]);
+    assert.equal(run.stderr, '');
+  }
+
+  {
+    console.log('Listing and redelivering dead letters');
+    const res = await fetch(`${BASE_URL}/admin/dead-letters`);
+    assert.equal(res.status, 200);
+    assert.deepEqual(await res.json(), []);
+    const redelivered = await fetch(`${BASE_URL}/admin/dead-letters/1/redeliver`, {
+      method: 'POST'
+    });
+    assert.equal(redelivered.status, 404);
+  }
+
+  {
+    console.log('Compressing large execution responses');
+    const res = await fetch(`${BASE_URL}/execute`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json', 'Accept-Encoding': 'gzip' },
+      body: JSON.stringify({
+        runtime_id: 2,
+        source_code: 'print("0123456789" * 20000)'
+      })
+    });
+    assert.equal(res.status, 200);
+    assert.equal(res.headers.get('content-encoding'), 'gzip');
+    const { run } = await res.json();
+    assert.equal(run.stdout, '0123456789'.repeat(20000) + '\n');
+
+    const small = await fetch(`${BASE_URL}/execute`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json', 'Accept-Encoding': 'gzip' },
+      body: JSON.stringify({ runtime_id: 2, source_code: 'print(1)' })
+    });
+    assert.equal(small.status, 200);
+    assert.equal(small.headers.get('content-encoding'), null);
+    assert.equal((await small.json()).run.stdout, '1\n');
+  }
+
+  {
+    console.log('Accepting gzip request bodies');
+    const post = (body) =>
+      fetch(`${BASE_URL}/execute`, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json', 'Content-Encoding': 'gzip' },
+        body
+      });
+    const res = await post(
+      zlib.gzipSync(JSON.stringify({ runtime_id: 2, source_code: 'print("compressed")' }))
+    );
+    assert.equal(res.status, 200);
+    assert.equal((await res.json()).run.stdout, 'compressed\n');
+
+    const malformed = await post(Buffer.from('not gzip'));
+    assert.equal(malformed.status, 400);
+    assert.equal((await malformed.json()).message, 'Malformed gzip request body');
+
+    const bomb = await post(zlib.gzipSync(Buffer.alloc(64 * 1024 * 1024, ' ')));
+    assert.equal(bomb.status, 413);
+    assert.equal((await bomb.json()).message, 'Request body is too large once decompressed');
+  }
+
+  {
+    console.log('Setting environment variables from the request');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'import os\nprint(os.environ["GREETING"])\nprint(os.environ["QUOTED"])',
+      env: { GREETING: 'hello', QUOTED: '$(echo no) `echo no` \'"' }
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, 'hello\n$(echo no) `echo no` \'"\n');
+
+    for (const [env, message] of [
+      [{ '1BAD': 'x' }, '1BAD is not a valid variable name, expected [A-Za-z_][A-Za-z0-9_]*'],
+      [{ LD_PRELOAD: '/tmp/x.so' }, "LD_PRELOAD can't be set by requests"],
+      [{ HOME: '/' }, "HOME can't be set by requests"]
+    ]) {
+      const denied = await sendRequest('POST', `${BASE_URL}/execute`, {
+        runtime_id: 2,
+        source_code: 'print(1)',
+        env
+      });
+      assert.equal(denied.status, 400);
+      assert.equal((await denied.json()).message, message);
+    }
+  }
+
+  {
+    console.log('Hashing stage output');
+    const sha256 = (data) => crypto.createHash('sha256').update(data).digest('hex');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'import sys\nsys.stdout.write("a" * 5000)\nsys.stderr.write("oops")',
+      max_output_bytes: 1000
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const { run } = JSON.parse(text);
+    assert.equal(run.stdout_sha256, sha256('a'.repeat(5000)));
+    assert.equal(run.returned_stdout_sha256, sha256('a'.repeat(1000)));
+    assert.equal(run.stderr_sha256, sha256('oops'));
+    assert.equal(run.returned_stderr_sha256, undefined);
+  }
+
+  {
+    console.log('Weighing boxes by priority');
+    for (const [priority, weight] of [
+      ['interactive', 100],
+      ['batch', 25]
+    ]) {
+      const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+        runtime_id: 2,
+        source_code: 'print(1)',
+        priority
+      });
+      const text = await res.text();
+      console.log(text);
+      assert.equal(res.status, 200);
+      assert.equal(JSON.parse(text).run.cpu_weight, weight);
+    }
+  }
+
+  {
+    console.log('Overriding the entry file name');
+    // The runtime compiles every .cpp file, its own source file name isn't one
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 6,
+      source_code: '#include <iostream>\nint main() { std::cout << "entry" << std::endl; }',
+      entry_file_name: 'main.cpp'
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, 'entry\n');
+
+    const escaping = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print(1)',
+      entry_file_name: '../app.py'
+    });
+    assert.equal(escaping.status, 400);
+    assert.equal((await escaping.json()).message, 'Invalid entry file name: ../app.py');
+  }
+
+  {
+    console.log('Mounting datasets read-only');
+    const upload = await fetch(`${BASE_URL}/datasets/sales.csv`, {
+      method: 'PUT',
+      body: 'region,total\nnorth,3\nsouth,4\n'
+    });
+    const uploaded = await upload.text();
+    console.log(uploaded);
+    assert.equal(upload.status, 200);
+    assert.deepEqual(JSON.parse(uploaded), { name: 'sales.csv', size: 29 });
+
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: `
+import csv
+with open('/datasets/sales.csv') as f:
+    print(sum(int(row['total']) for row in csv.DictReader(f)))
+try:
+    open('/datasets/sales.csv', 'a').write('west,5\\n')
+    print('written')
+except OSError:
+    print('read-only')`,
+      datasets: ['sales.csv']
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, '7\nread-only\n');
+
+    const listed = await sendRequest('GET', `${BASE_URL}/datasets`);
+    assert.deepEqual(await listed.json(), [{ name: 'sales.csv', size: 29 }]);
+
+    const deleted = await sendRequest('DELETE', `${BASE_URL}/datasets/sales.csv`);
+    assert.equal(deleted.status, 200);
+    const missing = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print(1)',
+      datasets: ['sales.csv']
+    });
+    assert.equal(missing.status, 404);
+    assert.equal((await missing.json()).message, 'No dataset named sales.csv');
+  }
+
+  {
+    console.log('Expiring job results');
+    const submit = async (body) => {
+      const res = await sendRequest('POST', `${BASE_URL}/jobs`, {
+        runtime_id: 2,
+        source_code: 'print("kept")',
+        ...body
+      });
+      assert.equal(res.status, 202);
+      const { id } = await res.json();
+      let job;
+      do {
+        await new Promise((resolve) => setTimeout(resolve, 200));
+        job = await (await fetch(`${BASE_URL}/jobs/${id}`)).json();
+      } while (job.state !== 'finished');
+      return job;
+    };
+
+    const shortLived = await submit({ retention_seconds: 1 });
+    console.log(shortLived);
+    assert(shortLived.expires_at <= Date.now() / 1000 + 1);
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    const expired = await fetch(`${BASE_URL}/jobs/${shortLived.id}`);
+    assert.equal(expired.status, 410);
+    assert.equal((await expired.json()).expired_at, shortLived.expires_at);
+
+    const purged = await submit({});
+    assert.equal(purged.result.run.stdout, 'kept\n');
+    const purge = await fetch(`${BASE_URL}/jobs/${purged.id}/result`, { method: 'DELETE' });
+    assert.equal(purge.status, 204);
+    assert.equal((await fetch(`${BASE_URL}/jobs/${purged.id}`)).status, 410);
+
+    const tooLong = await sendRequest('POST', `${BASE_URL}/jobs`, {
+      runtime_id: 2,
+      source_code: 'print(1)',
+      retention_seconds: 10 * 24 * 60 * 60
+    });
+    assert.equal(tooLong.status, 400);
+    assert.equal(
+      (await tooLong.json()).message,
+      "retention_seconds can't be more than MAX_JOB_RETENTION (86400)"
+    );
+  }
+  {
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: [
+        'import os',
+        'open("out.txt", "w").write("written")',
+        'os.symlink("/etc/passwd", "link.txt")'
+      ].join('\n'),
+      output_files: ['out.txt', 'missing.txt', 'link.txt']
+    });
+    assert.equal(res.status, 200);
+    const { output_files } = await res.json();
+    console.log(output_files);
+    assert.deepEqual(output_files, [
+      { name: 'out.txt', content: Buffer.from('written').toString('base64'), size: 7 },
+      { name: 'missing.txt', missing: true },
+      { name: 'link.txt', error: 'Not a regular file' }
+    ]);
+
+    const outside = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print(1)',
+      output_files: ['../x']
+    });
+    assert.equal(outside.status, 400);
+    assert.equal((await outside.json()).message, 'Invalid output file name: ../x');
+  }
+  {
+    const status = await (await fetch(`${BASE_URL}/admin/status`)).json();
+    if (status.compile_cache.max_bytes > 0) {
+      const body = {
+        runtime_id: 3,
+        source_code: `#include <iostream>\nint main() { std::cout << "cached"; } // ${Date.now()}`
+      };
+      const first = await (await sendRequest('POST', `${BASE_URL}/execute`, body)).json();
+      assert.equal(first.compile_cache, 'miss');
+      assert.equal(first.compile.exit_code, 0);
+      const second = await (await sendRequest('POST', `${BASE_URL}/execute`, body)).json();
+      console.log(second);
+      assert.equal(second.compile_cache, 'hit');
+      assert.equal(second.compile, null);
+      assert.equal(second.run.stdout, 'cached');
+      const after = await (await fetch(`${BASE_URL}/admin/status`)).json();
+      assert(after.compile_cache.hits > status.compile_cache.hits);
+      // Compiling under other limits doesn't use it
+      const lowered = await (
+        await sendRequest('POST', `${BASE_URL}/execute`, {
+          ...body,
+          compile_limits: { memory: COMPILE_MEMORY - 1 }
+        })
+      ).json();
+      assert.equal(lowered.compile_cache, 'miss');
+    }
+  }
+  {
+    const utf16 = Buffer.concat([
+      Buffer.from([0xff, 0xfe]),
+      Buffer.from('print("from utf-16")\n', 'utf16le')
+    ]).toString('base64');
+    const transcoded = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      files: [{ name: 'main.py', content: utf16, encoding: 'base64' }]
+    });
+    assert.equal(transcoded.status, 200);
+    const transcodedBody = await transcoded.json();
+    assert.equal(transcodedBody.run.stdout, 'from utf-16\n');
+    assert.deepEqual(transcodedBody.warnings, ['utf16_transcoded']);
+
+    const rejected = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      files: [{ name: 'main.py', content: utf16, encoding: 'base64' }],
+      transcode: false
+    });
+    assert.equal(rejected.status, 400);
+    assert.equal(
+      (await rejected.json()).message,
+      'main.py is encoded as UTF-16, save it as UTF-8 or pass transcode: true'
+    );
+
+    const stripped = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: '\ufeffprint("no bom")'
+    });
+    const strippedBody = await stripped.json();
+    assert.equal(strippedBody.run.stdout, 'no bom\n');
+    assert.deepEqual(strippedBody.warnings, ['utf8_bom_stripped']);
+  }
+  {
+    const since = Date.now();
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: 'print("recorded")'
+    });
+    assert.equal(res.status, 200);
+    let history;
+    for (let attempt = 0; attempt < 20; attempt++) {
+      history = await (
+        await fetch(`${BASE_URL}/executions?runtime_id=2&since=${since}&limit=1`)
+      ).json();
+      if (history.executions.length > 0) break;
+      await new Promise((resolve) => setTimeout(resolve, 100));
+    }
+    console.log(history);
+    const [execution] = history.executions;
+    assert.equal(execution.runtime_id, 2);
+    assert.equal(execution.stdout, 'recorded\n');
+    assert.equal(execution.exit_code, 0);
+    assert(execution.limits.memory > 0);
+    assert(execution.started_at >= since);
+
+    const tooMany = await fetch(`${BASE_URL}/executions?limit=501`);
+    assert.equal(tooMany.status, 400);
+  }
+  {
+    // The kernel may write a real core over it, so only the size is checked
+    const crashed = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: 'printf fake > core; kill -SEGV $$',
+      capture_core: true
+    });
+    assert.equal(crashed.status, 200);
+    const { core_dump } = await crashed.json();
+    console.log(core_dump);
+    assert.equal(core_dump.produced, true);
+    assert.equal(core_dump.signal, 11);
+    const core = await fetch(`${BASE_URL}/cores/${core_dump.id}`);
+    assert.equal(core.status, 200);
+    assert.equal((await core.arrayBuffer()).byteLength, core_dump.size);
+
+    const exited = await (
+      await sendRequest('POST', `${BASE_URL}/execute`, {
+        runtime_id: 4,
+        source_code: 'echo fine',
+        capture_core: true
+      })
+    ).json();
+    assert.equal(exited.core_dump.produced, false);
+    assert.equal(exited.core_dump.reason, 'The run was not killed by a signal');
+
+    const withCases = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: 'echo fine',
+      cases: [{ stdin: '' }],
+      capture_core: true
+    });
+    assert.equal(withCases.status, 400);
+
+    const unknown = await fetch(`${BASE_URL}/cores/0-0`);
+    assert.equal(unknown.status, 404);
+  }
+  {
+    // The history keeps the 5 most recent executions, there were more before
+    const pruned = await sendRequest('POST', `${BASE_URL}/admin/prune`);
+    assert.equal(pruned.status, 200);
+    const { removed } = await pruned.json();
+    assert(removed > 0);
+    const history = await (await fetch(`${BASE_URL}/executions?limit=500`)).json();
+    assert(history.executions.length <= 5);
+  }
+  {
+    // The first request runs, the two sent while it does wait for its result
+    const since = Date.now();
+    const request = {
+      runtime_id: 4,
+      source_code: 'sleep 1; date +%s%N',
+      coalesce: true
+    };
+    const results = await Promise.all(
+      [0, 1, 2].map(async () => (await sendRequest('POST', `${BASE_URL}/execute`, request)).json())
+    );
+    console.log(results);
+    const [first, ...rest] = results;
+    for (const res of rest) {
+      assert.equal(res.run.stdout, first.run.stdout);
+    }
+    assert.equal(results.filter((res) => res.coalesced).length, 2);
+    let history;
+    for (let attempt = 0; attempt < 20; attempt++) {
+      await new Promise((resolve) => setTimeout(resolve, 100));
+      history = await (await fetch(`${BASE_URL}/executions?runtime_id=4&since=${since}`)).json();
+      if (history.executions.length > 0) break;
+    }
+    assert.equal(history.executions.length, 1);
+  }
+  {
+    // Boxes are given out in order: the compiled runtime compiles in the box
+    // after the first execution's and runs in a new one
+    const execute = async (runtime_id, source_code) =>
+      (await sendRequest('POST', `${BASE_URL}/execute`, { runtime_id, source_code })).json();
+    const before = await execute(4, 'echo before');
+    const compiled = await execute(3, 'int main() { return 0; }');
+    assert.equal(before.sandbox.backend, 'isolate');
+    assert.equal(typeof before.sandbox.host, 'string');
+    assert.equal(compiled.sandbox.box_id, before.sandbox.box_id + 2);
+    assert.equal(compiled.sandbox.host, before.sandbox.host);
+  }
+  {
+    // Bulk deletion is a dry run by default and needs a filter
+    const bulkDelete = (body) =>
+      fetch(`${BASE_URL}/runtimes`, {
+        method: 'DELETE',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify(body)
+      });
+    const noFilter = await bulkDelete({ dry_run: true });
+    assert.equal(noFilter.status, 400);
+    const dryRun = await (await bulkDelete({ name_prefix: 'Bas' })).json();
+    console.log(dryRun);
+    assert.equal(dryRun.dry_run, true);
+    assert.deepEqual(
+      dryRun.runtimes.map((runtime) => [runtime.id, runtime.outcome]),
+      [[4, 'would_delete']]
+    );
+    const unknownField = await bulkDelete({ name_prefix: 'Bas', tags: ['old'] });
+    assert.equal(unknownField.status, 400);
+    const none = await (await bulkDelete({ name_prefix: 'No such runtime', dry_run: false })).json();
+    assert.deepEqual(none, { dry_run: false, runtimes: [] });
+    const stillThere = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: 'echo still there'
+    });
+    assert.equal(stillThere.status, 200);
+  }
+  {
+    // What a program wrote before being killed is kept, and the files its
+    // output is captured to can't be asked for
+    const captureToFiles = OUTPUT_CAPTURE === 'files';
+    const res = await (
+      await sendRequest('POST', `${BASE_URL}/execute`, {
+        runtime_id: 2,
+        source_code:
+          "import os, signal, sys\nprint(os.readlink('/proc/self/fd/1'), flush=True)\nsys.stderr.write('on stderr\\n')\nsys.stderr.flush()\nos.kill(os.getppid(), signal.SIGKILL)\nos.kill(os.getpid(), signal.SIGKILL)"
+      })
+    ).json();
+    console.log(res);
+    if (captureToFiles) {
+      assert(res.run.stdout.endsWith('/.envicutor_stdout\n'));
+    } else {
+      assert(res.run.stdout.startsWith('pipe:'));
+    }
+    assert.equal(res.run.stderr, 'on stderr\n');
+    assert.equal(res.run.exit_signal, 9);
+    assert.equal(res.run.exit_signal_name, 'SIGKILL');
+    if (captureToFiles) {
+      // Output past max_file_size stops the program, and is reported as truncated
+      const { run } = await (
+        await sendRequest('POST', `${BASE_URL}/execute`, {
+          runtime_id: 2,
+          source_code: 'print("a" * 4096)',
+          run_limits: { max_file_size: 1 }
+        })
+      ).json();
+      console.log(run);
+      assert.equal(run.stdout, 'a'.repeat(1024));
+      assert.equal(run.stdout_truncated, true);
+      assert.notEqual(run.exit_code, 0);
+    }
+    const reserved = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: 'true',
+      output_files: ['.envicutor_stdout']
+    });
+    assert.equal(reserved.status, 400);
+  }
+  {
+    // The timing of every stage adds up to its wall time
+    const res = await (
+      await sendRequest('POST', `${BASE_URL}/execute`, {
+        runtime_id: 3,
+        source_code: '#include <unistd.h>\nint main() { usleep(300000); return 0; }'
+      })
+    ).json();
+    console.log(res.compile.timing, res.run.timing);
+    for (const stage of [res.compile, res.run]) {
+      assert.equal(typeof stage.timing.sandbox_init_ms, 'number');
+      assert.equal(typeof stage.timing.environment_activation_ms, 'number');
+      assert.equal(typeof stage.timing.program_ms, 'number');
+    }
+    assert.ok(res.run.timing.program_ms >= 300);
+    assert.ok(res.run.timing.environment_activation_ms < res.run.timing.program_ms);
+    const reserved = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: 'true',
+      output_files: ['.envicutor_activated']
+    });
+    assert.equal(reserved.status, 400);
+  }
+  {
+    // Signals are named, including real-time ones. The run script is killed,
+    // the submission runs as its child
+    const killWith = async (signal) =>
+      (
+        await (
+          await sendRequest('POST', `${BASE_URL}/execute`, {
+            runtime_id: 4,
+            source_code: `kill -${signal} $PPID`
+          })
+        ).json()
+      ).run;
+    const segv = await killWith(11);
+    assert.equal(segv.exit_signal, 11);
+    assert.equal(segv.exit_signal_name, 'SIGSEGV');
+    const realtime = await killWith(34);
+    assert.equal(realtime.exit_signal, 34);
+    assert.equal(realtime.exit_signal_name, 'SIG34');
+    const exited = await killWith(0);
+    assert.equal(exited.exit_signal_name, null);
+  }
+  {
+    // A recorded execution runs again with the request it was recorded with
+    const since = Date.now();
+    const res = await sendRequest('POST', `${BASE_URL}/execute?explain_limits=true`, {
+      runtime_id: 4,
+      source_code: 'echo "replayed $1"',
+      args: ['twice']
+    });
+    assert.equal(res.status, 200);
+    const findExecution = async (since) => {
+      for (let attempt = 0; attempt < 20; attempt++) {
+        await new Promise((resolve) => setTimeout(resolve, 100));
+        const history = await (
+          await fetch(`${BASE_URL}/executions?runtime_id=4&since=${since}&limit=1`)
+        ).json();
+        if (history.executions.length > 0) return history.executions[0];
+      }
+    };
+    const execution = await findExecution(since);
+    const replayed = await sendRequest('POST', `${BASE_URL}/executions/${execution.id}/replay`);
+    assert.equal(replayed.status, 200);
+    const replay = await replayed.json();
+    console.log(replay.diff);
+    assert.equal(replay.original.id, execution.id);
+    assert.equal(replay.replay.run.stdout, 'replayed twice\n');
+    assert.equal(replay.runtime_changed, false);
+    assert.equal(replay.diff.exit_code.original, 0);
+    assert.equal(replay.diff.exit_code.changed, false);
+    assert.equal(replay.diff.stdout_sha256.changed, false);
+    assert.equal(replay.diff.stdout_sha256.original, execution.stdout_sha256);
+
+    const missing = await sendRequest('POST', `${BASE_URL}/executions/999999999/replay`);
+    assert.equal(missing.status, 404);
+    // A body that can't be read is refused rather than taken as no override
+    const badOverride = await sendRequest('POST', `${BASE_URL}/executions/${execution.id}/replay`, {
+      allow_changed_runtime: 'yes'
+    });
+    assert.equal(badOverride.status, 400);
+    assert.equal((await badOverride.json()).field, 'allow_changed_runtime');
+
+    // Requests over EXECUTION_HISTORY_REQUEST_BYTES aren't kept
+    const bigSince = Date.now();
+    await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: `# ${'x'.repeat(8192)}\necho big`
+    });
+    const big = await findExecution(bigSince);
+    const gone = await sendRequest('POST', `${BASE_URL}/executions/${big.id}/replay`);
+    assert.equal(gone.status, 410);
+  }
+  {
+    // How a stage ended is given as a kind next to isolate's status
+    const runWith = async (source_code, limits = {}) =>
+      (
+        await (
+          await sendRequest('POST', `${BASE_URL}/execute`, {
+            runtime_id: 4,
+            source_code,
+            ...limits
+          })
+        ).json()
+      ).run;
+    const ok = await runWith('true');
+    assert.equal(ok.exit_kind, 'ok');
+    const failed = await runWith('exit 3');
+    assert.equal(failed.exit_kind, 'non_zero_exit');
+    assert.equal(failed.exit_status, 'RE');
+    const signalled = await runWith('kill -9 $PPID');
+    assert.equal(signalled.exit_kind, 'signalled');
+    assert.equal(signalled.exit_status, 'SG');
+    const timedOut = await runWith('sleep 5', { run_limits: { wall_time: 1 } });
+    assert.equal(timedOut.exit_kind, 'timed_out');
+    assert.equal(timedOut.exit_status, 'TO');
+  }
+  {
+    // A judged run killed for its memory gets its own verdict
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: "x = b'x' * (256 * 1024 * 1024)\nprint('allocated')",
+      expected_output: 'allocated\n',
+      run_limits: { memory: 64000 }
+    });
+    assert.equal(res.status, 200);
+    const body = await res.json();
+    console.log(body.run);
+    assert.equal(body.run.memory_limit_exceeded, true);
+    assert.equal(body.verdict, 'memory_limit_exceeded');
+
+    const fits = await (
+      await sendRequest('POST', `${BASE_URL}/execute`, {
+        runtime_id: 2,
+        source_code: "print('small')",
+        expected_output: 'small\n',
+        run_limits: { memory: 64000 }
+      })
+    ).json();
+    assert.equal(fits.run.memory_limit_exceeded, false);
+    assert.equal(fits.verdict, 'ok');
+  }
+  {
+    // Sandboxed programs see no descriptor of the server
+    const res = await sendRequest('POST', `${BASE_URL}/admin/self-test`);
+    const body = await res.json();
+    console.log(body.descriptor_canary);
+    assert.equal(res.status, 200);
+    assert.equal(body.passed, true);
+    assert.deepEqual(body.descriptor_canary.unexpected, []);
+    const fds = body.descriptor_canary.descriptors.map((descriptor) => descriptor.fd);
+    assert.deepEqual(fds.slice(0, 3), [0, 1, 2]);
+  }
+  {
+    // Stages are timestamped around the isolate invocation
+    const before = Date.now();
+    const res = await (
+      await sendRequest('POST', `${BASE_URL}/execute`, {
+        runtime_id: 3,
+        source_code: '#include <unistd.h>\nint main() { usleep(200000); return 0; }'
+      })
+    ).json();
+    const after = Date.now();
+    const rfc3339 = /^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}\.\d{3}Z$/;
+    console.log(res.compile.started_at, res.run.finished_at);
+    for (const stage of [res.compile, res.run]) {
+      assert.match(stage.started_at, rfc3339);
+      assert.match(stage.finished_at, rfc3339);
+      assert(Date.parse(stage.started_at) <= Date.parse(stage.finished_at));
+    }
+    assert(Date.parse(res.compile.finished_at) <= Date.parse(res.run.started_at));
+    assert(Date.parse(res.run.finished_at) - Date.parse(res.run.started_at) >= 200);
+    // The server's clock may be a little off from the test's
+    assert(Math.abs(Date.parse(res.compile.started_at) - before) < 5000);
+    assert(Math.abs(Date.parse(res.run.finished_at) - after) < 5000);
+  }
+  {
+    // Every problem of a request is given at once, the first one as the message
+    const install = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: '',
+      nix_shell: '',
+      compile_script: '',
+      run_script: 'bash main.sh\r\n',
+      source_file_name: '../main.sh',
+      aliases: ['dup', 'dup']
+    });
+    assert.equal(install.status, 400);
+    const installErrors = await install.json();
+    console.log(installErrors);
+    assert.deepEqual(
+      installErrors.errors.map((error) => error.field),
+      ['aliases', 'name', 'nix_shell', 'source_file_name', 'run_script']
+    );
+    assert.equal(installErrors.message, 'Duplicate alias: dup');
+    assert.equal(installErrors.errors[1].message, "Name can't be empty");
+
+    const execute = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: 'true',
+      run_limits: { cpu_time: RUN_CPU_TIME + 1 },
+      expected_exit_code: 300,
+      args: ['a\0b']
+    });
+    assert.equal(execute.status, 400);
+    const executeErrors = await execute.json();
+    console.log(executeErrors);
+    assert.deepEqual(
+      executeErrors.errors.map((error) => error.field),
+      ['run_limits', 'expected_exit_code', 'args']
+    );
+    assert.equal(
+      executeErrors.message,
+      `Invalid run limits: cpu_time can't exceed ${RUN_CPU_TIME} seconds`
+    );
+
+    // Including the ones that are checked against the runtime
+    const againstRuntime = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      files: [{ name: 'main.sh', content: 'echo started' }],
+      entry: 'other.sh',
+      run_limits: { memory: 2048 },
+      args: ['a\0b']
+    });
+    assert.equal(againstRuntime.status, 400);
+    const againstRuntimeErrors = await againstRuntime.json();
+    console.log(againstRuntimeErrors);
+    assert.deepEqual(
+      againstRuntimeErrors.errors.map((error) => error.field),
+      ['args', 'run_limits', 'entry']
+    );
+    assert.equal(againstRuntimeErrors.errors[1].code, 'memory_below_runtime_floor');
+    assert.equal(againstRuntimeErrors.errors[1].min_memory, 4096);
+  }
+  {
+    // Bodies that can't be read are answered with a JSON message too
+    const post = (body, contentType = 'application/json') =>
+      fetch(`${BASE_URL}/execute`, {
+        method: 'POST',
+        headers: { 'Content-Type': contentType },
+        body
+      });
+    const malformed = await post('{"runtime_id": 4,');
+    assert.equal(malformed.status, 400);
+    const malformedBody = await malformed.json();
+    console.log(malformedBody);
+    assert(malformedBody.message.startsWith('The request body is not valid JSON'));
+    assert.equal(malformedBody.field, undefined);
+
+    const wrongType = await post(JSON.stringify({ runtime_id: 4, source_code: 'true', args: [1] }));
+    assert.equal(wrongType.status, 400);
+    const wrongTypeBody = await wrongType.json();
+    console.log(wrongTypeBody);
+    assert.equal(wrongTypeBody.field, 'args[0]');
+    assert(wrongTypeBody.message.startsWith('Invalid args[0]: invalid type'));
+
+    const missing = await fetch(`${BASE_URL}/runtimes`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ name: 'NoShell' })
+    });
+    assert.equal(missing.status, 400);
+    assert.match((await missing.json()).message, /missing field/);
+
+    const plainText = await post(JSON.stringify({ runtime_id: 4, source_code: 'true' }), 'text/plain');
+    assert.equal(plainText.status, 415);
+    assert.equal(
+      (await plainText.json()).message,
+      'Expected a request with Content-Type: application/json'
+    );
+  }
+  {
+    // A malformed bootstrap body is refused instead of installing every example
+    const res = await fetch(`${BASE_URL}/admin/bootstrap`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: '{"only": ["python3"'
+    });
+    assert.equal(res.status, 400);
+    assert((await res.json()).message.startsWith('The request body is not valid JSON'));
+  }
+  {
+    console.log('Passing the source path to the compile and run scripts');
+    let res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Bash (source path)',
+      nix_shell: `
+{ pkgs ? import (
+  fetchTarball {
+    url="https://github.com/NixOS/nixpkgs/archive/72da83d9515b43550436891f538ff41d68eecc7f.tar.gz";
+    sha256="177sws22nqkvv8am76qmy9knham2adfh3gv7hrjf6492z1mvy02y";
+  }
+) {} }:
+pkgs.mkShell {
+  nativeBuildInputs = with pkgs; [
+      bash
+  ];
+}`,
+      compile_script: 'test -f "$1" && cp "$1" compiled.sh',
+      run_script: 'echo "$1" && bash compiled.sh "${@:2}"',
+      source_file_name: 'script.sh',
+      aliases: ['source-path']
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 200);
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime: 'source-path',
+      source_code: 'echo "ran with $1"',
+      args: ['an argument']
+    });
+    let text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    let body = JSON.parse(text);
+    assert.equal(body.compile.exit_code, 0);
+    assert.equal(body.run.stdout, 'script.sh\nran with an argument\n');
+
+    res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime: 'source-path',
+      files: [
+        { name: 'lib/entry.sh', content: 'echo from the entry' },
+        { name: 'script.sh', content: 'echo not the entry' }
+      ],
+      entry: 'lib/entry.sh'
+    });
+    text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    body = JSON.parse(text);
+    assert.equal(body.run.stdout, 'lib/entry.sh\nfrom the entry\n');
+
+    const [{ id }] = await (
+      await sendRequest('GET', `${BASE_URL}/runtimes?name=source-path`)
+    ).json();
+    res = await sendRequest('DELETE', `${BASE_URL}/runtimes/${id}`);
+    console.log(await res.text());
+    assert.equal(res.status, 200);
+  }
 })();
@@ -311,4 +311,57 @@ int main() {
     assert.ok(duration < 200);
     await installation_promise;
   }
+
+  {
+    console.log(
+      'Executing an interactive submission while batch ones wait (it should get the next free permits)'
+    );
+    const run = async (priority) => {
+      const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+        runtime_id: 2,
+        source_code: 'import time\ntime.sleep(0.5)',
+        priority
+      });
+      assert.equal(res.status, 200);
+      return new Date();
+    };
+    const batch = [];
+    for (let i = 0; i < MAX_CONCURRENT_SUBMISSIONS * 2; ++i) {
+      batch.push(run('batch'));
+    }
+    await sleep(100);
+    const interactiveFinishedAt = await run('interactive');
+    const batchFinishedAt = await Promise.all(batch);
+    const lastBatchFinishedAt = Math.max(...batchFinishedAt.map((date) => date.getTime()));
+    console.log(`The interactive submission finished ${lastBatchFinishedAt - interactiveFinishedAt} ms before the last batch one`);
+    assert.ok(interactiveFinishedAt.getTime() < lastBatchFinishedAt);
+  }
+
+  {
+    console.log('Getting the queue status while submissions wait for permits');
+    const idle = await (await sendRequest('GET', `${BASE_URL}/status`)).json();
+    assert.deepEqual(idle, {
+      permits: MAX_CONCURRENT_SUBMISSIONS,
+      permits_in_use: 0,
+      queue_depth: 0,
+      max_queue_length: null
+    });
+    const promises = [];
+    for (let i = 0; i < MAX_CONCURRENT_SUBMISSIONS * 2; ++i) {
+      promises.push(
+        sendRequest('POST', `${BASE_URL}/execute`, {
+          runtime_id: 2,
+          source_code: 'import time\ntime.sleep(0.5)'
+        })
+      );
+    }
+    await sleep(200);
+    const busy = await (await sendRequest('GET', `${BASE_URL}/status`)).json();
+    console.log(busy);
+    assert.equal(busy.permits_in_use, MAX_CONCURRENT_SUBMISSIONS);
+    assert.equal(busy.queue_depth, MAX_CONCURRENT_SUBMISSIONS);
+    for (const res of await Promise.all(promises)) {
+      assert.equal(res.status, 200);
+    }
+  }
 })();
@@ -65,7 +65,9 @@ pkgs.mkShell {
     console.log(text);
     assert.equal(res.status, 200);
     let body = JSON.parse(text);
-    assert.deepEqual(body, [{ id: 1, name: 'Python' }]);
+    assert.deepEqual(body, [
+      { id: 1, name: 'Python', health: { state: 'healthy', executions: 0, sandbox_errors: 0 } }
+    ]);
   }
 
   {
@@ -154,11 +156,18 @@ pkgs.mkShell {
 }`,
       compile_script: 'exec g++ main.cpp',
       run_script: 'exec ./a.out',
-      source_file_name: 'main.cpp'
+      source_file_name: 'main.cpp',
+      nix_cores: 1,
+      nix_max_jobs: 1000000
     });
 
-    console.log(await res.text());
+    const text = await res.text();
+    console.log(text);
     assert.equal(res.status, 200);
+    // The jobs are clamped to NIX_MAX_JOBS, the number of CPUs by default
+    const { nix_build } = JSON.parse(text);
+    assert.equal(nix_build.cores, 1);
+    assert(nix_build.max_jobs >= 1 && nix_build.max_jobs < 1000000);
   }
 
   {
@@ -187,4 +196,103 @@ pkgs.mkShell {
     console.log(await res.text());
     assert.equal(res.status, 400);
   }
+
+  {
+    console.log('Making an installation with a missing nix attribute (should be classified)');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Missing attribute',
+      nix_shell: `
+{ pkgs ? import (
+  fetchTarball {
+    url="https://github.com/NixOS/nixpkgs/archive/72da83d9515b43550436891f538ff41d68eecc7f.tar.gz";
+    sha256="177sws22nqkvv8am76qmy9knham2adfh3gv7hrjf6492z1mvy02y";
+  }
+) {} }:
+pkgs.mkShell {
+  nativeBuildInputs = with pkgs; [
+      python39_does_not_exist
+  ];
+}
+`,
+      compile_script: '',
+      run_script: 'python3 main.py',
+      source_file_name: 'main.py'
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 400);
+    const body = JSON.parse(text);
+    assert.equal(body.failure_category, 'missing_attribute');
+    assert.ok(body.failure_excerpt.includes('error:'));
+  }
+
+  {
+    console.log('Making an installation with too many env patterns');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Too many patterns',
+      nix_shell: '{ pkgs ? import <nixpkgs> {} }: pkgs.mkShell {}',
+      compile_script: '',
+      run_script: 'python3 main.py',
+      source_file_name: 'main.py',
+      env_include: Array.from({ length: 129 }, (_, i) => `VAR_${i}`)
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 400);
+    const body = JSON.parse(text);
+    assert.equal(body.message, "env_include can't have more than 128 entries");
+  }
+
+  {
+    console.log('Rejecting scripts with CRLF line endings');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'CRLF',
+      nix_shell: '{ pkgs ? import <nixpkgs> {} }: pkgs.mkShell {}',
+      compile_script: '',
+      run_script: 'echo hi\r\nbash main.sh\r\n',
+      source_file_name: 'main.sh'
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 400);
+    assert.ok(JSON.parse(text).message.includes('CRLF'));
+  }
+
+  {
+    console.log('Rejecting a missing interpreter with strict scripts');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Missing interpreter',
+      nix_shell: `
+{ pkgs ? import (
+  fetchTarball {
+    url="https://github.com/NixOS/nixpkgs/archive/72da83d9515b43550436891f538ff41d68eecc7f.tar.gz";
+    sha256="177sws22nqkvv8am76qmy9knham2adfh3gv7hrjf6492z1mvy02y";
+  }
+) {} }:
+pkgs.mkShell {
+  nativeBuildInputs = with pkgs; [
+      bash
+  ];
+}`,
+      compile_script: 'echo $2\n',
+      run_script: '#!/usr/bin/env ruby_does_not_exist\nputs ARGV\n',
+      source_file_name: 'main.rb',
+      strict_scripts: true
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 400);
+    const body = JSON.parse(text);
+    assert.deepEqual(
+      body.script_warnings.map((w) => [w.script, w.kind, w.line]),
+      [
+        ['compile', 'unprovided_positional_parameter', 1],
+        ['run', 'missing_interpreter', 1]
+      ]
+    );
+  }
 })();
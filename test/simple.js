@@ -12,8 +12,8 @@ const { sendRequest, BASE_URL } = require('./common');
     let body = JSON.parse(text);
     body.sort((x, y) => x.id - y.id);
     assert.deepEqual(body, [
-      { id: 2, name: 'Python' },
-      { id: 3, name: 'C++' }
+      { id: 2, name: 'Python', health: { state: 'healthy', executions: 0, sandbox_errors: 0 } },
+      { id: 3, name: 'C++', health: { state: 'healthy', executions: 0, sandbox_errors: 0 } }
     ]);
   }
 
@@ -32,6 +32,9 @@ const { sendRequest, BASE_URL } = require('./common');
     assert.equal(body.run.stdout, 'Hello world\n');
     assert.equal(body.run.stderr, '');
     assert.ok(body.run.memory !== null && body.run.memory !== undefined);
+    assert.ok(['cgroup', 'rss'].includes(body.run.memory_source));
+    assert.ok(body.run.cpu_user_ms >= 0);
+    assert.ok(body.run.cpu_system_ms >= 0);
   }
 
   {
@@ -55,6 +58,8 @@ int main() {
     const body = JSON.parse(text);
     assert.equal(body.run.stdout, 'Hello\n');
     assert.equal(body.run.stderr, '');
+    assert.deepEqual(body.compile.stage, { name: 'compile', index: 0, attempt: 1 });
+    assert.deepEqual(body.run.stage, { name: 'run', index: 1, attempt: 1 });
     assert.ok(
       body.compile.memory !== null &&
         body.compile.memory !== undefined &&